@@ -1,25 +1,21 @@
 use std::time::Duration;
 
-use agg_ws::client::{BlockingClient, Channel, ChannelType, Exchange};
+use agg_ws::client::{BlockingClient, Channel, Exchange};
 
 fn main() {
     better_panic::install();
     tracing_subscriber::fmt::init();
 
-    let client = BlockingClient::new();
+    let client = BlockingClient::new().expect("client built");
 
-    let channel = Channel {
-        exchange: Exchange::Hyperliquid,
-        channel: ChannelType::Book,
-        market: "BTC".to_string(),
-    };
+    let channel = Channel::book(Exchange::Hyperliquid, "BTC");
 
     let sub = client.start_and_subscribe(channel.clone());
     tracing::info!("Subscribe: {:?}", sub);
 
-    for i in (1..2).rev() {
-        tracing::info!("Receiving updates.. {} seconds remaining.", i * 5);
-        std::thread::sleep(Duration::from_secs(5));
+    while !client.is_ready(channel.clone()).unwrap_or(true) {
+        tracing::info!("Waiting for snapshot..");
+        std::thread::sleep(Duration::from_millis(100));
     }
 
     let book = client.get_book(channel.clone());
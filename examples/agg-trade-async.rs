@@ -1,19 +1,20 @@
 use tokio::time::Duration;
 
-use agg_ws::client::{AsyncClient, Channel, ChannelType, ClientResp, ClientRespMsg, Exchange};
+use agg_ws::client::{AsyncClient, Channel, ClientResp, ClientRespMsg, Exchange};
 
 #[tokio::main]
 async fn main() {
     better_panic::install();
     tracing_subscriber::fmt::init();
 
-    let mut client = AsyncClient::new();
+    let mut client = AsyncClient::new().expect("client built");
 
-    let channel = Channel {
-        exchange: Exchange::Gdax,
-        channel: ChannelType::Tape,
-        market: "BTC-USD".to_string(),
-    };
+    let channel = Channel::builder()
+        .exchange(Exchange::Gdax)
+        .market("BTC-USD")
+        .tape()
+        .build()
+        .expect("channel fields set");
 
     let req = client.start_and_subscribe(channel.clone()).await;
     tracing::info!("Subscribe request: {:?}", req);
@@ -34,11 +35,13 @@ async fn main() {
     let resp = client.receiver.recv().await;
     tracing::info!("Tape response: {:?}", resp);
 
-    if let Some(Ok(ClientRespMsg { resp, .. })) = resp {
-        if let ClientResp::Tape(t) = resp {
-            for trade in t.iter().rev() {
-                tracing::info!("{:?}", trade);
-            }
+    if let Some(Ok(ClientRespMsg {
+        resp: ClientResp::Tape(t),
+        ..
+    })) = resp
+    {
+        for trade in t.iter().rev() {
+            tracing::info!("{:?}", trade);
         }
     }
 
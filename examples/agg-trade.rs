@@ -1,18 +1,14 @@
 use std::time::Duration;
 
-use agg_ws::client::{BlockingClient, Channel, ChannelType, Exchange};
+use agg_ws::client::{BlockingClient, Channel, Exchange};
 
 fn main() {
     better_panic::install();
     tracing_subscriber::fmt::init();
 
-    let client = BlockingClient::new();
+    let client = BlockingClient::new().expect("client built");
 
-    let channel = Channel {
-        exchange: Exchange::Hyperliquid,
-        channel: ChannelType::Tape,
-        market: "BTC".to_string(),
-    };
+    let channel = Channel::tape(Exchange::Hyperliquid, "BTC");
 
     let sub = client.start_and_subscribe(channel.clone());
     tracing::info!("Subscribe: {:?}", sub);
@@ -1,16 +1,54 @@
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Arc, Mutex};
 
-use chrono::Utc;
-use futures::SinkExt;
+use chrono::{DateTime, Duration, Utc};
+use futures::future::join_all;
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tokio::sync::mpsc;
+use tokio::time::{Duration as StdDuration, Instant};
 use tokio_tungstenite::tungstenite::Message;
 
-use crate::book::Book;
-use crate::client::{Channel, ChannelType, ClientReq, ClientResp, ClientRespMsg, Exchange, State};
+use crate::bitstamp::OrderBookDiff as BitstampDiff;
+use crate::book::{Book, BookCheck};
+use crate::candles::resample;
+use crate::client::{
+    Channel, ChannelType, ClientConfig, ClientReq, ClientResp, ClientRespMsg, Exchange,
+    LatencyMode, Responder, State, SubscribeConfig,
+};
 use crate::error::{Error, Result};
-use crate::websocket::Websocket;
+use crate::gdax::{Heartbeat, L2update};
+use crate::kraken::LevelTimes;
+use crate::liquidations::LIQUIDATION_CAPACITY;
+use crate::market::MarketMeta;
+use crate::orders::{FILL_CAPACITY, ORDER_CAPACITY};
+use crate::recorder::Recorder;
+use crate::rest::{book_url, http_client};
+use crate::sync::LockRecover;
+use crate::trades::{agg_trades, trade_rate, trades_since, vwap, Ema, TapeStats, TAPE_CAPACITY};
+use crate::websocket::{
+    ConnectionStatus, ErrorPolicy, Transport, Websocket, WsCommand, WsEvent, WsSender, WsTransport,
+};
+
+/// Max time to wait for socket tasks to exit on shutdown before they are aborted.
+pub const SHUTDOWN_TIMEOUT: StdDuration = StdDuration::from_secs(5);
+
+/// Default time allowed for the exchange to confirm the subscriptions sent on a new socket.
+pub const DEFAULT_SUBSCRIBE_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+/// Channels without a message for this many seconds are logged as stale on the client loop tick.
+pub const DEFAULT_STALE_SECS: i64 = 60;
+
+/// Default time between client loop maintenance ticks, which log stale channels and resubscribe
+/// errored channels.
+pub const DEFAULT_MAINTENANCE_INTERVAL: StdDuration = StdDuration::from_secs(15);
+
+/// Default time between checks of the subscription queue and unconfirmed subscriptions.
+pub const DEFAULT_QUEUE_INTERVAL: StdDuration = StdDuration::from_millis(100);
+
+/// Shortest client loop tick. Configured intervals below it, e.g. zero, are raised to it.
+pub const MIN_TICK_INTERVAL: StdDuration = StdDuration::from_millis(1);
 
 /// App manages all Client requests, Websocket messages and data State. App is created during the
 /// initialization of a new Client. App can be updated by receiving requests from the Client as well
@@ -18,13 +56,13 @@ use crate::websocket::Websocket;
 ///
 /// Examples of Client requests:
 ///
-///      Subscribe - Subscribe to a new websocket channel. Data is sent from Websocket to Client where it is stored in the App.
+/// - Subscribe - Subscribe to a new websocket channel. Data is sent from Websocket to Client where it is stored in the App.
 ///
-///      Unsubscribe - Unsubscribe and drop an existing websocket channel.
+/// - Unsubscribe - Unsubscribe and drop an existing websocket channel.
 ///
-///      Trades - Returns the last 100 trades sent from the given Websocket channel.
+/// - Trades - Returns the last 100 trades sent from the given Websocket channel.
 ///
-///      Trades Agg - Returns the last 100 aggregated trades sent from the given Websocket channels.
+/// - Trades Agg - Returns the last 100 aggregated trades sent from the given Websocket channels.
 ///
 ///
 #[derive(Debug)]
@@ -38,119 +76,238 @@ pub struct App {
     pub state: Arc<State>,
     // Used to send messages from websockets to client runtime where they are processed
     // by the App. Clone and use in each new socket subscription.
-    pub ws_sender: mpsc::UnboundedSender<(Channel, Result<Message>)>,
+    pub ws_sender: WsSender,
     // Queue for new subscription requests. Used to adhere to rate limits for subscriptions
     // imposed by exchanges. If enough time has lapsed since last sub and there is a sub
     // in the queue - client will process the subscription.
-    pub sub_queue: HashMap<Exchange, HashSet<Channel>>,
+    pub sub_queue: HashMap<Exchange, VecDeque<PendingSub>>,
+    // Time of the last socket opened for each exchange.
+    pub last_sub: HashMap<Exchange, Instant>,
+    // Max depth requested for book channels on subscribe. Books are trimmed to this many levels
+    // on each side after every update.
+    pub book_depths: HashMap<Channel, usize>,
+    // Update cadence requested for book channels on subscribe. Channels without an entry use
+    // LatencyMode::Batched.
+    pub latency_modes: HashMap<Channel, LatencyMode>,
+    // EMA of the trade price for each tape channel, updated on every trade
+    pub emas: HashMap<Channel, Ema>,
+    // Smallest trade kept on each filtered tape channel
+    pub min_sizes: HashMap<Channel, Decimal>,
+    // Channels resubscribed on the next interval tick. Book channels that failed to handle a
+    // message and Coinbase private channels whose socket reconnected with an expired signature.
+    pub errored: HashSet<Channel>,
+    // Timestamp of the last update applied at each level of Kraken books
+    pub kraken_level_times: HashMap<Channel, LevelTimes>,
+    // Price and size precision of subscribed markets listed in the market table
+    pub market_meta: HashMap<Channel, MarketMeta>,
+    // Consecutive updates each book channel was crossed on when uncross_books is set. Updated
+    // while the books lock is held so kept behind its own lock.
+    pub crossed_books: Mutex<HashMap<Channel, usize>>,
+    // Updates applied to each book channel since its last snapshot, used by check_book and
+    // flag_resnapshot_books. Updated while the books lock is held so kept behind its own lock.
+    pub book_checks: Mutex<HashMap<Channel, BookCheck>>,
+    // Book channels holding their first snapshot since subscribing or resubscribing. Set as the
+    // book is published so kept behind its own lock.
+    pub ready_books: Mutex<HashSet<Channel>>,
+    // Last book published on each channel streaming deltas, the base the next delta is computed
+    // from. Set as the book is published so kept behind its own lock.
+    pub book_deltas: Mutex<HashMap<Channel, Arc<Book>>>,
+    // Last heartbeat received on each Coinbase Pro channel, used to detect missed messages
+    pub gdax_heartbeats: HashMap<Channel, Heartbeat>,
+    // Sequence of the last ticker applied to each Coinbase Pro tape channel, used to drop
+    // reordered and duplicate ticks
+    pub gdax_sequences: HashMap<Channel, u64>,
+    // Coinbase Pro book channels waiting for their snapshot, with the l2updates received so far.
+    // The updates are applied on top of the snapshot once it arrives.
+    pub gdax_pending_updates: HashMap<Channel, Vec<L2update>>,
+    // Time of the REST book each Coinbase Pro book waiting for its snapshot was seeded from
+    pub gdax_rest_times: HashMap<Channel, DateTime<Utc>>,
+    // Diffs received on each Bitstamp book before its REST snapshot, applied once it arrives
+    pub bitstamp_pending_diffs: HashMap<Channel, Vec<BitstampDiff>>,
+    // Book channels subscribed with rest_snapshot, seeded over REST whenever the book is reset
+    pub rest_books: HashSet<Channel>,
+    // Last change_id applied to each Deribit book channel, used to detect missed messages
+    pub deribit_change_ids: HashMap<Channel, u64>,
+    // Hyperliquid tape channels merging trades that share a hash into a single fill.
+    pub aggregate_fills: HashSet<Channel>,
+    // Sockets whose Start request is answered once the exchange confirms the subscriptions,
+    // keyed by the socket channel. Kraken confirmations are matched by reqid, Coinbase Pro and
+    // Hyperliquid confirmations by the socket they arrive on.
+    pub unconfirmed: HashMap<Channel, UnconfirmedSub>,
+    // Socket channel each outstanding Kraken subscribe reqid was sent on
+    pub kraken_reqids: HashMap<i64, Channel>,
+    // Channels sharing one socket, keyed by the lead channel the socket is stored under. Holds
+    // every market of a channel group or every channel type of a channel bundle.
+    pub groups: HashMap<Channel, Vec<Channel>>,
+    // Recorders appending the raw messages received on each recorded socket, keyed by the
+    // socket channel.
+    pub recorders: HashMap<Channel, Recorder>,
+    // Channels streaming each new trade or book update to the async client as it arrives.
+    pub streams: HashSet<Channel>,
     // Used to send responses from App back to async client
     pub app_sender: Option<mpsc::UnboundedSender<Result<ClientRespMsg>>>,
+    // Client configuration applied to every socket opened by the App.
+    pub config: ClientConfig,
+    // HTTP client fetching REST book snapshots, built from the config proxy and TLS connector.
+    pub http: reqwest::Client,
+    // Opens the sockets. WsTransport outside of tests.
+    pub transport: Arc<dyn Transport>,
 }
 
 impl App {
     pub fn new(
-        ws_sender: mpsc::UnboundedSender<(Channel, Result<Message>)>,
+        ws_sender: WsSender,
         app_sender: Option<mpsc::UnboundedSender<Result<ClientRespMsg>>>,
-    ) -> Self {
-        Self {
+        config: ClientConfig,
+    ) -> Result<Self> {
+        let http = http_client(&config)?;
+        Ok(Self {
             sockets: Mutex::new(HashMap::new()),
             state: Arc::new(State::new()),
             ws_sender,
             sub_queue: HashMap::new(),
+            last_sub: HashMap::new(),
+            book_depths: HashMap::new(),
+            latency_modes: HashMap::new(),
+            emas: HashMap::new(),
+            min_sizes: HashMap::new(),
+            errored: HashSet::new(),
+            kraken_level_times: HashMap::new(),
+            gdax_heartbeats: HashMap::new(),
+            gdax_sequences: HashMap::new(),
+            gdax_pending_updates: HashMap::new(),
+            gdax_rest_times: HashMap::new(),
+            bitstamp_pending_diffs: HashMap::new(),
+            rest_books: HashSet::new(),
+            deribit_change_ids: HashMap::new(),
+            crossed_books: Mutex::new(HashMap::new()),
+            book_checks: Mutex::new(HashMap::new()),
+            ready_books: Mutex::new(HashSet::new()),
+            book_deltas: Mutex::new(HashMap::new()),
+            market_meta: HashMap::new(),
+            streams: HashSet::new(),
+            recorders: HashMap::new(),
+            aggregate_fills: HashSet::new(),
+            groups: HashMap::new(),
+            unconfirmed: HashMap::new(),
+            kraken_reqids: HashMap::new(),
             app_sender,
-        }
+            config,
+            http,
+            transport: Arc::new(WsTransport),
+        })
     }
 
     #[tracing::instrument(skip(self))]
     pub async fn handle_client_req(&mut self, req: ClientReq) {
         match req {
-            ClientReq::Start { channel, resp, .. } => {
-                // Create hashmap entry for the state
-                let state_setup = match channel.channel {
-                    ChannelType::Tape => {
-                        let mut tapes = self.state.tapes.lock().unwrap();
-                        if !tapes.contains_key(&channel) {
-                            tapes.insert(channel.clone(), VecDeque::with_capacity(100));
-                            Ok(())
-                        } else {
-                            Err(Error::ChannelAlreadySubscribed)
-                        }
+            ClientReq::Start {
+                channel,
+                config,
+                resp,
+            } => {
+                // Open the recording before creating state so a bad path leaves nothing behind
+                let recorder = config.record.as_deref().map(Recorder::create).transpose();
+                let response = recorder.and_then(|r| {
+                    if config.rest_snapshot {
+                        self.check_rest_book(&channel)?;
                     }
-                    ChannelType::Book => {
-                        let mut books = self.state.books.lock().unwrap();
-                        if !books.contains_key(&channel) {
-                            books.insert(channel.clone(), Book::new());
-                            Ok(())
-                        } else {
-                            Err(Error::ChannelAlreadySubscribed)
+                    self.setup_state(&channel, config.max_depth)?;
+                    Ok(r)
+                });
+                match response {
+                    Ok(recorder) => {
+                        if let Some(r) = recorder {
+                            self.recorders.insert(channel.clone(), r);
                         }
-                    }
-                };
-                let response = match state_setup {
-                    Ok(_) => {
-                        match Websocket::new(self.ws_sender.clone(), channel.clone()).await {
-                            Ok(ws) => {
-                                // Store the socket
-                                tracing::info!("Websocket created for channel.");
-                                let mut sockets = self.sockets.lock().unwrap();
-                                sockets.insert(channel.clone(), ws);
-                                Ok(())
-                            }
-                            Err(e) => Err(e),
-                        }
-                    }
-                    Err(e) => Err(e),
-                };
-                // Ignore errors - send response via oneshot or mpsc channel based on async or block
-                match resp {
-                    Some(r) => {
-                        let _ = r.send(response);
-                    }
-                    None => {
-                        let client_resp_msg = match response {
-                            Ok(_) => Ok(ClientRespMsg {
+                        self.apply_subscribe_config(&channel, &config);
+                        // Queue the subscription and open it now if the exchange rate limit allows
+                        self.sub_queue
+                            .entry(channel.exchange)
+                            .or_default()
+                            .push_back(PendingSub {
                                 channel,
-                                resp: ClientResp::Subscribed,
-                            }),
-                            Err(e) => Err(e),
-                        };
-                        let _ = self.app_sender.as_ref().unwrap().send(client_resp_msg);
+                                members: None,
+                                resp,
+                            });
+                        self.process_sub_queue().await;
                     }
+                    Err(e) => self.respond(channel, resp, Err(e), |_| ClientResp::Subscribed),
                 }
             }
-            ClientReq::Stop { channel, resp } => {
-                let mut sockets = self.sockets.lock().unwrap();
-                let socket = sockets.remove(&channel);
-                let response = match socket {
-                    Some(mut ws) => {
-                        // Send unsub message
-                        let unsub = channel.unsubscribe_message();
-                        let _ = ws.write.send(Message::Text(unsub.to_string())).await;
-                        // Send the kill shot to the socket
-                        let _ = ws.killshot.send(true);
-                        Ok(())
-                    }
-                    None => Err(Error::SocketDoesNotExist),
+            ClientReq::StartGroup {
+                group,
+                config,
+                resp,
+            } => {
+                let members = match group.is_supported() {
+                    true => group.channels(),
+                    false => Vec::new(),
                 };
-                // Ignore errors - send response via oneshot or mpsc channel based on async or block
-                match resp {
-                    Some(r) => {
-                        let _ = r.send(response);
+                self.start_shared(members, config, resp).await;
+            }
+            ClientReq::StartBundle {
+                bundle,
+                config,
+                resp,
+            } => {
+                let members = match bundle.is_supported() {
+                    true => bundle.channels(),
+                    false => Vec::new(),
+                };
+                self.start_shared(members, config, resp).await;
+            }
+            ClientReq::Stop {
+                channel,
+                keep_state,
+                resp,
+            } => {
+                self.streams.remove(&channel);
+                self.book_deltas.lock_recover().remove(&channel);
+                self.aggregate_fills.remove(&channel);
+                self.recorders.remove(&channel);
+                let response = match self.dequeue_sub(&channel) {
+                    Some(pending) => {
+                        let members = pending
+                            .members
+                            .clone()
+                            .unwrap_or_else(|| vec![channel.clone()]);
+                        self.respond(
+                            channel.clone(),
+                            pending.resp,
+                            Err(Error::SubscriptionCancelled),
+                            |_| ClientResp::Subscribed,
+                        );
+                        Ok(members)
                     }
                     None => {
-                        let client_resp_msg = match response {
-                            Ok(_) => Ok(ClientRespMsg {
-                                channel,
-                                resp: ClientResp::Unsubscribed,
-                            }),
-                            Err(e) => Err(e),
-                        };
-                        let _ = self.app_sender.as_ref().unwrap().send(client_resp_msg);
+                        self.cancel_unconfirmed(&channel);
+                        let members = self.socket_members(&channel);
+                        match self.close_socket(&channel).await {
+                            // State kept by an earlier Stop with keep_state
+                            Err(Error::SocketDoesNotExist)
+                                if self.has_state(&channel) && !self.is_active(&channel) =>
+                            {
+                                Ok(vec![channel.clone()])
+                            }
+                            response => response.map(|_| members),
+                        }
+                    }
+                };
+                if let (false, Ok(members)) = (keep_state, response.as_ref()) {
+                    for member in members {
+                        self.clear_state(member);
                     }
                 }
+                let response = response.map(|_| ());
+                self.respond(channel, resp, response, |_| ClientResp::Unsubscribed);
+            }
+            ClientReq::StopExchange { exchange, resp } => {
+                let stopped = self.stop_exchange(exchange).await;
+                self.respond(None, resp, Ok(stopped), ClientResp::ExchangeStopped);
             }
             ClientReq::Tape { channel, resp } => {
-                let tapes = self.state.tapes.lock().unwrap();
+                let tapes = self.state.tapes.lock_recover();
                 let tape = tapes.get(&channel);
                 let response = match tape {
                     Some(t) => {
@@ -159,97 +316,1347 @@ impl App {
                     }
                     None => Err(Error::ChannelDoesNotExist),
                 };
-                // Ignore errors - send response via oneshot or mpsc channel based on async or block
-                match resp {
-                    Some(r) => {
-                        let _ = r.send(response);
-                    }
-                    None => {
-                        let client_resp_msg = match response {
-                            Ok(trades) => Ok(ClientRespMsg {
-                                channel,
-                                resp: ClientResp::Tape(trades),
-                            }),
-                            Err(e) => Err(e),
-                        };
-                        let _ = self.app_sender.as_ref().unwrap().send(client_resp_msg);
-                    }
-                }
+                self.respond(channel, resp, response, ClientResp::Tape);
             }
-            ClientReq::Book { channel, resp } => {
-                let books = self.state.books.lock().unwrap();
-                let book = books.get(&channel);
-                let response = match book {
-                    Some(b) => {
-                        let b = b.clone();
-                        Ok(b)
-                    }
+            ClientReq::TapeSince {
+                channel,
+                since,
+                resp,
+            } => {
+                let response = match self.state.tapes.lock_recover().get(&channel) {
+                    Some(t) => Ok(trades_since(t, since)),
                     None => Err(Error::ChannelDoesNotExist),
                 };
-                match resp {
-                    Some(r) => {
-                        let _ = r.send(response);
-                    }
-                    None => {
-                        let client_resp_msg = match response {
-                            Ok(book) => Ok(ClientRespMsg {
-                                channel,
-                                resp: ClientResp::Book(book),
-                            }),
-                            Err(e) => Err(e),
-                        };
-                        let _ = self.app_sender.as_ref().unwrap().send(client_resp_msg);
-                    }
-                }
+                self.respond(channel, resp, response, ClientResp::Tape);
+            }
+            ClientReq::Liquidations { channel, resp } => {
+                let response = match self.state.liquidations.lock_recover().get(&channel) {
+                    Some(l) => Ok(l.clone()),
+                    None => Err(Error::ChannelDoesNotExist),
+                };
+                self.respond(channel, resp, response, ClientResp::Liquidations);
+            }
+            ClientReq::Fills { channel, resp } => {
+                let response = match self.state.fills.lock_recover().get(&channel) {
+                    Some(f) => Ok(f.clone()),
+                    None => Err(Error::ChannelDoesNotExist),
+                };
+                self.respond(channel, resp, response, ClientResp::Fills);
+            }
+            ClientReq::Orders { channel, resp } => {
+                let response = match self.state.orders.lock_recover().get(&channel) {
+                    Some(o) => Ok(o.clone()),
+                    None => Err(Error::ChannelDoesNotExist),
+                };
+                self.respond(channel, resp, response, ClientResp::Orders);
+            }
+            ClientReq::Funding { channel, resp } => {
+                let response = match self.state.funding.lock_recover().get(&channel) {
+                    Some(f) => f.clone().ok_or(Error::FundingUnavailable),
+                    None => Err(Error::ChannelDoesNotExist),
+                };
+                self.respond(channel, resp, response, ClientResp::Funding);
+            }
+            ClientReq::MarkPrice { channel, resp } => {
+                let response = match self.state.mark_prices.lock_recover().get(&channel) {
+                    Some(m) => m.clone().ok_or(Error::MarkPriceUnavailable),
+                    None => Err(Error::ChannelDoesNotExist),
+                };
+                self.respond(channel, resp, response, ClientResp::MarkPrice);
+            }
+            ClientReq::TapeStats { channel, resp } => {
+                let response = match self.state.tape_stats.lock_recover().get(&channel) {
+                    Some(s) => Ok(s.clone()),
+                    None => Err(Error::ChannelDoesNotExist),
+                };
+                self.respond(channel, resp, response, ClientResp::TapeStats);
+            }
+            ClientReq::Vwap { channel, resp } => {
+                let response = match self.state.tapes.lock_recover().get(&channel) {
+                    Some(t) => vwap(t),
+                    None => Err(Error::ChannelDoesNotExist),
+                };
+                self.respond(channel, resp, response, ClientResp::Vwap);
+            }
+            ClientReq::AggTrades { channel, resp } => {
+                let response = match self.state.tapes.lock_recover().get(&channel) {
+                    Some(t) => Ok(agg_trades(t)),
+                    None => Err(Error::ChannelDoesNotExist),
+                };
+                self.respond(channel, resp, response, ClientResp::AggTrades);
+            }
+            ClientReq::TradeRate {
+                channel,
+                window,
+                resp,
+            } => {
+                let response = match self.state.tapes.lock_recover().get(&channel) {
+                    Some(t) => Ok(trade_rate(t, Utc::now(), window)),
+                    None => Err(Error::ChannelDoesNotExist),
+                };
+                self.respond(channel, resp, response, ClientResp::TradeRate);
+            }
+            ClientReq::TapeAgg {
+                market,
+                channels,
+                resp,
+            } => {
+                let response = self.tape_agg(&channels);
+                self.respond(None, resp, response, |tape| ClientResp::TapeAgg {
+                    market,
+                    tape,
+                });
+            }
+            ClientReq::Book { channel, resp } => {
+                let response = self.read_book(&channel).ok_or(Error::ChannelDoesNotExist);
+                self.respond(channel, resp, response, ClientResp::Book);
+            }
+            ClientReq::TapesBatch { channels, resp } => {
+                let response = self.tapes_batch(&channels);
+                self.respond(None, resp, response, ClientResp::Tapes);
+            }
+            ClientReq::BooksBatch { channels, resp } => {
+                let response = self.books_batch(&channels);
+                self.respond(None, resp, response, ClientResp::Books);
+            }
+            ClientReq::BookAgg {
+                channels,
+                precision,
+                resp,
+            } => {
+                let response = self.book_agg(&channels, precision);
+                self.respond(None, resp, response, ClientResp::BookAgg);
             }
             ClientReq::Last { channel, resp } => {
-                let sockets = self.sockets.lock().unwrap();
-                let response = match sockets.get(&channel) {
+                let sockets = self.sockets.lock_recover();
+                let response = match sockets.get(&self.socket_key(&channel)) {
                     Some(ws) => Ok(ws.last_message),
                     None => Err(Error::SocketDoesNotExist),
                 };
-                match resp {
-                    Some(r) => {
-                        let _ = r.send(response);
+                self.respond(channel, resp, response, ClientResp::Last);
+            }
+            ClientReq::LastAge { channel, resp } => {
+                let sockets = self.sockets.lock_recover();
+                let response = match sockets.get(&self.socket_key(&channel)) {
+                    Some(ws) => Ok(Utc::now() - ws.last_message),
+                    None => Err(Error::SocketDoesNotExist),
+                };
+                self.respond(channel, resp, response, ClientResp::LastAge);
+            }
+            ClientReq::Candles {
+                channel,
+                interval,
+                resp,
+            } => {
+                let candles = self.state.candles.lock_recover();
+                let response = match candles.get(&channel) {
+                    Some(c) => resample(c, interval),
+                    None => Err(Error::ChannelDoesNotExist),
+                };
+                self.respond(channel, resp, response, ClientResp::Candles);
+            }
+            ClientReq::LastTrade { channel, resp } => {
+                let response = if channel.channel != ChannelType::Tape {
+                    Err(Error::ChannelResponseMismatch)
+                } else {
+                    match self.state.tapes.lock_recover().get(&channel) {
+                        Some(t) => Ok(t.back().cloned()),
+                        None => Err(Error::ChannelDoesNotExist),
                     }
-                    None => {
-                        let client_resp_msg = match response {
-                            Ok(dt) => Ok(ClientRespMsg {
-                                channel,
-                                resp: ClientResp::Last(dt),
-                            }),
-                            Err(e) => Err(e),
-                        };
-                        let _ = self.app_sender.as_ref().unwrap().send(client_resp_msg);
+                };
+                self.respond(channel, resp, response, ClientResp::LastTrade);
+            }
+            ClientReq::Ema { channel, resp } => {
+                let response = match self.emas.get(&channel) {
+                    Some(ema) => Ok(ema.value),
+                    None if channel.channel != ChannelType::Tape => {
+                        Err(Error::ChannelResponseMismatch)
                     }
+                    None => Err(Error::ChannelDoesNotExist),
+                };
+                self.respond(channel, resp, response, ClientResp::Ema);
+            }
+            ClientReq::TopOfBook { channel, resp } => {
+                let response = self.with_book(&channel, |b, depth| {
+                    let top = b.top_of_book().filter(|_| depth > 0);
+                    top.ok_or(Error::BookSideEmpty)
+                });
+                self.respond(channel, resp, response, ClientResp::TopOfBook);
+            }
+            ClientReq::BookStats { channel, resp } => {
+                let response = self.with_book(&channel, |b, depth| Ok(b.stats_to_depth(depth)));
+                self.respond(channel, resp, response, ClientResp::BookStats);
+            }
+            ClientReq::Imbalance {
+                channel,
+                depth,
+                resp,
+            } => {
+                let response = self.with_book(&channel, |b, max| {
+                    b.imbalance(depth.min(max)).ok_or(Error::BookSideEmpty)
+                });
+                self.respond(channel, resp, response, ClientResp::Imbalance);
+            }
+            ClientReq::IsReady { channel, resp } => {
+                let response = if channel.channel != ChannelType::Book {
+                    Err(Error::ChannelResponseMismatch)
+                } else if !self.has_state(&channel) {
+                    Err(Error::ChannelDoesNotExist)
+                } else {
+                    Ok(self.ready_books.lock_recover().contains(&channel))
+                };
+                self.respond(channel, resp, response, ClientResp::IsReady);
+            }
+            ClientReq::Depth {
+                channel,
+                levels,
+                resp,
+            } => {
+                let response = self.with_book(&channel, |b, depth| Ok(b.depth(levels.min(depth))));
+                self.respond(channel, resp, response, ClientResp::Depth);
+            }
+            ClientReq::DepthChart {
+                channel,
+                side,
+                levels,
+                resp,
+            } => {
+                let response = self.with_book(&channel, |b, depth| {
+                    Ok(b.depth_chart(side, levels.min(depth)))
+                });
+                self.respond(channel, resp, response, ClientResp::DepthChart);
+            }
+            ClientReq::Sweep {
+                channel,
+                side,
+                notional,
+                resp,
+            } => {
+                let response = self.with_book(&channel, |b, depth| {
+                    Ok(b.sweep_to_depth(side, notional, depth))
+                });
+                self.respond(channel, resp, response, ClientResp::Sweep);
+            }
+            ClientReq::StaleChannels { threshold, resp } => {
+                let response = Ok(self.stale_channels(threshold));
+                self.respond(None, resp, response, ClientResp::StaleChannels);
+            }
+            ClientReq::MarketMeta { channel, resp } => {
+                let response = match self.market_meta.get(&channel) {
+                    Some(meta) => Ok(*meta),
+                    None => Err(Error::MarketMetaUnavailable),
+                };
+                self.respond(channel, resp, response, ClientResp::MarketMeta);
+            }
+            ClientReq::ListSubscriptions { resp } => {
+                let response = Ok(self.live_subscriptions());
+                self.respond(None, resp, response, ClientResp::Subscriptions);
+            }
+            ClientReq::Metrics { resp } => {
+                let response = Ok(self.metrics());
+                self.respond(None, resp, response, ClientResp::Metrics);
+            }
+            ClientReq::Shutdown { resp } => {
+                let response = self.shutdown().await;
+                self.respond(None, resp, response, |_| ClientResp::Shutdown);
+            }
+            ClientReq::Replay {
+                channel,
+                messages,
+                resp,
+            } => {
+                let response = self.replay(channel.clone(), messages).await;
+                self.respond(channel, resp, response, |_| ClientResp::Replayed);
+            }
+            ClientReq::AggregateFills { channel, resp } => {
+                let response = self.start_aggregate_fills(&channel);
+                self.respond(channel, resp, response, |_| ClientResp::AggregatingFills);
+            }
+            ClientReq::StreamTrades { channel, resp } => {
+                let response = self.start_stream(&channel, ChannelType::Tape);
+                self.respond(channel, resp, response, |_| ClientResp::Streaming);
+            }
+            ClientReq::StreamBook { channel, resp } => {
+                let response = self.start_stream(&channel, ChannelType::Book);
+                self.respond(channel, resp, response, |_| ClientResp::Streaming);
+            }
+            ClientReq::StreamBookDeltas { channel, resp } => {
+                let response = self.start_delta_stream(&channel);
+                self.respond(channel, resp, response, |_| ClientResp::Streaming);
+            }
+        }
+    }
+
+    /// Creates the tape and candles or book entry for the channel.
+    pub fn setup_state(&mut self, channel: &Channel, max_depth: Option<usize>) -> Result<()> {
+        // Reject channel types the exchange has no feed for before creating any state
+        self.subscribe_message(channel)?;
+        if self.has_state(channel) {
+            if self.is_active(channel) {
+                return Err(Error::ChannelAlreadySubscribed);
+            }
+            // State kept by a Stop with keep_state is replaced by the new subscription
+            self.clear_state(channel);
+        }
+        match channel.channel {
+            ChannelType::Tape => {
+                let mut tapes = self.state.tapes.lock_recover();
+                tapes.insert(channel.clone(), VecDeque::with_capacity(TAPE_CAPACITY));
+                let mut candles = self.state.candles.lock_recover();
+                candles.insert(channel.clone(), VecDeque::new());
+                let mut stats = self.state.tape_stats.lock_recover();
+                stats.insert(channel.clone(), TapeStats::default());
+                self.emas.insert(channel.clone(), Ema::default());
+            }
+            ChannelType::Book => {
+                let mut books = self.state.books.lock_recover();
+                books.insert(channel.clone(), Book::new());
+                if channel.exchange == Exchange::Gdax {
+                    self.gdax_pending_updates
+                        .insert(channel.clone(), Vec::new());
+                }
+                if channel.exchange == Exchange::Bitstamp {
+                    self.bitstamp_pending_diffs
+                        .insert(channel.clone(), Vec::new());
+                }
+                if let Some(depth) = max_depth {
+                    self.book_depths.insert(channel.clone(), depth);
+                }
+            }
+            ChannelType::Liquidations => {
+                let mut liquidations = self.state.liquidations.lock_recover();
+                liquidations.insert(
+                    channel.clone(),
+                    VecDeque::with_capacity(LIQUIDATION_CAPACITY),
+                );
+            }
+            ChannelType::Funding => {
+                let mut funding = self.state.funding.lock_recover();
+                funding.insert(channel.clone(), None);
+            }
+            ChannelType::MarkPrice => {
+                let mut marks = self.state.mark_prices.lock_recover();
+                marks.insert(channel.clone(), None);
+            }
+            ChannelType::Fills => {
+                let mut fills = self.state.fills.lock_recover();
+                fills.insert(channel.clone(), VecDeque::with_capacity(FILL_CAPACITY));
+            }
+            ChannelType::Orders => {
+                let mut orders = self.state.orders.lock_recover();
+                orders.insert(channel.clone(), VecDeque::with_capacity(ORDER_CAPACITY));
+            }
+        }
+        if let Some(meta) = MarketMeta::lookup(&channel.exchange, &channel.market) {
+            self.market_meta.insert(channel.clone(), meta);
+        }
+        Ok(())
+    }
+
+    /// Removes the State entries created by setup_state and any per channel bookkeeping for the
+    /// channel. Called when the channel is unsubscribed unless the caller keeps the state.
+    fn clear_state(&mut self, channel: &Channel) {
+        match channel.channel {
+            ChannelType::Tape => {
+                self.state.tapes.lock_recover().remove(channel);
+                self.state.candles.lock_recover().remove(channel);
+                self.state.tape_stats.lock_recover().remove(channel);
+                self.emas.remove(channel);
+                self.min_sizes.remove(channel);
+                self.gdax_sequences.remove(channel);
+                self.gdax_heartbeats.remove(channel);
+            }
+            ChannelType::Book => {
+                self.state.books.lock_recover().remove(channel);
+                self.book_depths.remove(channel);
+                self.latency_modes.remove(channel);
+                self.kraken_level_times.remove(channel);
+                self.gdax_heartbeats.remove(channel);
+                self.gdax_pending_updates.remove(channel);
+                self.gdax_rest_times.remove(channel);
+                self.bitstamp_pending_diffs.remove(channel);
+                self.rest_books.remove(channel);
+                self.deribit_change_ids.remove(channel);
+                self.crossed_books.lock_recover().remove(channel);
+                self.book_checks.lock_recover().remove(channel);
+                self.ready_books.lock_recover().remove(channel);
+            }
+            ChannelType::Liquidations => {
+                self.state.liquidations.lock_recover().remove(channel);
+            }
+            ChannelType::Funding => {
+                self.state.funding.lock_recover().remove(channel);
+            }
+            ChannelType::MarkPrice => {
+                self.state.mark_prices.lock_recover().remove(channel);
+            }
+            ChannelType::Fills => {
+                self.state.fills.lock_recover().remove(channel);
+            }
+            ChannelType::Orders => {
+                self.state.orders.lock_recover().remove(channel);
+            }
+        }
+        self.market_meta.remove(channel);
+        self.errored.remove(channel);
+    }
+
+    /// Whether the channel has an open socket, either its own or a shared one, or is queued to
+    /// open one.
+    fn is_active(&self, channel: &Channel) -> bool {
+        let key = self.socket_key(channel);
+        self.sockets.lock_recover().contains_key(&key)
+            || self.sub_queue.get(&channel.exchange).is_some_and(|queue| {
+                queue.iter().any(|p| {
+                    p.channel == *channel || p.members.as_ref().is_some_and(|m| m.contains(channel))
+                })
+            })
+    }
+
+    fn has_state(&self, channel: &Channel) -> bool {
+        match channel.channel {
+            ChannelType::Tape => self.state.tapes.lock_recover().contains_key(channel),
+            ChannelType::Book => self.state.books.lock_recover().contains_key(channel),
+            ChannelType::Liquidations => {
+                self.state.liquidations.lock_recover().contains_key(channel)
+            }
+            ChannelType::Funding => self.state.funding.lock_recover().contains_key(channel),
+            ChannelType::MarkPrice => self.state.mark_prices.lock_recover().contains_key(channel),
+            ChannelType::Fills => self.state.fills.lock_recover().contains_key(channel),
+            ChannelType::Orders => self.state.orders.lock_recover().contains_key(channel),
+        }
+    }
+
+    /// Subscription for the channel. Private channels and the other subscriptions described by
+    /// Channel::is_signed are signed with the credentials configured for the exchange.
+    fn subscribe_message(&self, channel: &Channel) -> Result<Value> {
+        if channel.is_signed(self.latency_mode(channel)) {
+            let credentials = self
+                .config
+                .credentials
+                .get(&channel.exchange)
+                .ok_or(Error::CredentialsRequired)?;
+            channel.subscribe_message_private(credentials)
+        } else {
+            channel.subscribe_message_with(self.latency_mode(channel))
+        }
+    }
+
+    fn unsubscribe_message(&self, channel: &Channel) -> Result<Value> {
+        match self.config.credentials.get(&channel.exchange) {
+            Some(credentials) if channel.channel.is_private() => {
+                channel.unsubscribe_message_private(credentials)
+            }
+            _ => channel.unsubscribe_message_with(self.latency_mode(channel)),
+        }
+    }
+
+    fn latency_mode(&self, channel: &Channel) -> LatencyMode {
+        self.latency_modes.get(channel).copied().unwrap_or_default()
+    }
+
+    /// Opens the next queued subscription for each exchange whose minimum subscription interval
+    /// has elapsed. Called on each new subscription and on the client loop queue tick.
+    pub async fn process_sub_queue(&mut self) {
+        let now = Instant::now();
+        let ready: Vec<Exchange> = self
+            .sub_queue
+            .iter()
+            .filter(|(exchange, queue)| !queue.is_empty() && self.sub_ready(exchange, now))
+            .map(|(exchange, _)| *exchange)
+            .collect();
+        for exchange in ready {
+            if let Some(pending) = self
+                .sub_queue
+                .get_mut(&exchange)
+                .and_then(|q| q.pop_front())
+            {
+                self.last_sub.insert(exchange, Instant::now());
+                self.open_socket(pending).await;
+            }
+        }
+    }
+
+    /// True if the minimum subscription interval for the exchange has elapsed since the last
+    /// socket was opened.
+    pub fn sub_ready(&self, exchange: &Exchange, now: Instant) -> bool {
+        match self.last_sub.get(exchange) {
+            Some(last) => now.duration_since(*last) >= self.config.sub_interval(exchange),
+            None => true,
+        }
+    }
+
+    /// Queues one socket for channels sharing it, stored under the first channel. Every member is
+    /// checked before any state is created so a rejected request leaves no partial subscription
+    /// behind. An empty member list is rejected as unsupported.
+    async fn start_shared(
+        &mut self,
+        members: Vec<Channel>,
+        config: SubscribeConfig,
+        resp: Option<Responder<()>>,
+    ) {
+        let lead = match members.first() {
+            Some(lead) => lead.clone(),
+            None => {
+                self.respond(None, resp, Err(Error::UnsupportedChannel), |_| {
+                    ClientResp::Subscribed
+                });
+                return;
+            }
+        };
+        let recorder = config.record.as_deref().map(Recorder::create).transpose();
+        let state_setup = recorder.and_then(|r| {
+            if members.iter().any(|c| self.has_state(c)) {
+                return Err(Error::ChannelAlreadySubscribed);
+            }
+            if config.rest_snapshot {
+                members
+                    .iter()
+                    .filter(|c| c.channel == ChannelType::Book)
+                    .try_for_each(|c| self.check_rest_book(c))?;
+            }
+            members
+                .iter()
+                .try_for_each(|c| self.setup_state(c, config.max_depth))?;
+            Ok(r)
+        });
+        match state_setup {
+            Ok(recorder) => {
+                // Every member's messages arrive on the lead's socket
+                if let Some(r) = recorder {
+                    self.recorders.insert(lead.clone(), r);
+                }
+                for member in members.iter() {
+                    self.apply_subscribe_config(member, &config);
+                }
+                self.sub_queue
+                    .entry(lead.exchange)
+                    .or_default()
+                    .push_back(PendingSub {
+                        channel: lead,
+                        members: Some(members),
+                        resp,
+                    });
+                self.process_sub_queue().await;
+            }
+            Err(e) => self.respond(lead, resp, Err(e), |_| ClientResp::Subscribed),
+        }
+    }
+
+    /// Applies the options of a subscription to the channel once its state is set up. Tape options
+    /// are ignored for other channel types and rest_snapshot for channels other than books.
+    fn apply_subscribe_config(&mut self, channel: &Channel, config: &SubscribeConfig) {
+        if config.latency != LatencyMode::Batched {
+            self.latency_modes.insert(channel.clone(), config.latency);
+        }
+        if let (Some(period), ChannelType::Tape) = (config.ema_period, &channel.channel) {
+            self.emas.insert(channel.clone(), Ema::new(period));
+        }
+        if let (Some(size), ChannelType::Tape) = (config.min_size, &channel.channel) {
+            self.min_sizes.insert(channel.clone(), size);
+        }
+        if config.rest_snapshot && channel.channel == ChannelType::Book {
+            self.rest_books.insert(channel.clone());
+            self.fetch_rest_book(channel);
+        }
+    }
+
+    /// Checks the book of the channel can be fetched over REST, for subscriptions with
+    /// rest_snapshot set.
+    fn check_rest_book(&self, channel: &Channel) -> Result<()> {
+        let base = self.config.rest_url(&channel.exchange);
+        book_url(&base.ok_or(Error::UnsupportedChannel)?, channel)?;
+        Ok(())
+    }
+
+    /// Removes the channel from the subscription queue if it has not been opened yet.
+    fn dequeue_sub(&mut self, channel: &Channel) -> Option<PendingSub> {
+        let queue = self.sub_queue.get_mut(&channel.exchange)?;
+        let i = queue.iter().position(|p| &p.channel == channel)?;
+        queue.remove(i)
+    }
+
+    /// Opens the socket for a dequeued subscription and responds to the original request.
+    async fn open_socket(&mut self, pending: PendingSub) {
+        let PendingSub {
+            channel,
+            members,
+            resp,
+        } = pending;
+        let subscribed = members.clone().unwrap_or_else(|| vec![channel.clone()]);
+        let subscriptions: Result<Vec<WsCommand>> = subscribed
+            .iter()
+            .map(|c| self.subscribe_command(c))
+            .collect();
+        let mut reqids = Vec::new();
+        let mut sub_count = 0;
+        self.send_status(&channel, ConnectionStatus::Connecting);
+        let response = match subscriptions {
+            Ok(subs) => {
+                reqids = subs
+                    .iter()
+                    .filter_map(|s| match s {
+                        WsCommand::Subscribe { message, .. } => message["reqid"].as_i64(),
+                        _ => None,
+                    })
+                    .collect();
+                sub_count = subs.len();
+                self.transport
+                    .open(
+                        self.ws_sender.clone(),
+                        channel.clone(),
+                        subs,
+                        self.config.socket_config(&channel.exchange),
+                    )
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        let response = match response {
+            Ok(ws) => {
+                // Store the socket
+                tracing::info!("Websocket created for channel.");
+                let mut sockets = self.sockets.lock_recover();
+                sockets.insert(channel.clone(), ws);
+                if let Some(m) = members {
+                    self.groups.insert(channel.clone(), m);
+                }
+                Ok(())
+            }
+            Err(e) => Err(e),
+        };
+        match response {
+            Ok(_) => self.send_status(&channel, ConnectionStatus::Connected),
+            Err(_) => {
+                self.send_status(&channel, ConnectionStatus::Closed);
+                // Nothing was subscribed so the channels can be started again
+                for member in subscribed.iter() {
+                    self.clear_state(member);
                 }
             }
         }
+        // Answered once the exchange confirms each subscription sent
+        if response.is_ok() && channel.exchange.confirms_subscriptions() {
+            for reqid in reqids.into_iter() {
+                self.kraken_reqids.insert(reqid, channel.clone());
+            }
+            let unconfirmed = UnconfirmedSub {
+                remaining: sub_count,
+                deadline: Instant::now() + self.config.subscribe_timeout,
+                resp,
+            };
+            self.unconfirmed.insert(channel, unconfirmed);
+            return;
+        }
+        self.set_status(&channel, ConnectionStatus::Subscribed);
+        self.respond(channel, resp, response, |_| ClientResp::Subscribed);
     }
 
-    #[tracing::instrument(skip(self, msg))]
-    pub async fn handle_ws_msg(&mut self, msg: (Channel, Result<Message>)) {
+    /// Updates the status of the socket stored under the channel and sends the transition to the
+    /// async client. Does nothing if the socket is not stored or the status is unchanged.
+    pub fn set_status(&self, channel: &Channel, status: ConnectionStatus) {
+        let changed = match self.sockets.lock_recover().get_mut(channel) {
+            Some(ws) if ws.status != status => {
+                ws.status = status;
+                true
+            }
+            _ => false,
+        };
+        if changed {
+            self.send_status(channel, status);
+        }
+    }
+
+    fn send_status(&self, channel: &Channel, status: ConnectionStatus) {
+        tracing::debug!(?status, "Socket status for {:?}", channel);
+        if let Some(sender) = self.app_sender.as_ref() {
+            let _ = sender.send(Ok(ClientRespMsg {
+                channel: Some(channel.clone()),
+                resp: ClientResp::Status {
+                    channel: channel.clone(),
+                    status,
+                },
+            }));
+        }
+    }
+
+    /// Answers a Start request still waiting on confirmation for a socket being closed.
+    fn cancel_unconfirmed(&mut self, channel: &Channel) {
+        if let Some(unconfirmed) = self.unconfirmed.remove(channel) {
+            self.kraken_reqids.retain(|_, c| c != channel);
+            self.respond(
+                channel.clone(),
+                unconfirmed.resp,
+                Err(Error::SubscriptionCancelled),
+                |_| ClientResp::Subscribed,
+            );
+        }
+    }
+
+    /// Counts a subscription confirmed by the exchange for a socket and answers its Start request
+    /// once every subscription sent is confirmed. A rejected subscription closes the socket and
+    /// clears the channel state so the subscription can be retried.
+    pub async fn confirm_sub(&mut self, channel: &Channel, result: Result<()>) {
+        let remaining = match self.unconfirmed.get_mut(channel) {
+            Some(u) => {
+                u.remaining = u.remaining.saturating_sub(1);
+                u.remaining
+            }
+            None => return,
+        };
+        if result.is_ok() && remaining > 0 {
+            return;
+        }
+        let unconfirmed = self.unconfirmed.remove(channel).unwrap();
+        self.kraken_reqids.retain(|_, c| c != channel);
+        if result.is_ok() {
+            self.set_status(channel, ConnectionStatus::Subscribed);
+        } else {
+            let members = self.socket_members(channel);
+            let _ = self.close_socket(channel).await;
+            for member in members.iter() {
+                self.streams.remove(member);
+                self.book_deltas.lock_recover().remove(member);
+                self.clear_state(member);
+            }
+        }
+        self.respond(channel.clone(), unconfirmed.resp, result, |_| {
+            ClientResp::Subscribed
+        });
+    }
+
+    /// Fails Start requests whose subscriptions were not confirmed within the subscribe timeout.
+    /// Called on the client loop queue tick.
+    pub async fn expire_unconfirmed(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<Channel> = self
+            .unconfirmed
+            .iter()
+            .filter(|(_, u)| u.deadline <= now)
+            .map(|(channel, _)| channel.clone())
+            .collect();
+        for channel in expired {
+            tracing::warn!("Subscription not confirmed for {:?}", channel);
+            self.confirm_sub(&channel, Err(Error::SubscribeTimeout))
+                .await;
+        }
+    }
+
+    /// Enables streaming for a subscribed channel of the given type.
+    fn start_stream(&mut self, channel: &Channel, channel_type: ChannelType) -> Result<()> {
+        if channel.channel != channel_type {
+            return Err(Error::ChannelResponseMismatch);
+        }
+        if !self
+            .sockets
+            .lock_recover()
+            .contains_key(&self.socket_key(channel))
+        {
+            return Err(Error::SocketDoesNotExist);
+        }
+        self.streams.insert(channel.clone());
+        Ok(())
+    }
+
+    /// Enables delta streaming for a subscribed book channel. The first delta carries every level
+    /// of the book as an addition.
+    fn start_delta_stream(&mut self, channel: &Channel) -> Result<()> {
+        if channel.channel != ChannelType::Book {
+            return Err(Error::ChannelResponseMismatch);
+        }
+        if !self
+            .sockets
+            .lock_recover()
+            .contains_key(&self.socket_key(channel))
+        {
+            return Err(Error::SocketDoesNotExist);
+        }
+        self.book_deltas
+            .lock_recover()
+            .insert(channel.clone(), Arc::default());
+        Ok(())
+    }
+
+    /// Channel the socket carrying this channel is stored under. Members of a shared socket map
+    /// to its lead, all other channels to themselves.
+    pub fn socket_key(&self, channel: &Channel) -> Channel {
+        self.groups
+            .iter()
+            .find(|(_, members)| members.contains(channel))
+            .map_or_else(|| channel.clone(), |(lead, _)| lead.clone())
+    }
+
+    /// Channel state is stored under for a message of the channel type on the socket channel.
+    /// Messages on a shared socket are routed by the market they carry and the type of message.
+    /// Messages matching no member fall back to the socket channel.
+    pub fn route(&self, channel: &Channel, kind: ChannelType, market: &str) -> Channel {
+        self.groups
+            .get(channel)
+            .and_then(|members| {
+                members
+                    .iter()
+                    .find(|c| c.channel == kind && c.market.eq_ignore_ascii_case(market))
+            })
+            .cloned()
+            .unwrap_or_else(|| channel.clone())
+    }
+
+    /// Like route, but errors with ChannelResponseMismatch if the message market is not the market
+    /// of the channel it routes to rather than attributing it to the socket channel.
+    pub fn route_exact(
+        &self,
+        channel: &Channel,
+        kind: ChannelType,
+        market: &str,
+    ) -> Result<Channel> {
+        let routed = self.route(channel, kind, market);
+        if routed.market.eq_ignore_ascii_case(market) {
+            Ok(routed)
+        } else {
+            tracing::error!("Message for {} sent on channel {:?}", market, routed);
+            Err(Error::ChannelResponseMismatch)
+        }
+    }
+
+    /// True if the socket stored under the channel carries messages of the channel type, either
+    /// as its own type or through a member sharing the socket.
+    pub fn carries(&self, channel: &Channel, kind: ChannelType) -> bool {
+        channel.channel == kind
+            || self
+                .groups
+                .get(channel)
+                .is_some_and(|members| members.iter().any(|c| c.channel == kind))
+    }
+
+    /// Socket channel and every channel sharing its socket.
+    fn socket_members(&self, channel: &Channel) -> Vec<Channel> {
+        match self.groups.get(channel) {
+            Some(members) => members.clone(),
+            None => vec![channel.clone()],
+        }
+    }
+
+    /// Subscriptions queued when the socket for the channel is opened.
+    fn subscriptions(&self, channel: &Channel) -> Result<Vec<WsCommand>> {
+        self.socket_members(channel)
+            .iter()
+            .map(|c| self.subscribe_command(c))
+            .collect()
+    }
+
+    fn subscribe_command(&self, channel: &Channel) -> Result<WsCommand> {
+        Ok(WsCommand::Subscribe {
+            channel: channel.clone(),
+            message: self.subscribe_message(channel)?,
+        })
+    }
+
+    /// Unsubscribe messages sent before the socket for the channel is closed.
+    fn unsubscriptions(&self, channel: &Channel) -> Vec<WsCommand> {
+        self.socket_members(channel)
+            .into_iter()
+            .filter_map(|c| {
+                let message = self.unsubscribe_message(&c).ok()?;
+                Some(WsCommand::Unsubscribe {
+                    channel: c,
+                    message,
+                })
+            })
+            .collect()
+    }
+
+    /// Enables merging of same hash fills for a subscribed Hyperliquid tape channel.
+    fn start_aggregate_fills(&mut self, channel: &Channel) -> Result<()> {
+        if channel.exchange != Exchange::Hyperliquid || channel.channel != ChannelType::Tape {
+            return Err(Error::UnsupportedChannel);
+        }
+        if !self
+            .sockets
+            .lock_recover()
+            .contains_key(&self.socket_key(channel))
+        {
+            return Err(Error::SocketDoesNotExist);
+        }
+        self.aggregate_fills.insert(channel.clone());
+        Ok(())
+    }
+
+    /// Ignore errors - send response via oneshot or mpsc channel based on async or block. Async
+    /// responses are wrapped in the ClientResp variant for the request.
+    fn respond<T>(
+        &self,
+        channel: impl Into<Option<Channel>>,
+        resp: Option<Responder<T>>,
+        response: Result<T>,
+        wrap: impl FnOnce(T) -> ClientResp,
+    ) {
+        match resp {
+            Some(r) => {
+                let _ = r.send(response);
+            }
+            None => {
+                let client_resp_msg = response.map(|t| ClientRespMsg {
+                    channel: channel.into(),
+                    resp: wrap(t),
+                });
+                let _ = self.app_sender.as_ref().unwrap().send(client_resp_msg);
+            }
+        }
+    }
+
+    #[tracing::instrument(
+        skip(self, msg),
+        fields(
+            exchange = ?msg.0.exchange,
+            market = %msg.0.market,
+            channel_type = ?msg.0.channel,
+        )
+    )]
+    pub async fn handle_ws_msg(&mut self, msg: (Channel, Result<WsEvent>)) {
         let (channel, msg) = (msg.0, msg.1);
-        tracing::info!("Msg: {:?}", msg);
-        match channel.exchange {
-            Exchange::Gdax => self
-                .handle_ws_msg_gdax(channel, msg)
-                .await
-                .expect("Expected gdax msg handled."),
-            Exchange::Kraken => self
-                .handle_ws_msg_kraken(channel, msg)
-                .await
-                .expect("Expected kraken msg handled."),
-            Exchange::Hyperliquid => self
-                .handle_ws_msg_hyperliquid(channel, msg)
-                .await
-                .expect("Expect hyperliquid msg handled."),
+        tracing::debug!(?msg, "Message received");
+        let msg = match msg {
+            Ok(WsEvent::Message(m)) => {
+                self.state.counters.message(channel.exchange);
+                self.record(&channel, &m);
+                Ok(m)
+            }
+            Ok(WsEvent::Reconnecting) => {
+                self.set_status(&channel, ConnectionStatus::Reconnecting);
+                return;
+            }
+            Ok(WsEvent::Reconnected) => {
+                self.set_status(&channel, ConnectionStatus::Subscribed);
+                self.handle_reconnect(channel);
+                return;
+            }
+            // Sockets killed by the App are removed before the read loop exits, so only sockets
+            // whose reconnect retries were exhausted are still stored.
+            Ok(WsEvent::Closed) => {
+                self.set_status(&channel, ConnectionStatus::Closed);
+                return;
+            }
+            Ok(WsEvent::RestBook(book)) => {
+                self.insert_rest_book(channel, book);
+                return;
+            }
+            Ok(WsEvent::Dropped(count)) => {
+                self.handle_dropped(channel, count);
+                return;
+            }
+            Err(e) if self.config.error_policy == ErrorPolicy::Reconnect => {
+                self.reconnect_on_error(channel, e);
+                return;
+            }
+            Err(e) => Err(e),
+        };
+        let result = match channel.exchange {
+            Exchange::Gdax => self.handle_ws_msg_gdax(channel.clone(), msg).await,
+            Exchange::Kraken => self.handle_ws_msg_kraken(channel.clone(), msg).await,
+            Exchange::Hyperliquid => self.handle_ws_msg_hyperliquid(channel.clone(), msg).await,
+            Exchange::Binance => self.handle_ws_msg_binance(channel.clone(), msg).await,
+            Exchange::Okx => self.handle_ws_msg_okx(channel.clone(), msg).await,
+            Exchange::Bybit => self.handle_ws_msg_bybit(channel.clone(), msg).await,
+            Exchange::Bitstamp => self.handle_ws_msg_bitstamp(channel.clone(), msg).await,
+            Exchange::KrakenV2 => self.handle_ws_msg_kraken_v2(channel.clone(), msg).await,
+            Exchange::Deribit => self.handle_ws_msg_deribit(channel.clone(), msg).await,
+            Exchange::CoinbaseAdvanced => {
+                self.handle_ws_msg_coinbase_advanced(channel.clone(), msg)
+                    .await
+            }
+        };
+        if let Err(e) = result {
+            self.handle_ws_error(channel, e);
+        }
+        self.flag_crossed_books();
+        self.flag_resnapshot_books();
+    }
+
+    /// Appends text messages to the channel's recording if it is recorded. A failed write is
+    /// logged and stops the recording rather than the feed.
+    fn record(&mut self, channel: &Channel, msg: &Message) {
+        if let (Some(recorder), Message::Text(text)) = (self.recorders.get_mut(channel), msg) {
+            if let Err(e) = recorder.record(Utc::now(), text) {
+                tracing::error!("Could not record to {:?}: {:?}", recorder.path, e);
+                self.recorders.remove(channel);
+            }
+        }
+    }
+
+    /// A message that could not be handled is logged and forwarded to async clients rather than
+    /// stopping the client loop. Book channels are flagged for resubscribe as the book may be
+    /// missing the update. A socket closed by the exchange reconnects on its own and the book is
+    /// reset on the reconnect.
+    #[tracing::instrument(skip(self))]
+    pub fn handle_ws_error(&mut self, channel: Channel, e: Error) {
+        tracing::error!("Error handling message for {:?}: {:?}", channel, e);
+        if matches!(e, Error::Parse { .. } | Error::Serde(_)) {
+            self.state.counters.parse_error(channel.exchange);
+        }
+        let closed = matches!(e, Error::SocketClosed { .. });
+        if channel.channel == ChannelType::Book && !closed {
+            self.errored.insert(channel.clone());
+        }
+        if let Some(sender) = self.app_sender.as_ref() {
+            let _ = sender.send(Ok(ClientRespMsg {
+                channel: Some(channel),
+                resp: ClientResp::Error(e),
+            }));
+        }
+    }
+
+    /// Recovers the socket from an error it delivered under ErrorPolicy::Reconnect instead of
+    /// forwarding the error. A socket closed by the exchange is already reconnecting so only
+    /// failed reads flag the socket for resubscribe.
+    pub fn reconnect_on_error(&mut self, channel: Channel, e: Error) {
+        tracing::warn!("Socket error on {:?}, reconnecting: {:?}", channel, e);
+        if !matches!(e, Error::SocketClosed { .. }) {
+            self.errored.insert(channel);
+        }
+    }
+
+    /// Flags the socket for resubscribe if it carries a book, as the book is missing the dropped
+    /// updates until it is rebuilt from a new snapshot.
+    pub fn handle_dropped(&mut self, channel: Channel, count: u64) {
+        tracing::warn!("{} messages dropped for {:?}", count, channel);
+        if self.carries(&channel, ChannelType::Book) {
+            self.errored.insert(channel);
+        }
+    }
+
+    /// Resubscribes channels flagged by handle_ws_error. Called on the client loop interval tick
+    /// so a channel sending repeated bad messages is resubscribed at most once per tick. Members
+    /// of a shared socket are resubscribed through the socket, once however many were flagged.
+    pub async fn resubscribe_errored(&mut self) {
+        let channels: Vec<Channel> = self.errored.drain().collect();
+        let mut resubscribed = HashSet::new();
+        for channel in channels {
+            let socket = self.socket_key(&channel);
+            // Skip channels unsubscribed since the error
+            if !self.sockets.lock_recover().contains_key(&socket) {
+                continue;
+            }
+            if !resubscribed.insert(socket.clone()) {
+                continue;
+            }
+            tracing::info!("Resubscribing errored channel {:?}", channel);
+            if let Err(e) = self.resubscribe(socket).await {
+                tracing::error!("Resubscribe failed: {:?}", e);
+            }
+        }
+    }
+
+    /// Channels whose last message is older than the threshold.
+    pub fn stale_channels(&self, threshold: Duration) -> Vec<Channel> {
+        let now = Utc::now();
+        let sockets = self.sockets.lock_recover();
+        sockets
+            .iter()
+            .filter(|(_, ws)| now - ws.last_message > threshold)
+            .map(|(channel, _)| channel.clone())
+            .collect()
+    }
+
+    /// Channels whose socket task is still running.
+    pub fn live_subscriptions(&self) -> Vec<Channel> {
+        let sockets = self.sockets.lock_recover();
+        sockets
+            .iter()
+            .filter(|(_, ws)| !ws.task.is_finished())
+            .map(|(channel, _)| channel.clone())
+            .collect()
+    }
+
+    /// Logs channels that have not received a message within the configured staleness threshold.
+    /// Called on the client loop maintenance tick.
+    pub fn log_stale_channels(&self) {
+        let threshold = Duration::from_std(self.config.stale_threshold)
+            .unwrap_or_else(|_| Duration::seconds(DEFAULT_STALE_SECS));
+        for channel in self.stale_channels(threshold) {
+            tracing::warn!(
+                "No message received in {}s for {:?}",
+                threshold.num_seconds(),
+                channel
+            );
+        }
+    }
+
+    /// Work done on the client loop maintenance tick.
+    pub async fn maintain(&mut self) {
+        self.log_stale_channels();
+        self.resubscribe_errored().await;
+    }
+
+    /// Work done on the client loop queue tick. Queued subscriptions are opened as exchange rate
+    /// limits allow and subscriptions the exchange has not confirmed in time are failed.
+    pub async fn process_queues(&mut self) {
+        self.process_sub_queue().await;
+        self.expire_unconfirmed().await;
+    }
+
+    /// Clears state built from the dropped connection. The book is rebuilt from the snapshot sent
+    /// after the socket resubscribes.
+    #[tracing::instrument(skip(self))]
+    pub fn handle_reconnect(&mut self, channel: Channel) {
+        tracing::info!("Socket reconnected.");
+        self.state.counters.reconnect();
+        {
+            let mut books = self.state.books.lock_recover();
+            for member in self.socket_members(&channel) {
+                if member.channel == ChannelType::Book {
+                    if self.rest_books.contains(&member) {
+                        self.fetch_rest_book(&member);
+                    }
+                    // Updates sent before the new snapshot are held until it arrives
+                    if member.exchange == Exchange::Gdax {
+                        self.gdax_pending_updates.insert(member.clone(), Vec::new());
+                        self.gdax_rest_times.remove(&member);
+                    }
+                    if member.exchange == Exchange::Bitstamp {
+                        self.bitstamp_pending_diffs
+                            .insert(member.clone(), Vec::new());
+                    }
+                    books.insert(member, Book::new());
+                }
+            }
+        }
+        // The socket resends the subscriptions it was opened with, which for Coinbase signed
+        // subscriptions carry an expired signature. Resubscribe to sign a new one.
+        let signed = self
+            .socket_members(&channel)
+            .iter()
+            .any(|m| m.is_signed(self.latency_mode(m)));
+        if channel.exchange == Exchange::Gdax && signed {
+            self.errored.insert(channel.clone());
+        }
+        let _ = self.update_last(channel.clone());
+        if let Some(sender) = self.app_sender.as_ref() {
+            let _ = sender.send(Ok(ClientRespMsg {
+                channel: Some(channel),
+                resp: ClientResp::Reconnected,
+            }));
+        }
+    }
+
+    /// Closes the connection for the socket serving the channel without dropping the socket. The
+    /// socket reconnects with backoff once the exchange answers the close, and the reconnect is
+    /// handled as if the connection had dropped.
+    #[tracing::instrument(skip(self))]
+    pub fn request_reconnect(&self, channel: &Channel) {
+        let key = self.socket_key(channel);
+        if let Some(ws) = self.sockets.lock_recover().get(&key) {
+            let _ = ws.write.send(WsCommand::Raw(Message::Close(None)));
+        }
+    }
+
+    /// Removes the socket for the channel, sends the unsubscribe message and the kill shot.
+    #[tracing::instrument(skip(self))]
+    pub async fn close_socket(&mut self, channel: &Channel) -> Result<()> {
+        let socket = self.sockets.lock_recover().remove(channel);
+        match socket {
+            Some(ws) => {
+                // Send unsub messages for the channel or every channel sharing its socket
+                let unsubs = self.unsubscriptions(channel);
+                self.groups.remove(channel);
+                for unsub in unsubs {
+                    let _ = ws.write.send(unsub);
+                }
+                // Send the kill shot to the socket
+                let _ = ws.killshot.send(true);
+                self.send_status(channel, ConnectionStatus::Closed);
+                Ok(())
+            }
+            None => Err(Error::SocketDoesNotExist),
+        }
+    }
+
+    /// Closes every socket on the exchange and cancels its queued subscriptions, clearing the state
+    /// of every channel stopped. Done in a single request so channels subscribed concurrently are
+    /// either stopped or opened after it. Returns the channels stopped.
+    #[tracing::instrument(skip(self))]
+    pub async fn stop_exchange(&mut self, exchange: Exchange) -> Vec<Channel> {
+        let mut stopped = Vec::new();
+        let pending = self.sub_queue.remove(&exchange).unwrap_or_default();
+        for p in pending {
+            stopped.extend(p.members.unwrap_or_else(|| vec![p.channel.clone()]));
+            self.respond(p.channel, p.resp, Err(Error::SubscriptionCancelled), |_| {
+                ClientResp::Subscribed
+            });
+        }
+        let sockets: Vec<Channel> = self
+            .sockets
+            .lock_recover()
+            .keys()
+            .filter(|c| c.exchange == exchange)
+            .cloned()
+            .collect();
+        for channel in sockets {
+            self.cancel_unconfirmed(&channel);
+            let members = self.socket_members(&channel);
+            let _ = self.close_socket(&channel).await;
+            stopped.extend(members);
+        }
+        for channel in stopped.iter() {
+            self.streams.remove(channel);
+            self.book_deltas.lock_recover().remove(channel);
+            self.aggregate_fills.remove(channel);
+            self.recorders.remove(channel);
+            self.clear_state(channel);
+        }
+        tracing::info!("Stopped {} channels on {:?}", stopped.len(), exchange);
+        stopped
+    }
+
+    /// Sends the unsubscribe message and kill shot to every socket, cancels queued subscriptions
+    /// and clears all state. Waits up to SHUTDOWN_TIMEOUT for the socket tasks to exit and aborts
+    /// any still running.
+    #[tracing::instrument(skip(self))]
+    pub async fn shutdown(&mut self) -> Result<()> {
+        let sockets: Vec<(Channel, Websocket)> = self.sockets.lock_recover().drain().collect();
+        let tasks: Vec<_> = sockets
+            .into_iter()
+            .map(|(channel, ws)| {
+                for unsub in self.unsubscriptions(&channel) {
+                    let _ = ws.write.send(unsub);
+                }
+                let _ = ws.killshot.send(true);
+                self.send_status(&channel, ConnectionStatus::Closed);
+                ws.task
+            })
+            .collect();
+        // Cancel subscriptions that were never opened
+        let pending: Vec<PendingSub> = self
+            .sub_queue
+            .drain()
+            .flat_map(|(_, queue)| queue.into_iter())
+            .collect();
+        for p in pending {
+            self.respond(p.channel, p.resp, Err(Error::SubscriptionCancelled), |_| {
+                ClientResp::Subscribed
+            });
+        }
+        let unconfirmed: Vec<Channel> = self.unconfirmed.keys().cloned().collect();
+        for channel in unconfirmed.iter() {
+            self.cancel_unconfirmed(channel);
+        }
+        self.state.tapes.lock_recover().clear();
+        self.state.tape_stats.lock_recover().clear();
+        self.state.books.lock_recover().clear();
+        self.state.candles.lock_recover().clear();
+        self.state.liquidations.lock_recover().clear();
+        self.state.funding.lock_recover().clear();
+        self.state.mark_prices.lock_recover().clear();
+        self.state.fills.lock_recover().clear();
+        self.state.orders.lock_recover().clear();
+        self.streams.clear();
+        self.book_deltas.lock_recover().clear();
+        self.recorders.clear();
+        self.aggregate_fills.clear();
+        self.groups.clear();
+        self.errored.clear();
+        self.kraken_level_times.clear();
+        self.gdax_heartbeats.clear();
+        self.gdax_sequences.clear();
+        self.gdax_pending_updates.clear();
+        self.gdax_rest_times.clear();
+        self.bitstamp_pending_diffs.clear();
+        self.rest_books.clear();
+        self.deribit_change_ids.clear();
+        self.crossed_books.lock_recover().clear();
+        self.book_checks.lock_recover().clear();
+        self.market_meta.clear();
+        self.book_depths.clear();
+        self.latency_modes.clear();
+        self.emas.clear();
+        self.min_sizes.clear();
+        self.last_sub.clear();
+        // Wait for the socket tasks to exit
+        let aborts: Vec<_> = tasks.iter().map(|t| t.abort_handle()).collect();
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, join_all(tasks))
+            .await
+            .is_err()
+        {
+            tracing::warn!("Timed out waiting for sockets to close. Aborting.");
+            aborts.iter().for_each(|a| a.abort());
+        }
+        Ok(())
+    }
+
+    /// Replaces the socket for the channel with a new one. Used to recover from a corrupt book -
+    /// the book is cleared and rebuilt from the snapshot sent on the new socket. The old socket is
+    /// only closed once the new one is open.
+    #[tracing::instrument(skip(self))]
+    pub async fn resubscribe(&mut self, channel: Channel) -> Result<()> {
+        // Members of a shared socket are resubscribed through the shared socket
+        let channel = self.socket_key(&channel);
+        let shared = self.groups.get(&channel).cloned();
+        let members = self.socket_members(&channel);
+        let subscriptions = self.subscriptions(&channel)?;
+        // Open the new socket before closing the old one so a failed open leaves the channel on
+        // its old socket, flagged to be retried on the next tick
+        let opened = self
+            .transport
+            .open(
+                self.ws_sender.clone(),
+                channel.clone(),
+                subscriptions,
+                self.config.socket_config(&channel.exchange),
+            )
+            .await;
+        let ws = match opened {
+            Ok(ws) => ws,
+            Err(e) => {
+                self.errored.insert(channel);
+                return Err(e);
+            }
+        };
+        let _ = self.close_socket(&channel).await;
+        for member in members {
+            match member.channel {
+                ChannelType::Book => {
+                    self.gdax_heartbeats.remove(&member);
+                    self.deribit_change_ids.remove(&member);
+                    self.crossed_books.lock_recover().remove(&member);
+                    self.book_checks.lock_recover().remove(&member);
+                    self.ready_books.lock_recover().remove(&member);
+                    if member.exchange == Exchange::Gdax {
+                        self.gdax_pending_updates.insert(member.clone(), Vec::new());
+                    }
+                    if member.exchange == Exchange::Bitstamp {
+                        self.bitstamp_pending_diffs
+                            .insert(member.clone(), Vec::new());
+                    }
+                    self.gdax_rest_times.remove(&member);
+                    if self.rest_books.contains(&member) {
+                        self.fetch_rest_book(&member);
+                    }
+                    self.state.books.lock_recover().insert(member, Book::new());
+                }
+                // Running totals restart with the new subscription
+                ChannelType::Tape => {
+                    self.gdax_sequences.remove(&member);
+                    self.gdax_heartbeats.remove(&member);
+                    let mut stats = self.state.tape_stats.lock_recover();
+                    stats.insert(member, TapeStats::default());
+                }
+                ChannelType::Liquidations
+                | ChannelType::Funding
+                | ChannelType::MarkPrice
+                | ChannelType::Fills
+                | ChannelType::Orders => {}
+            }
         }
+        let mut sockets = self.sockets.lock_recover();
+        sockets.insert(channel.clone(), ws);
+        if let Some(m) = shared {
+            self.groups.insert(channel, m);
+        }
+        Ok(())
     }
 
     #[tracing::instrument(skip(self))]
     pub fn update_last(&mut self, channel: Channel) -> Result<()> {
-        let mut sockets = self.sockets.lock().unwrap();
+        let mut sockets = self.sockets.lock_recover();
         sockets.entry(channel).and_modify(|ws| {
             ws.last_message = Utc::now();
         });
@@ -257,9 +1664,668 @@ impl App {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Opened socket waiting for the exchange to confirm the subscriptions sent on it.
+#[derive(Debug)]
+pub struct UnconfirmedSub {
+    // Subscriptions not yet confirmed. One per channel on a shared socket.
+    pub remaining: usize,
+    // Start request fails with SubscribeTimeout if not confirmed by then
+    pub deadline: Instant,
+    pub resp: Option<Responder<()>>,
+}
+
+/// Subscription waiting in the queue for the exchange rate limit.
+#[derive(Debug)]
+pub struct PendingSub {
+    pub channel: Channel,
+    // Channels sharing the socket when the channel is the lead of a group or bundle
+    pub members: Option<Vec<Channel>>,
+    pub resp: Option<Responder<()>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TradeSide {
     Buy,
     Sell,
 }
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use tokio::sync::{mpsc, oneshot};
+    use tokio::time::{Duration, Instant};
+    use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+    use crate::app::{App, PendingSub, UnconfirmedSub};
+    use crate::client::{
+        Channel, ChannelBundle, ChannelGroup, ChannelType, ClientConfig, ClientReq, ClientResp,
+        Exchange, LatencyMode, SubscribeConfig,
+    };
+    use crate::error::Error;
+    use crate::websocket::{mock, ConnectionStatus, ErrorPolicy, Transport, Websocket, WsEvent};
+
+    #[tokio::test]
+    async fn socket_status_transitions_sent() {
+        let (ws_send, _) = mpsc::channel(1);
+        let (app_send, mut app_recv) = mpsc::unbounded_channel();
+        let mut app = App::new(ws_send, Some(app_send), ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Bitstamp,
+            channel: ChannelType::Tape,
+            market: "btcusd".to_string(),
+        };
+        let (write, _write_rx) = mpsc::unbounded_channel();
+        let (killshot, _kill_rx) = mpsc::unbounded_channel();
+        let ws = Websocket {
+            write,
+            killshot,
+            last_message: chrono::Utc::now(),
+            status: ConnectionStatus::Subscribed,
+            task: tokio::spawn(async {}),
+        };
+        app.sockets.lock().unwrap().insert(channel.clone(), ws);
+
+        for event in [WsEvent::Reconnecting, WsEvent::Reconnected, WsEvent::Closed] {
+            app.handle_ws_msg((channel.clone(), Ok(event))).await;
+        }
+        let statuses: Vec<ConnectionStatus> = std::iter::from_fn(|| app_recv.try_recv().ok())
+            .filter_map(|r| match r.unwrap().resp {
+                ClientResp::Status { status, .. } => Some(status),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ConnectionStatus::Reconnecting,
+                ConnectionStatus::Subscribed,
+                ConnectionStatus::Closed
+            ]
+        );
+        let sockets = app.sockets.lock().unwrap();
+        assert_eq!(sockets[&channel].status, ConnectionStatus::Closed);
+    }
+
+    #[tokio::test]
+    async fn last_age_measured_by_app() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel::tape(Exchange::Bitstamp, "btcusd");
+        let (write, _write_rx) = mpsc::unbounded_channel();
+        let (killshot, _kill_rx) = mpsc::unbounded_channel();
+        let ws = Websocket {
+            write,
+            killshot,
+            last_message: chrono::Utc::now() - chrono::Duration::seconds(5),
+            status: ConnectionStatus::Subscribed,
+            task: tokio::spawn(async {}),
+        };
+        app.sockets.lock().unwrap().insert(channel.clone(), ws);
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        app.handle_client_req(ClientReq::LastAge {
+            channel: channel.clone(),
+            resp: Some(resp_tx),
+        })
+        .await;
+        let age = resp_rx.await.unwrap().unwrap();
+        assert!(age >= chrono::Duration::seconds(5) && age < chrono::Duration::seconds(6));
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        app.handle_client_req(ClientReq::LastAge {
+            channel: Channel::tape(Exchange::Bitstamp, "ethusd"),
+            resp: Some(resp_tx),
+        })
+        .await;
+        assert!(matches!(
+            resp_rx.await.unwrap(),
+            Err(Error::SocketDoesNotExist)
+        ));
+    }
+
+    #[tokio::test]
+    async fn poisoned_state_still_served() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel::tape(Exchange::Bitstamp, "btcusd");
+        app.state
+            .tapes
+            .lock()
+            .unwrap()
+            .insert(channel.clone(), Default::default());
+        let state = app.state.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = state.tapes.lock().unwrap();
+            panic!("poison the tapes lock");
+        })
+        .join();
+        assert!(app.state.tapes.is_poisoned());
+
+        let (resp_tx, resp_rx) = oneshot::channel();
+        app.handle_client_req(ClientReq::Tape {
+            channel,
+            resp: Some(resp_tx),
+        })
+        .await;
+        assert!(resp_rx.await.unwrap().is_ok());
+        assert!(!app.state.tapes.is_poisoned());
+    }
+
+    #[tokio::test]
+    async fn parse_error_carries_raw_message() {
+        let (ws_send, _) = mpsc::channel(1);
+        let (app_send, mut app_recv) = mpsc::unbounded_channel();
+        let mut app = App::new(ws_send, Some(app_send), ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Okx,
+            channel: ChannelType::Tape,
+            market: "BTC-USDT".to_string(),
+        };
+        let garbled = "{\"arg\":".to_string();
+        let msg = WsEvent::Message(Message::Text(garbled.clone()));
+        app.handle_ws_msg((channel.clone(), Ok(msg))).await;
+
+        let resp = app_recv.try_recv().unwrap().unwrap();
+        assert_eq!(resp.channel, Some(channel));
+        match resp.resp {
+            ClientResp::Error(Error::Parse { exchange, raw, .. }) => {
+                assert_eq!(exchange, Exchange::Okx);
+                assert_eq!(raw, garbled);
+            }
+            r => panic!("Expected parse error, got {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn sub_ready_after_min_interval() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut config = ClientConfig::default();
+        config
+            .sub_intervals
+            .insert(Exchange::Gdax, Duration::from_secs(1));
+        let mut app = App::new(ws_send, None, config).unwrap();
+
+        let now = Instant::now();
+        assert!(app.sub_ready(&Exchange::Gdax, now));
+        app.last_sub.insert(Exchange::Gdax, now);
+        assert!(!app.sub_ready(&Exchange::Gdax, now + Duration::from_millis(500)));
+        assert!(app.sub_ready(&Exchange::Gdax, now + Duration::from_secs(1)));
+        // Limits are tracked per exchange
+        assert!(app.sub_ready(&Exchange::Kraken, now));
+    }
+
+    #[tokio::test]
+    async fn start_applies_subscribe_config() {
+        let (mut app, _transport) = mock::app();
+        let tape = Channel::tape(Exchange::Gdax, "BTC-USD");
+        let (resp_tx, _resp_rx) = oneshot::channel();
+        app.handle_client_req(ClientReq::Start {
+            channel: tape.clone(),
+            config: SubscribeConfig {
+                latency: LatencyMode::Realtime,
+                ema_period: Some(5),
+                min_size: Some(dec!(0.5)),
+                ..Default::default()
+            },
+            resp: Some(resp_tx),
+        })
+        .await;
+        assert_eq!(app.latency_modes[&tape], LatencyMode::Realtime);
+        assert!(app.emas.contains_key(&tape));
+        assert_eq!(app.min_sizes[&tape], dec!(0.5));
+    }
+
+    #[tokio::test]
+    async fn stop_exchange_stops_only_its_channels() {
+        let (mut app, _transport) = mock::app();
+        let tape = Channel::tape(Exchange::Gdax, "BTC-USD");
+        let book = Channel::book(Exchange::Gdax, "BTC-USD");
+        let kraken = Channel::tape(Exchange::Kraken, "XBT/USD");
+        for channel in [&tape, &book, &kraken] {
+            mock::start(&mut app, channel).await;
+        }
+        let (resp_tx, resp_rx) = oneshot::channel();
+        app.handle_client_req(ClientReq::StopExchange {
+            exchange: Exchange::Gdax,
+            resp: Some(resp_tx),
+        })
+        .await;
+        let stopped = resp_rx.await.unwrap().unwrap();
+        assert_eq!(stopped.len(), 2);
+        assert!(stopped.contains(&tape) && stopped.contains(&book));
+        assert!(!app.has_state(&tape));
+        assert!(!app.has_state(&book));
+        assert!(app.has_state(&kraken));
+        let queued = app.sub_queue.values().flatten().map(|p| &p.channel);
+        let open = app
+            .sockets
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        assert!(queued
+            .chain(open.iter())
+            .all(|c| c.exchange != Exchange::Gdax));
+    }
+
+    #[tokio::test]
+    async fn stop_clears_state_unless_kept() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut config = ClientConfig::default();
+        config
+            .sub_intervals
+            .insert(Exchange::Gdax, Duration::from_secs(60));
+        let (app_send, _app_recv) = mpsc::unbounded_channel();
+        let mut app = App::new(ws_send, Some(app_send), config).unwrap();
+        // Hold subscriptions in the queue so no socket is opened
+        app.last_sub.insert(Exchange::Gdax, Instant::now());
+        let tape = Channel {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Tape,
+            market: "BTC-USD".to_string(),
+        };
+        let book = Channel {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Book,
+            market: "BTC-USD".to_string(),
+        };
+        for channel in [&tape, &book] {
+            app.handle_client_req(ClientReq::Start {
+                channel: channel.clone(),
+                config: SubscribeConfig {
+                    max_depth: Some(10),
+                    ..Default::default()
+                },
+                resp: None,
+            })
+            .await;
+        }
+        assert!(app.state.tapes.lock().unwrap().contains_key(&tape));
+        assert!(app.book_depths.contains_key(&book));
+
+        app.handle_client_req(ClientReq::Stop {
+            channel: tape.clone(),
+            keep_state: false,
+            resp: None,
+        })
+        .await;
+        assert!(!app.state.tapes.lock().unwrap().contains_key(&tape));
+        assert!(!app.state.candles.lock().unwrap().contains_key(&tape));
+        assert!(!app.state.tape_stats.lock().unwrap().contains_key(&tape));
+
+        app.handle_client_req(ClientReq::Stop {
+            channel: book.clone(),
+            keep_state: true,
+            resp: None,
+        })
+        .await;
+        assert!(app.state.books.lock().unwrap().contains_key(&book));
+    }
+
+    #[tokio::test]
+    async fn kept_state_restarted_and_stopped() {
+        let (mut app, _transport) = mock::app();
+        let tape = Channel::tape(Exchange::Gdax, "BTC-USD");
+        mock::start(&mut app, &tape).await;
+        mock::stop(&mut app, &tape, true).await.unwrap();
+        assert!(app.has_state(&tape));
+
+        // Start replaces the kept state and queues a new socket behind the rate limit
+        let mut resp = mock::start(&mut app, &tape).await;
+        assert!(!matches!(resp.try_recv(), Ok(Err(_))));
+        assert!(app.is_active(&tape));
+        mock::stop(&mut app, &tape, true).await.unwrap();
+
+        // Stopping again clears the kept state, once there is state to clear
+        mock::stop(&mut app, &tape, true).await.unwrap();
+        assert!(app.has_state(&tape));
+        mock::stop(&mut app, &tape, false).await.unwrap();
+        assert!(!app.has_state(&tape));
+        assert!(matches!(
+            mock::stop(&mut app, &tape, false).await,
+            Err(Error::SocketDoesNotExist)
+        ));
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_sub_times_out() {
+        let (ws_send, _) = mpsc::channel(1);
+        let (app_send, mut app_recv) = mpsc::unbounded_channel();
+        let mut app = App::new(ws_send, Some(app_send), ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Hyperliquid,
+            channel: ChannelType::Tape,
+            market: "BTC".to_string(),
+        };
+        app.unconfirmed.insert(
+            channel.clone(),
+            UnconfirmedSub {
+                remaining: 1,
+                deadline: Instant::now() + Duration::from_secs(60),
+                resp: None,
+            },
+        );
+        app.expire_unconfirmed().await;
+        assert!(app.unconfirmed.contains_key(&channel));
+
+        app.unconfirmed.get_mut(&channel).unwrap().deadline = Instant::now();
+        app.expire_unconfirmed().await;
+        assert!(app.unconfirmed.is_empty());
+        assert!(matches!(
+            app_recv.recv().await.unwrap(),
+            Err(Error::SubscribeTimeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn dequeue_pending_sub() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Tape,
+            market: "BTC-USD".to_string(),
+        };
+        app.sub_queue
+            .entry(Exchange::Gdax)
+            .or_default()
+            .push_back(PendingSub {
+                channel: channel.clone(),
+                members: None,
+                resp: None,
+            });
+        assert!(app.dequeue_sub(&channel).is_some());
+        assert!(app.dequeue_sub(&channel).is_none());
+    }
+
+    #[tokio::test]
+    async fn malformed_message_does_not_panic() {
+        let (ws_send, _) = mpsc::channel(1);
+        let (app_send, mut app_recv) = mpsc::unbounded_channel();
+        let mut app = App::new(ws_send, Some(app_send), ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Book,
+            market: "BTC-USD".to_string(),
+        };
+        let msg = Message::Text("{\"type\":\"unknown\"}".to_string());
+        app.handle_ws_msg((channel.clone(), Ok(WsEvent::Message(msg))))
+            .await;
+
+        let resp = app_recv.recv().await.unwrap().unwrap();
+        assert_eq!(resp.channel, Some(channel.clone()));
+        assert!(matches!(resp.resp, ClientResp::Error(_)));
+        assert!(app.errored.contains(&channel));
+    }
+
+    #[tokio::test]
+    async fn socket_close_forwarded_without_resubscribe() {
+        let (ws_send, _) = mpsc::channel(1);
+        let (app_send, mut app_recv) = mpsc::unbounded_channel();
+        let mut app = App::new(ws_send, Some(app_send), ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Kraken,
+            channel: ChannelType::Book,
+            market: "XBT/USD".to_string(),
+        };
+        let closed = Error::SocketClosed {
+            code: 1001,
+            reason: "Going away".to_string(),
+        };
+        app.handle_ws_msg((channel.clone(), Err(closed))).await;
+
+        let resp = app_recv.recv().await.unwrap().unwrap();
+        assert!(matches!(
+            resp.resp,
+            ClientResp::Error(Error::SocketClosed { code: 1001, .. })
+        ));
+        assert!(app.errored.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reconnect_policy_recovers_without_forwarding() {
+        let (ws_send, _) = mpsc::channel(1);
+        let (app_send, mut app_recv) = mpsc::unbounded_channel();
+        let config = ClientConfig {
+            error_policy: ErrorPolicy::Reconnect,
+            ..Default::default()
+        };
+        let mut app = App::new(ws_send, Some(app_send), config).unwrap();
+        let channel = Channel::tape(Exchange::Bitstamp, "btcusd");
+        let closed = Error::SocketClosed {
+            code: 1001,
+            reason: "Going away".to_string(),
+        };
+        app.handle_ws_msg((channel.clone(), Err(closed))).await;
+        assert!(app.errored.is_empty());
+        let failed = Error::Tungstenite(WsError::ConnectionClosed);
+        app.handle_ws_msg((channel.clone(), Err(failed))).await;
+        assert!(app.errored.contains(&channel));
+        assert!(app_recv.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn dropped_messages_flag_books_for_resubscribe() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let tape = Channel::tape(Exchange::Bitstamp, "btcusd");
+        app.handle_ws_msg((tape.clone(), Ok(WsEvent::Dropped(3))))
+            .await;
+        assert!(app.errored.is_empty());
+
+        let book = Channel {
+            channel: ChannelType::Book,
+            ..tape
+        };
+        app.handle_ws_msg((book.clone(), Ok(WsEvent::Dropped(3))))
+            .await;
+        assert!(app.errored.contains(&book));
+    }
+
+    #[tokio::test]
+    async fn gdax_heartbeat_gap_flags_book_stale() {
+        let (ws_send, _) = mpsc::channel(1);
+        let (app_send, mut app_recv) = mpsc::unbounded_channel();
+        let mut app = App::new(ws_send, Some(app_send), ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Book,
+            market: "BTC-USD".to_string(),
+        };
+        let heartbeat = |sequence: i64, time: &str| {
+            let text = format!("{{\"type\":\"heartbeat\",\"sequence\":{},\"last_trade_id\":20,\"product_id\":\"BTC-USD\",\"time\":\"{}\"}}", sequence, time);
+            (channel.clone(), Ok(WsEvent::Message(Message::Text(text))))
+        };
+        app.handle_ws_msg(heartbeat(90, "2014-11-07T08:19:28.464459Z"))
+            .await;
+        app.handle_ws_msg(heartbeat(90, "2014-11-07T08:19:29.464459Z"))
+            .await;
+        assert!(app.errored.is_empty());
+
+        // Five seconds without a heartbeat
+        app.handle_ws_msg(heartbeat(95, "2014-11-07T08:19:34.464459Z"))
+            .await;
+        assert!(app.errored.contains(&channel));
+        let resp = app_recv.recv().await.unwrap().unwrap();
+        assert_eq!(resp.channel, Some(channel));
+        assert!(matches!(resp.resp, ClientResp::BookStale));
+    }
+
+    #[tokio::test]
+    async fn gdax_heartbeat_gap_resubscribes_tape() {
+        let (ws_send, _) = mpsc::channel(1);
+        let (app_send, mut app_recv) = mpsc::unbounded_channel();
+        let mut app = App::new(ws_send, Some(app_send), ClientConfig::default()).unwrap();
+        let channel = Channel::tape(Exchange::Gdax, "BTC-USD");
+        let sub = channel.subscribe_message().unwrap();
+        assert_eq!(sub["channels"][1]["name"], "heartbeat");
+
+        let heartbeat = |sequence: i64| {
+            let text = format!("{{\"type\":\"heartbeat\",\"sequence\":{},\"last_trade_id\":20,\"product_id\":\"BTC-USD\",\"time\":\"2014-11-07T08:19:28.464459Z\"}}", sequence);
+            (channel.clone(), Ok(WsEvent::Message(Message::Text(text))))
+        };
+        app.handle_ws_msg(heartbeat(90)).await;
+        assert!(app.gdax_heartbeats.contains_key(&channel));
+        // Sequence moved backwards
+        app.handle_ws_msg(heartbeat(80)).await;
+        assert!(app.errored.contains(&channel));
+        // Books are flagged stale, tapes are only resubscribed
+        assert!(app_recv.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_resubscribe_keeps_socket_and_retries() {
+        let (mut app, transport) = mock::app();
+        let book = Channel::book(Exchange::Gdax, "BTC-USD");
+        mock::start(&mut app, &book).await;
+        let snapshot = "{\"type\":\"snapshot\",\"product_id\":\"BTC-USD\",\"bids\":[[\"19285.00\",\"1.5\"]],\"asks\":[[\"19285.50\",\"2.0\"]]}";
+        mock::deliver(&mut app, &book, snapshot).await;
+
+        *transport.refuse.lock().unwrap() = true;
+        assert!(app.resubscribe(book.clone()).await.is_err());
+        assert!(app.sockets.lock().unwrap().contains_key(&book));
+        assert_eq!(app.state.books.lock().unwrap()[&book].bids.len(), 1);
+        assert!(app.errored.contains(&book));
+
+        // Retried on the next tick once the exchange is reachable
+        *transport.refuse.lock().unwrap() = false;
+        app.resubscribe_errored().await;
+        assert!(app.errored.is_empty());
+        assert!(app.sockets.lock().unwrap().contains_key(&book));
+        assert!(app.state.books.lock().unwrap()[&book].is_empty());
+    }
+
+    #[tokio::test]
+    async fn errored_group_member_resubscribed_once() {
+        let (mut app, transport) = mock::app();
+        let group = ChannelGroup {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Book,
+            markets: vec!["BTC-USD".to_string(), "ETH-USD".to_string()],
+        };
+        let lead = group.lead().unwrap();
+        for member in group.channels() {
+            app.setup_state(&member, None).unwrap();
+        }
+        app.groups.insert(lead.clone(), group.channels());
+        let ws = transport
+            .open(
+                app.ws_sender.clone(),
+                lead.clone(),
+                Vec::new(),
+                app.config.socket_config(&lead.exchange),
+            )
+            .await
+            .unwrap();
+        app.sockets.lock().unwrap().insert(lead.clone(), ws);
+        let snapshot = "{\"type\":\"snapshot\",\"product_id\":\"ETH-USD\",\"bids\":[[\"1285.00\",\"1.5\"]],\"asks\":[[\"1285.50\",\"2.0\"]]}";
+        mock::deliver(&mut app, &lead, snapshot).await;
+        let eth = group.member("ETH-USD").unwrap();
+        assert_eq!(app.state.books.lock().unwrap()[&eth].bids.len(), 1);
+
+        // Members are flagged rather than the socket, e.g. for a crossed book
+        app.errored.insert(eth.clone());
+        app.errored.insert(lead.clone());
+        app.resubscribe_errored().await;
+        assert!(app.errored.is_empty());
+        assert!(app.state.books.lock().unwrap()[&eth].is_empty());
+        // The shared socket is reopened with every market
+        assert_eq!(app.groups[&lead].len(), 2);
+        assert_eq!(app.sockets.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn group_message_routed_by_market() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let group = ChannelGroup {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Tape,
+            markets: vec!["BTC-USD".to_string(), "ETH-USD".to_string()],
+        };
+        let lead = group.lead().unwrap();
+        for member in group.channels() {
+            app.setup_state(&member, None).unwrap();
+        }
+        app.groups.insert(lead.clone(), group.channels());
+
+        let ticker = "{\"type\":\"ticker\",\"sequence\":37475248783,\"product_id\":\"ETH-USD\",\"price\":\"1285.22\",\"side\":\"buy\",\"time\":\"2022-10-19T23:28:22.061769Z\",\"trade_id\":370843401,\"last_size\":\"11.4396987\"}";
+        let msg = Message::Text(ticker.to_string());
+        app.handle_ws_msg((lead.clone(), Ok(WsEvent::Message(msg))))
+            .await;
+
+        let eth = group.member("ETH-USD").unwrap();
+        let tapes = app.state.tapes.lock().unwrap();
+        assert_eq!(tapes.get(&eth).unwrap().len(), 1);
+        assert!(tapes.get(&lead).unwrap().is_empty());
+        assert_eq!(app.socket_key(&eth), lead);
+    }
+
+    #[tokio::test]
+    async fn bundle_message_routed_by_type() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let bundle = ChannelBundle {
+            exchange: Exchange::Gdax,
+            market: "BTC-USD".to_string(),
+            channels: vec![ChannelType::Tape, ChannelType::Book],
+        };
+        assert!(bundle.is_supported());
+        let lead = bundle.lead().unwrap();
+        for member in bundle.channels() {
+            app.setup_state(&member, None).unwrap();
+        }
+        app.groups.insert(lead.clone(), bundle.channels());
+        assert_eq!(app.subscriptions(&lead).unwrap().len(), 2);
+
+        let ticker = "{\"type\":\"ticker\",\"sequence\":37475248783,\"product_id\":\"BTC-USD\",\"price\":\"19285.22\",\"side\":\"buy\",\"time\":\"2022-10-19T23:28:22.061769Z\",\"trade_id\":370843401,\"last_size\":\"0.4396987\"}";
+        let snapshot = "{\"type\":\"snapshot\",\"product_id\":\"BTC-USD\",\"bids\":[[\"19285.00\",\"1.5\"]],\"asks\":[[\"19285.50\",\"2.0\"]]}";
+        for text in [ticker, snapshot] {
+            let msg = Message::Text(text.to_string());
+            app.handle_ws_msg((lead.clone(), Ok(WsEvent::Message(msg))))
+                .await;
+        }
+
+        let book = Channel::book(Exchange::Gdax, "BTC-USD");
+        assert_eq!(app.state.tapes.lock().unwrap()[&lead].len(), 1);
+        assert_eq!(app.state.books.lock().unwrap()[&book].bids.len(), 1);
+        assert!(app.carries(&lead, ChannelType::Book));
+        assert_eq!(app.socket_key(&book), lead);
+
+        // Binance streams cannot share a socket and are opened one per channel
+        let binance = ChannelBundle {
+            exchange: Exchange::Binance,
+            ..bundle
+        };
+        assert!(!binance.is_shared());
+    }
+
+    #[tokio::test]
+    async fn bundle_config_applied_to_members() {
+        let (mut app, _transport) = mock::app();
+        let bundle = ChannelBundle {
+            exchange: Exchange::Gdax,
+            market: "BTC-USD".to_string(),
+            channels: vec![ChannelType::Tape, ChannelType::Book],
+        };
+        app.handle_client_req(ClientReq::StartBundle {
+            bundle,
+            config: SubscribeConfig {
+                max_depth: Some(10),
+                min_size: Some(dec!(1)),
+                ..Default::default()
+            },
+            resp: None,
+        })
+        .await;
+
+        let tape = Channel::tape(Exchange::Gdax, "BTC-USD");
+        let book = Channel::book(Exchange::Gdax, "BTC-USD");
+        assert_eq!(app.book_depths.get(&book), Some(&10));
+        assert_eq!(app.min_sizes.get(&tape), Some(&dec!(1)));
+        // Tape options are ignored for the book
+        assert!(!app.min_sizes.contains_key(&book));
+    }
+}
@@ -0,0 +1,267 @@
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    app::App,
+    client::{Channel, ChannelType},
+    error::{Error, Result},
+    trades::Trade as AppTrade,
+};
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Response {
+    Trade(Trade),
+    Depth(Depth),
+    SubscriptionResponse(SubscriptionResponse),
+}
+
+/// Struct mapping for:
+///
+/// Trade message from Binance <symbol>@trade stream
+/// {
+///     "e": "trade",
+///     "E": 1686270879026,
+///     "s": "BTCUSDT",
+///     "t": 3132465013,
+///     "p": "26433.01000000",
+///     "q": "0.00378000",
+///     "T": 1686270879025,
+///     "m": false,
+///     "M": true
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct Trade {
+    #[serde(rename = "e")]
+    pub event_type: String,
+    #[serde(rename = "E")]
+    pub event_time: i64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "t")]
+    pub trade_id: i64,
+    #[serde(rename = "p")]
+    pub price: Decimal,
+    #[serde(rename = "q")]
+    pub quantity: Decimal,
+    #[serde(rename = "T")]
+    pub trade_time: i64,
+    #[serde(rename = "m")]
+    pub is_buyer_maker: bool,
+}
+
+/// Struct mapping for:
+///
+/// Partial book depth message from Binance <symbol>@depth<levels>@100ms stream. Each message is a
+/// full snapshot of the top levels.
+/// {
+///     "lastUpdateId": 37509612447,
+///     "bids": [["26432.99000000", "4.71537000"]],
+///     "asks": [["26433.00000000", "2.56497000"]]
+/// }
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Depth {
+    pub last_update_id: i64,
+    pub bids: Vec<(Decimal, Decimal)>,
+    pub asks: Vec<(Decimal, Decimal)>,
+}
+
+/// Struct mapping for:
+///
+/// Response to a SUBSCRIBE / UNSUBSCRIBE request
+/// {"result": null, "id": 1}
+/// {"error": {"code": 2, "msg": "Invalid request"}, "id": 1}
+#[derive(Clone, Deserialize, Debug)]
+pub struct SubscriptionResponse {
+    pub id: i64,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<BinanceError>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct BinanceError {
+    pub code: i64,
+    pub msg: String,
+}
+
+/// Depth levels requested on the partial book depth stream.
+pub const BINANCE_BOOK_DEPTH: usize = 20;
+
+/// Binance stream names are the lowercase symbol and stream type, e.g. btcusdt@trade.
+pub fn stream_name(channel: &Channel) -> String {
+    match channel.channel {
+        ChannelType::Tape => format!("{}@trade", channel.market.to_lowercase()),
+        ChannelType::Book => format!(
+            "{}@depth{}@100ms",
+            channel.market.to_lowercase(),
+            BINANCE_BOOK_DEPTH
+        ),
+        // Futures streams only. Liquidations and funding are not subscribed on the spot stream.
+        ChannelType::Liquidations => format!("{}@forceOrder", channel.market.to_lowercase()),
+        ChannelType::Funding | ChannelType::MarkPrice => {
+            format!("{}@markPrice", channel.market.to_lowercase())
+        }
+        // Account updates are sent on a user data stream opened with a listen key, never subscribed
+        ChannelType::Fills | ChannelType::Orders => channel.market.to_lowercase(),
+    }
+}
+
+impl App {
+    #[tracing::instrument(skip(self, channel, msg), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
+    pub async fn handle_ws_msg_binance(
+        &mut self,
+        channel: Channel,
+        msg: Result<Message>,
+    ) -> Result<()> {
+        match msg {
+            Ok(m) => {
+                // Update socket last message
+                self.update_last(channel.clone())?;
+                // Parse message
+                if let Message::Text(text) = m {
+                    let response: Response = match serde_json::from_str(&text) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            tracing::error!("Could not parse message {:?}", text);
+                            tracing::error!("Error: {:?}", e);
+                            return Err(Error::Parse {
+                                exchange: channel.exchange,
+                                raw: text,
+                                source: e,
+                            });
+                        }
+                    };
+                    tracing::debug!("Response: {:?}", response);
+                    self.handle_ws_response_binance(channel.clone(), response)
+                        .await?;
+                } else {
+                    tracing::warn!("Non-text Message: {:?}", m);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // Return Err
+                tracing::error!("Error: {:?}", e);
+                Err(e)
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self, channel, response), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
+    pub async fn handle_ws_response_binance(
+        &mut self,
+        channel: Channel,
+        response: Response,
+    ) -> Result<()> {
+        match response {
+            Response::Trade(trade) => {
+                if channel.channel == ChannelType::Tape {
+                    // Convert binance trade to trade and insert into trades state
+                    let t: AppTrade = trade.try_into()?;
+                    self.insert_trade(channel, t).await?;
+                } else {
+                    // Trade message sent on a none tape channel
+                    tracing::error!("Trade message {:?} sent on channel {:?}", trade, channel);
+                    return Err(Error::ChannelResponseMismatch);
+                }
+            }
+            Response::Depth(depth) => self.insert_binance_snapshot(channel, depth).await,
+            Response::SubscriptionResponse(_) => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use serde_json::{Result, Value};
+
+    use crate::app::TradeSide;
+    use crate::binance::Response;
+    use crate::client::{Channel, Exchange};
+    use crate::websocket::mock;
+
+    pub fn messages(s: String) -> String {
+        let sub = "{\"result\":null,\"id\":1}";
+        let trade = "{\"e\":\"trade\",\"E\":1686270879026,\"s\":\"BTCUSDT\",\"t\":3132465013,\"p\":\"26433.01000000\",\"q\":\"0.00378000\",\"b\":21193538497,\"a\":21193538478,\"T\":1686270879025,\"m\":false,\"M\":true}";
+        let depth = "{\"lastUpdateId\":37509612447,\"bids\":[[\"26432.99000000\",\"4.71537000\"],[\"26432.98000000\",\"0.00100000\"],[\"26432.50000000\",\"0.03000000\"]],\"asks\":[[\"26433.00000000\",\"2.56497000\"],[\"26433.01000000\",\"0.09810000\"],[\"26433.50000000\",\"0.41000000\"]]}";
+        if s == "subscription" {
+            sub.to_string()
+        } else if s == "trade" {
+            trade.to_string()
+        } else if s == "depth" {
+            depth.to_string()
+        } else {
+            "none".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn tape_built_over_mock_transport() {
+        let (mut app, transport) = mock::app();
+        let channel = Channel::tape(Exchange::Binance, "BTCUSDT");
+        let started = mock::start(&mut app, &channel).await;
+        assert!(started.await.unwrap().is_ok());
+        assert_eq!(
+            transport.subscription(&channel)["params"][0],
+            "btcusdt@trade"
+        );
+
+        mock::deliver(&mut app, &channel, &messages("subscription".to_string())).await;
+        let trade = messages("trade".to_string());
+        mock::deliver(&mut app, &channel, &trade).await;
+        let next = trade
+            .replace("3132465013", "3132465014")
+            .replace("\"m\":false", "\"m\":true");
+        mock::deliver(&mut app, &channel, &next).await;
+
+        let tape = mock::tape(&mut app, &channel).await.unwrap();
+        assert_eq!(tape.len(), 2);
+        assert_eq!(tape[0].price, dec!(26433.01));
+        assert_eq!(tape[0].side, TradeSide::Buy);
+        // Buyer was the maker so the taker sold
+        assert_eq!(tape[1].side, TradeSide::Sell);
+    }
+
+    #[test]
+    pub fn deserialize_sub() -> Result<()> {
+        let data = messages("subscription".to_string());
+
+        let v: Value = serde_json::from_str(&data)?;
+        println!("Value: {:?}", v);
+
+        let v: Response = serde_json::from_str(&data)?;
+        println!("Response: {:?}", v);
+        assert!(matches!(v, Response::SubscriptionResponse(_)));
+        Ok(())
+    }
+
+    #[test]
+    pub fn deserialize_trade() -> Result<()> {
+        let data = messages("trade".to_string());
+
+        let v: Value = serde_json::from_str(&data)?;
+        println!("Value: {:?}", v);
+
+        let v: Response = serde_json::from_str(&data)?;
+        println!("Response: {:?}", v);
+        assert!(matches!(v, Response::Trade(_)));
+        Ok(())
+    }
+
+    #[test]
+    pub fn deserialize_depth() -> Result<()> {
+        let data = messages("depth".to_string());
+
+        let v: Value = serde_json::from_str(&data)?;
+        println!("Value: {:?}", v);
+
+        let v: Response = serde_json::from_str(&data)?;
+        println!("Response: {:?}", v);
+        assert!(matches!(v, Response::Depth(_)));
+        Ok(())
+    }
+}
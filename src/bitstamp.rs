@@ -0,0 +1,268 @@
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    app::App,
+    client::{Channel, ChannelType},
+    error::{Error, Result},
+    trades::Trade as AppTrade,
+};
+
+/// Channel name prefixes. The market is appended, e.g. live_trades_btcusd.
+pub const TRADES_PREFIX: &str = "live_trades_";
+pub const DIFF_BOOK_PREFIX: &str = "diff_order_book_";
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(tag = "event")]
+pub enum Response {
+    #[serde(rename = "bts:subscription_succeeded")]
+    SubscriptionSucceeded(Envelope<serde_json::Value>),
+    #[serde(rename = "bts:unsubscription_succeeded")]
+    UnsubscriptionSucceeded(Envelope<serde_json::Value>),
+    #[serde(rename = "bts:heartbeat")]
+    Heartbeat(Envelope<serde_json::Value>),
+    #[serde(rename = "bts:request_reconnect")]
+    RequestReconnect(Envelope<serde_json::Value>),
+    #[serde(rename = "trade")]
+    Trade(Envelope<Trade>),
+    #[serde(rename = "data")]
+    OrderBookDiff(Envelope<OrderBookDiff>),
+}
+
+/// Struct mapping for:
+///
+/// Message envelope from Bitstamp. The event tags the message type.
+/// {
+///     "event": "bts:subscription_succeeded",
+///     "channel": "live_trades_btcusd",
+///     "data": {}
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct Envelope<T> {
+    pub channel: String,
+    pub data: T,
+}
+
+/// Struct mapping for:
+///
+/// Live trade message from Bitstamp live_trades_<market> channel. Type 0 is a buy and 1 is a
+/// sell.
+/// {
+///     "data": {
+///         "id": 254238421,
+///         "timestamp": "1664391233",
+///         "amount": 0.0105,
+///         "amount_str": "0.01050000",
+///         "price": 19420,
+///         "price_str": "19420",
+///         "type": 0,
+///         "microtimestamp": "1664391233465000",
+///         "buy_order_id": 1534163837894656,
+///         "sell_order_id": 1534163831799808
+///     },
+///     "channel": "live_trades_btcusd",
+///     "event": "trade"
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct Trade {
+    pub id: u64,
+    #[serde(rename = "amount_str")]
+    pub amount: Decimal,
+    #[serde(rename = "price_str")]
+    pub price: Decimal,
+    pub r#type: u8,
+    pub microtimestamp: Decimal,
+}
+
+/// Struct mapping for:
+///
+/// Order book diff from Bitstamp diff_order_book_<market> channel. A zero amount removes the
+/// level. The REST order_book snapshot the diffs are applied to has the same fields.
+/// {
+///     "data": {
+///         "timestamp": "1664391234",
+///         "microtimestamp": "1664391234129305",
+///         "bids": [["19419", "0.25000000"]],
+///         "asks": [["19425", "0.00000000"]]
+///     },
+///     "channel": "diff_order_book_btcusd",
+///     "event": "data"
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct OrderBookDiff {
+    pub microtimestamp: Decimal,
+    pub bids: Vec<(Decimal, Decimal)>,
+    pub asks: Vec<(Decimal, Decimal)>,
+}
+
+/// Bitstamp channels are the stream name and lowercase market, e.g. diff_order_book_btcusd.
+pub fn channel_name(channel: &Channel) -> String {
+    match channel.channel {
+        ChannelType::Tape => format!("{}{}", TRADES_PREFIX, channel.market),
+        ChannelType::Book => format!("{}{}", DIFF_BOOK_PREFIX, channel.market),
+        // Never subscribed, Bitstamp only lists spot markets and private channels need a token
+        ChannelType::Liquidations
+        | ChannelType::Funding
+        | ChannelType::MarkPrice
+        | ChannelType::Fills
+        | ChannelType::Orders => channel.market.clone(),
+    }
+}
+
+/// Market of a channel name, e.g. btcusd for live_trades_btcusd.
+pub fn market(channel_name: &str) -> &str {
+    channel_name
+        .strip_prefix(TRADES_PREFIX)
+        .or_else(|| channel_name.strip_prefix(DIFF_BOOK_PREFIX))
+        .unwrap_or(channel_name)
+}
+
+impl App {
+    #[tracing::instrument(skip(self, channel, msg), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
+    pub async fn handle_ws_msg_bitstamp(
+        &mut self,
+        channel: Channel,
+        msg: Result<Message>,
+    ) -> Result<()> {
+        match msg {
+            Ok(m) => {
+                // Update socket last message
+                self.update_last(channel.clone())?;
+                // Parse message
+                if let Message::Text(text) = m {
+                    let response: Response = match serde_json::from_str(&text) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            tracing::error!("Could not parse message {:?}", text);
+                            tracing::error!("Error: {:?}", e);
+                            return Err(Error::Parse {
+                                exchange: channel.exchange,
+                                raw: text,
+                                source: e,
+                            });
+                        }
+                    };
+                    tracing::debug!("Response: {:?}", response);
+                    self.handle_ws_response_bitstamp(channel.clone(), response)
+                        .await?;
+                } else {
+                    tracing::warn!("Non-text Message: {:?}", m);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // Return Err
+                tracing::error!("Error: {:?}", e);
+                Err(e)
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self, channel, response), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
+    pub async fn handle_ws_response_bitstamp(
+        &mut self,
+        channel: Channel,
+        response: Response,
+    ) -> Result<()> {
+        match response {
+            Response::Trade(trade) => {
+                if self.carries(&channel, ChannelType::Tape) {
+                    // Convert bitstamp trade to trade and insert into trades state
+                    let channel = self.route(&channel, ChannelType::Tape, market(&trade.channel));
+                    let t: AppTrade = trade.data.try_into()?;
+                    self.insert_trade(channel, t).await?;
+                } else {
+                    // Trade message sent on a none tape channel
+                    tracing::error!("Trade message {:?} sent on channel {:?}", trade, channel);
+                    return Err(Error::ChannelResponseMismatch);
+                }
+            }
+            Response::OrderBookDiff(diff) => {
+                let channel = self.route(&channel, ChannelType::Book, market(&diff.channel));
+                self.insert_bitstamp_diff(channel, diff.data).await
+            }
+            Response::RequestReconnect(_) => {
+                // Sent ahead of maintenance. The socket reconnects once the close is answered.
+                tracing::warn!("Reconnect requested by Bitstamp for {:?}", channel);
+                self.request_reconnect(&channel);
+            }
+            Response::SubscriptionSucceeded(_)
+            | Response::UnsubscriptionSucceeded(_)
+            | Response::Heartbeat(_) => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use serde_json::Result;
+
+    use crate::bitstamp::{market, Response};
+    use crate::client::{Channel, Exchange};
+    use crate::websocket::mock;
+
+    #[tokio::test]
+    async fn tape_built_over_mock_transport() {
+        let (mut app, transport) = mock::app();
+        let channel = Channel::tape(Exchange::Bitstamp, "btcusd");
+        let started = mock::start(&mut app, &channel).await;
+        assert!(started.await.unwrap().is_ok());
+        assert_eq!(
+            transport.subscription(&channel)["data"]["channel"],
+            "live_trades_btcusd"
+        );
+
+        let trade = "{\"data\":{\"id\":254238421,\"timestamp\":\"1664391233\",\"amount\":0.0105,\"amount_str\":\"0.01050000\",\"price\":19420,\"price_str\":\"19420\",\"type\":0,\"microtimestamp\":\"1664391233465000\",\"buy_order_id\":1534163837894656,\"sell_order_id\":1534163831799808},\"channel\":\"live_trades_btcusd\",\"event\":\"trade\"}";
+        mock::deliver(&mut app, &channel, trade).await;
+        let next = trade
+            .replace("254238421", "254238422")
+            .replace("\"type\":0", "\"type\":1");
+        mock::deliver(&mut app, &channel, &next).await;
+
+        let tape = mock::tape(&mut app, &channel).await.unwrap();
+        assert_eq!(tape.len(), 2);
+        assert_eq!(tape[0].price, dec!(19420));
+        assert_eq!(tape[1].id, Some("254238422".to_string()));
+    }
+
+    #[test]
+    pub fn deserialize_live_trade() -> Result<()> {
+        let data = "{\"data\":{\"id\":254238421,\"timestamp\":\"1664391233\",\"amount\":0.0105,\"amount_str\":\"0.01050000\",\"price\":19420,\"price_str\":\"19420\",\"type\":0,\"microtimestamp\":\"1664391233465000\",\"buy_order_id\":1534163837894656,\"sell_order_id\":1534163831799808},\"channel\":\"live_trades_btcusd\",\"event\":\"trade\"}";
+        let v: Response = serde_json::from_str(data)?;
+        match v {
+            Response::Trade(trade) => {
+                assert_eq!(market(&trade.channel), "btcusd");
+                assert_eq!(trade.data.price, dec!(19420));
+                assert_eq!(trade.data.amount, dec!(0.0105));
+            }
+            _ => panic!("Expected trade, got {:?}", v),
+        }
+        Ok(())
+    }
+
+    #[test]
+    pub fn deserialize_diff_order_book() -> Result<()> {
+        let data = "{\"data\":{\"timestamp\":\"1664391234\",\"microtimestamp\":\"1664391234129305\",\"bids\":[[\"19419\",\"0.25000000\"]],\"asks\":[[\"19425\",\"0.00000000\"]]},\"channel\":\"diff_order_book_btcusd\",\"event\":\"data\"}";
+        let v: Response = serde_json::from_str(data)?;
+        match v {
+            Response::OrderBookDiff(diff) => {
+                assert_eq!(market(&diff.channel), "btcusd");
+                assert_eq!(diff.data.bids[0], (dec!(19419), dec!(0.25)));
+                assert_eq!(diff.data.asks[0].1, dec!(0));
+            }
+            _ => panic!("Expected book diff, got {:?}", v),
+        }
+        Ok(())
+    }
+
+    #[test]
+    pub fn deserialize_request_reconnect() -> Result<()> {
+        let data = "{\"event\":\"bts:request_reconnect\",\"channel\":\"\",\"data\":\"\"}";
+        let v: Response = serde_json::from_str(data)?;
+        assert!(matches!(v, Response::RequestReconnect(_)));
+        Ok(())
+    }
+}
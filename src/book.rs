@@ -1,19 +1,181 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
-use rust_decimal::Decimal;
+use chrono::{DateTime, Utc};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::time::Instant;
 
 use crate::{
     app::{App, TradeSide},
-    client::Channel,
-    gdax::{L2update, Snapshot as GdaxSnapshot},
+    binance::Depth as BinanceDepth,
+    bitstamp::OrderBookDiff as BitstampDiff,
+    bybit::{OrderBook as BybitOrderBook, UpdateType as BybitUpdateType},
+    client::{Channel, ChannelType, ClientResp, ClientRespMsg, Exchange},
+    deribit::{
+        base_amount as deribit_base_amount, BookData as DeribitBook,
+        BookUpdateType as DeribitUpdateType,
+    },
+    error::{Error, Result},
+    gdax::{
+        L2Data, L2DataType, L2Side, L2update, RestBook as GdaxRestBook, Snapshot as GdaxSnapshot,
+    },
     hyperliquid::L2Book,
-    kraken::{L2updateAsk, L2updateBid, L2updateBoth, Snapshot as KrakenSnapshot},
+    kraken::{
+        newer_levels, BidAsks as KrakenBidAsks, L2updateAsk, L2updateBid, L2updateBoth,
+        Level as KrakenLevel, LevelTimes, RestDepth as KrakenRestDepth, Snapshot as KrakenSnapshot,
+        V2Book as KrakenV2Book, V2UpdateType, KRAKEN_BOOK_DEPTH,
+    },
+    market::MarketMeta,
+    okx::{BookAction, Books as OkxBooks},
+    rest::{book_url, get_json},
+    sync::LockRecover,
+    websocket::WsEvent,
 };
 
-#[derive(Debug, Clone)]
+/// Consecutive updates a book may be left crossed before it is resnapshotted.
+pub const CROSSED_UPDATE_LIMIT: usize = 3;
+
+/// Updates buffered for a book waiting for its snapshot before the book is resubscribed, in case
+/// the snapshot was lost.
+pub const PENDING_UPDATE_LIMIT: usize = 10_000;
+
+/// Default number of updates between consistency checks of each book.
+pub const DEFAULT_BOOK_CHECK_UPDATES: u64 = 100;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Book {
     pub bids: BTreeMap<Decimal, Decimal>,
     pub asks: BTreeMap<Decimal, Decimal>,
+    // Time the book was built or last changed by an exchange message
+    #[serde(default = "Utc::now")]
+    pub last_update: DateTime<Utc>,
+}
+
+/// Book invariant found broken by Book::check.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BookViolation {
+    // Best bid at or above the best ask
+    Crossed {
+        bid: Decimal,
+        ask: Decimal,
+    },
+    // Level left with a zero or negative size instead of being removed
+    NonPositiveSize {
+        side: TradeSide,
+        price: Decimal,
+        size: Decimal,
+    },
+}
+
+/// Updates applied to a book since it was last built from a snapshot, used to schedule
+/// consistency checks and resnapshots.
+#[derive(Debug, Clone)]
+pub struct BookCheck {
+    pub updates: u64,
+    pub since: Instant,
+}
+
+impl BookCheck {
+    pub fn new() -> Self {
+        Self {
+            updates: 0,
+            since: Instant::now(),
+        }
+    }
+}
+
+impl Default for BookCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Top of book summary computed without cloning the book.
+#[derive(Debug, Clone)]
+pub struct TopOfBook {
+    pub best_bid: (Decimal, Decimal),
+    pub best_ask: (Decimal, Decimal),
+    pub mid: Decimal,
+    // Mid weighted by the size on the other side, see Book::weighted_mid
+    pub weighted_mid: Decimal,
+    pub spread: Decimal,
+    pub ts: DateTime<Utc>,
+}
+
+/// Level counts and total volume of each side of a book computed without cloning the book. The
+/// best bid and ask are None when their side is empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookStats {
+    pub bid_levels: usize,
+    pub ask_levels: usize,
+    pub total_bid_volume: Decimal,
+    pub total_ask_volume: Decimal,
+    pub best_bid: Option<(Decimal, Decimal)>,
+    pub best_ask: Option<(Decimal, Decimal)>,
+}
+
+/// Top levels of each side of a book ordered from the top of book, bids by descending price and
+/// asks by ascending price.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Depth {
+    pub bids: Vec<(Decimal, Decimal)>,
+    pub asks: Vec<(Decimal, Decimal)>,
+}
+
+/// Level of a depth chart with the total size from the top of book down to and including it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepthPoint {
+    pub price: Decimal,
+    pub size: Decimal,
+    pub cumulative: Decimal,
+}
+
+/// Levels that changed between two versions of a book, sorted by ascending price on each side.
+/// A size of None means the level was removed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookDelta {
+    pub bids: Vec<(Decimal, Option<Decimal>)>,
+    pub asks: Vec<(Decimal, Option<Decimal>)>,
+}
+
+impl BookDelta {
+    pub fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+}
+
+/// Result of walking one side of the book with a notional amount. A buy sweeps the asks from the
+/// best ask up and a sell sweeps the bids from the best bid down.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sweep {
+    // Size filled
+    pub quantity: Decimal,
+    // Notional filled, less than requested if the book side ran out
+    pub notional: Decimal,
+    // Volume weighted price of the fill, None if nothing filled
+    pub avg_price: Option<Decimal>,
+    // Price of the last level reached
+    pub worst_price: Option<Decimal>,
+}
+
+fn side_diff(
+    prev: &BTreeMap<Decimal, Decimal>,
+    next: &BTreeMap<Decimal, Decimal>,
+) -> Vec<(Decimal, Option<Decimal>)> {
+    let mut changes: Vec<_> = next
+        .iter()
+        .filter(|(price, size)| prev.get(price) != Some(size))
+        .map(|(price, size)| (*price, Some(*size)))
+        .chain(
+            prev.keys()
+                .filter(|price| !next.contains_key(price))
+                .map(|price| (*price, None)),
+        )
+        .collect();
+    changes.sort_by_key(|(price, _)| *price);
+    changes
 }
 
 impl Book {
@@ -21,8 +183,444 @@ impl Book {
         Book {
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
+            last_update: Utc::now(),
+        }
+    }
+
+    /// Time since the book last changed. A live feed can keep the socket fresh while a quiet
+    /// market leaves the book untouched, so this is tracked separately from the socket.
+    pub fn age(&self) -> chrono::Duration {
+        Utc::now() - self.last_update
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(s: &str) -> Result<Self> {
+        Ok(serde_json::from_str(s)?)
+    }
+
+    /// Book as `{"bids": [[price, size], ...], "asks": [[price, size], ...]}` with the best
+    /// levels first and prices and sizes as strings so they survive a JS number. All levels are
+    /// included if no depth is given.
+    pub fn to_levels_json(&self, depth: Option<usize>) -> serde_json::Value {
+        let depth = depth.unwrap_or(usize::MAX);
+        let levels = |side: Vec<(Decimal, Decimal)>| -> Vec<[String; 2]> {
+            side.into_iter()
+                .map(|(p, s)| [p.to_string(), s.to_string()])
+                .collect()
+        };
+        serde_json::json!({
+            "bids": levels(self.bids_depth(depth)),
+            "asks": levels(self.asks_depth(depth)),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+
+    /// Levels added, resized or removed since `prev`.
+    pub fn diff(&self, prev: &Book) -> BookDelta {
+        BookDelta {
+            bids: side_diff(&prev.bids, &self.bids),
+            asks: side_diff(&prev.asks, &self.asks),
+        }
+    }
+
+    /// Trims the book to the top `depth` levels on each side, removing the levels furthest from
+    /// the mid (lowest bids and highest asks).
+    pub fn trim(&mut self, depth: usize) {
+        while self.bids.len() > depth {
+            self.bids.pop_first();
+        }
+        while self.asks.len() > depth {
+            self.asks.pop_last();
         }
     }
+
+    /// Highest bid price and size. Bids are stored ascending so the top of book is the last key.
+    pub fn best_bid(&self) -> Option<(Decimal, Decimal)> {
+        self.bids.iter().next_back().map(|(p, s)| (*p, *s))
+    }
+
+    /// Lowest ask price and size. Asks are stored ascending so the top of book is the first key.
+    pub fn best_ask(&self) -> Option<(Decimal, Decimal)> {
+        self.asks.iter().next().map(|(p, s)| (*p, *s))
+    }
+
+    pub fn mid_price(&self) -> Option<Decimal> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some((bid, _)), Some((ask, _))) => Some((bid + ask) / Decimal::TWO),
+            _ => None,
+        }
+    }
+
+    /// Microprice `(bid_px * ask_sz + ask_px * bid_sz) / (bid_sz + ask_sz)`, which leans toward
+    /// the side with less size as that level is the more likely to trade through.
+    pub fn weighted_mid(&self) -> Option<Decimal> {
+        let ((bid_px, bid_sz), (ask_px, ask_sz)) = (self.best_bid()?, self.best_ask()?);
+        let total = bid_sz + ask_sz;
+        if total.is_zero() {
+            return None;
+        }
+        Some((bid_px * ask_sz + ask_px * bid_sz) / total)
+    }
+
+    /// True if the best bid is at or above the best ask, usually from a missed delete. Crossed
+    /// books have a negative or zero spread.
+    pub fn is_crossed(&self) -> bool {
+        match (self.best_bid(), self.best_ask()) {
+            (Some((bid, _)), Some((ask, _))) => bid >= ask,
+            _ => false,
+        }
+    }
+
+    /// Checks the invariants a maintained book should hold: the book is not crossed and every
+    /// level has a positive size. Returns the violations found, empty for a consistent book.
+    pub fn check(&self) -> Vec<BookViolation> {
+        let mut violations = Vec::new();
+        if let (Some((bid, _)), Some((ask, _))) = (self.best_bid(), self.best_ask()) {
+            if bid >= ask {
+                violations.push(BookViolation::Crossed { bid, ask });
+            }
+        }
+        let sides = [(TradeSide::Buy, &self.bids), (TradeSide::Sell, &self.asks)];
+        for (side, levels) in sides {
+            violations.extend(
+                levels
+                    .iter()
+                    .filter(|(_, size)| **size <= Decimal::ZERO)
+                    .map(|(price, size)| BookViolation::NonPositiveSize {
+                        side,
+                        price: *price,
+                        size: *size,
+                    }),
+            );
+        }
+        violations
+    }
+
+    /// Removes the levels crossing the opposite side, bids at or above the best ask and asks at or
+    /// below the best bid. Either side may hold the stale level so both are removed. Returns true
+    /// if any levels were removed.
+    pub fn uncross(&mut self) -> bool {
+        let (bid, ask) = match (self.best_bid(), self.best_ask()) {
+            (Some((bid, _)), Some((ask, _))) if bid >= ask => (bid, ask),
+            _ => return false,
+        };
+        self.bids.retain(|p, _| *p < ask);
+        self.asks.retain(|p, _| *p > bid);
+        true
+    }
+
+    pub fn spread(&self) -> Option<Decimal> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some((bid, _)), Some((ask, _))) => Some(ask - bid),
+            _ => None,
+        }
+    }
+
+    /// Top of book summary. Returns None if either side of the book is empty.
+    pub fn top_of_book(&self) -> Option<TopOfBook> {
+        let (best_bid, best_ask) = (self.best_bid()?, self.best_ask()?);
+        Some(TopOfBook {
+            best_bid,
+            best_ask,
+            mid: (best_bid.0 + best_ask.0) / Decimal::TWO,
+            weighted_mid: self.weighted_mid()?,
+            spread: best_ask.0 - best_bid.0,
+            ts: Utc::now(),
+        })
+    }
+
+    pub fn bid_levels(&self) -> usize {
+        self.bids.len()
+    }
+
+    pub fn ask_levels(&self) -> usize {
+        self.asks.len()
+    }
+
+    pub fn total_bid_volume(&self) -> Decimal {
+        self.bids.values().sum()
+    }
+
+    pub fn total_ask_volume(&self) -> Decimal {
+        self.asks.values().sum()
+    }
+
+    /// Level counts, total volume and best level of each side. Unlike top_of_book it is returned
+    /// for books with an empty side.
+    pub fn stats(&self) -> BookStats {
+        self.stats_to_depth(usize::MAX)
+    }
+
+    /// Stats over the top `depth` levels of each side, as for a book trimmed to that depth.
+    pub fn stats_to_depth(&self, depth: usize) -> BookStats {
+        let bids = || self.bids.iter().rev().take(depth);
+        let asks = || self.asks.iter().take(depth);
+        BookStats {
+            bid_levels: self.bids.len().min(depth),
+            ask_levels: self.asks.len().min(depth),
+            total_bid_volume: bids().map(|(_, s)| *s).sum(),
+            total_ask_volume: asks().map(|(_, s)| *s).sum(),
+            best_bid: bids().next().map(|(p, s)| (*p, *s)),
+            best_ask: asks().next().map(|(p, s)| (*p, *s)),
+        }
+    }
+
+    /// Top n bid levels by descending price, best bid first.
+    pub fn bids_depth(&self, n: usize) -> Vec<(Decimal, Decimal)> {
+        self.bids
+            .iter()
+            .rev()
+            .take(n)
+            .map(|(p, s)| (*p, *s))
+            .collect()
+    }
+
+    /// Top n ask levels by ascending price, best ask first.
+    pub fn asks_depth(&self, n: usize) -> Vec<(Decimal, Decimal)> {
+        self.asks.iter().take(n).map(|(p, s)| (*p, *s)).collect()
+    }
+
+    pub fn depth(&self, n: usize) -> Depth {
+        Depth {
+            bids: self.bids_depth(n),
+            asks: self.asks_depth(n),
+        }
+    }
+
+    /// Top levels of one side of the book with the running total size from the top of book, the
+    /// series a depth chart plots. Buy is the bid side walking down from the best bid and Sell
+    /// the ask side walking up from the best ask.
+    pub fn depth_chart(&self, side: TradeSide, levels: usize) -> Vec<DepthPoint> {
+        let depth = match side {
+            TradeSide::Buy => self.bids_depth(levels),
+            TradeSide::Sell => self.asks_depth(levels),
+        };
+        let mut cumulative = Decimal::ZERO;
+        depth
+            .into_iter()
+            .map(|(price, size)| {
+                cumulative += size;
+                DepthPoint {
+                    price,
+                    size,
+                    cumulative,
+                }
+            })
+            .collect()
+    }
+
+    /// Total bid size at prices at or above `down_to`, the size a sell could fill before the
+    /// price falls below it.
+    pub fn cumulative_bid_volume(&self, down_to: Decimal) -> Decimal {
+        self.bids.range(down_to..).map(|(_, s)| *s).sum()
+    }
+
+    /// Total ask size at prices at or below `up_to`, the size a buy could fill before the price
+    /// rises above it.
+    pub fn cumulative_ask_volume(&self, up_to: Decimal) -> Decimal {
+        self.asks.range(..=up_to).map(|(_, s)| *s).sum()
+    }
+
+    /// Walks the side of the book a market order on `side` would take, filling levels until the
+    /// notional is spent. The last level reached is filled partially.
+    pub fn sweep(&self, side: TradeSide, notional: Decimal) -> Sweep {
+        self.sweep_to_depth(side, notional, usize::MAX)
+    }
+
+    /// Sweep over the top `depth` levels of the side, as for a book trimmed to that depth.
+    pub fn sweep_to_depth(&self, side: TradeSide, notional: Decimal, depth: usize) -> Sweep {
+        let levels: Box<dyn Iterator<Item = (&Decimal, &Decimal)>> = match side {
+            TradeSide::Buy => Box::new(self.asks.iter().take(depth)),
+            TradeSide::Sell => Box::new(self.bids.iter().rev().take(depth)),
+        };
+        let mut remaining = notional;
+        let mut quantity = Decimal::ZERO;
+        let mut worst_price = None;
+        for (price, size) in levels {
+            if remaining <= Decimal::ZERO {
+                break;
+            }
+            let level_notional = price * size;
+            worst_price = Some(*price);
+            if level_notional >= remaining {
+                quantity += remaining / price;
+                remaining = Decimal::ZERO;
+            } else {
+                quantity += size;
+                remaining -= level_notional;
+            }
+        }
+        let filled = notional - remaining;
+        Sweep {
+            quantity,
+            notional: filled,
+            avg_price: (!quantity.is_zero()).then(|| filled / quantity),
+            worst_price,
+        }
+    }
+
+    /// Size a market order on `side` for the notional amount would fill. See sweep for the
+    /// average price.
+    pub fn quantity_for_notional(&self, side: TradeSide, notional: Decimal) -> Decimal {
+        self.sweep(side, notional).quantity
+    }
+
+    /// Volume imbalance over the top depth levels on each side, (bid_vol - ask_vol) /
+    /// (bid_vol + ask_vol). Ranges from -1 when all volume is on the asks to 1 when all volume is
+    /// on the bids. Returns None if either side of the book is empty.
+    pub fn imbalance(&self, depth: usize) -> Option<Decimal> {
+        let bid_vol: Decimal = self.bids.values().rev().take(depth).sum();
+        let ask_vol: Decimal = self.asks.values().take(depth).sum();
+        if self.bids.is_empty() || self.asks.is_empty() || (bid_vol + ask_vol).is_zero() {
+            return None;
+        }
+        Some((bid_vol - ask_vol) / (bid_vol + ask_vol))
+    }
+}
+
+impl Book {
+    /// Kraken CRC32 checksum of the book. The top 10 asks (ascending) followed by the top 10 bids
+    /// (descending) are formatted as price then volume with the decimal point and leading zeros
+    /// removed, concatenated and hashed. Prices and volumes are formatted to the precision they
+    /// were received with, padded to the market precision if known so levels that lost trailing
+    /// zeros still hash as Kraken formats them.
+    pub fn kraken_checksum(&self, meta: Option<&MarketMeta>) -> u32 {
+        let price_dp = meta.map_or(0, |m| m.price_decimals);
+        let size_dp = meta.map_or(0, |m| m.size_decimals);
+        crc32fast::hash(self.kraken_checksum_input(price_dp, size_dp).as_bytes())
+    }
+
+    /// String hashed by `kraken_checksum`, with prices and volumes padded to at least the given
+    /// decimals. Useful to diff against Kraken's formatting when a checksum fails.
+    pub fn kraken_checksum_input(&self, price_decimals: u32, size_decimals: u32) -> String {
+        let asks = self.asks.iter().take(10);
+        let bids = self.bids.iter().rev().take(10);
+        asks.chain(bids)
+            .map(|(price, volume)| {
+                kraken_checksum_field(price, price_decimals)
+                    + &kraken_checksum_field(volume, size_decimals)
+            })
+            .collect()
+    }
+
+    fn apply_gdax_l2update(&mut self, l2update: &L2update) {
+        for (side, price, size) in l2update.changes.iter() {
+            let levels = match side {
+                TradeSide::Buy => &mut self.bids,
+                TradeSide::Sell => &mut self.asks,
+            };
+            if *size == Decimal::ZERO {
+                levels.remove(price);
+            } else {
+                levels.insert(*price, *size);
+            }
+        }
+    }
+
+    fn apply_bitstamp_diff(&mut self, diff: &BitstampDiff) {
+        for (levels, changes) in [(&mut self.bids, &diff.bids), (&mut self.asks, &diff.asks)] {
+            for (price, size) in changes.iter() {
+                if *size == Decimal::ZERO {
+                    levels.remove(price);
+                } else {
+                    levels.insert(*price, *size);
+                }
+            }
+        }
+    }
+
+    fn apply_kraken_asks(&mut self, levels: &[KrakenLevel]) {
+        for ask in levels.iter() {
+            if ask.volume == Decimal::ZERO {
+                self.asks.remove(&ask.price);
+            } else {
+                self.asks.insert(ask.price, ask.volume);
+            }
+        }
+        // Kraken does not send deletes for levels pushed out of the subscribed depth
+        while self.asks.len() > KRAKEN_BOOK_DEPTH {
+            self.asks.pop_last();
+        }
+    }
+
+    fn apply_kraken_bids(&mut self, levels: &[KrakenLevel]) {
+        for bid in levels.iter() {
+            if bid.volume == Decimal::ZERO {
+                self.bids.remove(&bid.price);
+            } else {
+                self.bids.insert(bid.price, bid.volume);
+            }
+        }
+        while self.bids.len() > KRAKEN_BOOK_DEPTH {
+            self.bids.pop_first();
+        }
+    }
+
+    fn verify_kraken_checksum(
+        &self,
+        channel: &Channel,
+        checksum: Option<&str>,
+        meta: Option<&MarketMeta>,
+    ) -> Result<()> {
+        let expected = match checksum.map(|c| c.parse::<u32>()) {
+            Some(Ok(c)) => c,
+            Some(Err(_)) => {
+                tracing::warn!("Could not parse checksum {:?}", checksum);
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        if self.kraken_checksum(meta) == expected {
+            Ok(())
+        } else {
+            Err(Error::ChecksumMismatch {
+                channel: channel.clone(),
+            })
+        }
+    }
+}
+
+fn kraken_checksum_field(d: &Decimal, min_dp: u32) -> String {
+    format!("{:.*}", min_dp.max(d.scale()) as usize, d)
+        .replace('.', "")
+        .trim_start_matches('0')
+        .to_string()
+}
+
+/// Consolidates several books into one by summing the size at each price level. If a precision is
+/// given, prices are rounded to that many decimal places before summing so venues with different
+/// tick sizes share levels. Bids are rounded down and asks up so merged levels are never better
+/// than the levels they were built from.
+pub fn aggregate_books<'a>(
+    books: impl IntoIterator<Item = &'a Book>,
+    precision: Option<u32>,
+) -> Book {
+    let round = |price: &Decimal, strategy: RoundingStrategy| match precision {
+        Some(dp) => price.round_dp_with_strategy(dp, strategy),
+        None => *price,
+    };
+    let mut agg = Book::new();
+    for book in books.into_iter() {
+        for (price, size) in book.bids.iter() {
+            *agg.bids
+                .entry(round(price, RoundingStrategy::ToZero))
+                .or_insert(Decimal::ZERO) += size;
+        }
+        for (price, size) in book.asks.iter() {
+            *agg.asks
+                .entry(round(price, RoundingStrategy::AwayFromZero))
+                .or_insert(Decimal::ZERO) += size;
+        }
+        // The aggregate is only as fresh as its stalest member
+        agg.last_update = agg.last_update.min(book.last_update);
+    }
+    agg
 }
 
 impl Default for Book {
@@ -32,41 +630,440 @@ impl Default for Book {
 }
 
 impl App {
+    /// Trims the book to the max depth requested for the channel on subscribe, if any. Kraken
+    /// books are kept at the subscribed depth as levels below a trimmed book are not resent when
+    /// they move into the checksum. Reads walk only the requested depth, see with_book.
+    pub fn trim_book(&self, channel: &Channel, book: &mut Book) {
+        if matches!(channel.exchange, Exchange::Kraken | Exchange::KrakenV2) {
+            return;
+        }
+        if let Some(depth) = self.book_depths.get(channel) {
+            book.trim(*depth);
+        }
+    }
+
+    /// Copy of the book as published and read by clients, trimmed to the max depth requested for
+    /// the channel. Only Kraken books are stored deeper than requested, see trim_book.
+    pub fn book_view(&self, channel: &Channel, book: &Book) -> Book {
+        let mut view = book.clone();
+        if let Some(depth) = self.book_depths.get(channel) {
+            view.trim(*depth);
+        }
+        view
+    }
+
+    /// Book of the channel as read by clients, see book_view.
+    pub fn read_book(&self, channel: &Channel) -> Option<Book> {
+        let books = self.state.books.lock_recover();
+        books.get(channel).map(|book| self.book_view(channel, book))
+    }
+
+    /// Calls `f` with the book of the channel and the max depth requested for it while holding
+    /// the books lock, so summaries can walk just the levels a client reads without cloning the
+    /// book.
+    pub fn with_book<T>(
+        &self,
+        channel: &Channel,
+        f: impl FnOnce(&Book, usize) -> Result<T>,
+    ) -> Result<T> {
+        let depth = self.book_depths.get(channel).copied().unwrap_or(usize::MAX);
+        match self.state.books.lock_recover().get(channel) {
+            Some(book) => f(book, depth),
+            None => Err(Error::ChannelDoesNotExist),
+        }
+    }
+
+    /// Removes crossed levels from the book if uncross_books is set in the client config. Kraken
+    /// books are left as is as the checksum covers the crossed levels and resubscribes on a
+    /// mismatch. Channels crossed on CROSSED_UPDATE_LIMIT consecutive updates are flagged stale by
+    /// flag_crossed_books.
+    pub fn uncross_book(&self, channel: &Channel, book: &mut Book) {
+        if !self.config.uncross_books
+            || matches!(channel.exchange, Exchange::Kraken | Exchange::KrakenV2)
+        {
+            return;
+        }
+        let mut crossed = self.crossed_books.lock_recover();
+        if book.uncross() {
+            tracing::warn!("Removed crossed levels from {:?}", channel);
+            *crossed.entry(channel.clone()).or_insert(0) += 1;
+        } else {
+            crossed.remove(channel);
+        }
+    }
+
+    /// Flags channels whose book stayed crossed for resubscribe to rebuild it from a new snapshot.
+    pub fn flag_crossed_books(&mut self) {
+        let stale: Vec<Channel> = {
+            let mut crossed = self.crossed_books.lock_recover();
+            let stale = crossed
+                .iter()
+                .filter(|(_, n)| **n >= CROSSED_UPDATE_LIMIT)
+                .map(|(c, _)| c.clone())
+                .collect();
+            crossed.retain(|_, n| *n < CROSSED_UPDATE_LIMIT);
+            stale
+        };
+        for channel in stale {
+            tracing::warn!("Book {:?} crossed on consecutive updates", channel);
+            self.errored.insert(channel.clone());
+            if let Some(sender) = self.app_sender.as_ref() {
+                let _ = sender.send(Ok(ClientRespMsg {
+                    channel: Some(channel),
+                    resp: ClientResp::BookStale,
+                }));
+            }
+        }
+    }
+
+    /// Counts the update towards the channel's resnapshot and checks the book's consistency every
+    /// book_check_updates updates. Violations are logged and counted in the metrics but the book
+    /// is left as is, resnapshot_updates and resnapshot_interval rebuild it periodically.
+    pub fn check_book(&self, channel: &Channel, book: &Book) {
+        let updates = {
+            let mut checks = self.book_checks.lock_recover();
+            let check = checks.entry(channel.clone()).or_default();
+            check.updates += 1;
+            check.updates
+        };
+        match self.config.book_check_updates {
+            Some(n) if n > 0 && updates % n == 0 => {}
+            _ => return,
+        }
+        let violations = book.check();
+        if !violations.is_empty() {
+            tracing::warn!("Book {:?} inconsistent: {:?}", channel, violations);
+            self.state
+                .counters
+                .book_violations(channel, violations.len() as u64);
+        }
+    }
+
+    /// Flags books due a resnapshot under resnapshot_updates or resnapshot_interval for
+    /// resubscribe to rebuild them from a new snapshot.
+    pub fn flag_resnapshot_books(&mut self) {
+        let (max_updates, max_age) = (
+            self.config.resnapshot_updates,
+            self.config.resnapshot_interval,
+        );
+        if max_updates.is_none() && max_age.is_none() {
+            return;
+        }
+        let due: Vec<Channel> = {
+            let mut checks = self.book_checks.lock_recover();
+            let due: Vec<Channel> = checks
+                .iter()
+                .filter(|(_, c)| {
+                    max_updates.is_some_and(|n| c.updates >= n)
+                        || max_age.is_some_and(|age| c.since.elapsed() >= age)
+                })
+                .map(|(channel, _)| channel.clone())
+                .collect();
+            due.iter().for_each(|channel| {
+                checks.remove(channel);
+            });
+            due
+        };
+        for channel in due {
+            tracing::info!("Resnapshotting book {:?}", channel);
+            self.errored.insert(channel);
+        }
+    }
+
+    /// Books of the channels as read by read_book, copied under a single lock so every book is
+    /// from the same point in time.
+    pub fn books_batch(&self, channels: &[Channel]) -> Result<HashMap<Channel, Book>> {
+        let books = self.state.books.lock_recover();
+        channels
+            .iter()
+            .map(|channel| match books.get(channel) {
+                Some(b) => Ok((channel.clone(), self.book_view(channel, b))),
+                None => Err(Error::ChannelDoesNotExist),
+            })
+            .collect()
+    }
+
+    /// Consolidated book across the book channels as read by read_book. See aggregate_books.
+    pub fn book_agg(&self, channels: &[Channel], precision: Option<u32>) -> Result<Book> {
+        let books = self.state.books.lock_recover();
+        let mut channel_books = Vec::with_capacity(channels.len());
+        for channel in channels.iter() {
+            if channel.channel != ChannelType::Book {
+                return Err(Error::ChannelResponseMismatch);
+            }
+            match books.get(channel) {
+                Some(b) => channel_books.push(self.book_view(channel, b)),
+                None => return Err(Error::ChannelDoesNotExist),
+            }
+        }
+        Ok(aggregate_books(&channel_books, precision))
+    }
+
+    /// Stamps the book with the update time, checks it with check_book, streams the book update
+    /// or the levels changed since the last update if requested and marks the book ready on its
+    /// first non empty update, sending ClientResp::Ready once to the async client.
+    pub fn publish_book(&self, channel: &Channel, book: &mut Book) {
+        book.last_update = Utc::now();
+        self.check_book(channel, book);
+        if !book.is_empty() && self.ready_books.lock_recover().insert(channel.clone()) {
+            tracing::info!("Book {:?} ready", channel);
+            if let Some(sender) = self.app_sender.as_ref() {
+                let _ = sender.send(Ok(ClientRespMsg {
+                    channel: Some(channel.clone()),
+                    resp: ClientResp::Ready,
+                }));
+            }
+        }
+        let Some(sender) = self.app_sender.as_ref() else {
+            return;
+        };
+        let streamed = self.streams.contains(channel);
+        let mut deltas = self.book_deltas.lock_recover();
+        let base = deltas.get_mut(channel);
+        if !streamed && base.is_none() {
+            return;
+        }
+        // One copy of the view is shared by the stream, the delta base and the client
+        let view = Arc::new(self.book_view(channel, book));
+        if let Some(base) = base {
+            let delta = view.diff(base);
+            if !delta.is_empty() {
+                let _ = sender.send(Ok(ClientRespMsg {
+                    channel: Some(channel.clone()),
+                    resp: ClientResp::BookDelta(delta),
+                }));
+            }
+            *base = view.clone();
+        }
+        if streamed {
+            let _ = sender.send(Ok(ClientRespMsg {
+                channel: Some(channel.clone()),
+                resp: ClientResp::BookUpdate(view),
+            }));
+        }
+    }
+
+    /// Fetches the book of a channel subscribed with rest_snapshot, or of a Bitstamp book, from
+    /// the exchange REST api on a separate task so the client loop is not held up. The book is
+    /// delivered to handle_ws_msg as a WsEvent::RestBook. A failed request is only logged as the
+    /// socket snapshot follows, see insert_bitstamp_diff for Bitstamp.
+    pub fn fetch_rest_book(&self, channel: &Channel) {
+        let url = self
+            .config
+            .rest_url(&channel.exchange)
+            .ok_or(Error::UnsupportedChannel)
+            .and_then(|base| book_url(&base, channel));
+        let url = match url {
+            Ok(url) => url,
+            Err(e) => {
+                tracing::warn!("No REST book for {:?}: {:?}", channel, e);
+                return;
+            }
+        };
+        let sender = self.ws_sender.clone();
+        let http = self.http.clone();
+        let channel = channel.clone();
+        tokio::spawn(async move {
+            match get_json(&http, url).await {
+                Ok(book) => {
+                    let _ = sender.send((channel, Ok(WsEvent::RestBook(book)))).await;
+                }
+                Err(e) => tracing::warn!("Could not fetch REST book for {:?}: {:?}", channel, e),
+            }
+        });
+    }
+
+    /// Seeds the book from a book fetched by fetch_rest_book. A book that cannot be parsed is
+    /// logged rather than flagged for resubscribe, as for a failed request.
+    pub fn insert_rest_book(&mut self, channel: Channel, book: Value) {
+        let result = match channel.exchange {
+            Exchange::Gdax => serde_json::from_value(book)
+                .map(|rest| self.insert_gdax_rest_book(channel.clone(), rest)),
+            Exchange::Kraken => serde_json::from_value::<KrakenRestDepth>(book).map(|depth| {
+                match depth.result.into_values().next() {
+                    Some(rest) if depth.error.is_empty() => {
+                        let snapshot = KrakenBidAsks {
+                            r#as: rest.asks,
+                            bs: rest.bids,
+                        };
+                        self.insert_kraken_rest_book(channel.clone(), snapshot);
+                    }
+                    _ => tracing::warn!("Kraken REST book errors: {:?}", depth.error),
+                }
+            }),
+            Exchange::Bitstamp => serde_json::from_value(book)
+                .map(|snapshot| self.insert_bitstamp_rest_book(channel.clone(), snapshot)),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            tracing::warn!("Could not parse REST book for {:?}: {:?}", channel, e);
+        }
+    }
+
+    /// Builds a Bitstamp book from the REST snapshot, applying the buffered diffs newer than it.
+    /// Dropped if the book is not waiting for a snapshot, e.g. after a resubscribe.
+    pub fn insert_bitstamp_rest_book(&mut self, channel: Channel, snapshot: BitstampDiff) {
+        let pending = match self.bitstamp_pending_diffs.remove(&channel) {
+            Some(pending) => pending,
+            None => {
+                tracing::debug!("Dropped REST book for {:?} not awaiting one", channel);
+                return;
+            }
+        };
+        let mut book = Book::new();
+        book.bids.extend(snapshot.bids);
+        book.asks.extend(snapshot.asks);
+        for diff in pending
+            .iter()
+            .filter(|d| d.microtimestamp > snapshot.microtimestamp)
+        {
+            book.apply_bitstamp_diff(diff);
+        }
+        self.uncross_book(&channel, &mut book);
+        self.trim_book(&channel, &mut book);
+        self.publish_book(&channel, &mut book);
+        let mut books = self.state.books.lock_recover();
+        books.insert(channel, book);
+    }
+
+    /// Seeds a Coinbase Pro book still waiting for its socket snapshot, applying the buffered
+    /// l2updates newer than the REST book. Until the snapshot replaces the book, l2updates newer
+    /// than the REST book are applied to it as well as buffered. Dropped if the snapshot has
+    /// already arrived.
+    pub fn insert_gdax_rest_book(&mut self, channel: Channel, rest: GdaxRestBook) {
+        let pending = match self.gdax_pending_updates.get(&channel) {
+            Some(pending) => pending,
+            None => {
+                tracing::debug!(
+                    "Dropped REST book received after snapshot for {:?}",
+                    channel
+                );
+                return;
+            }
+        };
+        let mut book = Book::new();
+        book.bids.extend(rest.bids.iter().map(|l| (l.0, l.1)));
+        book.asks.extend(rest.asks.iter().map(|l| (l.0, l.1)));
+        for l2update in pending.iter().filter(|u| u.time > rest.time) {
+            book.apply_gdax_l2update(l2update);
+        }
+        self.gdax_rest_times.insert(channel.clone(), rest.time);
+        self.uncross_book(&channel, &mut book);
+        self.trim_book(&channel, &mut book);
+        self.publish_book(&channel, &mut book);
+        let mut books = self.state.books.lock_recover();
+        books.insert(channel, book);
+    }
+
+    /// Seeds a Kraken book still waiting for its socket snapshot. Socket updates are applied on
+    /// top where their level timestamps are newer, as after a snapshot. Dropped if the snapshot
+    /// has already arrived.
+    pub fn insert_kraken_rest_book(&mut self, channel: Channel, snapshot: KrakenBidAsks) {
+        if self
+            .state
+            .books
+            .lock_recover()
+            .get(&channel)
+            .is_none_or(|b| !b.is_empty())
+        {
+            tracing::debug!(
+                "Dropped REST book received after snapshot for {:?}",
+                channel
+            );
+            return;
+        }
+        let mut book = Book::new();
+        book.bids
+            .extend(snapshot.bs.iter().map(|l| (l.price, l.volume)));
+        book.asks
+            .extend(snapshot.r#as.iter().map(|l| (l.price, l.volume)));
+        self.uncross_book(&channel, &mut book);
+        self.trim_book(&channel, &mut book);
+        self.publish_book(&channel, &mut book);
+        self.kraken_level_times
+            .insert(channel.clone(), LevelTimes::from_snapshot(&snapshot));
+        let mut books = self.state.books.lock_recover();
+        books.insert(channel, book);
+    }
+
     pub async fn insert_gdax_snapshot(&mut self, channel: Channel, snapshot: GdaxSnapshot) {
         let mut book = Book::new();
-        book.bids.extend(snapshot.bids.into_iter());
-        book.asks.extend(snapshot.asks.into_iter());
-        let mut books = self.state.books.lock().unwrap();
+        book.bids.extend(snapshot.bids);
+        book.asks.extend(snapshot.asks);
+        self.gdax_rest_times.remove(&channel);
+        let pending = self
+            .gdax_pending_updates
+            .remove(&channel)
+            .unwrap_or_default();
+        if !pending.is_empty() {
+            tracing::info!(
+                "Applying {} l2updates received before snapshot",
+                pending.len()
+            );
+        }
+        for l2update in pending.iter() {
+            book.apply_gdax_l2update(l2update);
+        }
+        self.uncross_book(&channel, &mut book);
+        self.trim_book(&channel, &mut book);
+        self.publish_book(&channel, &mut book);
+        let mut books = self.state.books.lock_recover();
         books.insert(channel, book);
     }
 
     pub async fn insert_gdax_l2update(&mut self, channel: Channel, l2update: L2update) {
-        let mut books = self.state.books.lock().unwrap();
-        for update in l2update.changes.iter() {
-            match update.0 {
-                TradeSide::Buy => {
-                    if update.2 == Decimal::ZERO {
-                        books.entry(channel.clone()).and_modify(|bt| {
-                            bt.bids.remove(&update.1);
-                        });
-                    } else {
-                        books.entry(channel.clone()).and_modify(|bt| {
-                            bt.bids.insert(update.1, update.2);
-                        });
-                    };
-                }
-                TradeSide::Sell => {
-                    if update.2 == Decimal::ZERO {
-                        books.entry(channel.clone()).and_modify(|bt| {
-                            bt.asks.remove(&update.1);
-                        });
-                    } else {
-                        books.entry(channel.clone()).and_modify(|bt| {
-                            bt.asks.insert(update.1, update.2);
-                        });
-                    }
+        // Possible on a fast resubscribe or reconnect. Held until the snapshot replaces the book,
+        // or dropped and the book resubscribed if PENDING_UPDATE_LIMIT updates arrive without one.
+        if let Some(pending) = self.gdax_pending_updates.get_mut(&channel) {
+            if pending.is_empty() {
+                tracing::warn!(
+                    "l2update received before snapshot on {:?}. Buffering.",
+                    channel
+                );
+            } else {
+                tracing::debug!("Buffering l2update on {:?}", channel);
+            }
+            pending.push(l2update.clone());
+            if pending.len() > PENDING_UPDATE_LIMIT {
+                tracing::warn!("No snapshot for {:?}. Resubscribing.", channel);
+                pending.clear();
+                self.errored.insert(channel);
+                return;
+            }
+            // Also applied to a book seeded over REST if newer than it
+            if !matches!(self.gdax_rest_times.get(&channel), Some(time) if l2update.time > *time) {
+                return;
+            }
+        }
+        let mut books = self.state.books.lock_recover();
+        if let Some(book) = books.get_mut(&channel) {
+            book.apply_gdax_l2update(&l2update);
+            self.uncross_book(&channel, book);
+            self.trim_book(&channel, book);
+            self.publish_book(&channel, book);
+        }
+    }
+
+    pub async fn insert_coinbase_advanced_l2(&mut self, channel: Channel, event: L2Data) {
+        let mut books = self.state.books.lock_recover();
+        if event.r#type == L2DataType::Snapshot {
+            books.insert(channel.clone(), Book::new());
+        }
+        if let Some(book) = books.get_mut(&channel) {
+            for update in event.updates.into_iter() {
+                let side = match update.side {
+                    L2Side::Bid => &mut book.bids,
+                    L2Side::Offer => &mut book.asks,
+                };
+                if update.new_quantity == Decimal::ZERO {
+                    side.remove(&update.price_level);
+                } else {
+                    side.insert(update.price_level, update.new_quantity);
                 }
             }
+            self.uncross_book(&channel, book);
+            self.trim_book(&channel, book);
+            self.publish_book(&channel, book);
         }
     }
 
@@ -76,63 +1073,282 @@ impl App {
             .extend(snapshot.snapshot.bs.iter().map(|l| (l.price, l.volume)));
         book.asks
             .extend(snapshot.snapshot.r#as.iter().map(|l| (l.price, l.volume)));
-        let mut books = self.state.books.lock().unwrap();
+        self.uncross_book(&channel, &mut book);
+        self.trim_book(&channel, &mut book);
+        self.publish_book(&channel, &mut book);
+        self.kraken_level_times.insert(
+            channel.clone(),
+            LevelTimes::from_snapshot(&snapshot.snapshot),
+        );
+        let mut books = self.state.books.lock_recover();
         books.insert(channel, book);
     }
 
-    pub async fn insert_kraken_update_ask(&mut self, channel: Channel, update: L2updateAsk) {
-        let mut books = self.state.books.lock().unwrap();
-        for ask in update.ask.update.iter() {
-            if ask.volume == Decimal::ZERO {
-                books.entry(channel.clone()).and_modify(|bt| {
-                    bt.asks.remove(&ask.price);
-                });
-            } else {
-                books.entry(channel.clone()).and_modify(|bt| {
-                    bt.asks.insert(ask.price, ask.volume);
-                });
-            };
+    pub async fn insert_kraken_update_ask(
+        &mut self,
+        channel: Channel,
+        update: L2updateAsk,
+    ) -> Result<()> {
+        let mut books = self.state.books.lock_recover();
+        match books.get_mut(&channel) {
+            Some(book) if book.is_empty() => {
+                tracing::warn!("Dropped update received before snapshot for {:?}", channel);
+                Ok(())
+            }
+            Some(book) => {
+                let times = self.kraken_level_times.entry(channel.clone()).or_default();
+                let asks = newer_levels(&mut times.asks, &update.ask.update, &book.asks);
+                book.apply_kraken_asks(&asks);
+                let meta = self.market_meta.get(&channel);
+                book.verify_kraken_checksum(&channel, update.ask.c.as_deref(), meta)?;
+                self.uncross_book(&channel, book);
+                self.trim_book(&channel, book);
+                self.publish_book(&channel, book);
+                Ok(())
+            }
+            None => Ok(()),
         }
     }
 
-    pub async fn insert_kraken_update_bid(&mut self, channel: Channel, update: L2updateBid) {
-        let mut books = self.state.books.lock().unwrap();
-        for bid in update.bid.update.iter() {
-            if bid.volume == Decimal::ZERO {
-                books.entry(channel.clone()).and_modify(|bt| {
-                    bt.bids.remove(&bid.price);
-                });
-            } else {
-                books.entry(channel.clone()).and_modify(|bt| {
-                    bt.bids.insert(bid.price, bid.volume);
-                });
-            };
+    pub async fn insert_kraken_update_bid(
+        &mut self,
+        channel: Channel,
+        update: L2updateBid,
+    ) -> Result<()> {
+        let mut books = self.state.books.lock_recover();
+        match books.get_mut(&channel) {
+            Some(book) if book.is_empty() => {
+                tracing::warn!("Dropped update received before snapshot for {:?}", channel);
+                Ok(())
+            }
+            Some(book) => {
+                let times = self.kraken_level_times.entry(channel.clone()).or_default();
+                let bids = newer_levels(&mut times.bids, &update.bid.update, &book.bids);
+                book.apply_kraken_bids(&bids);
+                let meta = self.market_meta.get(&channel);
+                book.verify_kraken_checksum(&channel, update.bid.c.as_deref(), meta)?;
+                self.uncross_book(&channel, book);
+                self.trim_book(&channel, book);
+                self.publish_book(&channel, book);
+                Ok(())
+            }
+            None => Ok(()),
         }
     }
 
-    pub async fn insert_kraken_update_both(&mut self, channel: Channel, update: L2updateBoth) {
-        let mut books = self.state.books.lock().unwrap();
-        for bid in update.bid.update.iter() {
-            if bid.volume == Decimal::ZERO {
-                books.entry(channel.clone()).and_modify(|bt| {
-                    bt.bids.remove(&bid.price);
-                });
+    pub async fn insert_kraken_update_both(
+        &mut self,
+        channel: Channel,
+        update: L2updateBoth,
+    ) -> Result<()> {
+        let mut books = self.state.books.lock_recover();
+        match books.get_mut(&channel) {
+            Some(book) if book.is_empty() => {
+                tracing::warn!("Dropped update received before snapshot for {:?}", channel);
+                Ok(())
+            }
+            Some(book) => {
+                let times = self.kraken_level_times.entry(channel.clone()).or_default();
+                let bids = newer_levels(&mut times.bids, &update.bid.update, &book.bids);
+                let asks = newer_levels(&mut times.asks, &update.ask.update, &book.asks);
+                book.apply_kraken_bids(&bids);
+                book.apply_kraken_asks(&asks);
+                // The checksum is sent in the last object of the message, which is the bids
+                let checksum = update.bid.c.as_deref().or(update.ask.c.as_deref());
+                let meta = self.market_meta.get(&channel);
+                book.verify_kraken_checksum(&channel, checksum, meta)?;
+                self.uncross_book(&channel, book);
+                self.trim_book(&channel, book);
+                self.publish_book(&channel, book);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Kraken v2 book snapshot or update. Updates received before the snapshot are dropped. The
+    /// checksum is verified after every message, including the snapshot, and the book is only
+    /// published once it matches.
+    pub async fn insert_kraken_v2_book(
+        &mut self,
+        channel: Channel,
+        update_type: V2UpdateType,
+        update: KrakenV2Book,
+    ) -> Result<()> {
+        let mut books = self.state.books.lock_recover();
+        let book = match (update_type, books.get_mut(&channel)) {
+            (_, None) => return Ok(()),
+            (V2UpdateType::Snapshot, Some(book)) => {
+                *book = Book::new();
+                book
+            }
+            (V2UpdateType::Update, Some(book)) if book.is_empty() => {
+                tracing::warn!("Dropped update received before snapshot for {:?}", channel);
+                return Ok(());
+            }
+            (V2UpdateType::Update, Some(book)) => book,
+        };
+        for bid in update.bids.iter() {
+            if bid.qty == Decimal::ZERO {
+                book.bids.remove(&bid.price);
             } else {
-                books.entry(channel.clone()).and_modify(|bt| {
-                    bt.bids.insert(bid.price, bid.volume);
-                });
-            };
+                book.bids.insert(bid.price, bid.qty);
+            }
         }
-        for ask in update.ask.update.iter() {
-            if ask.volume == Decimal::ZERO {
-                books.entry(channel.clone()).and_modify(|bt| {
-                    bt.asks.remove(&ask.price);
-                });
+        for ask in update.asks.iter() {
+            if ask.qty == Decimal::ZERO {
+                book.asks.remove(&ask.price);
             } else {
-                books.entry(channel.clone()).and_modify(|bt| {
-                    bt.asks.insert(ask.price, ask.volume);
-                });
-            };
+                book.asks.insert(ask.price, ask.qty);
+            }
+        }
+        // Levels pushed out of the subscribed depth are not deleted by the exchange
+        book.trim(KRAKEN_BOOK_DEPTH);
+        let meta = self.market_meta.get(&channel);
+        let checksum = update.checksum.to_string();
+        book.verify_kraken_checksum(&channel, Some(&checksum), meta)?;
+        self.uncross_book(&channel, book);
+        self.trim_book(&channel, book);
+        self.publish_book(&channel, book);
+        Ok(())
+    }
+
+    pub async fn insert_binance_snapshot(&mut self, channel: Channel, snapshot: BinanceDepth) {
+        let mut book = Book::new();
+        book.bids.extend(snapshot.bids);
+        book.asks.extend(snapshot.asks);
+        self.uncross_book(&channel, &mut book);
+        self.trim_book(&channel, &mut book);
+        self.publish_book(&channel, &mut book);
+        let mut books = self.state.books.lock_recover();
+        books.insert(channel, book);
+    }
+
+    pub async fn insert_okx_books(&mut self, channel: Channel, books_msg: OkxBooks) {
+        let mut books = self.state.books.lock_recover();
+        for data in books_msg.data.into_iter() {
+            match books_msg.action {
+                BookAction::Snapshot => {
+                    let mut book = Book::new();
+                    book.bids.extend(data.bids.iter().map(|l| (l.0, l.1)));
+                    book.asks.extend(data.asks.iter().map(|l| (l.0, l.1)));
+                    self.uncross_book(&channel, &mut book);
+                    self.trim_book(&channel, &mut book);
+                    self.publish_book(&channel, &mut book);
+                    books.insert(channel.clone(), book);
+                }
+                BookAction::Update => {
+                    if let Some(book) = books.get_mut(&channel) {
+                        for bid in data.bids.iter() {
+                            if bid.1 == Decimal::ZERO {
+                                book.bids.remove(&bid.0);
+                            } else {
+                                book.bids.insert(bid.0, bid.1);
+                            }
+                        }
+                        for ask in data.asks.iter() {
+                            if ask.1 == Decimal::ZERO {
+                                book.asks.remove(&ask.0);
+                            } else {
+                                book.asks.insert(ask.0, ask.1);
+                            }
+                        }
+                        self.uncross_book(&channel, book);
+                        self.trim_book(&channel, book);
+                        self.publish_book(&channel, book);
+                    }
+                }
+            }
+        }
+    }
+
+    pub async fn insert_bybit_book(&mut self, channel: Channel, book_msg: BybitOrderBook) {
+        let mut books = self.state.books.lock_recover();
+        let data = book_msg.data;
+        match book_msg.r#type {
+            // Bybit resends a snapshot after a service restart which replaces the book
+            BybitUpdateType::Snapshot => {
+                let mut book = Book::new();
+                book.bids.extend(data.bids);
+                book.asks.extend(data.asks);
+                self.uncross_book(&channel, &mut book);
+                self.trim_book(&channel, &mut book);
+                self.publish_book(&channel, &mut book);
+                books.insert(channel, book);
+            }
+            BybitUpdateType::Delta => {
+                if let Some(book) = books.get_mut(&channel) {
+                    for (price, size) in data.bids.into_iter() {
+                        if size == Decimal::ZERO {
+                            book.bids.remove(&price);
+                        } else {
+                            book.bids.insert(price, size);
+                        }
+                    }
+                    for (price, size) in data.asks.into_iter() {
+                        if size == Decimal::ZERO {
+                            book.asks.remove(&price);
+                        } else {
+                            book.asks.insert(price, size);
+                        }
+                    }
+                    self.uncross_book(&channel, book);
+                    self.trim_book(&channel, book);
+                    self.publish_book(&channel, book);
+                }
+            }
+        }
+    }
+
+    /// Applies a Bitstamp diff to the book, or buffers it until the REST snapshot arrives. The
+    /// diff channel sends no snapshot so one is fetched once the first diff shows the socket is
+    /// subscribed. A snapshot that never arrives is retried by resubscribing after
+    /// PENDING_UPDATE_LIMIT diffs.
+    pub async fn insert_bitstamp_diff(&mut self, channel: Channel, diff: BitstampDiff) {
+        if let Some(pending) = self.bitstamp_pending_diffs.get_mut(&channel) {
+            pending.push(diff);
+            if pending.len() == 1 {
+                self.fetch_rest_book(&channel);
+            } else if pending.len() > PENDING_UPDATE_LIMIT {
+                tracing::warn!("No snapshot for {:?}. Resubscribing.", channel);
+                pending.clear();
+                self.errored.insert(channel);
+            }
+            return;
+        }
+        let mut books = self.state.books.lock_recover();
+        if let Some(book) = books.get_mut(&channel) {
+            book.apply_bitstamp_diff(&diff);
+            self.uncross_book(&channel, book);
+            self.trim_book(&channel, book);
+            self.publish_book(&channel, book);
+        }
+    }
+
+    /// Deribit book snapshot or change. Changes are checked for missed messages by
+    /// check_deribit_change before they are applied. Deletes are sent with a zero amount.
+    pub async fn insert_deribit_book(&mut self, channel: Channel, update: DeribitBook) {
+        let mut books = self.state.books.lock_recover();
+        if let Some(book) = books.get_mut(&channel) {
+            if update.r#type == DeribitUpdateType::Snapshot {
+                *book = Book::new();
+            }
+            for (levels, changes) in [
+                (&mut book.bids, &update.bids),
+                (&mut book.asks, &update.asks),
+            ] {
+                for level in changes.iter() {
+                    if level.2 == Decimal::ZERO {
+                        levels.remove(&level.1);
+                    } else {
+                        let amount = deribit_base_amount(&update.instrument_name, level.1, level.2);
+                        levels.insert(level.1, amount);
+                    }
+                }
+            }
+            self.uncross_book(&channel, book);
+            self.trim_book(&channel, book);
+            self.publish_book(&channel, book);
         }
     }
 
@@ -142,7 +1358,955 @@ impl App {
             .extend(snapshot.levels.bids.iter().map(|l| (l.px, l.sz)));
         book.asks
             .extend(snapshot.levels.asks.iter().map(|l| (l.px, l.sz)));
-        let mut books = self.state.books.lock().unwrap();
+        self.uncross_book(&channel, &mut book);
+        self.trim_book(&channel, &mut book);
+        self.publish_book(&channel, &mut book);
+        let mut books = self.state.books.lock_recover();
         books.insert(channel, book);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+
+    use chrono::{TimeZone, Utc};
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+    use serde_json::json;
+    use tokio::sync::mpsc;
+    use url::Url;
+
+    use crate::app::App;
+    use crate::app::TradeSide;
+    use crate::bitstamp::OrderBookDiff as BitstampDiff;
+    use crate::book::{
+        aggregate_books, Book, BookStats, BookViolation, DepthPoint, CROSSED_UPDATE_LIMIT,
+        PENDING_UPDATE_LIMIT,
+    };
+    use crate::client::{Channel, ChannelType, ClientConfig, ClientResp, Exchange, StateSnapshot};
+    use crate::error::Error;
+    use crate::gdax::{L2update, Snapshot as GdaxSnapshot};
+    use crate::kraken::{
+        Asks, BidAsks, L2updateAsk, Level, Snapshot as KrakenSnapshot, V2Book, V2Level,
+        V2UpdateType,
+    };
+    use crate::market::MarketMeta;
+    use crate::trades::Trade;
+
+    fn app() -> App {
+        let (ws_send, _) = mpsc::channel(1);
+        App::new(ws_send, None, ClientConfig::default()).unwrap()
+    }
+
+    fn kraken_level(price: &str, volume: &str) -> Level {
+        kraken_level_at(price, volume, dec!(1686499924.936167))
+    }
+
+    fn kraken_level_at(price: &str, volume: &str, timestamp: Decimal) -> Level {
+        Level {
+            price: price.parse().unwrap(),
+            volume: volume.parse().unwrap(),
+            timestamp,
+            update_type: None,
+        }
+    }
+
+    fn book() -> Book {
+        let mut book = Book::new();
+        book.bids.insert(dec!(99.5), dec!(3));
+        book.bids.insert(dec!(100), dec!(1));
+        book.bids.insert(dec!(98), dec!(5));
+        book.asks.insert(dec!(101), dec!(2));
+        book.asks.insert(dec!(103), dec!(4));
+        book.asks.insert(dec!(102.5), dec!(6));
+        book
+    }
+
+    #[test]
+    pub fn levels_json_ordered_from_top() {
+        let mut book = Book::new();
+        book.bids.insert(dec!(100.10), dec!(1));
+        book.bids.insert(dec!(100.20), dec!(2.5));
+        book.bids.insert(dec!(99.90), dec!(3));
+        book.asks.insert(dec!(100.40), dec!(4));
+        book.asks.insert(dec!(100.30), dec!(0.10));
+
+        let expected = serde_json::json!({
+            "bids": [["100.20", "2.5"], ["100.10", "1"]],
+            "asks": [["100.30", "0.10"], ["100.40", "4"]],
+        });
+        assert_eq!(book.to_levels_json(Some(2)), expected);
+        assert_eq!(book.to_levels_json(None)["bids"][2][0], "99.90");
+    }
+
+    #[test]
+    pub fn top_of_book() {
+        let book = book();
+        assert_eq!(book.best_bid(), Some((dec!(100), dec!(1))));
+        assert_eq!(book.best_ask(), Some((dec!(101), dec!(2))));
+        assert_eq!(book.mid_price(), Some(dec!(100.5)));
+        assert_eq!(book.spread(), Some(dec!(1)));
+    }
+
+    #[test]
+    pub fn top_of_book_summary() {
+        let tob = book().top_of_book().unwrap();
+        assert_eq!(tob.best_bid, (dec!(100), dec!(1)));
+        assert_eq!(tob.best_ask, (dec!(101), dec!(2)));
+        assert_eq!(tob.mid, dec!(100.5));
+        assert_eq!(tob.weighted_mid, book().weighted_mid().unwrap());
+        assert_eq!(tob.spread, dec!(1));
+
+        let mut empty_side = book();
+        empty_side.bids.clear();
+        assert!(empty_side.top_of_book().is_none());
+    }
+
+    #[test]
+    pub fn weighted_mid_leans_to_thinner_side() {
+        // Bid 100 x 1, ask 101 x 2: (100 * 2 + 101 * 1) / 3
+        let book = book();
+        let microprice = book.weighted_mid().unwrap();
+        assert_eq!(microprice, dec!(301) / dec!(3));
+        // The bid is thinner so fair value sits below the mid
+        assert!(microprice < book.mid_price().unwrap());
+
+        let mut empty_side = book;
+        empty_side.asks.clear();
+        assert_eq!(empty_side.weighted_mid(), None);
+    }
+
+    #[test]
+    pub fn top_of_book_empty_side() {
+        let mut book = book();
+        book.asks.clear();
+        assert_eq!(book.best_bid(), Some((dec!(100), dec!(1))));
+        assert_eq!(book.best_ask(), None);
+        assert_eq!(book.mid_price(), None);
+        assert_eq!(book.spread(), None);
+    }
+
+    #[test]
+    pub fn depth_ordered_from_top_and_truncated() {
+        let book = book();
+        assert_eq!(
+            book.bids_depth(2),
+            vec![(dec!(100), dec!(1)), (dec!(99.5), dec!(3))]
+        );
+        assert_eq!(
+            book.asks_depth(2),
+            vec![(dec!(101), dec!(2)), (dec!(102.5), dec!(6))]
+        );
+        // Fewer levels than requested returns the whole side
+        let depth = book.depth(10);
+        assert_eq!(depth.bids.len(), 3);
+        assert_eq!(depth.bids[2], (dec!(98), dec!(5)));
+        assert_eq!(depth.asks[2], (dec!(103), dec!(4)));
+        assert!(book.depth(0).bids.is_empty());
+    }
+
+    #[test]
+    pub fn cumulative_volume_to_price() {
+        let book = book();
+        assert_eq!(book.cumulative_bid_volume(dec!(99.5)), dec!(4));
+        assert_eq!(book.cumulative_bid_volume(dec!(90)), dec!(9));
+        assert_eq!(book.cumulative_bid_volume(dec!(101)), Decimal::ZERO);
+        assert_eq!(book.cumulative_ask_volume(dec!(102.5)), dec!(8));
+        assert_eq!(book.cumulative_ask_volume(dec!(100)), Decimal::ZERO);
+    }
+
+    #[test]
+    pub fn sweep_walks_levels() {
+        let book = book();
+        // Asks 2 @ 101 (202) then 6 @ 102.5 (615). 407 fills the first level and 2 of the second.
+        let buy = book.sweep(TradeSide::Buy, dec!(407));
+        assert_eq!(buy.quantity, dec!(4));
+        assert_eq!(buy.notional, dec!(407));
+        assert_eq!(buy.avg_price, Some(dec!(101.75)));
+        assert_eq!(buy.worst_price, Some(dec!(102.5)));
+        assert_eq!(
+            book.quantity_for_notional(TradeSide::Buy, dec!(101)),
+            dec!(1)
+        );
+
+        // Bids 1 @ 100, 3 @ 99.5, 5 @ 98 total 888.5. The side runs out before 1000 is spent.
+        let sell = book.sweep(TradeSide::Sell, dec!(1000));
+        assert_eq!(sell.quantity, dec!(9));
+        assert_eq!(sell.notional, dec!(888.5));
+        assert_eq!(sell.worst_price, Some(dec!(98)));
+
+        let empty = Book::new().sweep(TradeSide::Sell, dec!(1000));
+        assert_eq!(empty.quantity, Decimal::ZERO);
+        assert_eq!(empty.avg_price, None);
+    }
+
+    #[test]
+    pub fn imbalance_over_depth() {
+        // Bids 1, 3, 5 and asks 2, 6, 4 from the top
+        let book = book();
+        assert_eq!(book.imbalance(1), Some(dec!(-1) / dec!(3)));
+        assert_eq!(book.imbalance(3), Some(dec!(-3) / dec!(21)));
+
+        let mut bid_heavy = book.clone();
+        bid_heavy.bids.insert(dec!(100.5), dec!(9));
+        // (9 - 2) / 11
+        assert!(bid_heavy.imbalance(1).unwrap() > Decimal::ZERO);
+        assert_eq!(bid_heavy.imbalance(1), Some(dec!(7) / dec!(11)));
+
+        let mut empty_side = book;
+        empty_side.asks.clear();
+        assert_eq!(empty_side.imbalance(5), None);
+    }
+
+    #[test]
+    pub fn kraken_checksum_formats_levels() {
+        let mut book = Book::new();
+        book.asks
+            .insert("0.05005".parse().unwrap(), "0.00000500".parse().unwrap());
+        book.asks
+            .insert("0.05010".parse().unwrap(), "0.00100000".parse().unwrap());
+        book.bids
+            .insert("0.05000".parse().unwrap(), "0.00000500".parse().unwrap());
+        book.bids
+            .insert("0.04995".parse().unwrap(), "1.50000000".parse().unwrap());
+
+        // Asks ascending then bids descending with decimal point and leading zeros removed
+        let input = "5005500501010000050005004995150000000";
+        assert_eq!(book.kraken_checksum_input(0, 0), input);
+        let expected = crc32fast::hash(input.as_bytes());
+        assert_eq!(book.kraken_checksum(None), expected);
+
+        // Levels that lost trailing zeros are padded to the market precision
+        let meta = MarketMeta::new(5, 8, dec!(0.00001));
+        let mut normalized = Book::new();
+        for (price, volume) in book.asks.iter() {
+            normalized
+                .asks
+                .insert(price.normalize(), volume.normalize());
+        }
+        for (price, volume) in book.bids.iter() {
+            normalized
+                .bids
+                .insert(price.normalize(), volume.normalize());
+        }
+        assert_ne!(normalized.kraken_checksum(None), expected);
+        assert_eq!(normalized.kraken_checksum_input(5, 8), input);
+        assert_eq!(normalized.kraken_checksum(Some(&meta)), expected);
+    }
+
+    #[test]
+    pub fn kraken_checksum_mismatch() {
+        let channel = Channel {
+            exchange: Exchange::Kraken,
+            channel: ChannelType::Book,
+            market: "XBT/USD".to_string(),
+        };
+        let mut book = Book::new();
+        book.apply_kraken_asks(&[kraken_level("25782.90000", "1.17100399")]);
+        book.apply_kraken_bids(&[kraken_level("25782.80000", "0.50000000")]);
+        let checksum = book.kraken_checksum(None).to_string();
+        assert!(book
+            .verify_kraken_checksum(&channel, Some(&checksum), None)
+            .is_ok());
+
+        book.apply_kraken_asks(&[kraken_level("25782.90000", "0.00000000")]);
+        let result = book.verify_kraken_checksum(&channel, Some(&checksum), None);
+        assert!(matches!(result, Err(Error::ChecksumMismatch { .. })));
+    }
+
+    #[tokio::test]
+    pub async fn kraken_v2_book_checksum_padded_to_market_precision() {
+        let channel = Channel {
+            exchange: Exchange::KrakenV2,
+            channel: ChannelType::Book,
+            market: "BTC/USD".to_string(),
+        };
+        let (ws_send, _) = mpsc::channel(1);
+        let (app_send, mut app_recv) = mpsc::unbounded_channel();
+        let mut app = App::new(ws_send, Some(app_send), ClientConfig::default()).unwrap();
+        app.streams.insert(channel.clone());
+        app.state
+            .books
+            .lock()
+            .unwrap()
+            .insert(channel.clone(), Book::new());
+        let meta = MarketMeta::lookup(&Exchange::KrakenV2, "BTC/USD").unwrap();
+        app.market_meta.insert(channel.clone(), meta);
+        // Kraken hashes 26517.0 and 1.25000000 as 265170 and 125000000
+        let mut expected = Book::new();
+        expected.asks.insert(dec!(26517), dec!(1.25));
+        expected.bids.insert(dec!(26516.8), dec!(0.5));
+        let level = |price, qty| V2Level { price, qty };
+        let snapshot = V2Book {
+            symbol: "BTC/USD".to_string(),
+            bids: vec![level(dec!(26516.8), dec!(0.5))],
+            asks: vec![level(dec!(26517), dec!(1.25))],
+            checksum: expected.kraken_checksum(Some(&meta)),
+            timestamp: None,
+        };
+        app.insert_kraken_v2_book(channel.clone(), V2UpdateType::Snapshot, snapshot)
+            .await
+            .unwrap();
+        let streamed = std::iter::from_fn(|| app_recv.try_recv().ok())
+            .filter(|r| matches!(r.as_ref().unwrap().resp, ClientResp::BookUpdate(_)))
+            .count();
+        assert_eq!(streamed, 1);
+
+        let update = V2Book {
+            symbol: "BTC/USD".to_string(),
+            bids: vec![level(dec!(26516.8), dec!(0))],
+            asks: vec![],
+            checksum: expected.kraken_checksum(Some(&meta)),
+            timestamp: None,
+        };
+        let result = app
+            .insert_kraken_v2_book(channel.clone(), V2UpdateType::Update, update)
+            .await;
+        assert!(matches!(result, Err(Error::ChecksumMismatch { .. })));
+        // The diverged book is not streamed
+        assert!(app_recv.try_recv().is_err());
+        let books = app.state.books.lock().unwrap();
+        assert!(books.get(&channel).unwrap().bids.is_empty());
+    }
+
+    #[test]
+    pub fn kraken_book_truncated_to_depth() {
+        let mut book = Book::new();
+        let levels: Vec<Level> = (1..=150)
+            .map(|i| kraken_level(&format!("{}.0", i), "1.0"))
+            .collect();
+        book.apply_kraken_asks(&levels);
+        book.apply_kraken_bids(&levels);
+        assert_eq!(book.asks.len(), 100);
+        assert_eq!(book.bids.len(), 100);
+        assert_eq!(book.asks.keys().next_back(), Some(&dec!(100.0)));
+        assert_eq!(book.bids.keys().next(), Some(&dec!(51.0)));
+    }
+
+    #[tokio::test]
+    pub async fn trim_book_to_max_depth() {
+        let channel = Channel {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Book,
+            market: "BTC-USD".to_string(),
+        };
+        let mut app = app();
+        app.book_depths.insert(channel.clone(), 50);
+        let snapshot = GdaxSnapshot {
+            product_id: "BTC-USD".to_string(),
+            bids: (1..=200).map(|i| (Decimal::from(i), dec!(1))).collect(),
+            asks: (201..=400).map(|i| (Decimal::from(i), dec!(1))).collect(),
+        };
+        app.insert_gdax_snapshot(channel.clone(), snapshot).await;
+
+        let books = app.state.books.lock().unwrap();
+        let book = books.get(&channel).unwrap();
+        assert_eq!(book.bids.len(), 50);
+        assert_eq!(book.asks.len(), 50);
+        // Furthest levels from mid are removed
+        assert_eq!(book.bids.keys().next(), Some(&dec!(151)));
+        assert_eq!(book.best_bid(), Some((dec!(200), dec!(1))));
+        assert_eq!(book.asks.keys().next_back(), Some(&dec!(250)));
+        assert_eq!(book.best_ask(), Some((dec!(201), dec!(1))));
+    }
+
+    #[tokio::test]
+    pub async fn kraken_book_trimmed_when_read_only() {
+        let mut app = app();
+        let channel = Channel::book(Exchange::Kraken, "XBT/USD");
+        app.setup_state(&channel, Some(5)).unwrap();
+        let levels = |prices: std::ops::RangeInclusive<u32>| {
+            prices
+                .map(|p| kraken_level_at(&p.to_string(), "1", dec!(10)))
+                .collect::<Vec<_>>()
+        };
+        let snapshot = KrakenSnapshot {
+            channel_id: 336,
+            snapshot: BidAsks {
+                r#as: levels(201..=220),
+                bs: levels(181..=200),
+            },
+            channel_name: "book-100".to_string(),
+            pair: "XBT/USD".to_string(),
+        };
+        app.insert_kraken_snapshot(channel.clone(), snapshot).await;
+
+        // Removing the best ask moves the 11th ask into the checksum
+        let mut expected = app.state.books.lock().unwrap()[&channel].clone();
+        expected.asks.remove(&dec!(201));
+        let update = L2updateAsk {
+            channel_id: 336,
+            ask: Asks {
+                update: vec![kraken_level_at("201", "0", dec!(11))],
+                c: Some(
+                    expected
+                        .kraken_checksum(app.market_meta.get(&channel))
+                        .to_string(),
+                ),
+            },
+            channel_name: "book-100".to_string(),
+            pair: "XBT/USD".to_string(),
+        };
+        app.insert_kraken_update_ask(channel.clone(), update)
+            .await
+            .unwrap();
+        assert_eq!(app.state.books.lock().unwrap()[&channel].asks.len(), 19);
+        let read = app.read_book(&channel).unwrap();
+        assert_eq!(read.asks.len(), 5);
+        assert_eq!(read.bids.len(), 5);
+        assert_eq!(read.best_ask(), Some((dec!(202), dec!(1))));
+        let batch = app.books_batch(std::slice::from_ref(&channel)).unwrap();
+        assert_eq!(batch[&channel].asks.len(), 5);
+        assert_eq!(batch[&channel].bids.len(), 5);
+        let agg = app.book_agg(std::slice::from_ref(&channel), None).unwrap();
+        assert_eq!(agg.asks.len(), 5);
+        assert_eq!(agg.bids.len(), 5);
+
+        // Summaries walk only the requested depth of the stored book
+        let stats = app
+            .with_book(&channel, |b, depth| Ok(b.stats_to_depth(depth)))
+            .unwrap();
+        assert_eq!(stats.ask_levels, 5);
+        assert_eq!(stats.total_ask_volume, dec!(5));
+        let sweep = app
+            .with_book(&channel, |b, depth| {
+                Ok(b.sweep_to_depth(TradeSide::Buy, dec!(100000), depth))
+            })
+            .unwrap();
+        assert_eq!(sweep.worst_price, Some(dec!(206)));
+    }
+
+    #[tokio::test]
+    pub async fn crossed_book_uncrossed_then_flagged() {
+        let mut crossed = book();
+        crossed.bids.insert(dec!(101.5), dec!(1));
+        assert!(crossed.is_crossed());
+        assert!(!book().is_crossed());
+
+        let channel = Channel {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Book,
+            market: "BTC-USD".to_string(),
+        };
+        let mut app = app();
+        app.config.uncross_books = true;
+        for _ in 0..CROSSED_UPDATE_LIMIT {
+            let snapshot = GdaxSnapshot {
+                product_id: "BTC-USD".to_string(),
+                bids: crossed.bids.clone().into_iter().collect(),
+                asks: crossed.asks.clone().into_iter().collect(),
+            };
+            app.insert_gdax_snapshot(channel.clone(), snapshot).await;
+            {
+                let books = app.state.books.lock().unwrap();
+                let book = books.get(&channel).unwrap();
+                assert!(!book.is_crossed());
+                // The crossing bid and the ask it crossed are both removed
+                assert_eq!(book.best_bid(), Some((dec!(100), dec!(1))));
+                assert_eq!(book.best_ask(), Some((dec!(102.5), dec!(6))));
+            }
+            assert!(app.errored.is_empty());
+            app.flag_crossed_books();
+        }
+        assert!(app.errored.contains(&channel));
+    }
+
+    #[test]
+    pub fn depth_chart_accumulates_from_top() {
+        let point = |price, size, cumulative| DepthPoint {
+            price,
+            size,
+            cumulative,
+        };
+        assert_eq!(
+            book().depth_chart(TradeSide::Buy, 10),
+            vec![
+                point(dec!(100), dec!(1), dec!(1)),
+                point(dec!(99.5), dec!(3), dec!(4)),
+                point(dec!(98), dec!(5), dec!(9)),
+            ]
+        );
+        assert_eq!(
+            book().depth_chart(TradeSide::Sell, 2),
+            vec![
+                point(dec!(101), dec!(2), dec!(2)),
+                point(dec!(102.5), dec!(6), dec!(8)),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn book_stats_count_levels_and_volume() {
+        let stats = book().stats();
+        assert_eq!(
+            stats,
+            BookStats {
+                bid_levels: 3,
+                ask_levels: 3,
+                total_bid_volume: dec!(9),
+                total_ask_volume: dec!(12),
+                best_bid: Some((dec!(100), dec!(1))),
+                best_ask: Some((dec!(101), dec!(2))),
+            }
+        );
+        let mut one_sided = book();
+        one_sided.asks.clear();
+        let stats = one_sided.stats();
+        assert_eq!(stats.ask_levels, 0);
+        assert_eq!(stats.total_ask_volume, Decimal::ZERO);
+        assert_eq!(stats.best_ask, None);
+        assert_eq!(stats.bid_levels, one_sided.bid_levels());
+    }
+
+    #[tokio::test]
+    pub async fn book_checked_and_resnapshotted() {
+        let mut bad = book();
+        bad.bids.insert(dec!(101.5), dec!(1));
+        bad.asks.insert(dec!(103), dec!(0));
+        assert_eq!(
+            bad.check(),
+            vec![
+                BookViolation::Crossed {
+                    bid: dec!(101.5),
+                    ask: dec!(101)
+                },
+                BookViolation::NonPositiveSize {
+                    side: TradeSide::Sell,
+                    price: dec!(103),
+                    size: dec!(0)
+                },
+            ]
+        );
+        assert!(book().check().is_empty());
+
+        let channel = Channel {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Book,
+            market: "BTC-USD".to_string(),
+        };
+        let mut app = app();
+        app.config.book_check_updates = Some(1);
+        app.config.resnapshot_updates = Some(3);
+        app.check_book(&channel, &bad);
+        assert_eq!(app.metrics().book_violations.get(&channel), Some(&2));
+        app.flag_resnapshot_books();
+        assert!(app.errored.is_empty());
+        app.check_book(&channel, &book());
+        app.check_book(&channel, &book());
+        assert_eq!(app.metrics().book_violations.get(&channel), Some(&2));
+        app.flag_resnapshot_books();
+        assert!(app.errored.contains(&channel));
+        assert!(app.book_checks.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn book_ready_after_first_snapshot() {
+        let (ws_send, _) = mpsc::channel(1);
+        let (app_send, mut app_recv) = mpsc::unbounded_channel();
+        let mut app = App::new(ws_send, Some(app_send), ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Book,
+            market: "BTC-USD".to_string(),
+        };
+        let is_ready = |app: &App| app.ready_books.lock().unwrap().contains(&channel);
+        app.setup_state(&channel, None).unwrap();
+        assert!(!is_ready(&app));
+
+        for _ in 0..2 {
+            let snapshot = GdaxSnapshot {
+                product_id: "BTC-USD".to_string(),
+                bids: book().bids.into_iter().collect(),
+                asks: book().asks.into_iter().collect(),
+            };
+            app.insert_gdax_snapshot(channel.clone(), snapshot).await;
+        }
+        assert!(is_ready(&app));
+        // Sent once for the first snapshot only
+        let ready = app_recv.try_recv().unwrap().unwrap();
+        assert_eq!(ready.channel, Some(channel.clone()));
+        assert!(matches!(ready.resp, ClientResp::Ready));
+        assert!(app_recv.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    pub async fn book_last_update_advances() {
+        let mut app = app();
+        let channel = Channel {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Book,
+            market: "BTC-USD".to_string(),
+        };
+        app.setup_state(&channel, None).unwrap();
+        let snapshot = GdaxSnapshot {
+            product_id: "BTC-USD".to_string(),
+            bids: book().bids.into_iter().collect(),
+            asks: book().asks.into_iter().collect(),
+        };
+        app.insert_gdax_snapshot(channel.clone(), snapshot).await;
+        let last_update = |app: &App| app.state.books.lock().unwrap()[&channel].last_update;
+        let snapshotted = last_update(&app);
+
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let update = L2update {
+            product_id: "BTC-USD".to_string(),
+            time: Utc::now(),
+            changes: vec![(TradeSide::Buy, dec!(100), dec!(2))],
+        };
+        app.insert_gdax_l2update(channel.clone(), update).await;
+        assert!(last_update(&app) > snapshotted);
+        assert!(app.state.books.lock().unwrap()[&channel].age() >= chrono::Duration::zero());
+    }
+
+    #[tokio::test]
+    pub async fn book_deltas_streamed() {
+        let (ws_send, _) = mpsc::channel(1);
+        let (app_send, mut app_recv) = mpsc::unbounded_channel();
+        let mut app = App::new(ws_send, Some(app_send), ClientConfig::default()).unwrap();
+        let channel = Channel::book(Exchange::Gdax, "BTC-USD");
+        app.setup_state(&channel, None).unwrap();
+        app.book_deltas
+            .lock()
+            .unwrap()
+            .insert(channel.clone(), Arc::default());
+        let mut next_delta = || loop {
+            match app_recv.try_recv().unwrap().unwrap().resp {
+                ClientResp::BookDelta(delta) => return delta,
+                _ => continue,
+            }
+        };
+
+        let snapshot = GdaxSnapshot {
+            product_id: "BTC-USD".to_string(),
+            bids: book().bids.into_iter().collect(),
+            asks: book().asks.into_iter().collect(),
+        };
+        app.insert_gdax_snapshot(channel.clone(), snapshot).await;
+        // The first delta adds every level
+        let delta = next_delta();
+        assert_eq!(delta.bids.len(), 3);
+        assert!(delta.bids.iter().all(|(_, size)| size.is_some()));
+        assert_eq!(delta.asks[0], (dec!(101), Some(dec!(2))));
+
+        let update = L2update {
+            product_id: "BTC-USD".to_string(),
+            time: Utc::now(),
+            changes: vec![
+                (TradeSide::Buy, dec!(100), dec!(2)),
+                (TradeSide::Sell, dec!(101), dec!(0)),
+            ],
+        };
+        app.insert_gdax_l2update(channel.clone(), update).await;
+        let delta = next_delta();
+        assert_eq!(delta.bids, vec![(dec!(100), Some(dec!(2)))]);
+        assert_eq!(delta.asks, vec![(dec!(101), None)]);
+    }
+
+    #[test]
+    fn book_json_round_trip() {
+        let book = book();
+        let json = book.to_json().unwrap();
+        assert_eq!(Book::from_json(&json).unwrap(), book);
+    }
+
+    #[test]
+    fn state_dump_round_trip() {
+        let app = app();
+        let tape_channel = Channel {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Tape,
+            market: "BTC-USD".to_string(),
+        };
+        let book_channel = Channel {
+            channel: ChannelType::Book,
+            ..tape_channel.clone()
+        };
+        let trade = Trade {
+            price: dec!(100.5),
+            size: dec!(0.25),
+            dt: Utc.timestamp_opt(1686270879, 0).unwrap(),
+            exchange: Exchange::Gdax,
+            side: TradeSide::Buy,
+            id: Some("1".to_string()),
+        };
+        app.state
+            .tapes
+            .lock()
+            .unwrap()
+            .insert(tape_channel.clone(), VecDeque::from([trade]));
+        let book = book();
+        app.state
+            .books
+            .lock()
+            .unwrap()
+            .insert(book_channel.clone(), book.clone());
+
+        let value = app.state.dump().unwrap();
+        let snapshot: StateSnapshot = serde_json::from_value(value).unwrap();
+        assert_eq!(snapshot.tapes.len(), 1);
+        let (channel, tape) = &snapshot.tapes[0];
+        assert_eq!(channel, &tape_channel);
+        assert_eq!(tape[0].price, dec!(100.5));
+        assert_eq!(tape[0].side, TradeSide::Buy);
+        assert_eq!(snapshot.books, vec![(book_channel, book)]);
+    }
+
+    #[test]
+    fn aggregate_overlapping_levels() {
+        let mut a = Book::new();
+        a.bids.insert(dec!(100.0), dec!(1));
+        a.asks.insert(dec!(101.0), dec!(2));
+        let mut b = Book::new();
+        b.bids.insert(dec!(100.0), dec!(3));
+        b.bids.insert(dec!(99.5), dec!(1));
+        b.asks.insert(dec!(101.5), dec!(1));
+
+        let agg = aggregate_books([&a, &b], None);
+        assert_eq!(agg.bids.get(&dec!(100)), Some(&dec!(4)));
+        assert_eq!(agg.bids.get(&dec!(99.5)), Some(&dec!(1)));
+        assert_eq!(agg.asks.len(), 2);
+
+        // Rounded to whole prices the bids share the 99 level below 100 and the asks share 102
+        let mut c = Book::new();
+        c.bids.insert(dec!(99.7), dec!(2));
+        c.asks.insert(dec!(101.2), dec!(5));
+        let agg = aggregate_books([&b, &c], Some(0));
+        assert_eq!(agg.bids.get(&dec!(99)), Some(&dec!(3)));
+        assert_eq!(agg.bids.get(&dec!(100)), Some(&dec!(3)));
+        assert_eq!(agg.asks.get(&dec!(102)), Some(&dec!(6)));
+    }
+
+    #[tokio::test]
+    async fn gdax_rest_book_seeds_until_snapshot() {
+        let mut app = app();
+        let channel = Channel::book(Exchange::Gdax, "BTC-USD");
+        app.setup_state(&channel, None).unwrap();
+        let rest_time = Utc.with_ymd_and_hms(2023, 6, 11, 16, 12, 4).unwrap();
+        let update = |secs: i64, price: Decimal, size: Decimal| L2update {
+            product_id: "BTC-USD".to_string(),
+            time: rest_time + chrono::Duration::seconds(secs),
+            changes: vec![(TradeSide::Buy, price, size)],
+        };
+        // Buffered before the REST book arrives, one older and one newer than it
+        app.insert_gdax_l2update(channel.clone(), update(-1, dec!(100), dec!(9)))
+            .await;
+        app.insert_gdax_l2update(channel.clone(), update(1, dec!(99), dec!(2)))
+            .await;
+        let rest = json!({
+            "bids": [["100", "1", 1]],
+            "asks": [["101", "2", 3]],
+            "sequence": 3,
+            "time": "2023-06-11T16:12:04Z",
+        });
+        app.insert_rest_book(channel.clone(), rest);
+        let levels = |app: &App| {
+            let book = &app.state.books.lock().unwrap()[&channel];
+            (book.bids.clone(), book.asks.clone())
+        };
+        let (bids, asks) = levels(&app);
+        assert_eq!(bids.get(&dec!(100)), Some(&dec!(1)));
+        assert_eq!(bids.get(&dec!(99)), Some(&dec!(2)));
+        assert_eq!(asks.get(&dec!(101)), Some(&dec!(2)));
+
+        // Updates newer than the REST book apply while the snapshot is awaited
+        app.insert_gdax_l2update(channel.clone(), update(2, dec!(98), dec!(4)))
+            .await;
+        assert_eq!(levels(&app).0.get(&dec!(98)), Some(&dec!(4)));
+
+        // The socket snapshot replaces the seeded book and a late REST book is dropped
+        let snapshot = GdaxSnapshot {
+            product_id: "BTC-USD".to_string(),
+            bids: vec![(dec!(97), dec!(1))],
+            asks: vec![(dec!(102), dec!(1))],
+        };
+        app.insert_gdax_snapshot(channel.clone(), snapshot).await;
+        assert!(app.gdax_rest_times.is_empty());
+        let late = json!({"bids": [], "asks": [], "sequence": 4, "time": "2023-06-11T16:12:05Z"});
+        app.insert_rest_book(channel.clone(), late);
+        let (bids, asks) = levels(&app);
+        assert_eq!(bids.get(&dec!(97)), Some(&dec!(1)));
+        assert_eq!(bids.get(&dec!(100)), Some(&dec!(9)));
+        assert_eq!(asks.keys().next(), Some(&dec!(102)));
+    }
+
+    #[tokio::test]
+    async fn bitstamp_diffs_buffered_until_rest_book() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut config = ClientConfig::default();
+        // Refused straight away, the snapshot is delivered by the test
+        let rest_url = Url::parse("http://127.0.0.1:1").unwrap();
+        config.rest_urls.insert(Exchange::Bitstamp, rest_url);
+        let mut app = App::new(ws_send, None, config).unwrap();
+        let channel = Channel::book(Exchange::Bitstamp, "btcusd");
+        app.setup_state(&channel, None).unwrap();
+        let diff = |micros: i64, bid: Decimal, size: Decimal| BitstampDiff {
+            microtimestamp: Decimal::from(micros),
+            bids: vec![(bid, size)],
+            asks: vec![],
+        };
+        app.insert_bitstamp_diff(channel.clone(), diff(100, dec!(99), dec!(7)))
+            .await;
+        app.insert_bitstamp_diff(channel.clone(), diff(300, dec!(98), dec!(2)))
+            .await;
+        assert!(app.state.books.lock().unwrap()[&channel].is_empty());
+
+        let snapshot = json!({
+            "timestamp": "1",
+            "microtimestamp": "200",
+            "bids": [["100", "1"], ["99", "1"]],
+            "asks": [["101", "1"]],
+        });
+        app.insert_rest_book(channel.clone(), snapshot);
+        let bids = app.state.books.lock().unwrap()[&channel].bids.clone();
+        // The diff older than the snapshot is discarded
+        assert_eq!(bids.get(&dec!(99)), Some(&dec!(1)));
+        assert_eq!(bids.get(&dec!(98)), Some(&dec!(2)));
+        assert!(app.bitstamp_pending_diffs.is_empty());
+
+        // Later diffs apply directly
+        app.insert_bitstamp_diff(channel.clone(), diff(400, dec!(100), dec!(0)))
+            .await;
+        assert_eq!(
+            app.state.books.lock().unwrap()[&channel].best_bid(),
+            Some((dec!(99), dec!(1)))
+        );
+    }
+
+    #[tokio::test]
+    async fn gdax_updates_buffered_after_reconnect_until_limit() {
+        let mut app = app();
+        let channel = Channel::book(Exchange::Gdax, "BTC-USD");
+        app.setup_state(&channel, None).unwrap();
+        let snapshot = GdaxSnapshot {
+            product_id: "BTC-USD".to_string(),
+            bids: book().bids.into_iter().collect(),
+            asks: book().asks.into_iter().collect(),
+        };
+        app.insert_gdax_snapshot(channel.clone(), snapshot).await;
+        assert!(app.gdax_pending_updates.is_empty());
+
+        // Updates on the reconnected socket wait for its snapshot
+        app.handle_reconnect(channel.clone());
+        let update = L2update {
+            product_id: "BTC-USD".to_string(),
+            time: Utc::now(),
+            changes: vec![(TradeSide::Buy, dec!(100), dec!(2))],
+        };
+        app.insert_gdax_l2update(channel.clone(), update.clone())
+            .await;
+        assert!(app.state.books.lock().unwrap()[&channel].is_empty());
+        assert_eq!(app.gdax_pending_updates[&channel].len(), 1);
+
+        // A snapshot that never arrives resubscribes the book
+        for _ in 0..PENDING_UPDATE_LIMIT {
+            app.insert_gdax_l2update(channel.clone(), update.clone())
+                .await;
+        }
+        assert!(app.errored.contains(&channel));
+        assert!(app.gdax_pending_updates[&channel].is_empty());
+    }
+
+    #[tokio::test]
+    async fn kraken_rest_book_seeds_empty_book() {
+        let mut app = app();
+        let channel = Channel::book(Exchange::Kraken, "XBT/USD");
+        app.setup_state(&channel, None).unwrap();
+        let rest = json!({
+            "error": [],
+            "result": {"XXBTZUSD": {
+                "asks": [["101.0", "1.0", 10]],
+                "bids": [["100.0", "1.0", 10]],
+            }},
+        });
+        app.insert_rest_book(channel.clone(), rest);
+        assert_eq!(
+            app.state.books.lock().unwrap()[&channel].best_bid(),
+            Some((dec!(100), dec!(1)))
+        );
+
+        // Socket updates apply on top when newer than the seeded levels
+        let update = L2updateAsk {
+            channel_id: 336,
+            ask: Asks {
+                update: vec![kraken_level_at("101.0", "3.0", dec!(11))],
+                c: None,
+            },
+            channel_name: "book-100".to_string(),
+            pair: "XBT/USD".to_string(),
+        };
+        app.insert_kraken_update_ask(channel.clone(), update)
+            .await
+            .unwrap();
+        assert_eq!(
+            app.state.books.lock().unwrap()[&channel]
+                .asks
+                .get(&dec!(101)),
+            Some(&dec!(3))
+        );
+
+        // A REST book arriving after the book has levels is dropped
+        let late = json!({"error": [], "result": {"XXBTZUSD": {"asks": [], "bids": []}}});
+        app.insert_rest_book(channel.clone(), late);
+        assert_eq!(app.state.books.lock().unwrap()[&channel].asks.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn kraken_out_of_order_update_dropped() {
+        let mut app = app();
+        let channel = Channel {
+            exchange: Exchange::Kraken,
+            channel: ChannelType::Book,
+            market: "XBT/USD".to_string(),
+        };
+        let snapshot = KrakenSnapshot {
+            channel_id: 336,
+            snapshot: BidAsks {
+                r#as: vec![kraken_level_at("101.0", "1.0", dec!(10))],
+                bs: vec![kraken_level_at("100.0", "1.0", dec!(10))],
+            },
+            channel_name: "book-100".to_string(),
+            pair: "XBT/USD".to_string(),
+        };
+        app.insert_kraken_snapshot(channel.clone(), snapshot).await;
+        let ask_update = |level: Level| L2updateAsk {
+            channel_id: 336,
+            ask: Asks {
+                update: vec![level],
+                c: None,
+            },
+            channel_name: "book-100".to_string(),
+            pair: "XBT/USD".to_string(),
+        };
+
+        // Level deleted, then a stale frame from before the delete arrives
+        app.insert_kraken_update_ask(
+            channel.clone(),
+            ask_update(kraken_level_at("101.0", "0", dec!(12))),
+        )
+        .await
+        .unwrap();
+        app.insert_kraken_update_ask(
+            channel.clone(),
+            ask_update(kraken_level_at("101.0", "5.0", dec!(11))),
+        )
+        .await
+        .unwrap();
+        assert!(app.state.books.lock().unwrap()[&channel].asks.is_empty());
+
+        // Newer updates still apply
+        app.insert_kraken_update_ask(
+            channel.clone(),
+            ask_update(kraken_level_at("101.0", "2.0", dec!(13))),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            app.state.books.lock().unwrap()[&channel]
+                .asks
+                .get(&dec!(101)),
+            Some(&dec!(2.0))
+        );
+    }
+}
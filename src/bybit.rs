@@ -0,0 +1,456 @@
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    app::App,
+    client::{Channel, ChannelType},
+    error::{Error, Result},
+    funding::FundingRate,
+    liquidations::Liquidation as AppLiquidation,
+    mark_price::MarkPrice,
+    trades::Trade as AppTrade,
+};
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Response {
+    OpResponse(OpResponse),
+    Trades(Trades),
+    OrderBook(OrderBook),
+    Liquidations(Liquidations),
+    Tickers(Tickers),
+}
+
+/// Struct mapping for:
+///
+/// Response to a subscribe, unsubscribe or ping op
+/// {"success": true, "ret_msg": "", "conn_id": "cejreaspqfh3sjdnldmg-p", "req_id": "", "op": "subscribe"}
+/// {"success": true, "ret_msg": "pong", "conn_id": "cejreaspqfh3sjdnldmg-p", "op": "ping"}
+#[derive(Clone, Deserialize, Debug)]
+pub struct OpResponse {
+    pub success: bool,
+    pub ret_msg: String,
+    pub conn_id: String,
+    pub req_id: Option<String>,
+    pub op: String,
+}
+
+/// Struct mapping for:
+///
+/// Public trade message from Bybit publicTrade.<symbol> topic
+/// {
+///     "topic": "publicTrade.BTCUSDT",
+///     "type": "snapshot",
+///     "ts": 1672304486868,
+///     "data": [{
+///         "T": 1672304486865,
+///         "s": "BTCUSDT",
+///         "S": "Buy",
+///         "v": "0.001",
+///         "p": "16578.50",
+///         "L": "PlusTick",
+///         "i": "20f43950-d8dd-5b31-9112-a178eb6023af",
+///         "BT": false
+///     }]
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct Trades {
+    pub topic: String,
+    pub r#type: UpdateType,
+    pub ts: i64,
+    pub data: Vec<Trade>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct Trade {
+    #[serde(rename = "T")]
+    pub trade_time: i64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    // Taker side
+    #[serde(rename = "S")]
+    pub side: String,
+    #[serde(rename = "v")]
+    pub size: Decimal,
+    #[serde(rename = "p")]
+    pub price: Decimal,
+    #[serde(rename = "i")]
+    pub trade_id: String,
+}
+
+/// Struct mapping for:
+///
+/// Order book message from Bybit orderbook.<depth>.<symbol> topic. The first message is a
+/// snapshot followed by deltas. A zero size in a delta removes the level.
+/// {
+///     "topic": "orderbook.50.BTCUSDT",
+///     "type": "snapshot",
+///     "ts": 1672304484978,
+///     "data": {
+///         "s": "BTCUSDT",
+///         "b": [["16493.50", "0.006"]],
+///         "a": [["16611.00", "0.029"]],
+///         "u": 18521288,
+///         "seq": 7961638724
+///     },
+///     "cts": 1672304484976
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct OrderBook {
+    pub topic: String,
+    pub r#type: UpdateType,
+    pub ts: i64,
+    pub data: OrderBookData,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct OrderBookData {
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "b")]
+    pub bids: Vec<(Decimal, Decimal)>,
+    #[serde(rename = "a")]
+    pub asks: Vec<(Decimal, Decimal)>,
+    #[serde(rename = "u")]
+    pub update_id: i64,
+    pub seq: i64,
+}
+
+/// Struct mapping for:
+///
+/// Liquidation message from Bybit allLiquidation.<symbol> topic. The side is the side of the
+/// liquidated position, Buy for a long.
+/// {
+///     "topic": "allLiquidation.ROSEUSDT",
+///     "type": "snapshot",
+///     "ts": 1739502303204,
+///     "data": [{
+///         "T": 1739502302929,
+///         "s": "ROSEUSDT",
+///         "S": "Sell",
+///         "v": "20000",
+///         "p": "0.04499"
+///     }]
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct Liquidations {
+    pub topic: String,
+    pub r#type: UpdateType,
+    pub ts: i64,
+    pub data: Vec<Liquidation>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct Liquidation {
+    #[serde(rename = "T")]
+    pub updated_time: i64,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "S")]
+    pub side: String,
+    #[serde(rename = "v")]
+    pub size: Decimal,
+    #[serde(rename = "p")]
+    pub price: Decimal,
+}
+
+/// Struct mapping for:
+///
+/// Ticker message from Bybit tickers.<symbol> topic. The first message is a snapshot with every
+/// field, followed by deltas carrying only the fields that changed.
+/// {
+///     "topic": "tickers.BTCUSDT",
+///     "type": "snapshot",
+///     "data": {
+///         "symbol": "BTCUSDT",
+///         "tickDirection": "PlusTick",
+///         "lastPrice": "17216.00",
+///         "markPrice": "17217.33",
+///         "indexPrice": "17227.36",
+///         "fundingRate": "-0.000212",
+///         "nextFundingTime": "1673280000000"
+///     },
+///     "cs": 24987956059,
+///     "ts": 1673272861686
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct Tickers {
+    pub topic: String,
+    pub r#type: UpdateType,
+    pub ts: i64,
+    pub data: TickerData,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TickerData {
+    pub symbol: String,
+    pub mark_price: Option<Decimal>,
+    pub index_price: Option<Decimal>,
+    pub funding_rate: Option<Decimal>,
+    pub next_funding_time: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum UpdateType {
+    Snapshot,
+    Delta,
+}
+
+/// Depth levels requested on the orderbook topic.
+pub const BYBIT_BOOK_DEPTH: usize = 50;
+
+/// Bybit topics are the stream type and symbol, e.g. publicTrade.BTCUSDT.
+pub fn topic(channel: &Channel) -> String {
+    match channel.channel {
+        ChannelType::Tape => format!("publicTrade.{}", channel.market),
+        ChannelType::Book => format!("orderbook.{}.{}", BYBIT_BOOK_DEPTH, channel.market),
+        ChannelType::Liquidations => format!("allLiquidation.{}", channel.market),
+        ChannelType::Funding | ChannelType::MarkPrice => format!("tickers.{}", channel.market),
+        // Private topics on the authenticated endpoint, never subscribed
+        ChannelType::Fills => "execution".to_string(),
+        ChannelType::Orders => "order".to_string(),
+    }
+}
+
+impl App {
+    #[tracing::instrument(skip(self, channel, msg), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
+    pub async fn handle_ws_msg_bybit(
+        &mut self,
+        channel: Channel,
+        msg: Result<Message>,
+    ) -> Result<()> {
+        match msg {
+            Ok(m) => {
+                // Update socket last message
+                self.update_last(channel.clone())?;
+                // Parse message
+                if let Message::Text(text) = m {
+                    let response: Response = match serde_json::from_str(&text) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            tracing::error!("Could not parse message {:?}", text);
+                            tracing::error!("Error: {:?}", e);
+                            return Err(Error::Parse {
+                                exchange: channel.exchange,
+                                raw: text,
+                                source: e,
+                            });
+                        }
+                    };
+                    tracing::debug!("Response: {:?}", response);
+                    self.handle_ws_response_bybit(channel.clone(), response)
+                        .await?;
+                } else {
+                    tracing::warn!("Non-text Message: {:?}", m);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // Return Err
+                tracing::error!("Error: {:?}", e);
+                Err(e)
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self, channel, response), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
+    pub async fn handle_ws_response_bybit(
+        &mut self,
+        channel: Channel,
+        response: Response,
+    ) -> Result<()> {
+        match response {
+            Response::Trades(trades) => {
+                if self.carries(&channel, ChannelType::Tape) {
+                    // Convert bybit trades to trade and insert into trades state
+                    for trade in trades.data.into_iter() {
+                        let channel = self.route(&channel, ChannelType::Tape, &trade.symbol);
+                        let t: AppTrade = trade.try_into()?;
+                        self.insert_trade(channel, t).await?;
+                    }
+                } else {
+                    // Trade message sent on a none tape channel
+                    tracing::error!("Trade message {:?} sent on channel {:?}", trades, channel);
+                    return Err(Error::ChannelResponseMismatch);
+                }
+            }
+            Response::OrderBook(book) => {
+                let channel = self.route(&channel, ChannelType::Book, &book.data.symbol);
+                self.insert_bybit_book(channel, book).await
+            }
+            Response::Liquidations(liquidations) => {
+                if self.carries(&channel, ChannelType::Liquidations) {
+                    for liquidation in liquidations.data.into_iter() {
+                        let channel =
+                            self.route(&channel, ChannelType::Liquidations, &liquidation.symbol);
+                        let l: AppLiquidation = liquidation.try_into()?;
+                        self.insert_liquidation(channel, l);
+                    }
+                } else {
+                    // Liquidation message sent on a none liquidations channel
+                    tracing::error!(
+                        "Liquidation message {:?} sent on channel {:?}",
+                        liquidations,
+                        channel
+                    );
+                    return Err(Error::ChannelResponseMismatch);
+                }
+            }
+            Response::Tickers(tickers) => {
+                if self.carries(&channel, ChannelType::Funding) {
+                    let channel = self.route(&channel, ChannelType::Funding, &tickers.data.symbol);
+                    let prev = self.last_funding(&channel);
+                    match FundingRate::from_bybit(tickers.data, tickers.ts, prev.as_ref()) {
+                        Some(funding) => self.insert_funding(channel, funding),
+                        None => tracing::warn!("Ticker delta received before snapshot."),
+                    }
+                } else if self.carries(&channel, ChannelType::MarkPrice) {
+                    let channel =
+                        self.route(&channel, ChannelType::MarkPrice, &tickers.data.symbol);
+                    let prev = self.last_mark_price(&channel);
+                    match MarkPrice::from_bybit(&tickers.data, tickers.ts, prev.as_ref()) {
+                        Some(mark) => self.insert_mark_price(channel, mark),
+                        None => tracing::warn!("Ticker delta received before snapshot."),
+                    }
+                } else {
+                    // Ticker message sent on a none funding or mark price channel
+                    tracing::error!("Ticker message {:?} sent on channel {:?}", tickers, channel);
+                    return Err(Error::ChannelResponseMismatch);
+                }
+            }
+            Response::OpResponse(op) => {
+                if !op.success {
+                    tracing::error!("Op failed: {:?}", op);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use serde_json::{Result, Value};
+
+    use crate::bybit::{Response, UpdateType};
+    use crate::client::{Channel, Exchange};
+    use crate::websocket::mock;
+
+    pub fn messages(s: String) -> String {
+        let sub = "{\"success\":true,\"ret_msg\":\"\",\"conn_id\":\"cejreaspqfh3sjdnldmg-p\",\"req_id\":\"\",\"op\":\"subscribe\"}";
+        let trade = "{\"topic\":\"publicTrade.BTCUSDT\",\"type\":\"snapshot\",\"ts\":1672304486868,\"data\":[{\"T\":1672304486865,\"s\":\"BTCUSDT\",\"S\":\"Buy\",\"v\":\"0.001\",\"p\":\"16578.50\",\"L\":\"PlusTick\",\"i\":\"20f43950-d8dd-5b31-9112-a178eb6023af\",\"BT\":false}]}";
+        let book = "{\"topic\":\"orderbook.50.BTCUSDT\",\"type\":\"snapshot\",\"ts\":1672304484978,\"data\":{\"s\":\"BTCUSDT\",\"b\":[[\"16493.50\",\"0.006\"],[\"16493.00\",\"0.100\"]],\"a\":[[\"16611.00\",\"0.029\"],[\"16612.00\",\"0.213\"]],\"u\":18521288,\"seq\":7961638724},\"cts\":1672304484976}";
+        let liquidation = "{\"topic\":\"allLiquidation.ROSEUSDT\",\"type\":\"snapshot\",\"ts\":1739502303204,\"data\":[{\"T\":1739502302929,\"s\":\"ROSEUSDT\",\"S\":\"Sell\",\"v\":\"20000\",\"p\":\"0.04499\"}]}";
+        let ticker = "{\"topic\":\"tickers.BTCUSDT\",\"type\":\"snapshot\",\"data\":{\"symbol\":\"BTCUSDT\",\"tickDirection\":\"PlusTick\",\"lastPrice\":\"17216.00\",\"markPrice\":\"17217.33\",\"indexPrice\":\"17227.36\",\"fundingRate\":\"-0.000212\",\"nextFundingTime\":\"1673280000000\"},\"cs\":24987956059,\"ts\":1673272861686}";
+        if s == "subscription" {
+            sub.to_string()
+        } else if s == "trade" {
+            trade.to_string()
+        } else if s == "book" {
+            book.to_string()
+        } else if s == "liquidation" {
+            liquidation.to_string()
+        } else if s == "ticker" {
+            ticker.to_string()
+        } else {
+            "none".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn book_built_over_mock_transport() {
+        let (mut app, transport) = mock::app();
+        let channel = Channel::book(Exchange::Bybit, "BTCUSDT");
+        let started = mock::start(&mut app, &channel).await;
+        assert!(started.await.unwrap().is_ok());
+        assert_eq!(
+            transport.subscription(&channel)["args"][0],
+            "orderbook.50.BTCUSDT"
+        );
+
+        mock::deliver(&mut app, &channel, &messages("subscription".to_string())).await;
+        mock::deliver(&mut app, &channel, &messages("book".to_string())).await;
+        // Delta removing the best bid and resizing the best ask
+        let delta = "{\"topic\":\"orderbook.50.BTCUSDT\",\"type\":\"delta\",\"ts\":1672304485978,\"data\":{\"s\":\"BTCUSDT\",\"b\":[[\"16493.50\",\"0\"]],\"a\":[[\"16611.00\",\"0.5\"]],\"u\":18521289,\"seq\":7961638725},\"cts\":1672304485976}";
+        mock::deliver(&mut app, &channel, delta).await;
+
+        let book = mock::book(&mut app, &channel).await.unwrap();
+        assert_eq!(book.best_bid(), Some((dec!(16493.00), dec!(0.100))));
+        assert_eq!(book.best_ask(), Some((dec!(16611.00), dec!(0.5))));
+    }
+
+    #[test]
+    pub fn deserialize_sub() -> Result<()> {
+        let data = messages("subscription".to_string());
+
+        let v: Value = serde_json::from_str(&data)?;
+        println!("Value: {:?}", v);
+
+        let v: Response = serde_json::from_str(&data)?;
+        println!("Response: {:?}", v);
+        assert!(matches!(v, Response::OpResponse(_)));
+        Ok(())
+    }
+
+    #[test]
+    pub fn deserialize_trade() -> Result<()> {
+        let data = messages("trade".to_string());
+
+        let v: Value = serde_json::from_str(&data)?;
+        println!("Value: {:?}", v);
+
+        let v: Response = serde_json::from_str(&data)?;
+        println!("Response: {:?}", v);
+        assert!(matches!(v, Response::Trades(_)));
+        Ok(())
+    }
+
+    #[test]
+    pub fn deserialize_book() -> Result<()> {
+        let data = messages("book".to_string());
+
+        let v: Value = serde_json::from_str(&data)?;
+        println!("Value: {:?}", v);
+
+        let v: Response = serde_json::from_str(&data)?;
+        println!("Response: {:?}", v);
+        match v {
+            Response::OrderBook(book) => {
+                assert_eq!(book.r#type, UpdateType::Snapshot);
+                assert_eq!(book.data.bids.len(), 2);
+            }
+            _ => panic!("Expected order book response"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    pub fn deserialize_liquidation() -> Result<()> {
+        let data = messages("liquidation".to_string());
+
+        let v: Value = serde_json::from_str(&data)?;
+        println!("Value: {:?}", v);
+
+        let v: Response = serde_json::from_str(&data)?;
+        println!("Response: {:?}", v);
+        assert!(matches!(v, Response::Liquidations(_)));
+        Ok(())
+    }
+
+    #[test]
+    pub fn deserialize_ticker() -> Result<()> {
+        let data = messages("ticker".to_string());
+
+        let v: Value = serde_json::from_str(&data)?;
+        println!("Value: {:?}", v);
+
+        let v: Response = serde_json::from_str(&data)?;
+        println!("Response: {:?}", v);
+        assert!(matches!(v, Response::Tickers(_)));
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use rust_decimal::Decimal;
+
+use crate::app::App;
+use crate::client::Channel;
+use crate::error::{Error, Result};
+use crate::sync::LockRecover;
+use crate::trades::Trade;
+
+/// Base candle interval maintained for each tape channel. Requested intervals are resampled from
+/// the base candles and must be a multiple of it.
+pub const BASE_INTERVAL_SECS: i64 = 60;
+/// Number of base candles retained per channel (one day of 1m candles).
+pub const CANDLE_CAPACITY: usize = 1440;
+
+/// OHLCV candle covering trades with start <= dt < end. Candles are only created for intervals
+/// that contain at least one trade.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candle {
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl Candle {
+    pub fn new(price: Decimal, size: Decimal, start: DateTime<Utc>, interval: Duration) -> Self {
+        Self {
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: size,
+            start,
+            end: start + interval,
+        }
+    }
+
+    pub fn update(&mut self, price: Decimal, size: Decimal) {
+        self.high = self.high.max(price);
+        self.low = self.low.min(price);
+        self.close = price;
+        self.volume += size;
+    }
+
+    /// Merges a later candle into this one.
+    pub fn merge(&mut self, other: &Candle) {
+        self.high = self.high.max(other.high);
+        self.low = self.low.min(other.low);
+        self.close = other.close;
+        self.volume += other.volume;
+    }
+}
+
+/// Start of the interval bucket containing dt.
+pub fn interval_start(dt: DateTime<Utc>, interval: Duration) -> DateTime<Utc> {
+    let secs = interval.num_seconds();
+    let ts = dt.timestamp();
+    Utc.timestamp_opt(ts - ts.rem_euclid(secs), 0).unwrap()
+}
+
+/// Resamples base candles into candles of the given interval. The interval must be a positive
+/// multiple of the base interval.
+pub fn resample(candles: &VecDeque<Candle>, interval: Duration) -> Result<Vec<Candle>> {
+    let secs = interval.num_seconds();
+    if secs <= 0 || secs % BASE_INTERVAL_SECS != 0 {
+        return Err(Error::InvalidInterval);
+    }
+    let mut resampled: Vec<Candle> = Vec::new();
+    for candle in candles.iter() {
+        let start = interval_start(candle.start, interval);
+        match resampled.last_mut() {
+            Some(last) if last.start == start => last.merge(candle),
+            _ => resampled.push(Candle {
+                start,
+                end: start + interval,
+                ..candle.clone()
+            }),
+        }
+    }
+    Ok(resampled)
+}
+
+impl App {
+    /// Updates the base candles for the channel with the trade. Trades older than the latest
+    /// candle update the high, low and volume of their candle but not the close.
+    #[tracing::instrument(skip(self))]
+    pub fn update_candles(&mut self, channel: &Channel, trade: &Trade) -> Result<()> {
+        let (price, size) = (trade.price, trade.size);
+        let interval = Duration::seconds(BASE_INTERVAL_SECS);
+        let start = interval_start(trade.dt, interval);
+        let mut candles = self.state.candles.lock_recover();
+        if let Some(vd) = candles.get_mut(channel) {
+            match vd.back_mut() {
+                Some(last) if last.start == start => last.update(price, size),
+                Some(last) if last.start > start => {
+                    if let Some(c) = vd.iter_mut().rev().find(|c| c.start == start) {
+                        c.high = c.high.max(price);
+                        c.low = c.low.min(price);
+                        c.volume += size;
+                    }
+                }
+                _ => {
+                    if vd.len() == CANDLE_CAPACITY {
+                        vd.pop_front();
+                    }
+                    vd.push_back(Candle::new(price, size, start, interval));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use chrono::{Duration, TimeZone, Utc};
+    use rust_decimal_macros::dec;
+
+    use crate::candles::{interval_start, resample, Candle};
+
+    #[test]
+    pub fn bucket_start() {
+        let dt = Utc.timestamp_opt(1686270879, 0).unwrap();
+        let start = interval_start(dt, Duration::minutes(1));
+        assert_eq!(start, Utc.timestamp_opt(1686270840, 0).unwrap());
+        let start = interval_start(dt, Duration::minutes(5));
+        assert_eq!(start, Utc.timestamp_opt(1686270600, 0).unwrap());
+    }
+
+    #[test]
+    pub fn resample_to_five_minutes() {
+        let base = Duration::minutes(1);
+        let t0 = Utc.timestamp_opt(1686270600, 0).unwrap();
+        let mut candles = VecDeque::new();
+        let mut c = Candle::new(dec!(100), dec!(1), t0, base);
+        c.update(dec!(105), dec!(2));
+        candles.push_back(c);
+        let mut c = Candle::new(dec!(104), dec!(1), t0 + Duration::minutes(3), base);
+        c.update(dec!(98), dec!(1));
+        candles.push_back(c);
+        candles.push_back(Candle::new(
+            dec!(99),
+            dec!(4),
+            t0 + Duration::minutes(5),
+            base,
+        ));
+
+        let resampled = resample(&candles, Duration::minutes(5)).unwrap();
+        assert_eq!(resampled.len(), 2);
+        assert_eq!(resampled[0].open, dec!(100));
+        assert_eq!(resampled[0].high, dec!(105));
+        assert_eq!(resampled[0].low, dec!(98));
+        assert_eq!(resampled[0].close, dec!(98));
+        assert_eq!(resampled[0].volume, dec!(5));
+        assert_eq!(resampled[0].end, t0 + Duration::minutes(5));
+        assert_eq!(resampled[1].open, dec!(99));
+        assert_eq!(resampled[1].volume, dec!(4));
+
+        assert!(resample(&candles, Duration::seconds(90)).is_err());
+    }
+}
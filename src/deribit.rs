@@ -0,0 +1,511 @@
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    app::{App, TradeSide},
+    client::{Channel, ChannelType, ClientResp, ClientRespMsg},
+    error::{Error, Result},
+    trades::Trade as AppTrade,
+};
+
+/// Channel name prefixes and suffixes. The market is placed between them, e.g.
+/// trades.BTC-PERPETUAL.raw. Books are subscribed on the incremental 100ms channel as the grouped
+/// depth channels send full snapshots without the prev_change_id used to detect missed updates.
+pub const TRADES_PREFIX: &str = "trades.";
+pub const TRADES_SUFFIX: &str = ".raw";
+pub const BOOK_PREFIX: &str = "book.";
+pub const BOOK_SUFFIX: &str = ".100ms";
+
+/// JSON-RPC request ids. Replies echo the id so subscription replies can be told apart from
+/// unsubscribe and keepalive replies.
+pub const SUBSCRIBE_ID: u64 = 1;
+pub const TEST_ID: u64 = 2;
+pub const UNSUBSCRIBE_ID: u64 = 3;
+
+/// JSON-RPC messages from Deribit. Subscribed data arrives as subscription notifications and
+/// requests are answered with either a result or an error.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Response {
+    Notification(Notification),
+    // Channels subscribed or unsubscribed by a public/subscribe or public/unsubscribe request
+    Subscribed(RpcResult<Vec<String>>),
+    // Reply to the public/test keepalive
+    Test(RpcResult<serde_json::Value>),
+    Error(RpcError),
+}
+
+/// Struct mapping for:
+///
+/// Subscription notification from Deribit. The data is a list of trades on trades channels and a
+/// book snapshot or change on book channels.
+/// {
+///     "jsonrpc": "2.0",
+///     "method": "subscription",
+///     "params": {
+///         "channel": "trades.BTC-PERPETUAL.raw",
+///         "data": [...]
+///     }
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct Notification {
+    pub method: String,
+    pub params: Params,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct Params {
+    pub channel: String,
+    pub data: Data,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Data {
+    Trades(Vec<Trade>),
+    Book(BookData),
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct RpcResult<T> {
+    pub id: u64,
+    pub result: T,
+}
+
+/// Struct mapping for:
+///
+/// Rejected request, e.g. a subscription to an unknown instrument.
+/// {
+///     "jsonrpc": "2.0",
+///     "id": 1,
+///     "error": {"message": "Invalid params", "code": -32602}
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct RpcError {
+    pub id: Option<u64>,
+    pub error: ErrorDetail,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct ErrorDetail {
+    pub code: i64,
+    pub message: String,
+}
+
+/// Struct mapping for:
+///
+/// Trade from Deribit trades.<instrument>.raw channel. Amounts are in USD contracts for inverse
+/// instruments and in the base currency for linear instruments, see base_amount.
+/// {
+///     "trade_seq": 30289432,
+///     "trade_id": "48079254",
+///     "timestamp": 1590484156350,
+///     "tick_direction": 0,
+///     "price": 8950.0,
+///     "mark_price": 8948.9,
+///     "instrument_name": "BTC-PERPETUAL",
+///     "index_price": 8955.88,
+///     "direction": "sell",
+///     "amount": 10.0
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct Trade {
+    pub trade_id: String,
+    pub timestamp: i64,
+    pub price: Decimal,
+    pub instrument_name: String,
+    pub direction: TradeSide,
+    pub amount: Decimal,
+}
+
+/// Struct mapping for:
+///
+/// Book snapshot or change from Deribit book.<instrument>.100ms channel. Each change carries the
+/// change_id of the message before it so missed messages can be detected.
+/// {
+///     "type": "change",
+///     "timestamp": 1554373911330,
+///     "prev_change_id": 297217,
+///     "instrument_name": "BTC-PERPETUAL",
+///     "change_id": 297218,
+///     "bids": [["delete", 5041.94, 0]],
+///     "asks": [["change", 5042.64, 41]]
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct BookData {
+    pub r#type: BookUpdateType,
+    pub timestamp: i64,
+    pub instrument_name: String,
+    pub change_id: u64,
+    pub prev_change_id: Option<u64>,
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BookUpdateType {
+    Snapshot,
+    Change,
+}
+
+/// Level as action, price and amount. Deletes are sent with a zero amount.
+#[derive(Clone, Deserialize, Debug)]
+pub struct Level(pub LevelAction, pub Decimal, pub Decimal);
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LevelAction {
+    New,
+    Change,
+    Delete,
+}
+
+/// Inverse futures and perpetuals, e.g. BTC-PERPETUAL, are quoted in USD. Linear instruments name
+/// their settlement currency, e.g. BTC_USDC-PERPETUAL, and options end in the put or call.
+pub fn is_inverse(instrument: &str) -> bool {
+    !instrument.contains('_') && !instrument.ends_with("-C") && !instrument.ends_with("-P")
+}
+
+/// Amount in the base currency, converting the USD amounts of inverse instruments at the price
+/// so trades and books compare with other exchanges.
+pub fn base_amount(instrument: &str, price: Decimal, amount: Decimal) -> Decimal {
+    if is_inverse(instrument) && !price.is_zero() {
+        amount / price
+    } else {
+        amount
+    }
+}
+
+/// Deribit channels wrap the market in the stream name and interval, e.g.
+/// book.BTC-PERPETUAL.100ms.
+pub fn channel_name(channel: &Channel) -> String {
+    match channel.channel {
+        ChannelType::Tape => format!("{}{}{}", TRADES_PREFIX, channel.market, TRADES_SUFFIX),
+        ChannelType::Book => format!("{}{}{}", BOOK_PREFIX, channel.market, BOOK_SUFFIX),
+        // Never subscribed, only trades and books are supported
+        ChannelType::Liquidations
+        | ChannelType::Funding
+        | ChannelType::MarkPrice
+        | ChannelType::Fills
+        | ChannelType::Orders => channel.market.clone(),
+    }
+}
+
+/// Market of a channel name, e.g. BTC-PERPETUAL for trades.BTC-PERPETUAL.raw.
+pub fn market(channel_name: &str) -> &str {
+    channel_name
+        .strip_prefix(TRADES_PREFIX)
+        .and_then(|c| c.strip_suffix(TRADES_SUFFIX))
+        .or_else(|| {
+            channel_name
+                .strip_prefix(BOOK_PREFIX)
+                .and_then(|c| c.strip_suffix(BOOK_SUFFIX))
+        })
+        .unwrap_or(channel_name)
+}
+
+impl App {
+    #[tracing::instrument(skip(self, channel, msg), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
+    pub async fn handle_ws_msg_deribit(
+        &mut self,
+        channel: Channel,
+        msg: Result<Message>,
+    ) -> Result<()> {
+        match msg {
+            Ok(m) => {
+                // Update socket last message
+                self.update_last(channel.clone())?;
+                // Parse message
+                if let Message::Text(text) = m {
+                    let response: Response = match serde_json::from_str(&text) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            tracing::error!("Could not parse message {:?}", text);
+                            tracing::error!("Error: {:?}", e);
+                            return Err(Error::Parse {
+                                exchange: channel.exchange,
+                                raw: text,
+                                source: e,
+                            });
+                        }
+                    };
+                    tracing::debug!("Response: {:?}", response);
+                    self.handle_ws_response_deribit(channel.clone(), response)
+                        .await?;
+                } else {
+                    tracing::warn!("Non-text Message: {:?}", m);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // Return Err
+                tracing::error!("Error: {:?}", e);
+                Err(e)
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self, channel, response), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
+    pub async fn handle_ws_response_deribit(
+        &mut self,
+        channel: Channel,
+        response: Response,
+    ) -> Result<()> {
+        match response {
+            Response::Notification(n) => {
+                let market = market(&n.params.channel).to_string();
+                match n.params.data {
+                    Data::Trades(trades) => {
+                        if !self.carries(&channel, ChannelType::Tape) {
+                            // Trade message sent on a none tape channel
+                            tracing::error!(
+                                "Trade message {:?} sent on channel {:?}",
+                                trades,
+                                channel
+                            );
+                            return Err(Error::ChannelResponseMismatch);
+                        }
+                        let channel = self.route(&channel, ChannelType::Tape, &market);
+                        for trade in trades.into_iter() {
+                            let t: AppTrade = trade.try_into()?;
+                            self.insert_trade(channel.clone(), t).await?;
+                        }
+                    }
+                    Data::Book(book) => {
+                        let channel = self.route(&channel, ChannelType::Book, &market);
+                        if self.check_deribit_change(&channel, &book) {
+                            self.insert_deribit_book(channel, book).await;
+                        }
+                    }
+                }
+            }
+            // Deribit answers a subscription to an unknown channel with no channels subscribed
+            Response::Subscribed(r) if r.id == SUBSCRIBE_ID && r.result.is_empty() => {
+                tracing::error!("No channels subscribed by Deribit on {:?}", channel);
+                let reason = "No channels subscribed".to_string();
+                self.confirm_sub(&channel, Err(Error::SubscribeRejected { reason }))
+                    .await;
+            }
+            Response::Subscribed(r) if r.id == SUBSCRIBE_ID => {
+                self.confirm_sub(&channel, Ok(())).await
+            }
+            Response::Subscribed(_) | Response::Test(_) => {}
+            Response::Error(e) => {
+                tracing::error!("Error from Deribit on {:?}: {:?}", channel, e);
+                let reason = e.error.message;
+                self.confirm_sub(&channel, Err(Error::SubscribeRejected { reason }))
+                    .await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Each book change carries the change_id of the change before it. A prev_change_id that does
+    /// not match the last change_id applied means the book missed updates. The book is flagged
+    /// stale and resubscribed on the next interval tick to rebuild it from a new snapshot. Returns
+    /// true if the update can be applied.
+    #[tracing::instrument(skip(self, book))]
+    pub fn check_deribit_change(&mut self, channel: &Channel, book: &BookData) -> bool {
+        let last = self.deribit_change_ids.get(channel).copied();
+        let gap = match book.r#type {
+            BookUpdateType::Snapshot => false,
+            // Changes before the snapshot are dropped without flagging the book
+            BookUpdateType::Change if last.is_none() => return false,
+            BookUpdateType::Change => book.prev_change_id != last,
+        };
+        if !gap {
+            self.deribit_change_ids
+                .insert(channel.clone(), book.change_id);
+            return true;
+        }
+        self.deribit_change_ids.remove(channel);
+        tracing::warn!(
+            "Change id gap on {:?}. Last change {:?}, received prev {:?}",
+            channel,
+            last,
+            book.prev_change_id
+        );
+        self.errored.insert(channel.clone());
+        if let Some(sender) = self.app_sender.as_ref() {
+            let _ = sender.send(Ok(ClientRespMsg {
+                channel: Some(channel.clone()),
+                resp: ClientResp::BookStale,
+            }));
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use tokio::sync::mpsc;
+
+    use crate::app::{App, TradeSide};
+    use crate::client::{Channel, ChannelType, ClientConfig, Exchange};
+    use crate::deribit::{
+        base_amount, market, BookUpdateType, Data, LevelAction, Response, SUBSCRIBE_ID,
+        UNSUBSCRIBE_ID,
+    };
+    use crate::error::Error;
+    use crate::trades::Trade;
+    use crate::websocket::mock;
+
+    const TRADES: &str = "{\"jsonrpc\":\"2.0\",\"method\":\"subscription\",\"params\":{\"channel\":\"trades.BTC-PERPETUAL.raw\",\"data\":[{\"trade_seq\":30289432,\"trade_id\":\"48079254\",\"timestamp\":1590484156350,\"tick_direction\":0,\"price\":8950.5,\"mark_price\":8948.9,\"instrument_name\":\"BTC-PERPETUAL\",\"index_price\":8955.88,\"direction\":\"sell\",\"amount\":10.0}]}}";
+    const SNAPSHOT: &str = "{\"jsonrpc\":\"2.0\",\"method\":\"subscription\",\"params\":{\"channel\":\"book.BTC-PERPETUAL.100ms\",\"data\":{\"type\":\"snapshot\",\"timestamp\":1554373962454,\"instrument_name\":\"BTC-PERPETUAL\",\"change_id\":297217,\"bids\":[[\"new\",5042.34,30],[\"new\",5041.94,20]],\"asks\":[[\"new\",5042.64,40],[\"new\",5043.3,40]]}}}";
+
+    fn change(prev_change_id: u64, change_id: u64) -> String {
+        format!("{{\"jsonrpc\":\"2.0\",\"method\":\"subscription\",\"params\":{{\"channel\":\"book.BTC-PERPETUAL.100ms\",\"data\":{{\"type\":\"change\",\"timestamp\":1554373911330,\"prev_change_id\":{},\"instrument_name\":\"BTC-PERPETUAL\",\"change_id\":{},\"bids\":[[\"delete\",5041.94,0]],\"asks\":[[\"change\",5042.64,41]]}}}}}}", prev_change_id, change_id)
+    }
+
+    #[test]
+    pub fn deserialize_trades_notification() {
+        let response: Response = serde_json::from_str(TRADES).unwrap();
+        let n = match response {
+            Response::Notification(n) => n,
+            r => panic!("Expected notification, got {:?}", r),
+        };
+        assert_eq!(market(&n.params.channel), "BTC-PERPETUAL");
+        let trade = match n.params.data {
+            Data::Trades(mut trades) => trades.remove(0),
+            d => panic!("Expected trades, got {:?}", d),
+        };
+        let trade: Trade = trade.try_into().unwrap();
+        assert_eq!(trade.price, dec!(8950.5));
+        // USD amount of the inverse perpetual converted to BTC
+        assert_eq!(trade.size, dec!(10) / dec!(8950.5));
+        assert_eq!(trade.side, TradeSide::Sell);
+        assert_eq!(trade.id, Some("48079254".to_string()));
+        assert_eq!(trade.dt.timestamp_millis(), 1590484156350);
+    }
+
+    #[test]
+    pub fn deserialize_book_notifications() {
+        let response: Response = serde_json::from_str(&change(297217, 297218)).unwrap();
+        let book = match response {
+            Response::Notification(n) => match n.params.data {
+                Data::Book(book) => book,
+                d => panic!("Expected book, got {:?}", d),
+            },
+            r => panic!("Expected notification, got {:?}", r),
+        };
+        assert_eq!(book.r#type, BookUpdateType::Change);
+        assert_eq!(book.prev_change_id, Some(297217));
+        assert_eq!(book.bids[0].0, LevelAction::Delete);
+        assert_eq!(book.asks[0].1, dec!(5042.64));
+        assert_eq!(book.asks[0].2, dec!(41));
+
+        let subscribed = "{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":[\"book.BTC-PERPETUAL.100ms\"]}";
+        let response: Response = serde_json::from_str(subscribed).unwrap();
+        assert!(matches!(response, Response::Subscribed(_)));
+        let error = "{\"jsonrpc\":\"2.0\",\"id\":1,\"error\":{\"message\":\"Invalid params\",\"code\":-32602}}";
+        let response: Response = serde_json::from_str(error).unwrap();
+        assert!(matches!(response, Response::Error(_)));
+    }
+
+    #[tokio::test]
+    pub async fn book_built_over_mock_transport() {
+        let (mut app, transport) = mock::app();
+        let channel = Channel::book(Exchange::Deribit, "BTC-PERPETUAL");
+        let mut started = mock::start(&mut app, &channel).await;
+        assert_eq!(
+            transport.subscription(&channel)["params"]["channels"][0],
+            "book.BTC-PERPETUAL.100ms"
+        );
+        assert!(started.try_recv().is_err());
+        let subscribed = "{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":[\"book.BTC-PERPETUAL.100ms\"]}";
+        mock::deliver(&mut app, &channel, subscribed).await;
+        assert!(started.await.unwrap().is_ok());
+
+        mock::deliver(&mut app, &channel, SNAPSHOT).await;
+        mock::deliver(&mut app, &channel, &change(297217, 297218)).await;
+
+        let book = mock::book(&mut app, &channel).await.unwrap();
+        // The change deletes the 5041.94 bid and resizes the 5042.64 ask
+        assert!(!book.bids.contains_key(&dec!(5041.94)));
+        let size = dec!(41) / dec!(5042.64);
+        assert_eq!(book.asks.get(&dec!(5042.64)), Some(&size));
+    }
+
+    #[test]
+    pub fn linear_and_option_amounts_kept() {
+        assert_eq!(
+            base_amount("BTC-PERPETUAL", dec!(20000), dec!(100)),
+            dec!(0.005)
+        );
+        assert_eq!(
+            base_amount("BTC-29MAR24", dec!(20000), dec!(100)),
+            dec!(0.005)
+        );
+        assert_eq!(
+            base_amount("BTC_USDC-PERPETUAL", dec!(20000), dec!(0.1)),
+            dec!(0.1)
+        );
+        assert_eq!(
+            base_amount("BTC-29MAR24-50000-C", dec!(0.05), dec!(1)),
+            dec!(1)
+        );
+    }
+
+    #[tokio::test]
+    pub async fn empty_subscribe_result_rejected() {
+        let (mut app, transport) = mock::app();
+        let channel = Channel::tape(Exchange::Deribit, "BTC-NOPE");
+        let started = mock::start(&mut app, &channel).await;
+        assert_eq!(transport.subscription(&channel)["id"], SUBSCRIBE_ID);
+        let unsubscribe = channel.unsubscribe_message().unwrap();
+        assert_eq!(unsubscribe["id"], UNSUBSCRIBE_ID);
+
+        // An unsubscribe reply with no channels does not answer the subscription
+        let unsubscribed = "{\"jsonrpc\":\"2.0\",\"id\":3,\"result\":[]}";
+        mock::deliver(&mut app, &channel, unsubscribed).await;
+        let subscribed = "{\"jsonrpc\":\"2.0\",\"id\":1,\"result\":[]}";
+        mock::deliver(&mut app, &channel, subscribed).await;
+        assert!(matches!(
+            started.await.unwrap(),
+            Err(Error::SubscribeRejected { .. })
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn change_id_gap_flags_book() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Deribit,
+            channel: ChannelType::Book,
+            market: "BTC-PERPETUAL".to_string(),
+        };
+        app.setup_state(&channel, None).unwrap();
+        let handle = |text: &str| serde_json::from_str::<Response>(text).unwrap();
+
+        // Changes before the snapshot are dropped
+        app.handle_ws_response_deribit(channel.clone(), handle(&change(1, 2)))
+            .await
+            .unwrap();
+        assert!(app.state.books.lock().unwrap()[&channel].is_empty());
+
+        app.handle_ws_response_deribit(channel.clone(), handle(SNAPSHOT))
+            .await
+            .unwrap();
+        app.handle_ws_response_deribit(channel.clone(), handle(&change(297217, 297218)))
+            .await
+            .unwrap();
+        {
+            let books = app.state.books.lock().unwrap();
+            let book = &books[&channel];
+            assert_eq!(book.bids.len(), 1);
+            let size = dec!(41) / dec!(5042.64);
+            assert_eq!(book.best_ask(), Some((dec!(5042.64), size)));
+        }
+        assert!(app.errored.is_empty());
+
+        // 297219 was missed
+        app.handle_ws_response_deribit(channel.clone(), handle(&change(297219, 297220)))
+            .await
+            .unwrap();
+        assert!(app.errored.contains(&channel));
+    }
+}
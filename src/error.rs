@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::client::{Channel, Exchange};
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, Error)]
@@ -14,10 +16,88 @@ pub enum Error {
     ChannelDoesNotExist,
     #[error("Channel Already Subscribed")]
     ChannelAlreadySubscribed,
+    #[error("Channel Not Supported By Exchange")]
+    UnsupportedChannel,
+    #[error("No Credentials Configured For Exchange")]
+    CredentialsRequired,
+    #[error("Credentials Could Not Be Used To Sign")]
+    InvalidCredentials,
+    #[error("Timed Out Connecting To Exchange")]
+    ConnectTimeout,
+    #[error("Proxy Url Or Socket Url Missing Host Or Port")]
+    InvalidProxy,
+    #[error("Proxy Rejected Connection: {0}")]
+    ProxyRejected(String),
+    #[error("Socket Closed By Exchange: {code} {reason}")]
+    SocketClosed { code: u16, reason: String },
+    #[error("Timed Out Waiting For Subscription Confirmation")]
+    SubscribeTimeout,
+    #[error("Subscription Rejected By Exchange: {reason}")]
+    SubscribeRejected { reason: String },
+    #[error("Subscription Cancelled Before Confirmed By Exchange")]
+    SubscriptionCancelled,
+    #[error("Checksum Mismatch For Channel: {channel:?}")]
+    ChecksumMismatch { channel: Channel },
+    #[error("Interval Must Be A Multiple Of The Base Candle Interval")]
+    InvalidInterval,
+    #[error("Book Side Empty")]
+    BookSideEmpty,
+    #[error("Tape Empty")]
+    TapeEmpty,
+    #[error("No Funding Rate Received")]
+    FundingUnavailable,
+    #[error("No Mark Price Received")]
+    MarkPriceUnavailable,
+    #[error("No Market Metadata For Channel")]
+    MarketMetaUnavailable,
+    #[error("Unknown Exchange: {0}")]
+    UnknownExchange(String),
+    #[error("Unknown Channel Type: {0}")]
+    UnknownChannelType(String),
+    #[error("Channel Builder Missing {0}")]
+    IncompleteChannel(&'static str),
+    #[error("Invalid Trade Side: {0}")]
+    InvalidTradeSide(String),
+    // Timestamp out of the range of DateTime, with the timestamp as received
+    #[error("Invalid {exchange:?} Timestamp: {raw}")]
+    InvalidTimestamp { exchange: Exchange, raw: String },
+    // Message received on a socket that could not be deserialized, with the message as received
+    #[error("Could Not Parse {exchange:?} Message: {source}")]
+    Parse {
+        exchange: Exchange,
+        raw: String,
+        source: serde_json::Error,
+    },
+    // REST url that could not be built for a request
+    #[error("REST Request Failed: {0}")]
+    RestRequest(String),
+    #[error("Invalid Recording Line: {0}")]
+    InvalidRecording(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
     #[error(transparent)]
     Oneshot(#[from] tokio::sync::oneshot::error::RecvError),
     #[error(transparent)]
     Tungstenite(#[from] tokio_tungstenite::tungstenite::Error),
     #[error(transparent)]
     Serde(#[from] serde_json::Error),
+    #[error(transparent)]
+    Decimal(#[from] rust_decimal::Error),
+    // REST request that could not be sent or was answered with an error status
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+}
+
+impl Error {
+    /// Errors from the connection rather than the request, which may succeed if retried.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Error::ConnectTimeout
+                | Error::SubscribeTimeout
+                | Error::SocketClosed { .. }
+                | Error::Io(_)
+                | Error::Tungstenite(_)
+        )
+    }
 }
@@ -0,0 +1,109 @@
+use chrono::{DateTime, Duration, DurationRound, TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::app::App;
+use crate::bybit::TickerData;
+use crate::client::{Channel, Exchange};
+use crate::hyperliquid::ActiveAssetCtx;
+use crate::sync::LockRecover;
+
+/// Current funding on a perpetual market. The rate is per funding interval as published by the
+/// exchange, e.g. hourly on Hyperliquid and 8 hourly on most Bybit markets.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FundingRate {
+    pub rate: Decimal,
+    pub next_funding_time: DateTime<Utc>,
+    pub mark_price: Decimal,
+    pub exchange: Exchange,
+    // Time of the update
+    pub dt: DateTime<Utc>,
+}
+
+impl FundingRate {
+    /// Hyperliquid pays funding every hour on the hour. The asset context carries no timestamp so
+    /// the update is stamped on receipt.
+    pub fn from_hyperliquid(ctx: ActiveAssetCtx, dt: DateTime<Utc>) -> Self {
+        let hour = Duration::hours(1);
+        Self {
+            rate: ctx.ctx.funding,
+            next_funding_time: dt.duration_trunc(hour).unwrap() + hour,
+            mark_price: ctx.ctx.mark_px,
+            exchange: Exchange::Hyperliquid,
+            dt,
+        }
+    }
+
+    /// Bybit sends a full ticker snapshot followed by deltas carrying only the changed fields.
+    /// Missing fields are taken from the previous rate. Returns None until a complete rate has
+    /// been received.
+    pub fn from_bybit(ticker: TickerData, ts: i64, prev: Option<&FundingRate>) -> Option<Self> {
+        let next_funding_time = ticker
+            .next_funding_time
+            .and_then(|t| t.parse::<i64>().ok())
+            .and_then(|t| Utc.timestamp_millis_opt(t).single());
+        Some(Self {
+            rate: ticker.funding_rate.or(prev.map(|p| p.rate))?,
+            next_funding_time: next_funding_time.or(prev.map(|p| p.next_funding_time))?,
+            mark_price: ticker.mark_price.or(prev.map(|p| p.mark_price))?,
+            exchange: Exchange::Bybit,
+            dt: Utc.timestamp_millis_opt(ts).single()?,
+        })
+    }
+}
+
+impl App {
+    #[tracing::instrument(skip(self))]
+    pub fn insert_funding(&mut self, channel: Channel, funding: FundingRate) {
+        let mut rates = self.state.funding.lock_recover();
+        rates.entry(channel).and_modify(|f| *f = Some(funding));
+    }
+
+    /// Last funding rate stored for the channel.
+    pub fn last_funding(&self, channel: &Channel) -> Option<FundingRate> {
+        self.state
+            .funding
+            .lock_recover()
+            .get(channel)
+            .cloned()
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use rust_decimal_macros::dec;
+
+    use crate::bybit::TickerData;
+    use crate::funding::FundingRate;
+
+    #[test]
+    pub fn bybit_delta_merged_with_previous() {
+        let snapshot = TickerData {
+            symbol: "BTCUSDT".to_string(),
+            mark_price: Some(dec!(17217.33)),
+            index_price: Some(dec!(17227.36)),
+            funding_rate: Some(dec!(-0.000212)),
+            next_funding_time: Some("1673280000000".to_string()),
+        };
+        let rate = FundingRate::from_bybit(snapshot, 1673272861686, None).unwrap();
+        assert_eq!(
+            rate.next_funding_time,
+            Utc.timestamp_millis_opt(1673280000000).unwrap()
+        );
+
+        let delta = TickerData {
+            symbol: "BTCUSDT".to_string(),
+            mark_price: Some(dec!(17220.00)),
+            index_price: None,
+            funding_rate: None,
+            next_funding_time: None,
+        };
+        let rate = FundingRate::from_bybit(delta.clone(), 1673272862686, Some(&rate)).unwrap();
+        assert_eq!(rate.rate, dec!(-0.000212));
+        assert_eq!(rate.mark_price, dec!(17220.00));
+        // A delta without a previous snapshot is incomplete
+        assert!(FundingRate::from_bybit(delta, 1673272862686, None).is_none());
+    }
+}
@@ -1,22 +1,36 @@
 use crate::{
     app::{App, TradeSide},
-    client::{Channel, ChannelType},
+    client::{Channel, ChannelType, ClientResp, ClientRespMsg},
     error::{Error, Result},
+    orders::{Fill, Order, OrderStatus},
     trades::Trade,
 };
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use tokio_tungstenite::tungstenite::Message;
 
+/// Heartbeats are sent every second. A longer wait between heartbeats means messages were
+/// dropped.
+pub const HEARTBEAT_GAP_SECS: i64 = 3;
+
 #[derive(Clone, Deserialize, Serialize, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Response {
     Subscriptions(Subscriptions),
+    Error(GdaxError),
     Heartbeat(Heartbeat),
     Ticker(Ticker),
     Snapshot(Snapshot),
     L2update(L2update),
+    // Every trade on the matches feed, also sent on the authenticated user channel
+    Match(Match),
+    // Most recent trade, sent once on subscribing to the matches feed
+    LastMatch(Match),
+    Received(UserOrder),
+    Open(UserOrder),
+    Done(UserOrder),
+    Change(UserOrder),
 }
 
 /// Struct mapping for:
@@ -55,6 +69,21 @@ pub struct Subscriptions {
     pub channels: Vec<GdaxChannel>,
 }
 
+/// Struct mapping for:
+///
+/// Error from Coinbase Pro, e.g. for a subscription to an unknown product
+/// {
+///     "type": "error",
+///     "message": "Failed to subscribe",
+///     "reason": "BTC-XYZ is not a valid product"
+/// }
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct GdaxError {
+    pub message: String,
+    pub reason: Option<String>,
+}
+
 #[derive(Clone, Deserialize, Serialize, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct GdaxChannel {
@@ -108,11 +137,12 @@ pub struct Heartbeat {
 pub struct Ticker {
     pub sequence: u64,
     pub product_id: String,
-    pub price: String,
+    pub price: Decimal,
     pub side: TradeSide,
     pub time: DateTime<Utc>,
     #[serde(alias = "last_size")]
-    pub size: String,
+    pub size: Decimal,
+    pub trade_id: Option<i64>,
 }
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
@@ -123,6 +153,25 @@ pub struct Snapshot {
     pub asks: Vec<(Decimal, Decimal)>,
 }
 
+/// Struct mapping for:
+///
+/// Level 2 book from the REST api, fetched to seed a book before the socket snapshot arrives.
+/// Levels are price, size and the number of orders at the level.
+/// {
+///     "bids": [["27000.01", "0.5", 3]],
+///     "asks": [["27000.02", "1.2", 1]],
+///     "sequence": 3,
+///     "time": "2023-06-11T16:12:04.936167Z"
+/// }
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct RestBook {
+    pub bids: Vec<(Decimal, Decimal, u64)>,
+    pub asks: Vec<(Decimal, Decimal, u64)>,
+    pub sequence: u64,
+    pub time: DateTime<Utc>,
+}
+
 #[derive(Clone, Deserialize, Serialize, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct L2update {
@@ -131,8 +180,195 @@ pub struct L2update {
     pub changes: Vec<(TradeSide, Decimal, Decimal)>,
 }
 
+/// Struct mapping for:
+///
+/// Match message from the Coinbase Pro matches feed and the authenticated user channel. The side
+/// is the maker order side. The user, profile and fee rate fields are only sent on the user
+/// channel, for the account's side of the match.
+/// {
+///     "type": "match",
+///     "trade_id": 10,
+///     "sequence": 50,
+///     "maker_order_id": "ac928c66-ca53-498f-9c13-a110027a60e8",
+///     "taker_order_id": "132fb6ae-456b-4654-b4e0-d681ac05cea1",
+///     "time": "2014-11-07T08:19:27.028459Z",
+///     "product_id": "BTC-USD",
+///     "size": "5.23512",
+///     "price": "400.23",
+///     "side": "sell",
+///     "taker_user_id": "5844eceecf7e803e259d0365",
+///     "taker_fee_rate": "0.005"
+/// }
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct Match {
+    pub trade_id: i64,
+    pub maker_order_id: String,
+    pub taker_order_id: String,
+    pub time: DateTime<Utc>,
+    pub product_id: String,
+    pub size: Decimal,
+    pub price: Decimal,
+    pub side: TradeSide,
+    pub taker_user_id: Option<String>,
+    pub maker_user_id: Option<String>,
+    pub taker_fee_rate: Option<Decimal>,
+    pub maker_fee_rate: Option<Decimal>,
+}
+
+/// Struct mapping for:
+///
+/// Received, open, done and change messages from the authenticated Coinbase Pro user channel.
+/// Fields not sent for a message type are None.
+/// {
+///     "type": "done",
+///     "time": "2014-11-07T08:19:27.028459Z",
+///     "product_id": "BTC-USD",
+///     "sequence": 10,
+///     "price": "200.2",
+///     "order_id": "d50ec984-77a8-460a-b958-66f114b0de9b",
+///     "reason": "filled",
+///     "side": "sell",
+///     "remaining_size": "0"
+/// }
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct UserOrder {
+    pub order_id: String,
+    pub product_id: String,
+    pub time: DateTime<Utc>,
+    pub side: TradeSide,
+    pub price: Option<Decimal>,
+    pub size: Option<Decimal>,
+    pub remaining_size: Option<Decimal>,
+    pub new_size: Option<Decimal>,
+    pub reason: Option<String>,
+}
+
+/// Messages from the Coinbase Advanced Trade feed. Every message shares an envelope tagged by
+/// channel with the payload in a list of events.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(tag = "channel", rename_all = "snake_case")]
+pub enum AdvancedResponse {
+    Subscriptions(Envelope<AdvancedSubscriptions>),
+    Heartbeats(Envelope<AdvancedHeartbeat>),
+    MarketTrades(Envelope<MarketTrades>),
+    L2Data(Envelope<L2Data>),
+}
+
+/// Struct mapping for:
+///
+/// Advanced Trade message envelope
+/// {
+///     "channel": "market_trades",
+///     "client_id": "",
+///     "timestamp": "2023-02-09T20:19:35.39625135Z",
+///     "sequence_num": 0,
+///     "events": [...]
+/// }
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct Envelope<T> {
+    pub client_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub sequence_num: i64,
+    pub events: Vec<T>,
+}
+
+/// {"subscriptions": {"market_trades": ["BTC-USD"]}}
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct AdvancedSubscriptions {
+    pub subscriptions: serde_json::Value,
+}
+
+/// {"current_time": "2023-06-23 20:31:56.121961769 +0000 UTC m=+91717.525857105", "heartbeat_counter": "3049"}
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct AdvancedHeartbeat {
+    pub current_time: String,
+    pub heartbeat_counter: serde_json::Value,
+}
+
+/// Struct mapping for:
+///
+/// Market trades event from Coinbase Advanced Trade
+/// {
+///     "type": "update",
+///     "trades": [{
+///         "trade_id": "000000000",
+///         "product_id": "ETH-USD",
+///         "price": "1260.01",
+///         "size": "0.3",
+///         "side": "BUY",
+///         "time": "2019-08-14T20:42:27.265Z"
+///     }]
+/// }
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct MarketTrades {
+    pub r#type: String,
+    pub trades: Vec<AdvancedTrade>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct AdvancedTrade {
+    pub trade_id: String,
+    pub product_id: String,
+    pub price: Decimal,
+    pub size: Decimal,
+    pub side: String,
+    pub time: DateTime<Utc>,
+}
+
+/// Struct mapping for:
+///
+/// Level2 event from Coinbase Advanced Trade. The first event is a snapshot followed by updates
+/// where a zero quantity removes the level.
+/// {
+///     "type": "snapshot",
+///     "product_id": "BTC-USD",
+///     "updates": [{
+///         "side": "bid",
+///         "event_time": "1970-01-01T00:00:00Z",
+///         "price_level": "21921.73",
+///         "new_quantity": "0.06317902"
+///     }]
+/// }
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct L2Data {
+    pub r#type: L2DataType,
+    pub product_id: String,
+    pub updates: Vec<L2DataUpdate>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum L2DataType {
+    Snapshot,
+    Update,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct L2DataUpdate {
+    pub side: L2Side,
+    pub event_time: DateTime<Utc>,
+    pub price_level: Decimal,
+    pub new_quantity: Decimal,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum L2Side {
+    Bid,
+    Offer,
+}
+
 impl App {
-    #[tracing::instrument(skip(self, msg))]
+    #[tracing::instrument(skip(self, channel, msg), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
     pub async fn handle_ws_msg_gdax(
         &mut self,
         channel: Channel,
@@ -149,10 +385,14 @@ impl App {
                         Err(e) => {
                             tracing::error!("Could not parse message {:?}", text);
                             tracing::error!("Error: {:?}", e);
-                            return Err(Error::Serde(e));
+                            return Err(Error::Parse {
+                                exchange: channel.exchange,
+                                raw: text,
+                                source: e,
+                            });
                         }
                     };
-                    tracing::info!("{:?}", response);
+                    tracing::debug!(?response, "Response parsed");
                     self.handle_ws_response_gdax(channel.clone(), response)
                         .await?;
                 } else {
@@ -168,30 +408,202 @@ impl App {
         }
     }
 
-    #[tracing::instrument(skip(self, response))]
+    #[tracing::instrument(skip(self, channel, response), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
     pub async fn handle_ws_response_gdax(
         &mut self,
         channel: Channel,
         response: Response,
     ) -> Result<()> {
         match response {
-            Response::Heartbeat(_) => {}
-            Response::Subscriptions(_) => {}
+            Response::Heartbeat(heartbeat) => {
+                // Checked against the book where a socket carries both, as resubscribing the book
+                // also resubscribes the tape sharing its socket
+                let kind = match self.carries(&channel, ChannelType::Book) {
+                    true => ChannelType::Book,
+                    false => ChannelType::Tape,
+                };
+                if self.carries(&channel, kind.clone()) {
+                    let channel = self.route(&channel, kind, &heartbeat.product_id);
+                    self.check_gdax_heartbeat(channel, heartbeat);
+                }
+            }
+            // Sent in reply to each subscribe and unsubscribe message
+            Response::Subscriptions(_) => self.confirm_sub(&channel, Ok(())).await,
+            Response::Error(e) => {
+                tracing::error!("Error from Coinbase on {:?}: {:?}", channel, e);
+                let reason = e.reason.unwrap_or(e.message);
+                self.confirm_sub(&channel, Err(Error::SubscribeRejected { reason }))
+                    .await;
+            }
             Response::Ticker(ticker) => {
-                if channel.channel == ChannelType::Tape {
+                if self.carries(&channel, ChannelType::Tape) {
                     // Convert gdax ticker to trade and insert into trades state
+                    let channel = self.route(&channel, ChannelType::Tape, &ticker.product_id);
+                    if let Some(last) = self.gdax_sequences.get(&channel) {
+                        if ticker.sequence <= *last {
+                            tracing::warn!(
+                                "Dropping ticker {} on {:?}, last sequence {}",
+                                ticker.sequence,
+                                channel,
+                                last
+                            );
+                            return Ok(());
+                        }
+                    }
+                    self.gdax_sequences.insert(channel.clone(), ticker.sequence);
                     let trade: Trade = ticker.try_into()?;
-                    tracing::info!("Inserting: {:?}", trade);
+                    tracing::debug!(?trade, "Inserting trade");
                     self.insert_trade(channel, trade).await?;
-                    tracing::info!("Inserted.");
                 } else {
                     // Ticker message sent on a none tape channel
                     tracing::error!("Ticker message {:?} sent on channel {:?}", ticker, channel);
                     return Err(Error::ChannelResponseMismatch);
                 }
             }
-            Response::Snapshot(snapshot) => self.insert_gdax_snapshot(channel, snapshot).await,
-            Response::L2update(l2update) => self.insert_gdax_l2update(channel, l2update).await,
+            Response::Snapshot(snapshot) => {
+                let channel = self.route(&channel, ChannelType::Book, &snapshot.product_id);
+                self.insert_gdax_snapshot(channel, snapshot).await
+            }
+            Response::L2update(l2update) => {
+                let channel = self.route(&channel, ChannelType::Book, &l2update.product_id);
+                self.insert_gdax_l2update(channel, l2update).await
+            }
+            // The user channel sends matches and order updates to both fills and orders channels
+            Response::Match(m) => {
+                if channel.channel == ChannelType::Fills {
+                    let fill: Fill = m.try_into()?;
+                    self.insert_fill(channel, fill);
+                } else if self.carries(&channel, ChannelType::Tape) {
+                    let channel = self.route(&channel, ChannelType::Tape, &m.product_id);
+                    let trade: Trade = m.try_into()?;
+                    self.insert_trade(channel, trade).await?;
+                }
+            }
+            // Printed before the subscription, so it is not added to the tape
+            Response::LastMatch(_) => {}
+            Response::Received(o) => self.insert_gdax_order(channel, OrderStatus::Received, o),
+            Response::Open(o) => self.insert_gdax_order(channel, OrderStatus::Open, o),
+            Response::Done(o) => self.insert_gdax_order(channel, OrderStatus::Filled, o),
+            Response::Change(o) => self.insert_gdax_order(channel, OrderStatus::Changed, o),
+        }
+        Ok(())
+    }
+}
+
+impl App {
+    fn insert_gdax_order(&mut self, channel: Channel, status: OrderStatus, order: UserOrder) {
+        if channel.channel == ChannelType::Orders {
+            self.insert_order(channel, Order::from_gdax(status, order));
+        }
+    }
+
+    /// Compares the heartbeat with the last one received on the channel. The sequence never
+    /// decreases on a healthy feed so a sequence that moves backwards, or heartbeats that stop
+    /// arriving, mean the channel may have missed messages. The channel is resubscribed on the
+    /// next interval tick, and books are flagged stale until rebuilt from a new snapshot.
+    #[tracing::instrument(skip(self))]
+    pub fn check_gdax_heartbeat(&mut self, channel: Channel, heartbeat: Heartbeat) {
+        let gap = self.gdax_heartbeats.get(&channel).is_some_and(|last| {
+            heartbeat.sequence < last.sequence
+                || heartbeat.time - last.time > Duration::seconds(HEARTBEAT_GAP_SECS)
+        });
+        if !gap {
+            self.gdax_heartbeats.insert(channel, heartbeat);
+            return;
+        }
+        let last = self.gdax_heartbeats.remove(&channel);
+        tracing::warn!(
+            "Sequence gap on {:?}. Last heartbeat {:?}, received {:?}",
+            channel,
+            last,
+            heartbeat
+        );
+        self.errored.insert(channel.clone());
+        if channel.channel != ChannelType::Book {
+            return;
+        }
+        if let Some(sender) = self.app_sender.as_ref() {
+            let _ = sender.send(Ok(ClientRespMsg {
+                channel: Some(channel),
+                resp: ClientResp::BookStale,
+            }));
+        }
+    }
+
+    #[tracing::instrument(skip(self, channel, msg), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
+    pub async fn handle_ws_msg_coinbase_advanced(
+        &mut self,
+        channel: Channel,
+        msg: Result<Message>,
+    ) -> Result<()> {
+        match msg {
+            Ok(m) => {
+                // Update socket last message
+                self.update_last(channel.clone())?;
+                // Parse message
+                if let Message::Text(text) = m {
+                    let response: AdvancedResponse = match serde_json::from_str(&text) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            tracing::error!("Could not parse message {:?}", text);
+                            tracing::error!("Error: {:?}", e);
+                            return Err(Error::Parse {
+                                exchange: channel.exchange,
+                                raw: text,
+                                source: e,
+                            });
+                        }
+                    };
+                    tracing::debug!("Response: {:?}", response);
+                    self.handle_ws_response_coinbase_advanced(channel.clone(), response)
+                        .await?;
+                } else {
+                    tracing::warn!("Non-Text Message: {:?}", m);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // Return Err
+                tracing::error!("Error: {:?}", e);
+                Err(e)
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self, channel, response), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
+    pub async fn handle_ws_response_coinbase_advanced(
+        &mut self,
+        channel: Channel,
+        response: AdvancedResponse,
+    ) -> Result<()> {
+        match response {
+            AdvancedResponse::Subscriptions(_) => {}
+            AdvancedResponse::Heartbeats(_) => {}
+            AdvancedResponse::MarketTrades(envelope) => {
+                if self.carries(&channel, ChannelType::Tape) {
+                    // Insert oldest first as the order of trades within an event is not
+                    // guaranteed
+                    for mut event in envelope.events.into_iter() {
+                        event.trades.sort_by_key(|t| t.time);
+                        for trade in event.trades.into_iter() {
+                            let channel =
+                                self.route(&channel, ChannelType::Tape, &trade.product_id);
+                            let t: Trade = trade.try_into()?;
+                            self.insert_trade(channel, t).await?;
+                        }
+                    }
+                } else {
+                    // Trade message sent on a none tape channel
+                    tracing::error!("Trades {:?} sent on channel {:?}", envelope, channel);
+                    return Err(Error::ChannelResponseMismatch);
+                }
+            }
+            AdvancedResponse::L2Data(envelope) => {
+                for event in envelope.events.into_iter() {
+                    let channel = self.route(&channel, ChannelType::Book, &event.product_id);
+                    self.insert_coinbase_advanced_l2(channel, event).await;
+                }
+            }
         }
         Ok(())
     }
@@ -199,9 +611,14 @@ impl App {
 
 #[cfg(test)]
 mod tests {
+    use rust_decimal_macros::dec;
     use serde_json::{Result, Value};
+    use tokio::sync::mpsc;
 
-    use crate::gdax::{Response, Subscriptions};
+    use crate::app::{App, TradeSide};
+    use crate::client::{Channel, ClientConfig, Exchange};
+    use crate::gdax::{AdvancedResponse, L2DataType, L2Side, Response, Subscriptions};
+    use crate::websocket::mock;
 
     #[test]
     pub fn deserialize_sub() -> Result<()> {
@@ -215,22 +632,176 @@ mod tests {
         }
         "#;
 
-        let value = data.clone();
+        let value = data;
         let v: Value = serde_json::from_str(value)?;
         println!("Value: {:?}", v);
 
-        let subscribe = data.clone();
+        let subscribe = data;
         let v: Subscriptions = serde_json::from_str(subscribe)?;
         println!("Subscribe: {:?}", v);
 
-        // let response = data.clone();
+        // let response = data;
         // let v: Response = serde_json::from_str(response)?;
         // println!("Response: {:?}", v);
 
-        let response_enum = data.clone();
+        let response_enum = data;
         let v: Response = serde_json::from_str(response_enum)?;
         println!("Response: {:?}", v);
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn book_built_over_mock_transport() {
+        let (mut app, transport) = mock::app();
+        let channel = Channel::book(Exchange::Gdax, "BTC-USD");
+        let mut started = mock::start(&mut app, &channel).await;
+        assert_eq!(
+            transport.subscription(&channel)["channels"][0]["name"],
+            "level2_batch"
+        );
+        // Answered once Coinbase confirms the subscription
+        assert!(started.try_recv().is_err());
+        let subscriptions = "{\"type\":\"subscriptions\",\"channels\":[{\"name\":\"level2_batch\",\"product_ids\":[\"BTC-USD\"]}]}";
+        mock::deliver(&mut app, &channel, subscriptions).await;
+        assert!(started.await.unwrap().is_ok());
+
+        let snapshot = "{\"type\":\"snapshot\",\"product_id\":\"BTC-USD\",\"bids\":[[\"10101.10\",\"0.45\"],[\"10101.00\",\"1.2\"]],\"asks\":[[\"10102.55\",\"0.57\"]]}";
+        mock::deliver(&mut app, &channel, snapshot).await;
+        let update = "{\"type\":\"l2update\",\"product_id\":\"BTC-USD\",\"time\":\"2019-08-14T20:42:27.265Z\",\"changes\":[[\"buy\",\"10101.10\",\"0\"],[\"sell\",\"10102.00\",\"0.3\"]]}";
+        mock::deliver(&mut app, &channel, update).await;
+
+        let book = mock::book(&mut app, &channel).await.unwrap();
+        assert_eq!(book.best_bid(), Some((dec!(10101.00), dec!(1.2))));
+        assert_eq!(book.best_ask(), Some((dec!(10102.00), dec!(0.3))));
+    }
+
+    #[tokio::test]
+    async fn l2update_before_snapshot_applied_after_it() {
+        let (mut app, _transport) = mock::app();
+        let channel = Channel::book(Exchange::Gdax, "BTC-USD");
+        let _started = mock::start(&mut app, &channel).await;
+
+        let update = "{\"type\":\"l2update\",\"product_id\":\"BTC-USD\",\"time\":\"2019-08-14T20:42:27.265Z\",\"changes\":[[\"buy\",\"10101.10\",\"0\"],[\"sell\",\"10102.00\",\"0.3\"]]}";
+        mock::deliver(&mut app, &channel, update).await;
+        assert!(mock::book(&mut app, &channel).await.unwrap().is_empty());
+
+        let snapshot = "{\"type\":\"snapshot\",\"product_id\":\"BTC-USD\",\"bids\":[[\"10101.10\",\"0.45\"],[\"10101.00\",\"1.2\"]],\"asks\":[[\"10102.55\",\"0.57\"]]}";
+        mock::deliver(&mut app, &channel, snapshot).await;
+        let book = mock::book(&mut app, &channel).await.unwrap();
+        assert_eq!(book.best_bid(), Some((dec!(10101.00), dec!(1.2))));
+        assert_eq!(book.best_ask(), Some((dec!(10102.00), dec!(0.3))));
+        assert!(app.gdax_pending_updates.is_empty());
+    }
+
+    #[tokio::test]
+    async fn out_of_order_ticker_dropped() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel::tape(Exchange::Gdax, "ETH-USD");
+        app.setup_state(&channel, None).unwrap();
+        let ticker = |sequence: u64| {
+            format!("{{\"type\":\"ticker\",\"sequence\":{},\"product_id\":\"ETH-USD\",\"price\":\"1285.22\",\"side\":\"buy\",\"time\":\"2022-10-19T23:28:22.061769Z\",\"trade_id\":370843401,\"last_size\":\"11.4396987\"}}", sequence)
+        };
+        // Reordered then duplicated tick
+        for sequence in [10, 12, 11, 12, 13] {
+            let response: Response = serde_json::from_str(&ticker(sequence)).unwrap();
+            app.handle_ws_response_gdax(channel.clone(), response)
+                .await
+                .unwrap();
+        }
+        assert_eq!(app.state.tapes.lock().unwrap()[&channel].len(), 3);
+        assert_eq!(app.gdax_sequences[&channel], 13);
+    }
+
+    #[tokio::test]
+    async fn matches_feed_builds_tape() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel::tape(Exchange::Gdax, "BTC-USD");
+        app.setup_state(&channel, None).unwrap();
+        let last_match = "{\"type\":\"last_match\",\"trade_id\":9,\"maker_order_id\":\"ac928c66-ca53-498f-9c13-a110027a60e8\",\"taker_order_id\":\"132fb6ae-456b-4654-b4e0-d681ac05cea1\",\"side\":\"buy\",\"size\":\"0.1\",\"price\":\"400.10\",\"product_id\":\"BTC-USD\",\"sequence\":49,\"time\":\"2014-11-07T08:19:26.028459Z\"}";
+        let matches = last_match
+            .replace("last_match", "match")
+            .replace("\"trade_id\":9", "\"trade_id\":10");
+        for text in [
+            last_match.to_string(),
+            matches.clone(),
+            matches.replace(":10,", ":11,"),
+        ] {
+            let response: Response = serde_json::from_str(&text).unwrap();
+            app.handle_ws_response_gdax(channel.clone(), response)
+                .await
+                .unwrap();
+        }
+        // Every match is a trade, the last_match sent on subscribe is not
+        let tapes = app.state.tapes.lock().unwrap();
+        let tape = &tapes[&channel];
+        assert_eq!(tape.len(), 2);
+        assert_eq!(tape[0].id, Some("10".to_string()));
+        assert_eq!(tape[0].price, dec!(400.10));
+        // Maker bought so the taker sold
+        assert_eq!(tape[0].side, TradeSide::Sell);
+    }
+
+    #[tokio::test]
+    async fn advanced_tape_built_over_mock_transport() {
+        let (mut app, transport) = mock::app();
+        let channel = Channel::tape(Exchange::CoinbaseAdvanced, "ETH-USD");
+        let started = mock::start(&mut app, &channel).await;
+        assert!(started.await.unwrap().is_ok());
+        assert_eq!(transport.subscription(&channel)["channel"], "market_trades");
+
+        let snapshot = "{\"channel\":\"market_trades\",\"client_id\":\"\",\"timestamp\":\"2023-02-09T20:19:35.39625135Z\",\"sequence_num\":0,\"events\":[{\"type\":\"snapshot\",\"trades\":[{\"trade_id\":\"000000000\",\"product_id\":\"ETH-USD\",\"price\":\"1260.01\",\"size\":\"0.3\",\"side\":\"BUY\",\"time\":\"2019-08-14T20:42:27.265Z\"}]}]}";
+        mock::deliver(&mut app, &channel, snapshot).await;
+        let update = snapshot
+            .replace("snapshot", "update")
+            .replace("000000000", "000000001")
+            .replace("1260.01", "1260.50");
+        mock::deliver(&mut app, &channel, &update).await;
+
+        let tape = mock::tape(&mut app, &channel).await.unwrap();
+        assert_eq!(tape.len(), 2);
+        assert_eq!(tape[1].price, dec!(1260.50));
+    }
+
+    #[test]
+    pub fn deserialize_advanced_market_trades() -> Result<()> {
+        let data = "{\"channel\":\"market_trades\",\"client_id\":\"\",\"timestamp\":\"2023-02-09T20:19:35.39625135Z\",\"sequence_num\":0,\"events\":[{\"type\":\"snapshot\",\"trades\":[{\"trade_id\":\"000000000\",\"product_id\":\"ETH-USD\",\"price\":\"1260.01\",\"size\":\"0.3\",\"side\":\"BUY\",\"time\":\"2019-08-14T20:42:27.265Z\"}]}]}";
+
+        let v: AdvancedResponse = serde_json::from_str(data)?;
+        println!("Response: {:?}", v);
+        match v {
+            AdvancedResponse::MarketTrades(envelope) => {
+                assert_eq!(envelope.events[0].trades[0].side, "BUY")
+            }
+            _ => panic!("Expected market trades"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    pub fn deserialize_advanced_l2_data() -> Result<()> {
+        let data = "{\"channel\":\"l2_data\",\"client_id\":\"\",\"timestamp\":\"2023-02-09T20:32:50.714964855Z\",\"sequence_num\":0,\"events\":[{\"type\":\"snapshot\",\"product_id\":\"BTC-USD\",\"updates\":[{\"side\":\"bid\",\"event_time\":\"1970-01-01T00:00:00Z\",\"price_level\":\"21921.73\",\"new_quantity\":\"0.06317902\"},{\"side\":\"offer\",\"event_time\":\"1970-01-01T00:00:00Z\",\"price_level\":\"21921.74\",\"new_quantity\":\"0.02\"}]}]}";
+
+        let v: AdvancedResponse = serde_json::from_str(data)?;
+        println!("Response: {:?}", v);
+        match v {
+            AdvancedResponse::L2Data(envelope) => {
+                assert_eq!(envelope.events[0].r#type, L2DataType::Snapshot);
+                assert_eq!(envelope.events[0].updates[1].side, L2Side::Offer);
+            }
+            _ => panic!("Expected l2 data"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    pub fn deserialize_advanced_subscriptions() -> Result<()> {
+        let data = "{\"channel\":\"subscriptions\",\"client_id\":\"\",\"timestamp\":\"2023-02-09T20:32:50.714964855Z\",\"sequence_num\":1,\"events\":[{\"subscriptions\":{\"level2\":[\"BTC-USD\"]}}]}";
+
+        let v: AdvancedResponse = serde_json::from_str(data)?;
+        assert!(matches!(v, AdvancedResponse::Subscriptions(_)));
+        Ok(())
+    }
 }
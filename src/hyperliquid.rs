@@ -1,3 +1,4 @@
+use chrono::Utc;
 use rust_decimal::Decimal;
 use serde::Deserialize;
 use tokio_tungstenite::tungstenite::Message;
@@ -6,6 +7,9 @@ use crate::{
     app::App,
     client::{Channel, ChannelType},
     error::{Error, Result},
+    funding::FundingRate,
+    mark_price::MarkPrice,
+    orders::{Fill, Order},
     trades::Trade as AppTrade,
 };
 
@@ -15,6 +19,13 @@ pub enum Response {
     SubscriptionResponse(Subscribe),
     Trades(Vec<Trade>),
     L2Book(L2Book),
+    ActiveAssetCtx(ActiveAssetCtx),
+    UserFills(UserFills),
+    OrderUpdates(Vec<OrderUpdate>),
+    // Reply to the client {"method":"ping"} keepalive
+    Pong,
+    // Rejected request, e.g. {"channel":"error","data":"Invalid subscription {...}"}
+    Error(String),
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -28,7 +39,9 @@ pub struct Subscribe {
 #[serde(rename_all = "camelCase")]
 pub struct Subscription {
     pub r#type: String,
-    pub coin: String,
+    // Market subscriptions are by coin and user subscriptions by address
+    pub coin: Option<String>,
+    pub user: Option<String>,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -42,6 +55,92 @@ pub struct Trade {
     pub hash: String,
 }
 
+/// Struct mapping for:
+///
+/// Fills for every coin traded by the user from the userFills subscription. The first message is
+/// a snapshot of recent fills, resent when the socket resubscribes, and replaces the stored fills.
+/// {
+///     "channel": "userFills",
+///     "data": {
+///         "isSnapshot": true,
+///         "user": "0x010461c14e146ac35fe42271bdc1134ee31c703a",
+///         "fills": [{
+///             "coin": "BTC",
+///             "px": "27005.0",
+///             "sz": "0.0045",
+///             "side": "B",
+///             "time": 1695302934931,
+///             "startPosition": "0.0",
+///             "dir": "Open Long",
+///             "closedPnl": "0.0",
+///             "hash": "0x2fd6b6b8a9e4b4e8f5ad040e2a2b7c0101c3f07dfba1e5c0ba54dd16d4f5b9e0",
+///             "oid": 2184421893,
+///             "crossed": true,
+///             "fee": "0.036454",
+///             "tid": 790402542213452,
+///             "feeToken": "USDC"
+///         }]
+///     }
+/// }
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UserFills {
+    #[serde(default)]
+    pub is_snapshot: bool,
+    pub user: String,
+    pub fills: Vec<UserFill>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UserFill {
+    pub coin: String,
+    pub px: Decimal,
+    pub sz: Decimal,
+    pub side: String,
+    pub time: i64,
+    pub oid: u64,
+    pub tid: u64,
+    pub fee: Decimal,
+}
+
+/// Struct mapping for:
+///
+/// Order status changes for the user from the orderUpdates subscription.
+/// {
+///     "channel": "orderUpdates",
+///     "data": [{
+///         "order": {
+///             "coin": "BTC",
+///             "side": "A",
+///             "limitPx": "27100.0",
+///             "sz": "0.01",
+///             "oid": 2184421894,
+///             "timestamp": 1695302934931,
+///             "origSz": "0.01"
+///         },
+///         "status": "open",
+///         "statusTimestamp": 1695302934931
+///     }]
+/// }
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderUpdate {
+    pub order: BasicOrder,
+    pub status: String,
+    pub status_timestamp: i64,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BasicOrder {
+    pub coin: String,
+    pub side: String,
+    pub limit_px: Decimal,
+    pub sz: Decimal,
+    pub oid: u64,
+}
+
 #[derive(Clone, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct L2Book {
@@ -50,13 +149,21 @@ pub struct L2Book {
     pub levels: Levels,
 }
 
+/// Levels are sent as a positional pair of sides, [[bids], [asks]], not an object with named
+/// sides. Bids are sent from the best bid down and asks from the best ask up.
 #[derive(Clone, Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
+#[serde(from = "(Vec<Level>, Vec<Level>)")]
 pub struct Levels {
     pub bids: Vec<Level>,
     pub asks: Vec<Level>,
 }
 
+impl From<(Vec<Level>, Vec<Level>)> for Levels {
+    fn from((bids, asks): (Vec<Level>, Vec<Level>)) -> Self {
+        Self { bids, asks }
+    }
+}
+
 #[derive(Clone, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Level {
@@ -65,8 +172,64 @@ pub struct Level {
     pub sz: Decimal,
 }
 
+/// Struct mapping for:
+///
+/// Perp asset context from the activeAssetCtx subscription. Sent on subscribe and on each change.
+/// {
+///     "channel": "activeAssetCtx",
+///     "data": {
+///         "coin": "BTC",
+///         "ctx": {
+///             "funding": "0.0000125",
+///             "openInterest": "9876.54321",
+///             "prevDayPx": "26120.0",
+///             "dayNtlVlm": "123456789.0",
+///             "premium": "0.00031",
+///             "oraclePx": "26433.0",
+///             "markPx": "26440.0",
+///             "midPx": "26439.5",
+///             "impactPxs": ["26439.0", "26440.0"]
+///         }
+///     }
+/// }
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ActiveAssetCtx {
+    pub coin: String,
+    pub ctx: PerpAssetCtx,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PerpAssetCtx {
+    pub funding: Decimal,
+    pub open_interest: Decimal,
+    pub oracle_px: Decimal,
+    pub mark_px: Decimal,
+    pub premium: Option<Decimal>,
+}
+
+/// Adds the trade to the fill. Size is summed and price is the size weighted average.
+pub fn merge_fill(fill: &mut AppTrade, trade: &AppTrade) {
+    let size = fill.size + trade.size;
+    if !size.is_zero() {
+        fill.price = (fill.price * fill.size + trade.price * trade.size) / size;
+    }
+    fill.size = size;
+}
+
+/// Hyperliquid account feed for a private channel, userFills for fills and orderUpdates for
+/// orders.
+pub fn user_feed(channel: &Channel) -> &'static str {
+    if channel.channel == ChannelType::Fills {
+        "userFills"
+    } else {
+        "orderUpdates"
+    }
+}
+
 impl App {
-    #[tracing::instrument(skip(self, msg))]
+    #[tracing::instrument(skip(self, channel, msg), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
     pub async fn handle_ws_msg_hyperliquid(
         &mut self,
         channel: Channel,
@@ -88,7 +251,11 @@ impl App {
                         Err(e) => {
                             tracing::error!("Could not parse messages {:?}", text);
                             tracing::error!("Error: {:?}", e);
-                            return Err(Error::Serde(e));
+                            return Err(Error::Parse {
+                                exchange: channel.exchange,
+                                raw: text,
+                                source: e,
+                            });
                         }
                     };
                     tracing::debug!("Response: {:?}", response);
@@ -107,7 +274,7 @@ impl App {
         }
     }
 
-    #[tracing::instrument(skip(self, response))]
+    #[tracing::instrument(skip(self, channel, response), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
     pub async fn handle_ws_response_hyperliquid(
         &mut self,
         channel: Channel,
@@ -115,13 +282,28 @@ impl App {
     ) -> Result<()> {
         match response {
             Response::Trades(trades) => {
-                if channel.channel == ChannelType::Tape {
-                    // Convert to app trade and insert into state
+                if self.carries(&channel, ChannelType::Tape) {
+                    // Convert to app trade, merging consecutive fills of the same order on
+                    // channels aggregating fills
+                    let mut fills: Vec<(Channel, AppTrade)> = Vec::new();
                     for trade in trades.into_iter() {
                         tracing::debug!("Converting {:?}", trade);
+                        let channel = self.route_exact(&channel, ChannelType::Tape, &trade.coin)?;
                         let t: AppTrade = trade.try_into()?;
+                        match fills.last_mut() {
+                            Some((c, fill))
+                                if *c == channel
+                                    && fill.id == t.id
+                                    && self.aggregate_fills.contains(c) =>
+                            {
+                                merge_fill(fill, &t)
+                            }
+                            _ => fills.push((channel, t)),
+                        }
+                    }
+                    for (channel, t) in fills.into_iter() {
                         tracing::debug!("Inserting {:?}", t);
-                        self.insert_trade(channel.clone(), t).await?;
+                        self.insert_trade(channel, t).await?;
                     }
                 } else {
                     // Ticker message sent on a none tape channel
@@ -130,9 +312,52 @@ impl App {
                 }
             }
             Response::L2Book(book) => {
+                let channel = self.route_exact(&channel, ChannelType::Book, &book.coin)?;
                 self.insert_hyperliquid_snapshot(channel, book).await;
             }
-            _ => {}
+            Response::ActiveAssetCtx(ctx) => {
+                if self.carries(&channel, ChannelType::Funding) {
+                    let channel = self.route_exact(&channel, ChannelType::Funding, &ctx.coin)?;
+                    let funding = FundingRate::from_hyperliquid(ctx, Utc::now());
+                    self.insert_funding(channel, funding);
+                } else if self.carries(&channel, ChannelType::MarkPrice) {
+                    let channel = self.route_exact(&channel, ChannelType::MarkPrice, &ctx.coin)?;
+                    let mark = MarkPrice::from_hyperliquid(&ctx, Utc::now());
+                    self.insert_mark_price(channel, mark);
+                } else {
+                    // Asset context sent on a none funding or mark price channel
+                    tracing::warn!("Asset ctx {:?} sent on channel {:?}", ctx, channel);
+                    return Err(Error::ChannelResponseMismatch);
+                }
+            }
+            // User subscriptions cover every coin, only the channel market is kept
+            Response::UserFills(user_fills) => {
+                // Resent on every subscribe, so a reconnect would otherwise duplicate the history
+                if user_fills.is_snapshot {
+                    self.clear_fills(&channel);
+                }
+                for fill in user_fills.fills.into_iter() {
+                    if fill.coin == channel.market {
+                        let f: Fill = fill.try_into()?;
+                        self.insert_fill(channel.clone(), f);
+                    }
+                }
+            }
+            Response::OrderUpdates(updates) => {
+                for update in updates.into_iter() {
+                    if update.order.coin == channel.market {
+                        let o: Order = update.try_into()?;
+                        self.insert_order(channel.clone(), o);
+                    }
+                }
+            }
+            Response::SubscriptionResponse(_) => self.confirm_sub(&channel, Ok(())).await,
+            Response::Error(reason) => {
+                tracing::error!("Error from Hyperliquid on {:?}: {}", channel, reason);
+                self.confirm_sub(&channel, Err(Error::SubscribeRejected { reason }))
+                    .await;
+            }
+            Response::Pong => {}
         }
         Ok(())
     }
@@ -140,28 +365,76 @@ impl App {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::VecDeque;
+
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
     use serde_json::{Result, Value};
+    use tokio::sync::mpsc;
 
-    use crate::hyperliquid::Response;
+    use crate::app::{App, TradeSide};
+    use crate::client::{Channel, ChannelType, ClientConfig, Exchange};
+    use crate::error::Error;
+    use crate::hyperliquid::{Levels, Response};
+    use crate::trades::TAPE_CAPACITY;
+    use crate::websocket::mock;
 
     pub fn messages(s: String) -> String {
         let sub = "{\"channel\":\"subscriptionResponse\",\"data\":{\"subscription\":{\"type\":\"trades\",\"coin\":\"BTC\"},\"method\":\"subscribe\"}}";
         let trades = "{\"channel\":\"trades\",\"data\":[{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26433.0\",\"sz\":\"0.03019\",\"time\":1686270879026,\"hash\":\"0x92c994c577ae6997692104025ad505012a006e4cca395d87c41cccc763aa215e\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26433.0\",\"sz\":\"0.02876\",\"time\":1686270879026,\"hash\":\"0x92c994c577ae6997692104025ad505012a006e4cca395d87c41cccc763aa215e\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26437.0\",\"sz\":\"0.03067\",\"time\":1686270876527,\"hash\":\"0xf7699d8eb8f5c19f79bb04025ad4fb012c00c61407da9739a2320e555f052171\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26437.0\",\"sz\":\"0.02822\",\"time\":1686270876527,\"hash\":\"0xf7699d8eb8f5c19f79bb04025ad4fb012c00c61407da9739a2320e555f052171\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26443.0\",\"sz\":\"0.03\",\"time\":1686270832485,\"hash\":\"0x3c2ff5514bfa9f7c0e5104025ad423015100f3d0fe4620f634fd3a6c372ac1c2\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26443.0\",\"sz\":\"0.02905\",\"time\":1686270832485,\"hash\":\"0x3c2ff5514bfa9f7c0e5104025ad423015100f3d0fe4620f634fd3a6c372ac1c2\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26445.0\",\"sz\":\"0.03112\",\"time\":1686270605587,\"hash\":\"0xa5d80e826f5416f9fef004025acf8b011000a3bda51f21e1aaa65a78a58b8b16\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26445.0\",\"sz\":\"0.03078\",\"time\":1686270605587,\"hash\":\"0xa5d80e826f5416f9fef004025acf8b011000a3bda51f21e1aaa65a78a58b8b16\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26424.0\",\"sz\":\"0.2318\",\"time\":1686270370783,\"hash\":\"0x5bf314d7b7eef816239c04025acab7013e009167dad1d23805d5f65d4eb9b486\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26424.0\",\"sz\":\"0.63384\",\"time\":1686270370339,\"hash\":\"0xbcc25b36b8a163220d7904025acab601a7007f2c9550662ea436860b5ce63b70\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26424.0\",\"sz\":\"0.05756\",\"time\":1686270370339,\"hash\":\"0xbcc25b36b8a163220d7904025acab601a7007f2c9550662ea436860b5ce63b70\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26424.0\",\"sz\":\"0.39899\",\"time\":1686270370339,\"hash\":\"0xb6202b23f385d245d71804025acab601a9001d215fd407bd6dfc5272803fb7d4\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26428.0\",\"sz\":\"0.28195\",\"time\":1686270369880,\"hash\":\"0x042b64021c53c26880a404025acab5014100de4fd7b2cb867043128c8a5ba979\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26427.0\",\"sz\":\"0.02776\",\"time\":1686270369880,\"hash\":\"0x042b64021c53c26880a404025acab5014100de4fd7b2cb867043128c8a5ba979\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26428.0\",\"sz\":\"0.48607\",\"time\":1686270369432,\"hash\":\"0x49361d2ee405efa558f504025acab4015a0010819d2f6af6e227a23bd8a1d5ed\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26428.0\",\"sz\":\"0.22225\",\"time\":1686270369432,\"hash\":\"0x8c258b0fcfe87a7d270604025acab4015d002c337099ff44cf2c6ae003d1e7eb\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26428.0\",\"sz\":\"0.3813\",\"time\":1686270369432,\"hash\":\"0x8c258b0fcfe87a7d270604025acab4015d002c337099ff44cf2c6ae003d1e7eb\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26431.0\",\"sz\":\"0.02737\",\"time\":1686270368980,\"hash\":\"0x80450b02ea566746749004025acab301270000e6b1f502d43c8597452ce97d52\"},{\"coin\":\"BTC\",\"side\":\"A\",\"px\":\"26432.0\",\"sz\":\"0.03267\",\"time\":1686270368525,\"hash\":\"0xbdb6cd669293450cea7604025acab201b600ad4189c7228d764851f637c75fa9\"}]}";
         let connection = "Websocket connection established.";
+        let pong = "{\"channel\":\"pong\"}";
+        let ctx = "{\"channel\":\"activeAssetCtx\",\"data\":{\"coin\":\"BTC\",\"ctx\":{\"funding\":\"0.0000125\",\"openInterest\":\"9876.54321\",\"prevDayPx\":\"26120.0\",\"dayNtlVlm\":\"123456789.0\",\"premium\":\"0.00031\",\"oraclePx\":\"26433.0\",\"markPx\":\"26440.0\",\"midPx\":\"26439.5\",\"impactPxs\":[\"26439.0\",\"26440.0\"]}}}";
         let book = "{\"channel\":\"l2Book\",\"data\":{\"coin\":\"BTC\",\"time\":1686537736732,\"levels\":[[{\"px\":\"25748.0\",\"sz\":\"0.07332\",\"n\":2},{\"px\":\"25745.0\",\"sz\":\"1.58759\",\"n\":2},{\"px\":\"25741.0\",\"sz\":\"1.50368\",\"n\":2},{\"px\":\"25738.0\",\"sz\":\"0.71586\",\"n\":1},{\"px\":\"25736.0\",\"sz\":\"0.71842\",\"n\":1},{\"px\":\"25727.0\",\"sz\":\"1.61872\",\"n\":2},{\"px\":\"25714.0\",\"sz\":\"0.56045\",\"n\":1},{\"px\":\"25709.0\",\"sz\":\"0.53767\",\"n\":1},{\"px\":\"25705.0\",\"sz\":\"0.60063\",\"n\":1},{\"px\":\"25563.0\",\"sz\":\"0.57734\",\"n\":1},{\"px\":\"25562.0\",\"sz\":\"0.53548\",\"n\":1},{\"px\":\"25547.0\",\"sz\":\"0.60878\",\"n\":1},{\"px\":\"25508.0\",\"sz\":\"0.54988\",\"n\":1},{\"px\":\"25493.0\",\"sz\":\"0.55266\",\"n\":1},{\"px\":\"25492.0\",\"sz\":\"0.63969\",\"n\":1},{\"px\":\"25346.0\",\"sz\":\"0.63694\",\"n\":1},{\"px\":\"25345.0\",\"sz\":\"0.54101\",\"n\":1},{\"px\":\"25188.0\",\"sz\":\"0.62759\",\"n\":1},{\"px\":\"25187.0\",\"sz\":\"0.63564\",\"n\":1}],[{\"px\":\"25775.0\",\"sz\":\"0.03637\",\"n\":2},{\"px\":\"25797.0\",\"sz\":\"0.439\",\"n\":1},{\"px\":\"25801.0\",\"sz\":\"0.38807\",\"n\":1},{\"px\":\"25842.0\",\"sz\":\"0.4017\",\"n\":1},{\"px\":\"25860.0\",\"sz\":\"0.4169\",\"n\":1},{\"px\":\"25934.0\",\"sz\":\"0.58648\",\"n\":1},{\"px\":\"25935.0\",\"sz\":\"0.63012\",\"n\":1},{\"px\":\"25938.0\",\"sz\":\"0.4198\",\"n\":1},{\"px\":\"25950.0\",\"sz\":\"0.41782\",\"n\":1},{\"px\":\"25960.0\",\"sz\":\"0.58504\",\"n\":1},{\"px\":\"25977.0\",\"sz\":\"0.56065\",\"n\":1},{\"px\":\"26017.0\",\"sz\":\"0.57934\",\"n\":1},{\"px\":\"26024.0\",\"sz\":\"0.52938\",\"n\":1},{\"px\":\"26079.0\",\"sz\":\"0.57799\",\"n\":1},{\"px\":\"26082.0\",\"sz\":\"0.62211\",\"n\":1},{\"px\":\"26159.0\",\"sz\":\"0.56783\",\"n\":1},{\"px\":\"26217.0\",\"sz\":\"0.54541\",\"n\":1},{\"px\":\"26276.0\",\"sz\":\"0.54991\",\"n\":1},{\"px\":\"26277.0\",\"sz\":\"0.62702\",\"n\":1}]]}}";
-        if s == "subscription".to_string() {
+        let fills = "{\"channel\":\"userFills\",\"data\":{\"isSnapshot\":true,\"user\":\"0x010461c14e146ac35fe42271bdc1134ee31c703a\",\"fills\":[{\"coin\":\"BTC\",\"px\":\"27005.0\",\"sz\":\"0.0045\",\"side\":\"B\",\"time\":1695302934931,\"startPosition\":\"0.0\",\"dir\":\"Open Long\",\"closedPnl\":\"0.0\",\"hash\":\"0x2fd6b6b8a9e4b4e8f5ad040e2a2b7c0101c3f07dfba1e5c0ba54dd16d4f5b9e0\",\"oid\":2184421893,\"crossed\":true,\"fee\":\"0.036454\",\"tid\":790402542213452,\"feeToken\":\"USDC\"},{\"coin\":\"ETH\",\"px\":\"1590.1\",\"sz\":\"0.1\",\"side\":\"A\",\"time\":1695302935931,\"startPosition\":\"0.1\",\"dir\":\"Close Long\",\"closedPnl\":\"0.5\",\"hash\":\"0x3fd6b6b8a9e4b4e8f5ad040e2a2b7c0101c3f07dfba1e5c0ba54dd16d4f5b9e0\",\"oid\":2184421899,\"crossed\":false,\"fee\":\"0.0159\",\"tid\":790402542213499,\"feeToken\":\"USDC\"}]}}";
+        if s == "subscription" {
             sub.to_string()
-        } else if s == "trades".to_string() {
+        } else if s == "trades" {
             trades.to_string()
-        } else if s == "connection".to_string() {
+        } else if s == "connection" {
             connection.to_string()
-        } else if s == "book".to_string() {
+        } else if s == "book" {
             book.to_string()
+        } else if s == "pong" {
+            pong.to_string()
+        } else if s == "ctx" {
+            ctx.to_string()
+        } else if s == "fills" {
+            fills.to_string()
         } else {
             "none".to_string()
         }
     }
 
+    #[test]
+    fn out_of_range_time_is_an_error() {
+        let trade = crate::hyperliquid::Trade {
+            coin: "BTC".to_string(),
+            side: "B".to_string(),
+            px: "26433.0".to_string(),
+            sz: "0.03".to_string(),
+            time: i64::MAX,
+            hash: "0x00".to_string(),
+        };
+        match crate::trades::Trade::try_from(trade) {
+            Err(Error::InvalidTimestamp { exchange, raw }) => {
+                assert_eq!(exchange, Exchange::Hyperliquid);
+                assert_eq!(raw, i64::MAX.to_string());
+            }
+            other => panic!("expected invalid timestamp, got {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn deserialize_pong() -> Result<()> {
+        let data = messages("pong".to_string());
+
+        let v: Response = serde_json::from_str(&data)?;
+        println!("Response: {:?}", v);
+        assert!(matches!(v, Response::Pong));
+        Ok(())
+    }
+
     #[test]
     pub fn deserialize_sub() -> Result<()> {
         let data = messages("subscription".to_string());
@@ -188,13 +461,16 @@ mod tests {
 
     #[test]
     pub fn deserialize_connection() -> Result<()> {
+        // Connection message is plain text and is skipped before parsing in the handler
         let data = messages("connection".to_string());
 
-        let v: Value = serde_json::from_str(&data)?;
+        let v: Result<Value> = serde_json::from_str(&data);
         println!("Value: {:?}", v);
+        assert!(v.is_err());
 
-        let v: Response = serde_json::from_str(&data)?;
+        let v: Result<Response> = serde_json::from_str(&data);
         println!("Response: {:?}", v);
+        assert!(v.is_err());
         Ok(())
     }
 
@@ -209,4 +485,235 @@ mod tests {
         println!("Response: {:?}", v);
         Ok(())
     }
+
+    #[test]
+    pub fn deserialize_book_levels_by_position() {
+        let data = messages("book".to_string());
+        let book = match serde_json::from_str::<Response>(&data).unwrap() {
+            Response::L2Book(book) => book,
+            r => panic!("Expected L2Book, got {:?}", r),
+        };
+        assert_eq!(book.coin, "BTC");
+        assert_eq!(book.levels.bids.len(), 19);
+        assert_eq!(book.levels.asks.len(), 19);
+        assert_eq!(book.levels.bids[0].px, dec!(25748.0));
+        assert_eq!(book.levels.bids[0].n, 2);
+        assert_eq!(book.levels.asks[0].px, dec!(25775.0));
+        assert_eq!(book.levels.asks[0].sz, dec!(0.03637));
+
+        // The sides are not keyed by name
+        let keyed = "{\"bids\":[],\"asks\":[]}";
+        assert!(serde_json::from_str::<Levels>(keyed).is_err());
+    }
+
+    #[test]
+    fn unsubscribe_messages_match_subscriptions() {
+        for channel in [
+            Channel::book(Exchange::Hyperliquid, "BTC"),
+            Channel::tape(Exchange::Hyperliquid, "BTC"),
+        ] {
+            let subscribe = channel.subscribe_message().unwrap();
+            let unsubscribe = channel.unsubscribe_message().unwrap();
+            assert_eq!(unsubscribe["method"], "unsubscribe");
+            assert_eq!(unsubscribe["subscription"], subscribe["subscription"]);
+        }
+    }
+
+    #[test]
+    pub fn deserialize_asset_ctx() -> Result<()> {
+        let data = messages("ctx".to_string());
+
+        let v: Response = serde_json::from_str(&data)?;
+        println!("Response: {:?}", v);
+        match v {
+            Response::ActiveAssetCtx(ctx) => {
+                assert_eq!(ctx.coin, "BTC");
+                assert_eq!(ctx.ctx.funding, Decimal::new(125, 7));
+            }
+            _ => panic!("Expected asset ctx response"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn user_fills_kept_for_channel_coin() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Hyperliquid,
+            channel: ChannelType::Fills,
+            market: "BTC".to_string(),
+        };
+        app.state
+            .fills
+            .lock()
+            .unwrap()
+            .insert(channel.clone(), VecDeque::new());
+
+        let response: Response = serde_json::from_str(&messages("fills".to_string())).unwrap();
+        app.handle_ws_response_hyperliquid(channel.clone(), response)
+            .await
+            .unwrap();
+
+        let fills = app.state.fills.lock().unwrap();
+        let fills = fills.get(&channel).unwrap();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].side, TradeSide::Buy);
+        assert_eq!(fills[0].fee, Some(dec!(0.036454)));
+    }
+
+    #[tokio::test]
+    async fn user_fills_snapshot_replaces_history() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel::new(Exchange::Hyperliquid, ChannelType::Fills, "BTC");
+        app.state
+            .fills
+            .lock()
+            .unwrap()
+            .insert(channel.clone(), VecDeque::new());
+        let snapshot = messages("fills".to_string());
+        let update = snapshot.replace("\"isSnapshot\":true", "\"isSnapshot\":false");
+        let fill_count = |app: &App| app.state.fills.lock().unwrap()[&channel].len();
+
+        for text in [&snapshot, &update] {
+            let response: Response = serde_json::from_str(text).unwrap();
+            app.handle_ws_response_hyperliquid(channel.clone(), response)
+                .await
+                .unwrap();
+        }
+        assert_eq!(fill_count(&app), 2);
+
+        // The snapshot resent after a reconnect replaces the fills rather than adding to them
+        let response: Response = serde_json::from_str(&snapshot).unwrap();
+        app.handle_ws_response_hyperliquid(channel.clone(), response)
+            .await
+            .unwrap();
+        assert_eq!(fill_count(&app), 1);
+    }
+
+    #[tokio::test]
+    async fn asset_ctx_stored_as_mark_price() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel::new(Exchange::Hyperliquid, ChannelType::MarkPrice, "BTC");
+        app.state
+            .mark_prices
+            .lock()
+            .unwrap()
+            .insert(channel.clone(), None);
+
+        let response: Response = serde_json::from_str(&messages("ctx".to_string())).unwrap();
+        app.handle_ws_response_hyperliquid(channel.clone(), response)
+            .await
+            .unwrap();
+
+        let mark = app.last_mark_price(&channel).unwrap();
+        assert_eq!(mark.price, dec!(26440.0));
+        assert_eq!(mark.index_price, dec!(26433.0));
+        // Spot only exchanges have no mark price feed
+        let spot = Channel::new(Exchange::Gdax, ChannelType::MarkPrice, "BTC-USD");
+        assert!(matches!(
+            spot.subscribe_message(),
+            Err(Error::UnsupportedChannel)
+        ));
+    }
+
+    #[tokio::test]
+    async fn book_built_over_mock_transport() {
+        let (mut app, transport) = mock::app();
+        let channel = Channel::book(Exchange::Hyperliquid, "BTC");
+        let mut started = mock::start(&mut app, &channel).await;
+        assert_eq!(
+            transport.subscription(&channel)["subscription"]["type"],
+            "l2Book"
+        );
+        assert!(started.try_recv().is_err());
+        let confirmed = messages("subscription".to_string()).replace("trades", "l2Book");
+        mock::deliver(&mut app, &channel, &confirmed).await;
+        assert!(started.await.unwrap().is_ok());
+
+        let snapshot = messages("book".to_string());
+        mock::deliver(&mut app, &channel, &snapshot).await;
+        // Each l2Book message is a full snapshot replacing the book
+        let update = snapshot.replace("{\"px\":\"25748.0\",\"sz\":\"0.07332\",\"n\":2},", "");
+        mock::deliver(&mut app, &channel, &update).await;
+
+        let book = mock::book(&mut app, &channel).await.unwrap();
+        assert_eq!(book.best_bid(), Some((dec!(25745.0), dec!(1.58759))));
+        assert_eq!(book.best_ask(), Some((dec!(25775.0), dec!(0.03637))));
+    }
+
+    #[tokio::test]
+    async fn mismatched_coin_rejected() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel::tape(Exchange::Hyperliquid, "ETH");
+        app.setup_state(&channel, None).unwrap();
+
+        // BTC trades sent on the ETH tape
+        let response: Response = serde_json::from_str(&messages("trades".to_string())).unwrap();
+        let result = app
+            .handle_ws_response_hyperliquid(channel.clone(), response)
+            .await;
+        assert!(matches!(result, Err(Error::ChannelResponseMismatch)));
+        assert!(app.state.tapes.lock().unwrap()[&channel].is_empty());
+
+        let book = Channel::book(Exchange::Hyperliquid, "ETH");
+        app.setup_state(&book, None).unwrap();
+        let response: Response = serde_json::from_str(&messages("book".to_string())).unwrap();
+        let result = app
+            .handle_ws_response_hyperliquid(book.clone(), response)
+            .await;
+        assert!(matches!(result, Err(Error::ChannelResponseMismatch)));
+        assert!(app.state.books.lock().unwrap()[&book].is_empty());
+    }
+
+    #[tokio::test]
+    async fn same_hash_fills_merged() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let raw = Channel {
+            exchange: Exchange::Hyperliquid,
+            channel: ChannelType::Tape,
+            market: "BTC".to_string(),
+        };
+        let aggregated = Channel {
+            market: "ETH".to_string(),
+            ..raw.clone()
+        };
+        for channel in [&raw, &aggregated] {
+            app.state
+                .tapes
+                .lock()
+                .unwrap()
+                .insert(channel.clone(), VecDeque::with_capacity(TAPE_CAPACITY));
+        }
+        app.aggregate_fills.insert(aggregated.clone());
+
+        let data = messages("trades".to_string());
+        for channel in [&raw, &aggregated] {
+            let data = data.replace(
+                "\"coin\":\"BTC\"",
+                &format!("\"coin\":\"{}\"", channel.market),
+            );
+            let response: Response = serde_json::from_str(&data).unwrap();
+            app.handle_ws_response_hyperliquid(channel.clone(), response)
+                .await
+                .unwrap();
+        }
+
+        let tapes = app.state.tapes.lock().unwrap();
+        // Raw mode keeps every fill
+        assert_eq!(tapes.get(&raw).unwrap().len(), 19);
+        // 19 fills from 12 orders
+        let tape = tapes.get(&aggregated).unwrap();
+        assert_eq!(tape.len(), 12);
+        assert_eq!(tape[0].size, dec!(0.05895));
+        assert_eq!(tape[0].price, dec!(26433.0));
+        // Fills at 26428 and 26427 for one order
+        let fill = &tape[7];
+        assert_eq!(fill.size, dec!(0.30971));
+        assert!(fill.price > dec!(26427) && fill.price < dec!(26428));
+    }
 }
@@ -1,14 +1,32 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use chrono::{DateTime, Utc};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use tokio_tungstenite::tungstenite::Message;
 
 use crate::{
-    app::App,
-    client::{Channel, ChannelType},
+    app::{App, TradeSide},
+    client::{Channel, ChannelType, ClientResp, ClientRespMsg},
     error::{Error, Result},
     trades::Trade as AppTrade,
 };
 
+/// Depth of the book subscription. Kraken checksums assume the book is truncated to this depth.
+pub const KRAKEN_BOOK_DEPTH: usize = 100;
+/// Seconds a level timestamp is kept after its price leaves the book. Out of order frames older
+/// than this are not expected.
+pub const KRAKEN_LEVEL_TTL_SECS: i64 = 60;
+
+static NEXT_REQID: AtomicI64 = AtomicI64::new(1);
+
+/// Unique request id for a subscribe or unsubscribe message. Kraken echoes it back on the
+/// subscriptionStatus answering the request.
+pub fn next_reqid() -> i64 {
+    NEXT_REQID.fetch_add(1, Ordering::Relaxed)
+}
+
 #[derive(Clone, Deserialize, Serialize, Debug)]
 #[serde(untagged, rename_all = "snake_case")]
 pub enum Response {
@@ -24,10 +42,19 @@ pub enum Response {
 #[serde(tag = "event", rename_all = "camelCase")]
 pub enum TaggedResp {
     Heartbeat,
+    Pong(Pong),
     SystemStatus(SystemStatus),
     SubscriptionStatus(SubscriptionStatus),
 }
 
+/// Reply to the client {"event":"ping"} keepalive.
+/// {"event":"pong","reqid":42}
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub struct Pong {
+    pub reqid: Option<i64>,
+}
+
 #[derive(Clone, Deserialize, Serialize, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct Subscription {
@@ -43,7 +70,8 @@ pub struct Subscription {
 #[derive(Clone, Deserialize, Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct SubscriptionStatus {
-    pub channel_name: String,
+    // Not sent on error statuses
+    pub channel_name: Option<String>,
     pub pair: Option<String>,
     pub reqid: Option<i64>,
     pub status: String,
@@ -89,6 +117,22 @@ pub struct Snapshot {
     pub pair: String,
 }
 
+/// Depth response from the REST api, fetched to seed a book before the socket snapshot arrives.
+/// The result is keyed by Kraken's name for the pair, e.g. XXBTZUSD, and level timestamps are
+/// whole seconds.
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct RestDepth {
+    pub error: Vec<String>,
+    #[serde(default)]
+    pub result: HashMap<String, RestBook>,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct RestBook {
+    pub asks: Vec<Level>,
+    pub bids: Vec<Level>,
+}
+
 #[derive(Clone, Deserialize, Serialize, Debug)]
 #[serde(rename_all = "snake_case")]
 pub struct BidAsks {
@@ -150,8 +194,169 @@ pub struct Level {
     pub update_type: Option<String>,
 }
 
+/// Messages from the v2 endpoint. Channel messages are tagged by channel and name their fields,
+/// with the type telling snapshots from updates. Replies to subscribe, unsubscribe and ping
+/// requests are tagged by method.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum V2Response {
+    Channel(V2Channel),
+    Method(V2Method),
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(tag = "channel", rename_all = "snake_case")]
+pub enum V2Channel {
+    Trade(V2Data<V2Trade>),
+    Book(V2Data<V2Book>),
+    Heartbeat,
+    Status(V2Data<serde_json::Value>),
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum V2UpdateType {
+    Snapshot,
+    Update,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct V2Data<T> {
+    #[serde(rename = "type")]
+    pub update_type: V2UpdateType,
+    pub data: Vec<T>,
+}
+
+/// Struct mapping for:
+///
+/// Trade from the v2 trade channel.
+/// {
+///     "channel": "trade",
+///     "type": "update",
+///     "data": [{
+///         "symbol": "BTC/USD",
+///         "side": "sell",
+///         "price": 26520.2,
+///         "qty": 0.00291,
+///         "ord_type": "market",
+///         "trade_id": 64523189,
+///         "timestamp": "2023-09-25T07:49:37.708706Z"
+///     }]
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct V2Trade {
+    pub symbol: String,
+    pub side: TradeSide,
+    pub price: Decimal,
+    pub qty: Decimal,
+    pub ord_type: String,
+    pub trade_id: u64,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Struct mapping for:
+///
+/// Book snapshot or update from the v2 book channel. A zero qty removes the level. Prices and
+/// quantities are sent as numbers so trailing zeros are lost and the checksum is computed with
+/// the market precision.
+/// {
+///     "channel": "book",
+///     "type": "update",
+///     "data": [{
+///         "symbol": "BTC/USD",
+///         "bids": [{"price": 26516.8, "qty": 0.0}],
+///         "asks": [],
+///         "checksum": 2439117997,
+///         "timestamp": "2023-10-06T17:35:55.440295Z"
+///     }]
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct V2Book {
+    pub symbol: String,
+    pub bids: Vec<V2Level>,
+    pub asks: Vec<V2Level>,
+    pub checksum: u32,
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+pub struct V2Level {
+    pub price: Decimal,
+    pub qty: Decimal,
+}
+
+/// Struct mapping for:
+///
+/// Reply to a v2 request.
+/// {
+///     "method": "subscribe",
+///     "result": {"channel": "book", "depth": 100, "snapshot": true, "symbol": "BTC/USD"},
+///     "success": true,
+///     "time_in": "2023-09-25T09:04:31.742599Z",
+///     "time_out": "2023-09-25T09:04:31.742648Z"
+/// }
+#[derive(Clone, Deserialize, Debug)]
+pub struct V2Method {
+    pub method: String,
+    pub success: Option<bool>,
+    pub error: Option<String>,
+    pub req_id: Option<i64>,
+}
+
+/// Timestamp of the last update applied at each price on each side of a Kraken book. Used to drop
+/// updates that arrive out of order, which would otherwise resurrect deleted levels.
+#[derive(Debug, Clone, Default)]
+pub struct LevelTimes {
+    pub asks: HashMap<Decimal, Decimal>,
+    pub bids: HashMap<Decimal, Decimal>,
+}
+
+impl LevelTimes {
+    pub fn from_snapshot(snapshot: &BidAsks) -> Self {
+        Self {
+            asks: snapshot
+                .r#as
+                .iter()
+                .map(|l| (l.price, l.timestamp))
+                .collect(),
+            bids: snapshot.bs.iter().map(|l| (l.price, l.timestamp)).collect(),
+        }
+    }
+}
+
+/// Returns the levels at least as new as the last update applied at their price and records their
+/// timestamps. Timestamps for prices no longer in the book side are dropped once they are older
+/// than KRAKEN_LEVEL_TTL_SECS.
+pub fn newer_levels(
+    times: &mut HashMap<Decimal, Decimal>,
+    levels: &[Level],
+    side: &BTreeMap<Decimal, Decimal>,
+) -> Vec<Level> {
+    let mut newer = Vec::with_capacity(levels.len());
+    for level in levels.iter() {
+        match times.get(&level.price) {
+            Some(last) if level.timestamp < *last => {
+                tracing::warn!(
+                    "Dropping out of order level {:?}, last update {}",
+                    level,
+                    last
+                );
+            }
+            _ => {
+                times.insert(level.price, level.timestamp);
+                newer.push(level.clone());
+            }
+        }
+    }
+    if let Some(latest) = times.values().max().copied() {
+        let cutoff = latest - Decimal::from(KRAKEN_LEVEL_TTL_SECS);
+        times.retain(|price, ts| *ts >= cutoff || side.contains_key(price));
+    }
+    newer
+}
+
 impl App {
-    #[tracing::instrument(skip(self, msg))]
+    #[tracing::instrument(skip(self, channel, msg), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
     pub async fn handle_ws_msg_kraken(
         &mut self,
         channel: Channel,
@@ -168,7 +373,11 @@ impl App {
                         Err(e) => {
                             tracing::error!("Could not parse message {:?}", text);
                             tracing::error!("Error: {:?}", e);
-                            return Err(Error::Serde(e));
+                            return Err(Error::Parse {
+                                exchange: channel.exchange,
+                                raw: text,
+                                source: e,
+                            });
                         }
                     };
                     tracing::debug!("{:?}", response);
@@ -187,7 +396,7 @@ impl App {
         }
     }
 
-    #[tracing::instrument(skip(self, response))]
+    #[tracing::instrument(skip(self, channel, response), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
     pub async fn handle_ws_response_kraken(
         &mut self,
         channel: Channel,
@@ -195,8 +404,9 @@ impl App {
     ) -> Result<()> {
         match response {
             Response::Trade(trade) => {
-                if channel.channel == ChannelType::Tape {
+                if self.carries(&channel, ChannelType::Tape) {
                     // Convert kraken trade to trade and insert into trades state
+                    let channel = self.route(&channel, ChannelType::Tape, &trade.pair);
                     for t in trade.trades.into_iter() {
                         let trade: AppTrade = t.try_into()?;
                         self.insert_trade(channel.clone(), trade).await?;
@@ -207,40 +417,238 @@ impl App {
                     return Err(Error::ChannelResponseMismatch);
                 }
             }
-            Response::Snapshot(snapshot) => self.insert_kraken_snapshot(channel, snapshot).await,
-            Response::L2updateAsk(update) => self.insert_kraken_update_ask(channel, update).await,
-            Response::L2updateBid(update) => self.insert_kraken_update_bid(channel, update).await,
-            Response::L2updateBoth(update) => self.insert_kraken_update_both(channel, update).await,
+            Response::Snapshot(snapshot) => {
+                let channel = self.route(&channel, ChannelType::Book, &snapshot.pair);
+                self.insert_kraken_snapshot(channel, snapshot).await
+            }
+            Response::L2updateAsk(update) => {
+                let channel = self.route(&channel, ChannelType::Book, &update.pair);
+                let result = self.insert_kraken_update_ask(channel, update).await;
+                self.recover_kraken_book(result).await?;
+            }
+            Response::L2updateBid(update) => {
+                let channel = self.route(&channel, ChannelType::Book, &update.pair);
+                let result = self.insert_kraken_update_bid(channel, update).await;
+                self.recover_kraken_book(result).await?;
+            }
+            Response::L2updateBoth(update) => {
+                let channel = self.route(&channel, ChannelType::Book, &update.pair);
+                let result = self.insert_kraken_update_both(channel, update).await;
+                self.recover_kraken_book(result).await?;
+            }
+            Response::TaggedResp(TaggedResp::SubscriptionStatus(status)) => {
+                self.handle_kraken_subscription_status(channel, status)
+                    .await
+            }
             Response::TaggedResp(_) => {}
         }
         Ok(())
     }
+
+    #[tracing::instrument(skip(self, channel, msg), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
+    pub async fn handle_ws_msg_kraken_v2(
+        &mut self,
+        channel: Channel,
+        msg: Result<Message>,
+    ) -> Result<()> {
+        match msg {
+            Ok(m) => {
+                // Update socket last message
+                self.update_last(channel.clone())?;
+                // Parse message
+                if let Message::Text(text) = m {
+                    let response: V2Response = match serde_json::from_str(&text) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            tracing::error!("Could not parse message {:?}", text);
+                            tracing::error!("Error: {:?}", e);
+                            return Err(Error::Parse {
+                                exchange: channel.exchange,
+                                raw: text,
+                                source: e,
+                            });
+                        }
+                    };
+                    tracing::debug!("{:?}", response);
+                    self.handle_ws_response_kraken_v2(channel.clone(), response)
+                        .await?;
+                } else {
+                    tracing::warn!("Non-Text Message: {:?}", m);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // Return Err
+                tracing::error!("Error: {:?}", e);
+                Err(e)
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self, channel, response), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
+    pub async fn handle_ws_response_kraken_v2(
+        &mut self,
+        channel: Channel,
+        response: V2Response,
+    ) -> Result<()> {
+        match response {
+            V2Response::Channel(V2Channel::Trade(trades)) => {
+                if !self.carries(&channel, ChannelType::Tape) {
+                    tracing::error!("Trade message {:?} sent on channel {:?}", trades, channel);
+                    return Err(Error::ChannelResponseMismatch);
+                }
+                for t in trades.data.into_iter() {
+                    let channel = self.route(&channel, ChannelType::Tape, &t.symbol);
+                    let trade: AppTrade = t.try_into()?;
+                    self.insert_trade(channel, trade).await?;
+                }
+            }
+            V2Response::Channel(V2Channel::Book(books)) => {
+                for book in books.data.into_iter() {
+                    let channel = self.route(&channel, ChannelType::Book, &book.symbol);
+                    let result = self
+                        .insert_kraken_v2_book(channel, books.update_type, book)
+                        .await;
+                    self.recover_kraken_book(result).await?;
+                }
+            }
+            V2Response::Method(reply) if reply.success == Some(false) => {
+                tracing::error!("Kraken {} request failed: {:?}", reply.method, reply.error);
+            }
+            V2Response::Channel(V2Channel::Heartbeat)
+            | V2Response::Channel(V2Channel::Status(_))
+            | V2Response::Method(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Resolves the Start request waiting on the subscription the status answers. Error statuses
+    /// without a reqid resolve the Start request waiting on the socket they arrived on. Statuses
+    /// for unsubscribes and for subscriptions resent after a reconnect carry reqids that are not
+    /// tracked. Untracked errors are forwarded to async clients, other untracked statuses are
+    /// only logged.
+    #[tracing::instrument(skip(self))]
+    pub async fn handle_kraken_subscription_status(
+        &mut self,
+        socket: Channel,
+        status: SubscriptionStatus,
+    ) {
+        let result = match status.status.as_str() {
+            "subscribed" => Ok(()),
+            "error" => {
+                let reason = status.error_message.clone().unwrap_or_default();
+                tracing::error!("Subscription rejected: {}", reason);
+                Err(Error::SubscribeRejected { reason })
+            }
+            _ => {
+                tracing::warn!("Unexpected subscription status {:?}", status);
+                return;
+            }
+        };
+        let channel = match status.reqid {
+            Some(r) => self.kraken_reqids.remove(&r),
+            None if result.is_err() && self.unconfirmed.contains_key(&socket) => {
+                Some(socket.clone())
+            }
+            None => None,
+        };
+        match (channel, result) {
+            (Some(c), result) => self.confirm_sub(&c, result).await,
+            (None, Err(e)) => {
+                if let Some(sender) = self.app_sender.as_ref() {
+                    let _ = sender.send(Ok(ClientRespMsg {
+                        channel: Some(socket),
+                        resp: ClientResp::Error(e),
+                    }));
+                }
+            }
+            (None, Ok(_)) => tracing::debug!("Untracked subscription status {:?}", status),
+        }
+    }
+
+    /// A checksum mismatch means the book has diverged from the exchange and will not recover from
+    /// further updates. Tear down the socket and resubscribe to pull a fresh snapshot, forwarding
+    /// the mismatch to async clients.
+    #[tracing::instrument(skip(self))]
+    pub async fn recover_kraken_book(&mut self, result: Result<()>) -> Result<()> {
+        match result {
+            Err(Error::ChecksumMismatch { channel }) => {
+                tracing::warn!("Checksum mismatch. Resubscribing to {:?}", channel);
+                self.resubscribe(channel.clone()).await?;
+                if let Some(sender) = self.app_sender.as_ref() {
+                    let _ = sender.send(Err(Error::ChecksumMismatch { channel }));
+                }
+                Ok(())
+            }
+            r => r,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use rust_decimal_macros::dec;
     use serde_json::{Result, Value};
+    use tokio::sync::{mpsc, oneshot};
+    use tokio::time::Instant;
 
-    use crate::kraken::Response;
+    use crate::app::{App, TradeSide, UnconfirmedSub, DEFAULT_SUBSCRIBE_TIMEOUT};
+    use crate::client::{Channel, ChannelType, ClientConfig, ClientResp, Exchange};
+    use crate::error::Error;
+    use crate::kraken::{Response, TaggedResp, V2Channel, V2Response, V2UpdateType};
+    use crate::websocket::mock;
 
     pub fn messages(s: String) -> String {
         let system_status = "{\"connectionID\":7697072686821276634,\"event\":\"systemStatus\",\"status\":\"online\",\"version\":\"1.9.1\"}";
         let subscription_status = "{\"channelID\":337,\"channelName\":\"trade\",\"event\":\"subscriptionStatus\",\"pair\":\"XBT/USD\",\"status\":\"subscribed\",\"subscription\":{\"name\":\"trade\"}}";
         let heartbeat = "{\"event\":\"heartbeat\"}";
+        let pong = "{\"event\":\"pong\",\"reqid\":42}";
         let update = "[336,{\"a\":[[\"25782.90000\",\"1.17100399\",\"1686499924.936167\"]],\"c\":\"3184832790\"},\"book-100\",\"XBT/USD\"]";
-        if s == "system_status".to_string() {
+        if s == "system_status" {
             system_status.to_string()
         } else if s == "heartbeat" {
             heartbeat.to_string()
-        } else if s == "subscription_status".to_string() {
+        } else if s == "subscription_status" {
             subscription_status.to_string()
-        } else if s == "update".to_string() {
+        } else if s == "update" {
             update.to_string()
+        } else if s == "pong" {
+            pong.to_string()
         } else {
             "none".to_string()
         }
     }
 
+    #[test]
+    fn out_of_range_time_is_an_error() {
+        let trade = crate::kraken::WsTrade {
+            price: dec!(26520.2),
+            volume: dec!(0.00291),
+            // Overflows i64 nanoseconds
+            time: dec!(99999999999999999),
+            side: "s".to_string(),
+            order_type: "m".to_string(),
+            misc: String::new(),
+        };
+        assert!(matches!(
+            crate::trades::Trade::try_from(trade),
+            Err(Error::InvalidTimestamp {
+                exchange: Exchange::Kraken,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    pub fn deserialize_pong() -> Result<()> {
+        let data = messages("pong".to_string());
+
+        let v: Response = serde_json::from_str(&data)?;
+        println!("Response: {:?}", v);
+        assert!(matches!(v, Response::TaggedResp(TaggedResp::Pong(_))));
+        Ok(())
+    }
+
     #[test]
     pub fn deserialize_system_status() -> Result<()> {
         let data = messages("system_status".to_string());
@@ -292,4 +700,166 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn subscription_error_resolves_start_by_reqid() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Kraken,
+            channel: ChannelType::Tape,
+            market: "XBT/XYZ".to_string(),
+        };
+        let sub = channel.subscribe_message().unwrap();
+        let reqid = sub["reqid"].as_i64().unwrap();
+        assert_ne!(
+            channel.subscribe_message().unwrap()["reqid"].as_i64(),
+            Some(reqid)
+        );
+        // Socket opened and waiting on the subscription status
+        let (resp_tx, resp_rx) = oneshot::channel();
+        app.kraken_reqids.insert(reqid, channel.clone());
+        app.unconfirmed.insert(
+            channel.clone(),
+            UnconfirmedSub {
+                remaining: 1,
+                deadline: Instant::now() + DEFAULT_SUBSCRIBE_TIMEOUT,
+                resp: Some(resp_tx),
+            },
+        );
+
+        // Status for another request is ignored
+        let other = format!("{{\"errorMessage\":\"Currency pair not supported XBT/XYZ\",\"event\":\"subscriptionStatus\",\"pair\":\"XBT/XYZ\",\"reqid\":{},\"status\":\"error\",\"subscription\":{{\"name\":\"trade\"}}}}", reqid + 1000);
+        let status = format!("{{\"errorMessage\":\"Currency pair not supported XBT/XYZ\",\"event\":\"subscriptionStatus\",\"pair\":\"XBT/XYZ\",\"reqid\":{},\"status\":\"error\",\"subscription\":{{\"name\":\"trade\"}}}}", reqid);
+        for text in [other, status] {
+            let response: Response = serde_json::from_str(&text).unwrap();
+            app.handle_ws_response_kraken(channel.clone(), response)
+                .await
+                .unwrap();
+        }
+        assert!(matches!(
+            resp_rx.await.unwrap(),
+            Err(Error::SubscribeRejected { reason }) if reason.contains("not supported")
+        ));
+        assert!(app.unconfirmed.is_empty());
+        assert!(app.kraken_reqids.is_empty());
+    }
+
+    #[tokio::test]
+    async fn untracked_subscription_error_forwarded() {
+        let (ws_send, _) = mpsc::channel(1);
+        let (app_send, mut app_recv) = mpsc::unbounded_channel();
+        let mut app = App::new(ws_send, Some(app_send), ClientConfig::default()).unwrap();
+        let channel = Channel::tape(Exchange::Kraken, "XBT/XYZ");
+        let status = "{\"errorMessage\":\"Currency pair not supported XBT/XYZ\",\"event\":\"subscriptionStatus\",\"pair\":\"XBT/XYZ\",\"reqid\":99,\"status\":\"error\",\"subscription\":{\"name\":\"trade\"}}";
+        let response: Response = serde_json::from_str(status).unwrap();
+        app.handle_ws_response_kraken(channel.clone(), response)
+            .await
+            .unwrap();
+        let msg = app_recv.try_recv().unwrap().unwrap();
+        assert_eq!(msg.channel, Some(channel.clone()));
+        assert!(matches!(
+            msg.resp,
+            ClientResp::Error(Error::SubscribeRejected { reason }) if reason.contains("XBT/XYZ")
+        ));
+
+        // Without a reqid the error resolves the Start waiting on the socket
+        let (resp_tx, resp_rx) = oneshot::channel();
+        app.unconfirmed.insert(
+            channel.clone(),
+            UnconfirmedSub {
+                remaining: 1,
+                deadline: Instant::now() + DEFAULT_SUBSCRIBE_TIMEOUT,
+                resp: Some(resp_tx),
+            },
+        );
+        let status = status.replace("\"reqid\":99,", "");
+        let response: Response = serde_json::from_str(&status).unwrap();
+        app.handle_ws_response_kraken(channel.clone(), response)
+            .await
+            .unwrap();
+        assert!(matches!(
+            resp_rx.await.unwrap(),
+            Err(Error::SubscribeRejected { .. })
+        ));
+        assert!(app.unconfirmed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn tape_built_over_mock_transport() {
+        let (mut app, transport) = mock::app();
+        let channel = Channel::tape(Exchange::Kraken, "XBT/USD");
+        let mut started = mock::start(&mut app, &channel).await;
+        let reqid = transport.subscription(&channel)["reqid"].as_i64().unwrap();
+        assert!(started.try_recv().is_err());
+        let status = format!("{{\"channelID\":337,\"channelName\":\"trade\",\"event\":\"subscriptionStatus\",\"pair\":\"XBT/USD\",\"reqid\":{reqid},\"status\":\"subscribed\",\"subscription\":{{\"name\":\"trade\"}}}}");
+        mock::deliver(&mut app, &channel, &status).await;
+        assert!(started.await.unwrap().is_ok());
+
+        let trades = "[337,[[\"25782.90000\",\"0.50000000\",\"1686499924.936167\",\"s\",\"m\",\"\"],[\"25783.10000\",\"0.25000000\",\"1686499925.101010\",\"b\",\"l\",\"\"]],\"trade\",\"XBT/USD\"]";
+        mock::deliver(&mut app, &channel, trades).await;
+
+        let tape = mock::tape(&mut app, &channel).await.unwrap();
+        assert_eq!(tape.len(), 2);
+        assert_eq!(tape[0].side, TradeSide::Sell);
+        assert_eq!(tape[1].price, dec!(25783.1));
+    }
+
+    #[tokio::test]
+    async fn v2_tape_built_over_mock_transport() {
+        let (mut app, transport) = mock::app();
+        let channel = Channel::tape(Exchange::KrakenV2, "BTC/USD");
+        let started = mock::start(&mut app, &channel).await;
+        assert!(started.await.unwrap().is_ok());
+        assert_eq!(
+            transport.subscription(&channel)["params"]["channel"],
+            "trade"
+        );
+
+        let trade = "{\"channel\":\"trade\",\"type\":\"snapshot\",\"data\":[{\"symbol\":\"BTC/USD\",\"side\":\"sell\",\"price\":26520.2,\"qty\":0.00291,\"ord_type\":\"market\",\"trade_id\":64523189,\"timestamp\":\"2023-09-25T07:49:37.708706Z\"}]}";
+        mock::deliver(&mut app, &channel, trade).await;
+        let update = trade
+            .replace("snapshot", "update")
+            .replace("64523189", "64523190")
+            .replace("\"sell\"", "\"buy\"");
+        mock::deliver(&mut app, &channel, &update).await;
+
+        let tape = mock::tape(&mut app, &channel).await.unwrap();
+        assert_eq!(tape.len(), 2);
+        assert_eq!(tape[1].side, TradeSide::Buy);
+    }
+
+    #[test]
+    pub fn deserialize_v2_messages() -> Result<()> {
+        let trade = "{\"channel\":\"trade\",\"type\":\"update\",\"data\":[{\"symbol\":\"BTC/USD\",\"side\":\"sell\",\"price\":26520.2,\"qty\":0.00291,\"ord_type\":\"market\",\"trade_id\":64523189,\"timestamp\":\"2023-09-25T07:49:37.708706Z\"}]}";
+        match serde_json::from_str(trade)? {
+            V2Response::Channel(V2Channel::Trade(t)) => {
+                assert_eq!(t.data[0].price, dec!(26520.2));
+                assert_eq!(t.data[0].qty, dec!(0.00291));
+                assert_eq!(t.data[0].side, TradeSide::Sell);
+            }
+            r => panic!("Expected trade, got {:?}", r),
+        }
+
+        let book = "{\"channel\":\"book\",\"type\":\"snapshot\",\"data\":[{\"symbol\":\"BTC/USD\",\"bids\":[{\"price\":26516.8,\"qty\":0.5}],\"asks\":[{\"price\":26517.0,\"qty\":1.25}],\"checksum\":2439117997}]}";
+        match serde_json::from_str(book)? {
+            V2Response::Channel(V2Channel::Book(b)) => {
+                assert_eq!(b.update_type, V2UpdateType::Snapshot);
+                assert_eq!(b.data[0].asks[0].price, dec!(26517));
+                assert_eq!(b.data[0].checksum, 2439117997);
+            }
+            r => panic!("Expected book, got {:?}", r),
+        }
+
+        let heartbeat = "{\"channel\":\"heartbeat\"}";
+        let v: V2Response = serde_json::from_str(heartbeat)?;
+        assert!(matches!(v, V2Response::Channel(V2Channel::Heartbeat)));
+
+        let rejected = "{\"error\":\"Currency pair not supported\",\"method\":\"subscribe\",\"success\":false,\"symbol\":\"BTC/XYZ\",\"time_in\":\"2023-09-25T09:04:31.742599Z\",\"time_out\":\"2023-09-25T09:04:31.742648Z\"}";
+        match serde_json::from_str(rejected)? {
+            V2Response::Method(m) => assert_eq!(m.success, Some(false)),
+            r => panic!("Expected method reply, got {:?}", r),
+        }
+        Ok(())
+    }
 }
@@ -1,9 +1,36 @@
+//! Logging: events and spans use the default module path targets, all under `agg_ws` (e.g.
+//! `agg_ws::gdax`), so `RUST_LOG=agg_ws=debug` or an `EnvFilter` directive on `agg_ws` routes the
+//! crate's logs separately from the application's. Message handling spans carry `exchange`,
+//! `market` and `channel_type` fields that events within them inherit. Per message events are
+//! logged at debug.
+
+#![allow(clippy::result_large_err)]
+
 pub mod app;
+pub mod auth;
+pub mod binance;
+pub mod bitstamp;
 pub mod book;
+pub mod bybit;
+pub mod candles;
 pub mod client;
+pub mod deribit;
 pub mod error;
+pub mod funding;
 pub mod gdax;
 pub mod hyperliquid;
 pub mod kraken;
+pub mod liquidations;
+pub mod mark_price;
+pub mod market;
+pub mod metrics;
+pub mod okx;
+pub mod orders;
+pub mod recorder;
+pub mod replay;
+pub mod rest;
+pub mod stream;
+pub mod symbol;
+pub mod sync;
 pub mod trades;
 pub mod websocket;
@@ -0,0 +1,79 @@
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::app::{App, TradeSide};
+use crate::bybit::Liquidation as BybitLiquidation;
+use crate::client::{Channel, Exchange};
+use crate::error::{Error, Result};
+use crate::sync::LockRecover;
+use crate::trades::from_millis;
+
+/// Number of liquidations kept for each liquidations channel.
+pub const LIQUIDATION_CAPACITY: usize = 100;
+
+/// Forced close of a position by the exchange.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Liquidation {
+    pub price: Decimal,
+    pub size: Decimal,
+    // Side of the liquidation order. A long position is liquidated with a sell.
+    pub side: TradeSide,
+    pub dt: DateTime<Utc>,
+    pub exchange: Exchange,
+}
+
+impl TryFrom<BybitLiquidation> for Liquidation {
+    type Error = Error;
+
+    fn try_from(l: BybitLiquidation) -> Result<Self> {
+        Ok(Self {
+            price: l.price,
+            size: l.size,
+            dt: from_millis(Exchange::Bybit, l.updated_time)?,
+            exchange: Exchange::Bybit,
+            // Bybit sends the side of the liquidated position
+            side: match l.side.as_str() {
+                "Buy" => TradeSide::Sell,
+                "Sell" => TradeSide::Buy,
+                _ => return Err(Error::InvalidTradeSide(l.side)),
+            },
+        })
+    }
+}
+
+impl App {
+    #[tracing::instrument(skip(self))]
+    pub fn insert_liquidation(&mut self, channel: Channel, liquidation: Liquidation) {
+        let mut liquidations = self.state.liquidations.lock_recover();
+        liquidations.entry(channel).and_modify(|vd| {
+            if vd.len() >= LIQUIDATION_CAPACITY {
+                vd.pop_front();
+            }
+            vd.push_back(liquidation);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::app::TradeSide;
+    use crate::bybit::Liquidation as BybitLiquidation;
+    use crate::liquidations::Liquidation;
+
+    #[test]
+    pub fn bybit_long_liquidated_with_sell() {
+        let l = BybitLiquidation {
+            updated_time: 1739502302929,
+            symbol: "ROSEUSDT".to_string(),
+            side: "Buy".to_string(),
+            size: dec!(20000),
+            price: dec!(0.04499),
+        };
+        let liquidation: Liquidation = l.try_into().unwrap();
+        assert_eq!(liquidation.side, TradeSide::Sell);
+        assert_eq!(liquidation.price, dec!(0.04499));
+    }
+}
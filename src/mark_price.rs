@@ -0,0 +1,99 @@
+use chrono::{DateTime, TimeZone, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::app::App;
+use crate::bybit::TickerData;
+use crate::client::{Channel, Exchange};
+use crate::hyperliquid::ActiveAssetCtx;
+use crate::sync::LockRecover;
+
+/// Mark price on a perpetual market, the price the exchange uses for margin and liquidations,
+/// with the index (oracle) price it tracks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarkPrice {
+    pub price: Decimal,
+    pub index_price: Decimal,
+    pub exchange: Exchange,
+    // Time of the update
+    pub dt: DateTime<Utc>,
+}
+
+impl MarkPrice {
+    /// Hyperliquid publishes the oracle price as the index. The asset context carries no
+    /// timestamp so the update is stamped on receipt.
+    pub fn from_hyperliquid(ctx: &ActiveAssetCtx, dt: DateTime<Utc>) -> Self {
+        Self {
+            price: ctx.ctx.mark_px,
+            index_price: ctx.ctx.oracle_px,
+            exchange: Exchange::Hyperliquid,
+            dt,
+        }
+    }
+
+    /// Bybit ticker deltas carry only the changed fields. Missing prices are taken from the
+    /// previous update. Returns None until both prices have been received.
+    pub fn from_bybit(ticker: &TickerData, ts: i64, prev: Option<&MarkPrice>) -> Option<Self> {
+        Some(Self {
+            price: ticker.mark_price.or(prev.map(|p| p.price))?,
+            index_price: ticker.index_price.or(prev.map(|p| p.index_price))?,
+            exchange: Exchange::Bybit,
+            dt: Utc.timestamp_millis_opt(ts).single()?,
+        })
+    }
+}
+
+impl App {
+    #[tracing::instrument(skip(self))]
+    pub fn insert_mark_price(&mut self, channel: Channel, mark: MarkPrice) {
+        let mut marks = self.state.mark_prices.lock_recover();
+        marks.entry(channel).and_modify(|m| *m = Some(mark));
+    }
+
+    /// Last mark price stored for the channel.
+    pub fn last_mark_price(&self, channel: &Channel) -> Option<MarkPrice> {
+        self.state
+            .mark_prices
+            .lock_recover()
+            .get(channel)
+            .cloned()
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use rust_decimal_macros::dec;
+
+    use crate::bybit::TickerData;
+    use crate::mark_price::MarkPrice;
+
+    #[test]
+    pub fn bybit_delta_merged_with_previous() {
+        let snapshot = TickerData {
+            symbol: "BTCUSDT".to_string(),
+            mark_price: Some(dec!(17217.33)),
+            index_price: Some(dec!(17227.36)),
+            funding_rate: Some(dec!(-0.000212)),
+            next_funding_time: Some("1673280000000".to_string()),
+        };
+        let mark = MarkPrice::from_bybit(&snapshot, 1673272861686, None).unwrap();
+        assert_eq!(mark.price, dec!(17217.33));
+        assert_eq!(mark.index_price, dec!(17227.36));
+
+        let delta = TickerData {
+            symbol: "BTCUSDT".to_string(),
+            mark_price: Some(dec!(17220.00)),
+            index_price: None,
+            funding_rate: None,
+            next_funding_time: None,
+        };
+        let mark = MarkPrice::from_bybit(&delta, 1673272862686, Some(&mark)).unwrap();
+        assert_eq!(mark.price, dec!(17220.00));
+        assert_eq!(mark.index_price, dec!(17227.36));
+        assert_eq!(mark.dt, Utc.timestamp_millis_opt(1673272862686).unwrap());
+        // A delta without a previous snapshot is incomplete
+        assert!(MarkPrice::from_bybit(&delta, 1673272862686, None).is_none());
+    }
+}
@@ -0,0 +1,89 @@
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
+
+use crate::client::Exchange;
+
+/// Precision of commonly subscribed markets as published by each exchange. Hyperliquid prices are
+/// limited to 5 significant figures rather than a fixed number of decimals so its markets are not
+/// listed. Columns are exchange, market, price decimals, size decimals and tick size.
+const MARKET_META: [(Exchange, &str, u32, u32, Decimal); 20] = [
+    (Exchange::Gdax, "BTC-USD", 2, 8, dec!(0.01)),
+    (Exchange::Gdax, "ETH-USD", 2, 8, dec!(0.01)),
+    (Exchange::CoinbaseAdvanced, "BTC-USD", 2, 8, dec!(0.01)),
+    (Exchange::CoinbaseAdvanced, "ETH-USD", 2, 8, dec!(0.01)),
+    (Exchange::Kraken, "XBT/USD", 1, 8, dec!(0.1)),
+    (Exchange::Kraken, "ETH/USD", 2, 8, dec!(0.01)),
+    (Exchange::KrakenV2, "BTC/USD", 1, 8, dec!(0.1)),
+    (Exchange::KrakenV2, "ETH/USD", 2, 8, dec!(0.01)),
+    (Exchange::Binance, "BTCUSDT", 2, 5, dec!(0.01)),
+    (Exchange::Binance, "ETHUSDT", 2, 4, dec!(0.01)),
+    (Exchange::Okx, "BTC-USDT", 1, 8, dec!(0.1)),
+    (Exchange::Okx, "ETH-USDT", 2, 6, dec!(0.01)),
+    (Exchange::Bybit, "BTCUSDT", 1, 3, dec!(0.1)),
+    (Exchange::Bybit, "ETHUSDT", 2, 2, dec!(0.01)),
+    (Exchange::Bitstamp, "btcusd", 0, 8, dec!(1)),
+    (Exchange::Bitstamp, "ethusd", 1, 8, dec!(0.1)),
+    (Exchange::Bitstamp, "btceur", 0, 8, dec!(1)),
+    (Exchange::Bitstamp, "etheur", 1, 8, dec!(0.1)),
+    (Exchange::Deribit, "BTC-PERPETUAL", 1, 0, dec!(0.5)),
+    (Exchange::Deribit, "ETH-PERPETUAL", 2, 0, dec!(0.05)),
+];
+
+/// Price and size precision of a market.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarketMeta {
+    pub price_decimals: u32,
+    pub size_decimals: u32,
+    pub tick_size: Decimal,
+}
+
+impl MarketMeta {
+    pub fn new(price_decimals: u32, size_decimals: u32, tick_size: Decimal) -> Self {
+        Self {
+            price_decimals,
+            size_decimals,
+            tick_size,
+        }
+    }
+
+    /// Precision of the exchange specific market. Returns None if the market is not listed.
+    pub fn lookup(exchange: &Exchange, market: &str) -> Option<Self> {
+        MARKET_META
+            .iter()
+            .find(|(e, m, ..)| e == exchange && *m == market)
+            .map(|(_, _, price_dp, size_dp, tick)| Self::new(*price_dp, *size_dp, *tick))
+    }
+
+    /// Price formatted with the market's decimals, e.g. 19420.50 for 19420.5 with 2 decimals.
+    pub fn format_price(&self, price: &Decimal) -> String {
+        format!("{:.*}", self.price_decimals as usize, price)
+    }
+
+    /// Size formatted with the market's decimals.
+    pub fn format_size(&self, size: &Decimal) -> String {
+        format!("{:.*}", self.size_decimals as usize, size)
+    }
+
+    /// Price rounded down to a multiple of the tick size.
+    pub fn round_to_tick(&self, price: &Decimal) -> Decimal {
+        (price / self.tick_size).floor() * self.tick_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::client::Exchange;
+    use crate::market::MarketMeta;
+
+    #[test]
+    pub fn format_to_market_precision() {
+        let meta = MarketMeta::lookup(&Exchange::Kraken, "XBT/USD").unwrap();
+        assert_eq!(meta.format_price(&dec!(27000)), "27000.0");
+        assert_eq!(meta.format_size(&dec!(0.5)), "0.50000000");
+        assert_eq!(meta.round_to_tick(&dec!(27000.17)), dec!(27000.1));
+        assert!(MarketMeta::lookup(&Exchange::Hyperliquid, "BTC").is_none());
+    }
+}
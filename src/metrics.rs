@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use chrono::{Duration, Utc};
+
+use crate::app::App;
+use crate::client::{Channel, Exchange};
+use crate::sync::LockRecover;
+
+/// Counters incremented by the App as messages are handled. Held in State so they can be read
+/// without going through the client loop.
+#[derive(Debug, Default)]
+pub struct Counters {
+    // Messages received on each exchange's sockets, including messages that failed to parse
+    pub messages: Mutex<HashMap<Exchange, u64>>,
+    // Trades inserted on each tape channel
+    pub trades: Mutex<HashMap<Channel, u64>>,
+    // Messages on each exchange's sockets that could not be parsed
+    pub parse_errors: Mutex<HashMap<Exchange, u64>>,
+    // Sockets reconnected after the connection dropped or was closed by the exchange
+    pub reconnects: AtomicU64,
+    // Consistency violations found by check_book on each book channel
+    pub book_violations: Mutex<HashMap<Channel, u64>>,
+}
+
+impl Counters {
+    pub fn message(&self, exchange: Exchange) {
+        *self.messages.lock_recover().entry(exchange).or_insert(0) += 1;
+    }
+
+    pub fn trade(&self, channel: &Channel) {
+        let mut trades = self.trades.lock_recover();
+        match trades.get_mut(channel) {
+            Some(n) => *n += 1,
+            None => {
+                trades.insert(channel.clone(), 1);
+            }
+        }
+    }
+
+    pub fn parse_error(&self, exchange: Exchange) {
+        *self
+            .parse_errors
+            .lock_recover()
+            .entry(exchange)
+            .or_insert(0) += 1;
+    }
+
+    pub fn book_violations(&self, channel: &Channel, n: u64) {
+        let mut violations = self.book_violations.lock_recover();
+        match violations.get_mut(channel) {
+            Some(count) => *count += n,
+            None => {
+                violations.insert(channel.clone(), n);
+            }
+        }
+    }
+
+    pub fn reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Point in time copy of the counters and socket health, e.g. for export to Prometheus.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    pub messages: HashMap<Exchange, u64>,
+    pub trades: HashMap<Channel, u64>,
+    pub parse_errors: HashMap<Exchange, u64>,
+    pub reconnects: u64,
+    pub book_violations: HashMap<Channel, u64>,
+    // Open sockets. Grouped channels share one socket.
+    pub sockets: usize,
+    // Time since the last message on each socket, keyed by the channel the socket is stored under
+    pub last_message_age: HashMap<Channel, Duration>,
+}
+
+impl App {
+    pub fn metrics(&self) -> Metrics {
+        let counters = &self.state.counters;
+        let now = Utc::now();
+        let sockets = self.sockets.lock_recover();
+        Metrics {
+            messages: counters.messages.lock_recover().clone(),
+            trades: counters.trades.lock_recover().clone(),
+            parse_errors: counters.parse_errors.lock_recover().clone(),
+            reconnects: counters.reconnects.load(Ordering::Relaxed),
+            book_violations: counters.book_violations.lock_recover().clone(),
+            sockets: sockets.len(),
+            last_message_age: sockets
+                .iter()
+                .map(|(channel, ws)| (channel.clone(), now - ws.last_message))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use rust_decimal_macros::dec;
+    use tokio::sync::mpsc;
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::app::{App, TradeSide};
+    use crate::client::{Channel, ChannelType, ClientConfig, Exchange};
+    use crate::trades::Trade;
+    use crate::websocket::WsEvent;
+
+    #[tokio::test]
+    async fn counters_reported_in_metrics() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Tape,
+            market: "BTC-USD".to_string(),
+        };
+        let garbled = Message::Text("{\"type\":".to_string());
+        app.handle_ws_msg((channel.clone(), Ok(WsEvent::Message(garbled))))
+            .await;
+        app.handle_ws_msg((channel.clone(), Ok(WsEvent::Reconnected)))
+            .await;
+        let trade = Trade {
+            price: dec!(27000),
+            size: dec!(0.5),
+            dt: Utc::now(),
+            exchange: Exchange::Gdax,
+            side: TradeSide::Sell,
+            id: None,
+        };
+        app.insert_trade(channel.clone(), trade).await.unwrap();
+
+        let metrics = app.metrics();
+        assert_eq!(metrics.messages.get(&Exchange::Gdax), Some(&1));
+        assert_eq!(metrics.parse_errors.get(&Exchange::Gdax), Some(&1));
+        assert_eq!(metrics.trades.get(&channel), Some(&1));
+        assert_eq!(metrics.reconnects, 1);
+        assert_eq!(metrics.sockets, 0);
+    }
+}
@@ -0,0 +1,282 @@
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::{
+    app::App,
+    client::{Channel, ChannelType},
+    error::{Error, Result},
+    trades::Trade as AppTrade,
+};
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Response {
+    Event(Event),
+    Books(Books),
+    Trades(Trades),
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Arg {
+    pub channel: String,
+    pub inst_id: String,
+}
+
+/// Struct mapping for:
+///
+/// Subscribe and error events from OKX
+/// {"event": "subscribe", "arg": {"channel": "trades", "instId": "BTC-USDT"}, "connId": "a4d3ae55"}
+/// {"event": "error", "code": "60012", "msg": "Invalid request", "connId": "a4d3ae55"}
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub event: String,
+    pub arg: Option<Arg>,
+    pub code: Option<String>,
+    pub msg: Option<String>,
+    pub conn_id: Option<String>,
+}
+
+/// Struct mapping for:
+///
+/// Trades message from OKX
+/// {
+///     "arg": {"channel": "trades", "instId": "BTC-USDT"},
+///     "data": [{
+///         "instId": "BTC-USDT",
+///         "tradeId": "130639474",
+///         "px": "42219.9",
+///         "sz": "0.12060306",
+///         "side": "buy",
+///         "ts": "1630048897897",
+///         "count": "3"
+///     }]
+/// }
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Trades {
+    pub arg: Arg,
+    pub data: Vec<Trade>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Trade {
+    pub inst_id: String,
+    pub trade_id: String,
+    pub px: Decimal,
+    pub sz: Decimal,
+    pub side: String,
+    pub ts: Decimal,
+}
+
+/// Struct mapping for:
+///
+/// Books message from OKX. The first message is a snapshot action followed by update actions.
+/// Levels are [price, size, deprecated, order count] and a zero size removes the level.
+/// {
+///     "arg": {"channel": "books", "instId": "BTC-USDT"},
+///     "action": "snapshot",
+///     "data": [{
+///         "asks": [["41006.8", "0.60038921", "0", "1"]],
+///         "bids": [["41006.3", "0.30178218", "0", "2"]],
+///         "ts": "1629966436396",
+///         "checksum": -1200119424,
+///         "prevSeqId": -1,
+///         "seqId": 123456
+///     }]
+/// }
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Books {
+    pub arg: Arg,
+    pub action: BookAction,
+    pub data: Vec<BookData>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum BookAction {
+    Snapshot,
+    Update,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BookData {
+    pub asks: Vec<(Decimal, Decimal, String, String)>,
+    pub bids: Vec<(Decimal, Decimal, String, String)>,
+    pub ts: Decimal,
+    pub checksum: Option<i64>,
+    pub seq_id: Option<i64>,
+    pub prev_seq_id: Option<i64>,
+}
+
+impl App {
+    #[tracing::instrument(skip(self, channel, msg), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
+    pub async fn handle_ws_msg_okx(
+        &mut self,
+        channel: Channel,
+        msg: Result<Message>,
+    ) -> Result<()> {
+        match msg {
+            Ok(m) => {
+                // Update socket last message
+                self.update_last(channel.clone())?;
+                // Parse message
+                if let Message::Text(text) = m {
+                    let response: Response = match serde_json::from_str(&text) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            tracing::error!("Could not parse message {:?}", text);
+                            tracing::error!("Error: {:?}", e);
+                            return Err(Error::Parse {
+                                exchange: channel.exchange,
+                                raw: text,
+                                source: e,
+                            });
+                        }
+                    };
+                    tracing::debug!("Response: {:?}", response);
+                    self.handle_ws_response_okx(channel.clone(), response)
+                        .await?;
+                } else {
+                    tracing::warn!("Non-text Message: {:?}", m);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // Return Err
+                tracing::error!("Error: {:?}", e);
+                Err(e)
+            }
+        }
+    }
+
+    #[tracing::instrument(skip(self, channel, response), fields(exchange = ?channel.exchange, market = %channel.market, channel_type = ?channel.channel))]
+    pub async fn handle_ws_response_okx(
+        &mut self,
+        channel: Channel,
+        response: Response,
+    ) -> Result<()> {
+        match response {
+            Response::Trades(trades) => {
+                if self.carries(&channel, ChannelType::Tape) {
+                    // Convert okx trades to trade and insert into trades state
+                    let channel = self.route(&channel, ChannelType::Tape, &trades.arg.inst_id);
+                    for trade in trades.data.into_iter() {
+                        let t: AppTrade = trade.try_into()?;
+                        self.insert_trade(channel.clone(), t).await?;
+                    }
+                } else {
+                    // Trade message sent on a none tape channel
+                    tracing::error!("Trade message {:?} sent on channel {:?}", trades, channel);
+                    return Err(Error::ChannelResponseMismatch);
+                }
+            }
+            Response::Books(books) => {
+                let channel = self.route(&channel, ChannelType::Book, &books.arg.inst_id);
+                self.insert_okx_books(channel, books).await
+            }
+            Response::Event(event) => {
+                if event.event == "error" {
+                    tracing::error!("Error event: {:?}", event);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use serde_json::{Result, Value};
+
+    use crate::client::{Channel, Exchange};
+    use crate::okx::{BookAction, Response};
+    use crate::websocket::mock;
+
+    pub fn messages(s: String) -> String {
+        let sub = "{\"event\":\"subscribe\",\"arg\":{\"channel\":\"trades\",\"instId\":\"BTC-USDT\"},\"connId\":\"a4d3ae55\"}";
+        let trades = "{\"arg\":{\"channel\":\"trades\",\"instId\":\"BTC-USDT\"},\"data\":[{\"instId\":\"BTC-USDT\",\"tradeId\":\"130639474\",\"px\":\"42219.9\",\"sz\":\"0.12060306\",\"side\":\"buy\",\"ts\":\"1630048897897\",\"count\":\"3\"}]}";
+        let book = "{\"arg\":{\"channel\":\"books\",\"instId\":\"BTC-USDT\"},\"action\":\"snapshot\",\"data\":[{\"asks\":[[\"41006.8\",\"0.60038921\",\"0\",\"1\"],[\"41007.1\",\"0.00133\",\"0\",\"1\"]],\"bids\":[[\"41006.3\",\"0.30178218\",\"0\",\"2\"],[\"41006.1\",\"0.78\",\"0\",\"1\"]],\"ts\":\"1629966436396\",\"checksum\":-1200119424,\"prevSeqId\":-1,\"seqId\":123456}]}";
+        if s == "subscription" {
+            sub.to_string()
+        } else if s == "trades" {
+            trades.to_string()
+        } else if s == "book" {
+            book.to_string()
+        } else {
+            "none".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn tape_built_over_mock_transport() {
+        let (mut app, transport) = mock::app();
+        let channel = Channel::tape(Exchange::Okx, "BTC-USDT");
+        let started = mock::start(&mut app, &channel).await;
+        assert!(started.await.unwrap().is_ok());
+        assert_eq!(
+            transport.subscription(&channel)["args"][0]["channel"],
+            "trades"
+        );
+
+        mock::deliver(&mut app, &channel, &messages("subscription".to_string())).await;
+        let trades = messages("trades".to_string());
+        mock::deliver(&mut app, &channel, &trades).await;
+        let next = trades
+            .replace("130639474", "130639475")
+            .replace("42219.9", "42220.1");
+        mock::deliver(&mut app, &channel, &next).await;
+
+        let tape = mock::tape(&mut app, &channel).await.unwrap();
+        assert_eq!(tape.len(), 2);
+        assert_eq!(tape[1].price, dec!(42220.1));
+    }
+
+    #[test]
+    pub fn deserialize_sub() -> Result<()> {
+        let data = messages("subscription".to_string());
+
+        let v: Value = serde_json::from_str(&data)?;
+        println!("Value: {:?}", v);
+
+        let v: Response = serde_json::from_str(&data)?;
+        println!("Response: {:?}", v);
+        assert!(matches!(v, Response::Event(_)));
+        Ok(())
+    }
+
+    #[test]
+    pub fn deserialize_trades() -> Result<()> {
+        let data = messages("trades".to_string());
+
+        let v: Value = serde_json::from_str(&data)?;
+        println!("Value: {:?}", v);
+
+        let v: Response = serde_json::from_str(&data)?;
+        println!("Response: {:?}", v);
+        assert!(matches!(v, Response::Trades(_)));
+        Ok(())
+    }
+
+    #[test]
+    pub fn deserialize_book() -> Result<()> {
+        let data = messages("book".to_string());
+
+        let v: Value = serde_json::from_str(&data)?;
+        println!("Value: {:?}", v);
+
+        let v: Response = serde_json::from_str(&data)?;
+        println!("Response: {:?}", v);
+        match v {
+            Response::Books(books) => assert_eq!(books.action, BookAction::Snapshot),
+            _ => panic!("Expected books response"),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::app::{App, TradeSide};
+use crate::client::{Channel, Exchange};
+use crate::error::{Error, Result};
+use crate::gdax::{Match as GdaxMatch, UserOrder as GdaxOrder};
+use crate::hyperliquid::{OrderUpdate as HLOrderUpdate, UserFill as HLFill};
+use crate::sync::LockRecover;
+use crate::trades::from_millis;
+
+/// Number of fills and order updates kept for each private channel.
+pub const FILL_CAPACITY: usize = 100;
+pub const ORDER_CAPACITY: usize = 100;
+
+/// Execution of one of the account's orders.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fill {
+    pub trade_id: String,
+    pub order_id: String,
+    pub price: Decimal,
+    pub size: Decimal,
+    // Side of the account's order
+    pub side: TradeSide,
+    // Fee charged in the quote currency. None if the exchange does not report it.
+    pub fee: Option<Decimal>,
+    pub dt: DateTime<Utc>,
+    pub exchange: Exchange,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderStatus {
+    // Accepted by the exchange but not yet on the book
+    Received,
+    Open,
+    Filled,
+    Cancelled,
+    // Size or price amended
+    Changed,
+    // Exchange specific status, e.g. Hyperliquid rejected or triggered
+    Other(String),
+}
+
+/// Update to one of the account's orders.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Order {
+    pub order_id: String,
+    pub status: OrderStatus,
+    pub side: TradeSide,
+    pub price: Option<Decimal>,
+    // Size left on the book after the update
+    pub remaining_size: Option<Decimal>,
+    pub dt: DateTime<Utc>,
+    pub exchange: Exchange,
+}
+
+impl TryFrom<GdaxMatch> for Fill {
+    type Error = Error;
+
+    /// Coinbase sends the maker side on matches. Only the account's side of the match carries
+    /// its user id and fee rate.
+    fn try_from(m: GdaxMatch) -> Result<Self> {
+        let maker_side = m.side;
+        let (order_id, side, fee_rate) = if m.taker_user_id.is_some() {
+            let taker_side = match maker_side {
+                TradeSide::Buy => TradeSide::Sell,
+                TradeSide::Sell => TradeSide::Buy,
+            };
+            (m.taker_order_id, taker_side, m.taker_fee_rate)
+        } else {
+            (m.maker_order_id, maker_side, m.maker_fee_rate)
+        };
+        Ok(Self {
+            trade_id: m.trade_id.to_string(),
+            order_id,
+            price: m.price,
+            size: m.size,
+            side,
+            fee: fee_rate.map(|r| r * m.price * m.size),
+            dt: m.time,
+            exchange: Exchange::Gdax,
+        })
+    }
+}
+
+impl TryFrom<HLFill> for Fill {
+    type Error = Error;
+
+    fn try_from(f: HLFill) -> Result<Self> {
+        Ok(Self {
+            trade_id: f.tid.to_string(),
+            order_id: f.oid.to_string(),
+            price: f.px,
+            size: f.sz,
+            side: hyperliquid_side(&f.side)?,
+            fee: Some(f.fee),
+            dt: from_millis(Exchange::Hyperliquid, f.time)?,
+            exchange: Exchange::Hyperliquid,
+        })
+    }
+}
+
+impl Order {
+    pub fn from_gdax(status: OrderStatus, o: GdaxOrder) -> Self {
+        // Done messages carry the reason the order left the book
+        let status = match (status, o.reason.as_deref()) {
+            (OrderStatus::Filled, Some("canceled")) => OrderStatus::Cancelled,
+            (status, _) => status,
+        };
+        Self {
+            order_id: o.order_id,
+            status,
+            side: o.side,
+            price: o.price,
+            remaining_size: o.remaining_size.or(o.new_size).or(o.size),
+            dt: o.time,
+            exchange: Exchange::Gdax,
+        }
+    }
+}
+
+impl TryFrom<HLOrderUpdate> for Order {
+    type Error = Error;
+
+    fn try_from(u: HLOrderUpdate) -> Result<Self> {
+        Ok(Self {
+            order_id: u.order.oid.to_string(),
+            status: match u.status.as_str() {
+                "open" => OrderStatus::Open,
+                "filled" => OrderStatus::Filled,
+                "canceled" | "marginCanceled" => OrderStatus::Cancelled,
+                other => OrderStatus::Other(other.to_string()),
+            },
+            side: hyperliquid_side(&u.order.side)?,
+            price: Some(u.order.limit_px),
+            remaining_size: Some(u.order.sz),
+            dt: from_millis(Exchange::Hyperliquid, u.status_timestamp)?,
+            exchange: Exchange::Hyperliquid,
+        })
+    }
+}
+
+// Hyperliquid sides are B for bid and A for ask
+fn hyperliquid_side(side: &str) -> Result<TradeSide> {
+    match side {
+        "B" => Ok(TradeSide::Buy),
+        "A" => Ok(TradeSide::Sell),
+        _ => Err(Error::InvalidTradeSide(side.to_string())),
+    }
+}
+
+impl App {
+    #[tracing::instrument(skip(self))]
+    pub fn insert_fill(&mut self, channel: Channel, fill: Fill) {
+        let mut fills = self.state.fills.lock_recover();
+        fills.entry(channel).and_modify(|vd| {
+            if vd.len() >= FILL_CAPACITY {
+                vd.pop_front();
+            }
+            vd.push_back(fill);
+        });
+    }
+
+    /// Empties the stored fills of the channel, e.g. before a snapshot replaces them.
+    pub fn clear_fills(&mut self, channel: &Channel) {
+        if let Some(fills) = self.state.fills.lock_recover().get_mut(channel) {
+            fills.clear();
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn insert_order(&mut self, channel: Channel, order: Order) {
+        let mut orders = self.state.orders.lock_recover();
+        orders.entry(channel).and_modify(|vd| {
+            if vd.len() >= ORDER_CAPACITY {
+                vd.pop_front();
+            }
+            vd.push_back(order);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use crate::app::TradeSide;
+    use crate::gdax::Response;
+    use crate::orders::{Fill, Order, OrderStatus};
+
+    #[test]
+    pub fn gdax_taker_fill_takes_opposite_side() {
+        let data = "{\"type\":\"match\",\"trade_id\":10,\"sequence\":50,\"maker_order_id\":\"ac928c66-ca53-498f-9c13-a110027a60e8\",\"taker_order_id\":\"132fb6ae-456b-4654-b4e0-d681ac05cea1\",\"time\":\"2014-11-07T08:19:27.028459Z\",\"product_id\":\"BTC-USD\",\"size\":\"5.23512\",\"price\":\"400.23\",\"side\":\"sell\",\"taker_user_id\":\"5844eceecf7e803e259d0365\",\"user_id\":\"5844eceecf7e803e259d0365\",\"taker_profile_id\":\"765d1549-9660-4be2-97d4-fa2d65fa3352\",\"profile_id\":\"765d1549-9660-4be2-97d4-fa2d65fa3352\",\"taker_fee_rate\":\"0.005\"}";
+        let fill: Fill = match serde_json::from_str(data).unwrap() {
+            Response::Match(m) => m.try_into().unwrap(),
+            r => panic!("Expected match, got {:?}", r),
+        };
+        assert_eq!(fill.side, TradeSide::Buy);
+        assert_eq!(fill.order_id, "132fb6ae-456b-4654-b4e0-d681ac05cea1");
+        assert_eq!(fill.fee, Some(dec!(0.005) * dec!(400.23) * dec!(5.23512)));
+
+        let done = "{\"type\":\"done\",\"time\":\"2014-11-07T08:19:27.028459Z\",\"product_id\":\"BTC-USD\",\"sequence\":10,\"price\":\"200.2\",\"order_id\":\"d50ec984-77a8-460a-b958-66f114b0de9b\",\"reason\":\"canceled\",\"side\":\"sell\",\"remaining_size\":\"0.2\"}";
+        let order = match serde_json::from_str(done).unwrap() {
+            Response::Done(o) => Order::from_gdax(OrderStatus::Filled, o),
+            r => panic!("Expected done, got {:?}", r),
+        };
+        assert_eq!(order.status, OrderStatus::Cancelled);
+        assert_eq!(order.remaining_size, Some(dec!(0.2)));
+    }
+}
@@ -0,0 +1,80 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+
+use crate::error::{Error, Result};
+
+/// Appends the raw text messages received on a channel's socket to a file, one per line, prefixed
+/// with the time received, e.g. 2023-06-11T16:12:04.936167Z {"type":"ticker",...}. Messages are
+/// written before they are parsed so messages that fail to parse are captured as sent.
+#[derive(Debug)]
+pub struct Recorder {
+    pub path: PathBuf,
+    writer: LineWriter<File>,
+}
+
+impl Recorder {
+    /// Opens the file for appending, creating it if it does not exist.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer: LineWriter::new(file),
+        })
+    }
+
+    pub fn record(&mut self, dt: DateTime<Utc>, text: &str) -> Result<()> {
+        // Exchanges send single line JSON. Any embedded newline would split the record.
+        let text = text.replace('\n', " ");
+        writeln!(self.writer, "{} {}", dt.to_rfc3339(), text)?;
+        Ok(())
+    }
+}
+
+/// Reads a file written by a Recorder into the time each message was received and the message.
+/// The messages can be passed to a replay.
+pub fn read_recording(path: &Path) -> Result<Vec<(DateTime<Utc>, String)>> {
+    let file = File::open(path)?;
+    let mut messages = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let (dt, text) = line
+            .split_once(' ')
+            .ok_or_else(|| Error::InvalidRecording(line.clone()))?;
+        let dt = DateTime::parse_from_rfc3339(dt)
+            .map_err(|_| Error::InvalidRecording(line.clone()))?
+            .with_timezone(&Utc);
+        messages.push((dt, text.to_string()));
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use crate::recorder::{read_recording, Recorder};
+
+    #[test]
+    pub fn recording_round_trip() {
+        let path = std::env::temp_dir().join(format!("agg-ws-{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let now = Utc::now();
+        let mut recorder = Recorder::create(&path).unwrap();
+        recorder.record(now, "{\"event\":\"heartbeat\"}").unwrap();
+        recorder.record(now, "{\"type\":").unwrap();
+        drop(recorder);
+
+        let messages = read_recording(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].0, now);
+        assert_eq!(messages[0].1, "{\"event\":\"heartbeat\"}");
+        assert_eq!(messages[1].1, "{\"type\":");
+    }
+}
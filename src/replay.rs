@@ -0,0 +1,64 @@
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::app::App;
+use crate::client::Channel;
+use crate::error::{Error, Result};
+use crate::websocket::WsEvent;
+
+impl App {
+    /// Feeds recorded raw messages for the channel through the same handlers as a live socket, in
+    /// order and without delay between them. State for the channel is created if it is not
+    /// subscribed. Tapes, candles and books are built from the timestamps the messages carry so a
+    /// replay is deterministic. Messages that fail to handle are forwarded as errors as they would
+    /// be live and do not stop the replay.
+    #[tracing::instrument(skip(self, messages))]
+    pub async fn replay(&mut self, channel: Channel, messages: Vec<String>) -> Result<()> {
+        match self.setup_state(&channel, None) {
+            Ok(_) | Err(Error::ChannelAlreadySubscribed) => {}
+            Err(e) => return Err(e),
+        }
+        tracing::info!("Replaying {} messages.", messages.len());
+        for text in messages.into_iter() {
+            let event = WsEvent::Message(Message::Text(text));
+            self.handle_ws_msg((channel.clone(), Ok(event))).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use tokio::sync::mpsc;
+
+    use crate::app::App;
+    use crate::client::{Channel, ChannelType, ClientConfig, Exchange};
+
+    #[tokio::test]
+    async fn replay_builds_tape_from_recorded_messages() {
+        let (ws_send, _) = mpsc::channel(1);
+        let (app_send, mut app_recv) = mpsc::unbounded_channel();
+        let mut app = App::new(ws_send, Some(app_send), ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Tape,
+            market: "ETH-USD".to_string(),
+        };
+        let ticker = |price: &str, trade_id: i64| {
+            format!("{{\"type\":\"ticker\",\"sequence\":{},\"product_id\":\"ETH-USD\",\"price\":\"{}\",\"side\":\"buy\",\"time\":\"2022-10-19T23:28:22.061769Z\",\"trade_id\":{},\"last_size\":\"11.4396987\"}}", trade_id, price, trade_id)
+        };
+        let messages = vec![
+            ticker("1285.22", 370843401),
+            "{\"type\":".to_string(),
+            ticker("1285.27", 370843402),
+        ];
+        app.replay(channel.clone(), messages).await.unwrap();
+
+        let tapes = app.state.tapes.lock().unwrap();
+        let tape = tapes.get(&channel).unwrap();
+        assert_eq!(tape.len(), 2);
+        assert_eq!(tape.back().unwrap().price, dec!(1285.27));
+        // The truncated message is forwarded as an error
+        assert!(app_recv.try_recv().is_ok());
+    }
+}
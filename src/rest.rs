@@ -0,0 +1,143 @@
+use reqwest::{Client, Proxy};
+use serde_json::Value;
+use tokio::time::Duration;
+use tokio_tungstenite::Connector;
+use url::Url;
+
+use crate::client::{Channel, ChannelType, ClientConfig, Exchange};
+use crate::error::{Error, Result};
+use crate::kraken::KRAKEN_BOOK_DEPTH;
+
+/// Time allowed for a REST request, from connecting to reading the whole response.
+pub const REST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Url of the REST book snapshot for the book channel relative to the exchange REST endpoint.
+/// Kraken books are fetched at the depth of the socket subscription so the checksum covers the
+/// same levels. Bitstamp books are fetched in full as the diff channel sends no snapshot. Returns
+/// UnsupportedChannel for other channel types and exchanges without a REST book.
+pub fn book_url(base: &Url, channel: &Channel) -> Result<Url> {
+    let path = match (&channel.exchange, &channel.channel) {
+        (Exchange::Gdax, ChannelType::Book) => {
+            format!("/products/{}/book?level=2", channel.market)
+        }
+        (Exchange::Kraken, ChannelType::Book) => format!(
+            "/0/public/Depth?pair={}&count={}",
+            channel.market.replace('/', ""),
+            KRAKEN_BOOK_DEPTH
+        ),
+        (Exchange::Bitstamp, ChannelType::Book) => {
+            format!("/api/v2/order_book/{}/", channel.market)
+        }
+        _ => return Err(Error::UnsupportedChannel),
+    };
+    base.join(&path)
+        .map_err(|e| Error::RestRequest(e.to_string()))
+}
+
+/// HTTP client for REST requests made by the App. Requests go through the configured proxy, as
+/// sockets do, and use the configured rustls connector if one is given, otherwise rustls with
+/// the native root certificates.
+pub fn http_client(config: &ClientConfig) -> Result<Client> {
+    let mut builder = Client::builder()
+        .timeout(REST_TIMEOUT)
+        .user_agent(concat!("agg-ws/", env!("CARGO_PKG_VERSION")));
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(Proxy::all(proxy.as_str())?);
+    }
+    if let Some(Connector::Rustls(tls)) = config.tls.as_ref().map(|t| &t.0) {
+        builder = builder.use_preconfigured_tls((**tls).clone());
+    }
+    Ok(builder.build()?)
+}
+
+/// Fetches the url with a GET request and parses the body as JSON. Responses without a 2xx status
+/// are returned as errors.
+pub async fn get_json(client: &Client, url: Url) -> Result<Value> {
+    let response = client.get(url).send().await?.error_for_status()?;
+    Ok(response.json().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use url::Url;
+
+    use crate::client::{Channel, ClientConfig, Exchange};
+    use crate::error::Error;
+    use crate::rest::{book_url, get_json, http_client};
+
+    /// Serves a single request on a local port with the response, returning the url and the
+    /// request head as received.
+    async fn serve(response: &'static str) -> (Url, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        let url = Url::parse(&format!("http://127.0.0.1:{port}/book?level=2")).unwrap();
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn chunked_json_fetched() {
+        let (url, server) = serve(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n\r\n\
+            7\r\n{\"bids\"\r\nc\r\n:[[\"1\",\"2\"]]\r\n1\r\n}\r\n0\r\n\r\n",
+        )
+        .await;
+        let client = http_client(&ClientConfig::default()).unwrap();
+        let value = get_json(&client, url).await.unwrap();
+        assert_eq!(value, json!({"bids": [["1", "2"]]}));
+        let request = server.await.unwrap();
+        assert!(request.starts_with("GET /book?level=2 HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn error_status_returned() {
+        let (url, _server) =
+            serve("HTTP/1.1 404 Not Found\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}")
+                .await;
+        let client = http_client(&ClientConfig::default()).unwrap();
+        let result = get_json(&client, url).await;
+        let status = match result {
+            Err(Error::Http(e)) => e.status(),
+            _ => None,
+        };
+        assert_eq!(status.map(|s| s.as_u16()), Some(404));
+    }
+
+    #[test]
+    fn book_urls() {
+        let gdax = Channel::book(Exchange::Gdax, "BTC-USD");
+        let base = Exchange::Gdax.default_rest_url().unwrap();
+        assert_eq!(
+            book_url(&base, &gdax).unwrap().as_str(),
+            "https://api.exchange.coinbase.com/products/BTC-USD/book?level=2"
+        );
+        let kraken = Channel::book(Exchange::Kraken, "XBT/USD");
+        let base = Exchange::Kraken.default_rest_url().unwrap();
+        assert_eq!(
+            book_url(&base, &kraken).unwrap().as_str(),
+            "https://api.kraken.com/0/public/Depth?pair=XBTUSD&count=100"
+        );
+        let bitstamp = Channel::book(Exchange::Bitstamp, "btcusd");
+        let base = Exchange::Bitstamp.default_rest_url().unwrap();
+        assert_eq!(
+            book_url(&base, &bitstamp).unwrap().as_str(),
+            "https://www.bitstamp.net/api/v2/order_book/btcusd/"
+        );
+        let tape = Channel::tape(Exchange::Gdax, "BTC-USD");
+        assert!(matches!(
+            book_url(&base, &tape),
+            Err(Error::UnsupportedChannel)
+        ));
+    }
+}
@@ -0,0 +1,128 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+use tokio::sync::mpsc;
+
+use crate::book::Book;
+use crate::client::{Channel, ClientResp, ClientRespMsg};
+use crate::error::Result;
+use crate::trades::Trade;
+
+type Receiver = mpsc::UnboundedReceiver<Result<ClientRespMsg>>;
+
+/// Stream of trades from channels streaming to the async client. Every other response read from
+/// the receiver, including errors, is dropped. Ends when the client shuts down.
+#[derive(Debug)]
+pub struct TradeStream<'a> {
+    receiver: &'a mut Receiver,
+}
+
+impl<'a> TradeStream<'a> {
+    pub fn new(receiver: &'a mut Receiver) -> Self {
+        Self { receiver }
+    }
+}
+
+impl<'a> Stream for TradeStream<'a> {
+    type Item = (Channel, Trade);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match self.receiver.poll_recv(cx) {
+                Poll::Ready(Some(Ok(ClientRespMsg {
+                    channel: Some(channel),
+                    resp: ClientResp::Trade(trade),
+                }))) => return Poll::Ready(Some((channel, trade))),
+                Poll::Ready(Some(_)) => continue,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Stream of book updates from channels streaming to the async client. Every other response read
+/// from the receiver, including errors, is dropped. Ends when the client shuts down.
+#[derive(Debug)]
+pub struct BookStream<'a> {
+    receiver: &'a mut Receiver,
+}
+
+impl<'a> BookStream<'a> {
+    pub fn new(receiver: &'a mut Receiver) -> Self {
+        Self { receiver }
+    }
+}
+
+impl<'a> Stream for BookStream<'a> {
+    type Item = (Channel, Arc<Book>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match self.receiver.poll_recv(cx) {
+                Poll::Ready(Some(Ok(ClientRespMsg {
+                    channel: Some(channel),
+                    resp: ClientResp::BookUpdate(book),
+                }))) => return Poll::Ready(Some((channel, book))),
+                Poll::Ready(Some(_)) => continue,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::Utc;
+    use futures::StreamExt;
+    use rust_decimal_macros::dec;
+    use tokio::sync::mpsc;
+
+    use crate::app::TradeSide;
+    use crate::client::{Channel, ChannelType, ClientResp, ClientRespMsg, Exchange};
+    use crate::error::Error;
+    use crate::stream::{BookStream, TradeStream};
+    use crate::trades::Trade;
+
+    #[tokio::test]
+    async fn trade_stream_skips_other_responses() {
+        let (send, mut recv) = mpsc::unbounded_channel();
+        let channel = Channel {
+            exchange: Exchange::Gdax,
+            channel: ChannelType::Tape,
+            market: "BTC-USD".to_string(),
+        };
+        let trade = Trade {
+            price: dec!(27000),
+            size: dec!(0.5),
+            dt: Utc::now(),
+            exchange: Exchange::Gdax,
+            side: TradeSide::Buy,
+            id: None,
+        };
+        let msg = |resp| {
+            Ok(ClientRespMsg {
+                channel: Some(channel.clone()),
+                resp,
+            })
+        };
+        send.send(msg(ClientResp::Subscribed)).unwrap();
+        send.send(Err(Error::UnexpectedShutdown)).unwrap();
+        send.send(msg(ClientResp::Trade(trade))).unwrap();
+        send.send(msg(ClientResp::BookUpdate(Arc::default())))
+            .unwrap();
+        drop(send);
+
+        let trades: Vec<_> = TradeStream::new(&mut recv).collect().await;
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].0, channel);
+        assert_eq!(trades[0].1.price, dec!(27000));
+        // The stream ended with the sender, nothing is left for a book stream
+        assert!(BookStream::new(&mut recv).next().await.is_none());
+    }
+}
@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+
+use crate::client::Exchange;
+
+/// Quote currencies recognised when splitting markets that concatenate base and quote, e.g.
+/// Binance BTCUSDT. Longer quotes are listed before their prefixes so USDT is not split as USD.
+const CONCAT_QUOTES: [&str; 8] = ["FDUSD", "USDT", "USDC", "BUSD", "USD", "EUR", "BTC", "ETH"];
+
+/// Kraken uses legacy ISO 4217-A3 style names for some assets in websocket pairs.
+const KRAKEN_ALIASES: [(&str, &str); 2] = [("BTC", "XBT"), ("DOGE", "XDG")];
+
+/// Exchange agnostic market symbol. Assets are stored uppercase using their common names, e.g.
+/// BTC rather than Kraken's XBT.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Symbol {
+    pub base: String,
+    pub quote: String,
+}
+
+impl Symbol {
+    pub fn new(base: &str, quote: &str) -> Self {
+        Self {
+            base: base.to_uppercase(),
+            quote: quote.to_uppercase(),
+        }
+    }
+
+    /// Exchange specific market string used in subscriptions and sent back on each message as the
+    /// product_id, pair or coin. Hyperliquid markets are perps quoted in USD so only the base is
+    /// used.
+    pub fn market(&self, exchange: &Exchange) -> String {
+        match exchange {
+            Exchange::Gdax | Exchange::Okx | Exchange::CoinbaseAdvanced => {
+                format!("{}-{}", self.base, self.quote)
+            }
+            Exchange::Kraken => {
+                format!("{}/{}", kraken_asset(&self.base), kraken_asset(&self.quote))
+            }
+            // The v2 feed uses common asset names
+            Exchange::KrakenV2 => format!("{}/{}", self.base, self.quote),
+            Exchange::Hyperliquid => self.base.clone(),
+            Exchange::Binance | Exchange::Bybit => format!("{}{}", self.base, self.quote),
+            Exchange::Bitstamp => format!("{}{}", self.base, self.quote).to_lowercase(),
+            // Perpetuals only. Inverse perps are quoted in USD and linear perps name the quote.
+            Exchange::Deribit if self.quote == "USD" => format!("{}-PERPETUAL", self.base),
+            Exchange::Deribit => format!("{}_{}-PERPETUAL", self.base, self.quote),
+        }
+    }
+
+    /// Maps an exchange specific market string back to the symbol. Returns None if the market
+    /// cannot be split into base and quote.
+    pub fn from_market(exchange: &Exchange, market: &str) -> Option<Self> {
+        let market = market.to_uppercase();
+        match exchange {
+            Exchange::Gdax | Exchange::Okx | Exchange::CoinbaseAdvanced => {
+                let (base, quote) = market.split_once('-')?;
+                Some(Self::new(base, quote))
+            }
+            Exchange::Kraken => {
+                let (base, quote) = market.split_once('/')?;
+                Some(Self::new(common_asset(base), common_asset(quote)))
+            }
+            Exchange::KrakenV2 => {
+                let (base, quote) = market.split_once('/')?;
+                Some(Self::new(base, quote))
+            }
+            Exchange::Hyperliquid => Some(Self::new(&market, "USD")),
+            // Dated futures and options have no quote in the name and are not mapped
+            Exchange::Deribit => {
+                let pair = market.strip_suffix("-PERPETUAL")?;
+                match pair.split_once('_') {
+                    Some((base, quote)) => Some(Self::new(base, quote)),
+                    None => Some(Self::new(pair, "USD")),
+                }
+            }
+            Exchange::Binance | Exchange::Bybit | Exchange::Bitstamp => {
+                CONCAT_QUOTES.iter().find_map(|quote| {
+                    market
+                        .strip_suffix(quote)
+                        .filter(|base| !base.is_empty())
+                        .map(|base| Self::new(base, quote))
+                })
+            }
+        }
+    }
+}
+
+fn kraken_asset(asset: &str) -> &str {
+    KRAKEN_ALIASES
+        .iter()
+        .find(|(common, _)| *common == asset)
+        .map_or(asset, |(_, kraken)| kraken)
+}
+
+fn common_asset(asset: &str) -> &str {
+    KRAKEN_ALIASES
+        .iter()
+        .find(|(_, kraken)| *kraken == asset)
+        .map_or(asset, |(common, _)| common)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::{Channel, ChannelType, Exchange};
+    use crate::symbol::Symbol;
+
+    #[test]
+    pub fn kraken_xbt_translation() {
+        let btc = Symbol::new("btc", "usd");
+        assert_eq!(btc.market(&Exchange::Kraken), "XBT/USD");
+        assert_eq!(Symbol::from_market(&Exchange::Kraken, "XBT/USD"), Some(btc));
+        let doge = Symbol::new("DOGE", "USD");
+        assert_eq!(doge.market(&Exchange::Kraken), "XDG/USD");
+        assert_eq!(
+            Symbol::from_market(&Exchange::Kraken, "XDG/USD"),
+            Some(doge)
+        );
+    }
+
+    #[test]
+    pub fn markets_per_exchange() {
+        let btc = Symbol::new("BTC", "USDT");
+        assert_eq!(btc.market(&Exchange::Gdax), "BTC-USDT");
+        assert_eq!(btc.market(&Exchange::Hyperliquid), "BTC");
+        assert_eq!(btc.market(&Exchange::Binance), "BTCUSDT");
+        assert_eq!(btc.market(&Exchange::Okx), "BTC-USDT");
+        assert_eq!(btc.market(&Exchange::Bitstamp), "btcusdt");
+        assert_eq!(btc.market(&Exchange::Deribit), "BTC_USDT-PERPETUAL");
+        assert_eq!(
+            Symbol::from_market(&Exchange::Binance, "BTCUSDT"),
+            Some(btc.clone())
+        );
+        assert_eq!(Symbol::from_market(&Exchange::Bybit, "BTCUSDT"), Some(btc));
+        assert_eq!(
+            Symbol::from_market(&Exchange::Hyperliquid, "BTC"),
+            Some(Symbol::new("BTC", "USD"))
+        );
+        assert_eq!(Symbol::from_market(&Exchange::Gdax, "BTCUSD"), None);
+        assert_eq!(
+            Symbol::from_market(&Exchange::Deribit, "BTC-PERPETUAL"),
+            Some(Symbol::new("BTC", "USD"))
+        );
+        assert_eq!(Symbol::from_market(&Exchange::Deribit, "BTC-29DEC23"), None);
+    }
+
+    #[test]
+    pub fn channel_round_trip() {
+        let btc = Symbol::new("BTC", "USD");
+        let channel = Channel::from_symbol(Exchange::Kraken, &btc, ChannelType::Book);
+        assert_eq!(channel.market, "XBT/USD");
+        assert_eq!(channel.symbol(), Some(btc));
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::{Mutex, MutexGuard};
+
+/// Locking that survives a poisoned mutex. A thread that panics while holding a State lock
+/// poisons it, and unwrapping every later lock would take down the whole client with it. State
+/// maps are only changed through single insert, remove and update calls, so the data behind a
+/// poisoned lock is still whole. The guard is recovered and the poison cleared so it is only
+/// logged once.
+pub trait LockRecover<T> {
+    fn lock_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> LockRecover<T> for Mutex<T> {
+    fn lock_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(|e| {
+            tracing::warn!("Recovered lock poisoned by a panicked thread");
+            self.clear_poison();
+            e.into_inner()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::sync::LockRecover;
+
+    #[test]
+    fn poisoned_lock_recovered() {
+        let lock = Arc::new(Mutex::new(vec![1]));
+        let poisoner = lock.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(lock.is_poisoned());
+        lock.lock_recover().push(2);
+        assert_eq!(*lock.lock_recover(), vec![1, 2]);
+        assert!(!lock.is_poisoned());
+    }
+}
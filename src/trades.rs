@@ -1,20 +1,43 @@
-use chrono::{DateTime, TimeZone, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
+use serde::{Deserialize, Serialize};
 
-use crate::app::App;
-use crate::client::{Channel, Exchange};
+use crate::app::{App, TradeSide};
+use crate::binance::Trade as BinanceTrade;
+use crate::bitstamp::Trade as BitstampTrade;
+use crate::bybit::Trade as BybitTrade;
+use crate::client::{Channel, ChannelType, ClientResp, ClientRespMsg, Exchange};
+use crate::deribit::{base_amount, Trade as DeribitTrade};
 use crate::error::{Error, Result};
-use crate::gdax::Ticker;
+use crate::gdax::{AdvancedTrade, Match as GdaxMatch, Ticker};
 use crate::hyperliquid::Trade as HLTrade;
-use crate::kraken::WsTrade;
+use crate::kraken::{V2Trade as KrakenV2Trade, WsTrade};
+use crate::okx::Trade as OkxTrade;
+use crate::sync::LockRecover;
+
+/// Number of trades kept on each tape and returned by an aggregated tape.
+pub const TAPE_CAPACITY: usize = 100;
 
-#[derive(Debug, Clone)]
+/// Trades averaged by the tape EMA unless another period is given on subscribe.
+pub const DEFAULT_EMA_PERIOD: usize = 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Trade {
-    pub price: String,
-    pub size: String,
+    pub price: Decimal,
+    pub size: Decimal,
     pub dt: DateTime<Utc>,
     pub exchange: Exchange,
+    // Taker side of the trade
+    pub side: TradeSide,
+    // Exchange trade identifier used to dedupe trades. Kraken does not publish trade ids on the
+    // websocket and Hyperliquid ids are the transaction hash, which is shared by every fill of an
+    // order.
+    pub id: Option<String>,
 }
 
 impl TryFrom<Ticker> for Trade {
@@ -26,6 +49,46 @@ impl TryFrom<Ticker> for Trade {
             size: t.size,
             dt: t.time,
             exchange: Exchange::Gdax,
+            side: t.side,
+            id: t.trade_id.map(|id| id.to_string()),
+        })
+    }
+}
+
+impl TryFrom<GdaxMatch> for Trade {
+    type Error = Error;
+
+    /// Coinbase sends the maker side on matches. The trade side is the taker side.
+    fn try_from(m: GdaxMatch) -> Result<Self> {
+        Ok(Self {
+            price: m.price,
+            size: m.size,
+            dt: m.time,
+            exchange: Exchange::Gdax,
+            side: match m.side {
+                TradeSide::Buy => TradeSide::Sell,
+                TradeSide::Sell => TradeSide::Buy,
+            },
+            id: Some(m.trade_id.to_string()),
+        })
+    }
+}
+
+impl TryFrom<AdvancedTrade> for Trade {
+    type Error = Error;
+
+    fn try_from(t: AdvancedTrade) -> Result<Self> {
+        Ok(Self {
+            price: t.price,
+            size: t.size,
+            dt: t.time,
+            exchange: Exchange::CoinbaseAdvanced,
+            side: match t.side.as_str() {
+                "BUY" => TradeSide::Buy,
+                "SELL" => TradeSide::Sell,
+                _ => return Err(Error::InvalidTradeSide(t.side)),
+            },
+            id: Some(t.trade_id),
         })
     }
 }
@@ -35,10 +98,31 @@ impl TryFrom<WsTrade> for Trade {
 
     fn try_from(t: WsTrade) -> Result<Self> {
         Ok(Self {
-            price: t.price.to_string(),
-            size: t.volume.to_string(),
-            dt: Utc.timestamp_nanos((t.time * dec!(1000000000)).to_i64().unwrap()),
+            price: t.price,
+            size: t.volume,
+            dt: from_units(Exchange::Kraken, t.time, dec!(1000000000))?,
             exchange: Exchange::Kraken,
+            side: match t.side.as_str() {
+                "b" => TradeSide::Buy,
+                "s" => TradeSide::Sell,
+                _ => return Err(Error::InvalidTradeSide(t.side)),
+            },
+            id: None,
+        })
+    }
+}
+
+impl TryFrom<KrakenV2Trade> for Trade {
+    type Error = Error;
+
+    fn try_from(t: KrakenV2Trade) -> Result<Self> {
+        Ok(Self {
+            price: t.price,
+            size: t.qty,
+            dt: t.timestamp,
+            exchange: Exchange::KrakenV2,
+            side: t.side,
+            id: Some(t.trade_id.to_string()),
         })
     }
 }
@@ -47,19 +131,324 @@ impl TryFrom<HLTrade> for Trade {
     type Error = Error;
 
     fn try_from(t: HLTrade) -> Result<Self> {
+        Ok(Self {
+            price: Decimal::from_str(&t.px)?,
+            size: Decimal::from_str(&t.sz)?,
+            dt: from_millis(Exchange::Hyperliquid, t.time)?,
+            exchange: Exchange::Hyperliquid,
+            side: match t.side.as_str() {
+                "B" => TradeSide::Buy,
+                "A" => TradeSide::Sell,
+                _ => return Err(Error::InvalidTradeSide(t.side)),
+            },
+            id: Some(t.hash),
+        })
+    }
+}
+
+impl TryFrom<BinanceTrade> for Trade {
+    type Error = Error;
+
+    fn try_from(t: BinanceTrade) -> Result<Self> {
+        Ok(Self {
+            price: t.price,
+            size: t.quantity,
+            dt: from_millis(Exchange::Binance, t.trade_time)?,
+            exchange: Exchange::Binance,
+            // Buyer is the maker when the seller is the taker
+            side: if t.is_buyer_maker {
+                TradeSide::Sell
+            } else {
+                TradeSide::Buy
+            },
+            id: Some(t.trade_id.to_string()),
+        })
+    }
+}
+
+impl TryFrom<OkxTrade> for Trade {
+    type Error = Error;
+
+    fn try_from(t: OkxTrade) -> Result<Self> {
         Ok(Self {
             price: t.px,
             size: t.sz,
-            dt: Utc.timestamp_millis_opt(t.time).unwrap(),
-            exchange: Exchange::Hyperliquid,
+            dt: from_units(Exchange::Okx, t.ts, dec!(1000000))?,
+            exchange: Exchange::Okx,
+            side: match t.side.as_str() {
+                "buy" => TradeSide::Buy,
+                "sell" => TradeSide::Sell,
+                _ => return Err(Error::InvalidTradeSide(t.side)),
+            },
+            id: Some(t.trade_id),
+        })
+    }
+}
+
+impl TryFrom<BybitTrade> for Trade {
+    type Error = Error;
+
+    fn try_from(t: BybitTrade) -> Result<Self> {
+        Ok(Self {
+            price: t.price,
+            size: t.size,
+            dt: from_millis(Exchange::Bybit, t.trade_time)?,
+            exchange: Exchange::Bybit,
+            side: match t.side.as_str() {
+                "Buy" => TradeSide::Buy,
+                "Sell" => TradeSide::Sell,
+                _ => return Err(Error::InvalidTradeSide(t.side)),
+            },
+            id: Some(t.trade_id),
         })
     }
 }
 
+impl TryFrom<BitstampTrade> for Trade {
+    type Error = Error;
+
+    fn try_from(t: BitstampTrade) -> Result<Self> {
+        Ok(Self {
+            price: t.price,
+            size: t.amount,
+            dt: from_units(Exchange::Bitstamp, t.microtimestamp, dec!(1000))?,
+            exchange: Exchange::Bitstamp,
+            side: match t.r#type {
+                0 => TradeSide::Buy,
+                1 => TradeSide::Sell,
+                _ => return Err(Error::InvalidTradeSide(t.r#type.to_string())),
+            },
+            id: Some(t.id.to_string()),
+        })
+    }
+}
+
+impl TryFrom<DeribitTrade> for Trade {
+    type Error = Error;
+
+    fn try_from(t: DeribitTrade) -> Result<Self> {
+        Ok(Self {
+            price: t.price,
+            size: base_amount(&t.instrument_name, t.price, t.amount),
+            dt: from_millis(Exchange::Deribit, t.timestamp)?,
+            exchange: Exchange::Deribit,
+            side: t.direction,
+            id: Some(t.trade_id),
+        })
+    }
+}
+
+/// Volume weighted average price and total volume over a tape.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Vwap {
+    pub vwap: Decimal,
+    pub volume: Decimal,
+}
+
+/// VWAP over the trades in the tape. Returns an error if the tape is empty or has no volume.
+pub fn vwap(tape: &VecDeque<Trade>) -> Result<Vwap> {
+    let mut notional = Decimal::ZERO;
+    let mut volume = Decimal::ZERO;
+    for trade in tape.iter() {
+        notional += trade.price * trade.size;
+        volume += trade.size;
+    }
+    if volume == Decimal::ZERO {
+        return Err(Error::TapeEmpty);
+    }
+    Ok(Vwap {
+        vwap: notional / volume,
+        volume,
+    })
+}
+
+/// Consecutive trades at the same price, side and time collapsed into a single print, as Binance
+/// aggTrades. Size is the total of the trades and count the number collapsed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AggTrade {
+    pub price: Decimal,
+    pub size: Decimal,
+    pub count: usize,
+    pub side: TradeSide,
+    pub dt: DateTime<Utc>,
+    pub exchange: Exchange,
+}
+
+/// Tape with consecutive trades at the same price, side and time collapsed into AggTrades, oldest
+/// first.
+pub fn agg_trades(tape: &VecDeque<Trade>) -> Vec<AggTrade> {
+    let mut aggs: Vec<AggTrade> = Vec::new();
+    for trade in tape.iter() {
+        match aggs.last_mut() {
+            Some(last)
+                if last.price == trade.price && last.side == trade.side && last.dt == trade.dt =>
+            {
+                last.size += trade.size;
+                last.count += 1;
+            }
+            _ => aggs.push(AggTrade {
+                price: trade.price,
+                size: trade.size,
+                count: 1,
+                side: trade.side,
+                dt: trade.dt,
+                exchange: trade.exchange,
+            }),
+        }
+    }
+    aggs
+}
+
+/// Millisecond epoch timestamp as sent by the exchange. Returns an error rather than panicking
+/// if it is out of range.
+pub fn from_millis(exchange: Exchange, ms: i64) -> Result<DateTime<Utc>> {
+    Utc.timestamp_millis_opt(ms)
+        .single()
+        .ok_or_else(|| Error::InvalidTimestamp {
+            exchange,
+            raw: ms.to_string(),
+        })
+}
+
+/// Epoch timestamp sent as a decimal number of units, each `nanos` nanoseconds long. Returns an
+/// error rather than panicking if it is out of range.
+pub fn from_units(exchange: Exchange, ts: Decimal, nanos: Decimal) -> Result<DateTime<Utc>> {
+    ts.checked_mul(nanos)
+        .and_then(|n| n.to_i64())
+        .map(|n| Utc.timestamp_nanos(n))
+        .ok_or_else(|| Error::InvalidTimestamp {
+            exchange,
+            raw: ts.to_string(),
+        })
+}
+
+/// Trades on the tape at or after `since`, oldest first.
+pub fn trades_since(tape: &VecDeque<Trade>, since: DateTime<Utc>) -> VecDeque<Trade> {
+    tape.iter().filter(|t| t.dt >= since).cloned().collect()
+}
+
+/// Trades per second over the `window` ending at `now`. Returns 0.0 for an empty or zero window.
+pub fn trade_rate(tape: &VecDeque<Trade>, now: DateTime<Utc>, window: Duration) -> f64 {
+    let seconds = window.num_milliseconds() as f64 / 1000.0;
+    if seconds <= 0.0 {
+        return 0.0;
+    }
+    let start = now - window;
+    let count = tape.iter().filter(|t| t.dt > start && t.dt <= now).count();
+    count as f64 / seconds
+}
+
+/// Exponential moving average of the trade price, weighting each trade by 2 / (period + 1). Kept
+/// per tape channel so it covers more history than the capped tape.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ema {
+    pub alpha: Decimal,
+    // None until the first trade, which seeds the average
+    pub value: Option<Decimal>,
+}
+
+impl Ema {
+    pub fn new(period: usize) -> Self {
+        Self {
+            alpha: Decimal::TWO / Decimal::from(period.max(1) + 1),
+            value: None,
+        }
+    }
+
+    pub fn update(&mut self, price: Decimal) {
+        self.value = Some(match self.value {
+            Some(v) => v + self.alpha * (price - v),
+            None => price,
+        });
+    }
+}
+
+impl Default for Ema {
+    fn default() -> Self {
+        Self::new(DEFAULT_EMA_PERIOD)
+    }
+}
+
+/// Running totals for a tape channel since it was subscribed. Unlike the tape these are not capped
+/// so they cover every trade received.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TapeStats {
+    pub cumulative_volume: Decimal,
+    pub trade_count: u64,
+    pub first_ts: Option<DateTime<Utc>>,
+    pub last_ts: Option<DateTime<Utc>>,
+}
+
+impl TapeStats {
+    pub fn update(&mut self, trade: &Trade) {
+        self.cumulative_volume += trade.size;
+        self.trade_count += 1;
+        self.first_ts = Some(self.first_ts.map_or(trade.dt, |ts| ts.min(trade.dt)));
+        self.last_ts = Some(self.last_ts.map_or(trade.dt, |ts| ts.max(trade.dt)));
+    }
+}
+
+/// Merges tapes from several channels into one tape sorted by trade time ascending, keeping the
+/// most recent `len` trades. Trades keep their exchange so the merged tape preserves provenance.
+pub fn merge_tapes<'a>(
+    tapes: impl IntoIterator<Item = &'a VecDeque<Trade>>,
+    len: usize,
+) -> VecDeque<Trade> {
+    let mut merged: Vec<Trade> = tapes.into_iter().flatten().cloned().collect();
+    merged.sort_by_key(|t| t.dt);
+    let skip = merged.len().saturating_sub(len);
+    merged.into_iter().skip(skip).collect()
+}
+
 impl App {
+    /// Aggregated tape across the tape channels. See merge_tapes.
+    pub fn tape_agg(&self, channels: &[Channel]) -> Result<VecDeque<Trade>> {
+        let tapes = self.state.tapes.lock_recover();
+        let mut channel_tapes = Vec::with_capacity(channels.len());
+        for channel in channels.iter() {
+            if channel.channel != ChannelType::Tape {
+                return Err(Error::ChannelResponseMismatch);
+            }
+            match tapes.get(channel) {
+                Some(t) => channel_tapes.push(t),
+                None => return Err(Error::ChannelDoesNotExist),
+            }
+        }
+        Ok(merge_tapes(channel_tapes, TAPE_CAPACITY))
+    }
+
+    /// Tapes of the channels cloned under a single lock so every tape is from the same point in
+    /// time.
+    pub fn tapes_batch(&self, channels: &[Channel]) -> Result<HashMap<Channel, VecDeque<Trade>>> {
+        let tapes = self.state.tapes.lock_recover();
+        channels
+            .iter()
+            .map(|channel| match tapes.get(channel) {
+                Some(t) => Ok((channel.clone(), t.clone())),
+                None => Err(Error::ChannelDoesNotExist),
+            })
+            .collect()
+    }
+
     #[tracing::instrument(skip(self))]
     pub async fn insert_trade(&mut self, channel: Channel, trade: Trade) -> Result<()> {
-        let mut tapes = self.state.tapes.lock().unwrap();
+        self.update_candles(&channel, &trade)?;
+        self.state.counters.trade(&channel);
+        if let Some(stats) = self.state.tape_stats.lock_recover().get_mut(&channel) {
+            stats.update(&trade);
+        }
+        if let Some(ema) = self.emas.get_mut(&channel) {
+            ema.update(trade.price);
+        }
+        if self
+            .min_sizes
+            .get(&channel)
+            .is_some_and(|min| trade.size < *min)
+        {
+            return Ok(());
+        }
+        self.publish_trade(&channel, &trade);
+        let mut tapes = self.state.tapes.lock_recover();
         tapes.entry(channel).and_modify(|vd| {
             if vd.len() == vd.capacity() {
                 vd.pop_front();
@@ -70,13 +459,39 @@ impl App {
         });
         Ok(())
     }
+
+    /// Forwards the trade to the async client if trade streaming was requested for the channel.
+    pub fn publish_trade(&self, channel: &Channel, trade: &Trade) {
+        if let (true, Some(sender)) = (self.streams.contains(channel), self.app_sender.as_ref()) {
+            let _ = sender.send(Ok(ClientRespMsg {
+                channel: Some(channel.clone()),
+                resp: ClientResp::Trade(trade.clone()),
+            }));
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use chrono::{TimeZone, Utc};
+    use std::collections::VecDeque;
+
+    use chrono::{Duration, TimeZone, Utc};
     use rust_decimal::prelude::*;
     use rust_decimal_macros::dec;
+    use tokio::sync::mpsc;
+
+    use crate::app::{App, TradeSide};
+    use crate::binance::Trade as BinanceTrade;
+    use crate::bybit::Trade as BybitTrade;
+    use crate::client::{Channel, ChannelType, ClientConfig, ClientResp, Exchange};
+    use crate::gdax::Ticker;
+    use crate::hyperliquid::Trade as HLTrade;
+    use crate::kraken::WsTrade;
+    use crate::okx::Trade as OkxTrade;
+    use crate::trades::{
+        agg_trades, merge_tapes, trade_rate, trades_since, vwap, Ema, TapeStats, Trade,
+        TAPE_CAPACITY,
+    };
 
     #[test]
     pub fn convert_kraken_date() {
@@ -98,4 +513,437 @@ mod tests {
         let dt = Utc.timestamp_millis_opt(hl_date).unwrap();
         println!("Dt: {:?}", dt);
     }
+
+    #[tokio::test]
+    pub async fn stream_trades_to_async_client() {
+        let (ws_send, _) = mpsc::channel(1);
+        let (app_send, mut app_recv) = mpsc::unbounded_channel();
+        let mut app = App::new(ws_send, Some(app_send), ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Hyperliquid,
+            channel: ChannelType::Tape,
+            market: "BTC".to_string(),
+        };
+        app.state
+            .tapes
+            .lock()
+            .unwrap()
+            .insert(channel.clone(), VecDeque::with_capacity(100));
+        let trade = Trade {
+            price: dec!(26433.0),
+            size: dec!(0.03019),
+            dt: Utc.timestamp_millis_opt(1686270879026).unwrap(),
+            exchange: Exchange::Hyperliquid,
+            side: TradeSide::Sell,
+            id: None,
+        };
+
+        // Not streamed until requested
+        app.insert_trade(channel.clone(), trade.clone())
+            .await
+            .unwrap();
+        assert!(app_recv.try_recv().is_err());
+
+        app.streams.insert(channel.clone());
+        app.insert_trade(channel.clone(), trade).await.unwrap();
+        let msg = app_recv.try_recv().unwrap().unwrap();
+        assert_eq!(msg.channel, Some(channel));
+        assert!(matches!(msg.resp, ClientResp::Trade(t) if t.price == dec!(26433.0)));
+    }
+
+    #[test]
+    pub fn gdax_trade_side() {
+        let ticker = Ticker {
+            sequence: 37475248783,
+            product_id: "ETH-USD".to_string(),
+            price: dec!(1285.22),
+            side: TradeSide::Sell,
+            time: Utc.timestamp_millis_opt(1666221702061).unwrap(),
+            size: dec!(11.4396987),
+            trade_id: Some(370843401),
+        };
+        let trade: Trade = ticker.try_into().unwrap();
+        assert_eq!(trade.side, TradeSide::Sell);
+        assert_eq!(trade.id, Some("370843401".to_string()));
+        assert_eq!(trade.price, dec!(1285.22));
+    }
+
+    #[test]
+    pub fn gdax_ticker_decimal_fields() {
+        let ticker = "{\"type\":\"ticker\",\"sequence\":37475248783,\"product_id\":\"ETH-USD\",\"price\":\"1285.22\",\"side\":\"buy\",\"time\":\"2022-10-19T23:28:22.061769Z\",\"trade_id\":370843401,\"last_size\":\"11.4396987\"}";
+        let t: Ticker = serde_json::from_str(ticker).unwrap();
+        assert_eq!(t.price, dec!(1285.22));
+        assert_eq!(t.size, dec!(11.4396987));
+
+        let bad_price = ticker.replace("1285.22", "1285.2x");
+        assert!(serde_json::from_str::<Ticker>(&bad_price).is_err());
+    }
+
+    #[test]
+    pub fn kraken_trade_side() {
+        let ws_trade = |side: &str| WsTrade {
+            price: dec!(25782.9),
+            volume: dec!(1.17100399),
+            time: dec!(1685895944.62050),
+            side: side.to_string(),
+            order_type: "m".to_string(),
+            misc: "".to_string(),
+        };
+        let trade: Trade = ws_trade("b").try_into().unwrap();
+        assert_eq!(trade.side, TradeSide::Buy);
+        let trade: Trade = ws_trade("s").try_into().unwrap();
+        assert_eq!(trade.side, TradeSide::Sell);
+        assert!(Trade::try_from(ws_trade("x")).is_err());
+    }
+
+    #[test]
+    pub fn hyperliquid_trade_side() {
+        let hl_trade = |side: &str| HLTrade {
+            coin: "BTC".to_string(),
+            side: side.to_string(),
+            px: "26433.0".to_string(),
+            sz: "0.03019".to_string(),
+            time: 1686270879026,
+            hash: "0x92c994c577ae6997692104025ad505012a006e4cca395d87c41cccc763aa215e".to_string(),
+        };
+        let trade: Trade = hl_trade("B").try_into().unwrap();
+        assert_eq!(trade.side, TradeSide::Buy);
+        let trade: Trade = hl_trade("A").try_into().unwrap();
+        assert_eq!(trade.side, TradeSide::Sell);
+        assert_eq!(
+            trade.id,
+            Some("0x92c994c577ae6997692104025ad505012a006e4cca395d87c41cccc763aa215e".to_string())
+        );
+    }
+
+    #[test]
+    pub fn binance_trade_side() {
+        let binance_trade = |is_buyer_maker: bool| BinanceTrade {
+            event_type: "trade".to_string(),
+            event_time: 1686270879026,
+            symbol: "BTCUSDT".to_string(),
+            trade_id: 3132465013,
+            price: dec!(26433.01),
+            quantity: dec!(0.00378),
+            trade_time: 1686270879025,
+            is_buyer_maker,
+        };
+        let trade: Trade = binance_trade(false).try_into().unwrap();
+        assert_eq!(trade.side, TradeSide::Buy);
+        let trade: Trade = binance_trade(true).try_into().unwrap();
+        assert_eq!(trade.side, TradeSide::Sell);
+        assert_eq!(trade.id, Some("3132465013".to_string()));
+    }
+
+    #[test]
+    pub fn okx_trade_side() {
+        let okx_trade = |side: &str| OkxTrade {
+            inst_id: "BTC-USDT".to_string(),
+            trade_id: "130639474".to_string(),
+            px: dec!(42219.9),
+            sz: dec!(0.12060306),
+            side: side.to_string(),
+            ts: dec!(1630048897897),
+        };
+        let trade: Trade = okx_trade("buy").try_into().unwrap();
+        assert_eq!(trade.side, TradeSide::Buy);
+        let trade: Trade = okx_trade("sell").try_into().unwrap();
+        assert_eq!(trade.side, TradeSide::Sell);
+    }
+
+    #[test]
+    pub fn bybit_trade_side() {
+        let bybit_trade = |side: &str| BybitTrade {
+            trade_time: 1672304486865,
+            symbol: "BTCUSDT".to_string(),
+            side: side.to_string(),
+            size: dec!(0.001),
+            price: dec!(16578.50),
+            trade_id: "20f43950-d8dd-5b31-9112-a178eb6023af".to_string(),
+        };
+        let trade: Trade = bybit_trade("Buy").try_into().unwrap();
+        assert_eq!(trade.side, TradeSide::Buy);
+        let trade: Trade = bybit_trade("Sell").try_into().unwrap();
+        assert_eq!(trade.side, TradeSide::Sell);
+        assert!(TryInto::<Trade>::try_into(bybit_trade("Unknown")).is_err());
+    }
+
+    #[test]
+    pub fn merge_tapes_sorted_by_time() {
+        let trade = |exchange: Exchange, ts: i64| Trade {
+            price: dec!(100),
+            size: dec!(1),
+            dt: Utc.timestamp_opt(ts, 0).unwrap(),
+            exchange,
+            side: TradeSide::Buy,
+            id: None,
+        };
+        let gdax = VecDeque::from([trade(Exchange::Gdax, 1), trade(Exchange::Gdax, 4)]);
+        let kraken = VecDeque::from([trade(Exchange::Kraken, 2), trade(Exchange::Kraken, 3)]);
+
+        let merged = merge_tapes([&gdax, &kraken], 100);
+        let order: Vec<(Exchange, i64)> = merged
+            .iter()
+            .map(|t| (t.exchange, t.dt.timestamp()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (Exchange::Gdax, 1),
+                (Exchange::Kraken, 2),
+                (Exchange::Kraken, 3),
+                (Exchange::Gdax, 4)
+            ]
+        );
+
+        // Keeps the most recent trades when capped
+        let merged = merge_tapes([&gdax, &kraken], 2);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].dt.timestamp(), 3);
+    }
+
+    #[test]
+    pub fn tape_since_cutoff_inclusive() {
+        let tape: VecDeque<Trade> = (1..=5)
+            .map(|ts| Trade {
+                price: dec!(100),
+                size: dec!(1),
+                dt: Utc.timestamp_opt(ts, 0).unwrap(),
+                exchange: Exchange::Gdax,
+                side: TradeSide::Buy,
+                id: Some(ts.to_string()),
+            })
+            .collect();
+        let since = trades_since(&tape, Utc.timestamp_opt(3, 0).unwrap());
+        let ids: Vec<_> = since.iter().map(|t| t.id.clone().unwrap()).collect();
+        assert_eq!(ids, vec!["3", "4", "5"]);
+        assert!(trades_since(&tape, Utc.timestamp_opt(6, 0).unwrap()).is_empty());
+        assert_eq!(
+            trades_since(&tape, Utc.timestamp_opt(0, 0).unwrap()).len(),
+            5
+        );
+    }
+
+    #[test]
+    pub fn trade_rate_over_window() {
+        let tape: VecDeque<Trade> = [1, 2, 5, 6, 7, 8]
+            .into_iter()
+            .map(|ts| Trade {
+                price: dec!(100),
+                size: dec!(1),
+                dt: Utc.timestamp_opt(ts, 0).unwrap(),
+                exchange: Exchange::Gdax,
+                side: TradeSide::Buy,
+                id: Some(ts.to_string()),
+            })
+            .collect();
+        let now = Utc.timestamp_opt(8, 0).unwrap();
+        // Trades at 5, 6, 7 and 8 fall in the 4 second window (4, 8]
+        assert_eq!(trade_rate(&tape, now, Duration::seconds(4)), 1.0);
+        assert_eq!(trade_rate(&tape, now, Duration::seconds(10)), 0.6);
+        let later = Utc.timestamp_opt(20, 0).unwrap();
+        assert_eq!(trade_rate(&tape, later, Duration::seconds(5)), 0.0);
+        assert_eq!(trade_rate(&tape, now, Duration::zero()), 0.0);
+    }
+
+    #[test]
+    pub fn consecutive_trades_aggregated() {
+        let t0 = Utc.timestamp_opt(1686270879, 0).unwrap();
+        let t1 = t0 + Duration::milliseconds(5);
+        let trade = |price: Decimal, size: Decimal, side: TradeSide, dt| Trade {
+            price,
+            size,
+            dt,
+            exchange: Exchange::Binance,
+            side,
+            id: None,
+        };
+        let tape = VecDeque::from([
+            trade(dec!(100), dec!(1), TradeSide::Buy, t0),
+            trade(dec!(100), dec!(2), TradeSide::Buy, t0),
+            trade(dec!(100), dec!(0.5), TradeSide::Buy, t0),
+            // Same price but the other side
+            trade(dec!(100), dec!(1), TradeSide::Sell, t0),
+            trade(dec!(101), dec!(1), TradeSide::Buy, t0),
+            // Same price and side but printed later
+            trade(dec!(101), dec!(4), TradeSide::Buy, t1),
+            trade(dec!(101), dec!(1), TradeSide::Buy, t1),
+        ]);
+        let aggs = agg_trades(&tape);
+        let summary: Vec<_> = aggs.iter().map(|a| (a.price, a.size, a.count)).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (dec!(100), dec!(3.5), 3),
+                (dec!(100), dec!(1), 1),
+                (dec!(101), dec!(1), 1),
+                (dec!(101), dec!(5), 2),
+            ]
+        );
+        assert_eq!(aggs[1].side, TradeSide::Sell);
+        assert_eq!(aggs[3].dt, t1);
+        assert!(agg_trades(&VecDeque::new()).is_empty());
+    }
+
+    #[test]
+    pub fn vwap_over_tape() {
+        let trade = |price: Decimal, size: Decimal| Trade {
+            price,
+            size,
+            dt: Utc.timestamp_opt(1686270879, 0).unwrap(),
+            exchange: Exchange::Gdax,
+            side: TradeSide::Buy,
+            id: None,
+        };
+        // (100 * 1 + 102 * 3) / 4 = 101.5
+        let tape = VecDeque::from([trade(dec!(100), dec!(1)), trade(dec!(102), dec!(3))]);
+        let v = vwap(&tape).unwrap();
+        assert_eq!(v.vwap, dec!(101.5));
+        assert_eq!(v.volume, dec!(4));
+
+        assert!(vwap(&VecDeque::new()).is_err());
+    }
+
+    #[tokio::test]
+    pub async fn tapes_batch_returns_each_channel() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let btc = Channel::tape(Exchange::Hyperliquid, "BTC");
+        let eth = Channel::tape(Exchange::Hyperliquid, "ETH");
+        for channel in [&btc, &eth] {
+            app.setup_state(channel, None).unwrap();
+        }
+        let trade = Trade {
+            price: dec!(26433.0),
+            size: dec!(0.03019),
+            dt: Utc.timestamp_millis_opt(1686270879026).unwrap(),
+            exchange: Exchange::Hyperliquid,
+            side: TradeSide::Sell,
+            id: None,
+        };
+        app.insert_trade(btc.clone(), trade).await.unwrap();
+
+        let tapes = app.tapes_batch(&[btc.clone(), eth.clone()]).unwrap();
+        assert_eq!(tapes.len(), 2);
+        assert_eq!(tapes[&btc].len(), 1);
+        assert!(tapes[&eth].is_empty());
+        let missing = Channel::tape(Exchange::Hyperliquid, "SOL");
+        assert!(app.tapes_batch(&[btc, missing]).is_err());
+    }
+
+    #[tokio::test]
+    pub async fn ema_converges_to_price() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel::tape(Exchange::Hyperliquid, "BTC");
+        app.setup_state(&channel, None).unwrap();
+        app.emas.insert(channel.clone(), Ema::new(3));
+        assert_eq!(app.emas[&channel].value, None);
+
+        let trade = |price| Trade {
+            price,
+            size: dec!(1),
+            dt: Utc.timestamp_millis_opt(1686270879026).unwrap(),
+            exchange: Exchange::Hyperliquid,
+            side: TradeSide::Buy,
+            id: None,
+        };
+        // Period 3 weights each trade by a half: 10, 15, 17.5, 18.75
+        for price in [dec!(10), dec!(20), dec!(20), dec!(20)] {
+            app.insert_trade(channel.clone(), trade(price))
+                .await
+                .unwrap();
+        }
+        assert_eq!(app.emas[&channel].value, Some(dec!(18.75)));
+        for _ in 0..50 {
+            app.insert_trade(channel.clone(), trade(dec!(20)))
+                .await
+                .unwrap();
+        }
+        let ema = app.emas[&channel].value.unwrap();
+        assert!((dec!(20) - ema).abs() < dec!(0.0000001));
+    }
+
+    #[tokio::test]
+    pub async fn small_trades_left_off_tape() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel::tape(Exchange::Hyperliquid, "BTC");
+        app.setup_state(&channel, None).unwrap();
+        app.min_sizes.insert(channel.clone(), dec!(1));
+        for size in [dec!(0.5), dec!(1), dec!(0.99), dec!(2.5)] {
+            let trade = Trade {
+                price: dec!(26433.0),
+                size,
+                dt: Utc.timestamp_millis_opt(1686270879026).unwrap(),
+                exchange: Exchange::Hyperliquid,
+                side: TradeSide::Buy,
+                id: None,
+            };
+            app.insert_trade(channel.clone(), trade).await.unwrap();
+        }
+
+        let tapes = app.state.tapes.lock().unwrap();
+        let sizes: Vec<_> = tapes[&channel].iter().map(|t| t.size).collect();
+        assert_eq!(sizes, vec![dec!(1), dec!(2.5)]);
+        // Stats still count every trade
+        assert_eq!(
+            app.state.tape_stats.lock().unwrap()[&channel].trade_count,
+            4
+        );
+    }
+
+    #[tokio::test]
+    pub async fn tape_stats_outlive_tape() {
+        let (ws_send, _) = mpsc::channel(1);
+        let mut app = App::new(ws_send, None, ClientConfig::default()).unwrap();
+        let channel = Channel {
+            exchange: Exchange::Hyperliquid,
+            channel: ChannelType::Tape,
+            market: "BTC".to_string(),
+        };
+        app.state
+            .tapes
+            .lock()
+            .unwrap()
+            .insert(channel.clone(), VecDeque::with_capacity(TAPE_CAPACITY));
+        app.state
+            .tape_stats
+            .lock()
+            .unwrap()
+            .insert(channel.clone(), TapeStats::default());
+        for i in 0..150 {
+            let trade = Trade {
+                price: dec!(26433.0),
+                size: dec!(0.5),
+                dt: Utc.timestamp_millis_opt(1686270879026 + i).unwrap(),
+                exchange: Exchange::Hyperliquid,
+                side: TradeSide::Buy,
+                id: None,
+            };
+            app.insert_trade(channel.clone(), trade).await.unwrap();
+        }
+
+        let tape_len = app.state.tapes.lock().unwrap().get(&channel).unwrap().len();
+        let stats = app
+            .state
+            .tape_stats
+            .lock()
+            .unwrap()
+            .get(&channel)
+            .unwrap()
+            .clone();
+        assert_eq!(tape_len, TAPE_CAPACITY);
+        assert_eq!(stats.trade_count, 150);
+        assert!(stats.trade_count as usize > tape_len);
+        assert_eq!(stats.cumulative_volume, dec!(75));
+        assert_eq!(
+            stats.first_ts,
+            Some(Utc.timestamp_millis_opt(1686270879026).unwrap())
+        );
+        assert_eq!(
+            stats.last_ts,
+            Some(Utc.timestamp_millis_opt(1686270879175).unwrap())
+        );
+    }
 }